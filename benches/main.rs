@@ -1,6 +1,6 @@
 #![feature(test)]
 extern crate test;
-use hitome::common::{Settings, StatBlock};
+use hitome::common::{FrameClock, Settings, StatBlock};
 use hitome::fs::FilesystemStats;
 use hitome::tasks::TaskStats;
 use test::Bencher;
@@ -9,12 +9,22 @@ use test::Bencher;
 fn bench_tasks(b: &mut Bencher) {
     let s: Settings = Default::default();
     let mut t = TaskStats::new(&s);
-    b.iter(|| t.update());
+    b.iter(|| t.update(&FrameClock::now()));
 }
 
 #[bench]
 fn bench_filesystems(b: &mut Bencher) {
     let s: Settings = Default::default();
     let mut fs = FilesystemStats::new(&s);
-    b.iter(|| fs.update());
+    b.iter(|| fs.update(&FrameClock::now()));
+}
+
+/// Same as bench_tasks, but without io_uring, to compare both
+#[cfg(feature = "io-uring")]
+#[bench]
+fn bench_tasks_serial(b: &mut Bencher) {
+    let s: Settings = Default::default();
+    let mut t = TaskStats::new(&s);
+    t.disable_io_uring();
+    b.iter(|| t.update(&FrameClock::now()));
 }
@@ -14,8 +14,11 @@
  */
 
 use super::common::*;
+use crate::procroot::ProcRoot;
 use std::collections::BTreeMap;
 use std::fmt;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 
 #[derive(Clone, Copy)]
@@ -29,11 +32,283 @@ struct DevStats {
 
 const SECTOR_SIZE: u64 = 512;
 
+/// Add what a device read and wrote between two samples to its session totals. Skips devices that
+/// were just (re)added, their previous counters are zeroes. Counters that went backwards (the
+/// device was recreated) count as zero for one refresh, the new values are the baseline after that.
+fn add_totals(
+    totals: &mut BTreeMap<String, (Bytes, Bytes)>,
+    kname: &str,
+    prev: &DevStats,
+    cur: &DevStats,
+) {
+    if prev.t == cur.t {
+        return;
+    }
+    let rd = Bytes(cur.read.0.saturating_sub(prev.read.0));
+    let wt = Bytes(cur.written.0.saturating_sub(prev.written.0));
+    match totals.get_mut(kname) {
+        Some(tot) => {
+            tot.0 .0 += rd.0;
+            tot.1 .0 += wt.0;
+        }
+        None => {
+            totals.insert(String::from(kname), (rd, wt));
+        }
+    }
+}
+
+/// (read/s, written/s, pressure) between two samples, or None if the device was just added
+fn rates(s: &(DevStats, DevStats, Stale)) -> Option<(Rate<Bytes>, Rate<Bytes>, Percentage)> {
+    let elapsed = s.1.t - s.0.t;
+    if elapsed.is_zero() {
+        return None;
+    }
+    /* Counters go backwards if a device is recreated under the same name, count that as zero */
+    Some((
+        Rate::new(s.0.read.0, s.1.read.0, elapsed).unwrap_or_default(),
+        Rate::new(s.0.written.0, s.1.written.0, elapsed).unwrap_or_default(),
+        /* Milliseconds spent doing I/O per second, as a percentage */
+        Percentage(s.1.wrt.saturating_sub(s.0.wrt) as f32 / elapsed.as_secs_f32() / 10.0),
+    ))
+}
+
+/// The last two components of the backing file of a loop device, eg. "images/debian.iso". An
+/// error if the device is detached.
+fn loop_backing_file(root: &ProcRoot, sys_block: &Path, kname: &str) -> std::io::Result<String> {
+    let mut buf = String::new();
+    root.read_to_string(sys_block.join(kname).join("loop/backing_file"), &mut buf)?;
+    let path = buf.trim_end();
+    let start = path.rmatch_indices('/').nth(1).map_or(0, |(i, _)| i + 1);
+    Ok(String::from(&path[start..]))
+}
+
+/// With --io-cgroups, name a cgroup's device when it does at least this share of its I/O
+const IO_CGROUP_DOMINANT: f32 = 0.9;
+
+/// Bytes (read, written) so far by a cgroup on a device (major, minor)
+type DevIo = ((u32, u32), u64, u64);
+
+/// The lines of the io.stat of a cgroup, eg. "259:0 rbytes=1234 wbytes=5678 rios=1 wios=2
+/// dbytes=0 dios=0". Lines that don't parse are skipped.
+fn parse_io_stat(stat: &str) -> impl Iterator<Item = DevIo> + '_ {
+    stat.lines().filter_map(|l| {
+        let mut fields = l.split_ascii_whitespace();
+        let (major, minor) = fields.next()?.split_once(':')?;
+        let dev = (major.parse().ok()?, minor.parse().ok()?);
+        let (mut rd, mut wt) = (None, None);
+        for kv in fields {
+            match kv.split_once('=') {
+                Some(("rbytes", v)) => rd = v.parse().ok(),
+                Some(("wbytes", v)) => wt = v.parse().ok(),
+                _ => (),
+            }
+        }
+        Some((dev, rd?, wt?))
+    })
+}
+
+/// Bytes (read, written) by a cgroup between two reads of its io.stat, and the device doing at
+/// least IO_CGROUP_DOMINANT of it if any. Devices that just appeared, or whose counters went
+/// backwards, count as zero.
+fn io_delta(prev: &[DevIo], cur: &[DevIo]) -> (u64, u64, Option<(u32, u32)>) {
+    let (mut rd, mut wt, mut most) = (0, 0, None);
+    for (dev, r, w) in cur.iter() {
+        let (pr, pw) = prev
+            .iter()
+            .find(|p| p.0 == *dev)
+            .map_or((*r, *w), |p| (p.1, p.2));
+        let (r, w) = (r.saturating_sub(pr), w.saturating_sub(pw));
+        rd += r;
+        wt += w;
+        if r + w > most.map_or(0, |(_, n)| n) {
+            most = Some((*dev, r + w));
+        }
+    }
+    let dominant = most
+        .filter(|(_, n)| *n as f32 >= IO_CGROUP_DOMINANT * (rd + wt) as f32)
+        .map(|(dev, _)| dev);
+    (rd, wt, dominant)
+}
+
+/// Call f with the path and io.stat of every leaf cgroup under dir. Parents are skipped, their
+/// io.stat includes their children. So are cgroups without the io controller, they have no
+/// io.stat.
+fn walk_leaf_cgroups(dir: &mut PathBuf, buf: &mut String, f: &mut impl FnMut(&Path, &str)) {
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    let mut leaf = true;
+    for e in entries.flatten() {
+        if !e.file_type().is_ok_and(|t| t.is_dir()) {
+            continue;
+        }
+        leaf = false;
+        dir.push(e.file_name());
+        walk_leaf_cgroups(dir, buf, f);
+        dir.pop();
+    }
+    if !leaf {
+        return;
+    }
+
+    dir.push("io.stat");
+    let ok = read_to_string(&dir, buf).is_ok();
+    dir.pop();
+    if ok {
+        f(dir, buf);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DiskField {
+    Read,
+    Write,
+    Pressure,
+}
+
+impl Field for DiskField {
+    const ALL: &'static [(&'static str, Self)] = &[
+        ("read", DiskField::Read),
+        ("write", DiskField::Write),
+        ("pressure", DiskField::Pressure),
+    ];
+
+    fn heading(&self) -> Heading<'static> {
+        match self {
+            DiskField::Read => Heading::new("READ/s"),
+            DiskField::Write => Heading::new("WRITE/s"),
+            DiskField::Pressure => Heading::with_short("PRESSURE", "PRESS"),
+        }
+    }
+}
+
 pub struct BlockDeviceStats<'a> {
     settings: &'a Settings,
     /* Use a BTreeMap to traverse in sorted order */
     devices: BTreeMap<String, (DevStats, DevStats, Stale)>,
+    /// Bytes (read, written) since hitome started, by kname. Unlike devices, entries are kept when
+    /// a device disappears (suspend, dm reload...), so totals carry on if it comes back.
+    totals: BTreeMap<String, (Bytes, Bytes)>,
+    /// With --disk-loops, kname -> backing file of loop devices, read when they first appear
+    loops: BTreeMap<String, String>,
+    /// Device suspected of holding up writeback, see set_writeback_stall()
+    stall: Option<String>,
+    /// With --io-cgroups, (major, minor) -> kname of all devices, from /proc/diskstats
+    knames: BTreeMap<(u32, u32), String>,
+    /// With --io-cgroups, bytes (read, written) so far by device of leaf cgroups, by path
+    cgroups: BTreeMap<String, (Instant, Vec<DevIo>, Stale)>,
+    /// The cgroups that read and wrote the most since the last refresh: (name, read/s, written/s,
+    /// the device doing most of it)
+    top_cgroups: Vec<(String, Bytes, Bytes, Option<String>)>,
+    cgroup_path: PathBuf,
     buf: String,
+    buf2: String,
+}
+
+impl<'a> BlockDeviceStats<'a> {
+    /// (device, read, written) since hitome started, including devices that disappeared
+    pub fn totals(&self) -> impl Iterator<Item = (&str, Bytes, Bytes)> {
+        self.totals.iter().map(|(k, v)| (k.as_str(), v.0, v.1))
+    }
+
+    /// (device, pressure, written/s) in the last refresh
+    pub fn writes(&self) -> impl Iterator<Item = (&str, Percentage, Bytes)> {
+        self.devices
+            .iter()
+            .filter_map(|(k, s)| rates(s).map(|(_, wt, p)| (k.as_str(), p, wt.0)))
+    }
+
+    /// Bytes read and written per second by all devices
+    pub fn throughput(&self) -> (Bytes, Bytes) {
+        self.devices
+            .values()
+            .filter_map(rates)
+            .fold((Bytes(0), Bytes(0)), |(rd, wt), (r, w, _)| {
+                (Bytes(rd.0 + r.0 .0), Bytes(wt.0 + w.0 .0))
+            })
+    }
+
+    /// Flag a device as the likely culprit of a writeback stall, until the next call
+    pub fn set_writeback_stall(&mut self, kname: Option<String>) {
+        self.stall = kname;
+    }
+
+    /// A label followed by a dimmed kname if there is room in w columns, eg. the backing file of a
+    /// loop device. Keeps the end of the label if it has to be truncated.
+    fn fmt_label(&self, f: &mut fmt::Formatter, w: usize, kname: &str, label: &str) -> fmt::Result {
+        let (lc, kc) = (label.chars().count(), kname.chars().count());
+        if lc + 1 + kc > w {
+            let label: String = label.chars().skip(lc.saturating_sub(w)).collect();
+            return write!(f, "{:>w$}", label);
+        }
+
+        let pad = w - lc - 1 - kc;
+        match self.settings.smart.get() {
+            false => write!(f, "{:pad$}{} {}", "", label, kname),
+            true => write!(f, "{:pad$}{} \x1B[2m{}\x1B[0m", "", label, kname),
+        }
+    }
+}
+
+impl<'a> BlockDeviceStats<'a> {
+    /// With --io-cgroups, measure how much each leaf cgroup read and wrote since the last refresh,
+    /// and keep the busiest ones
+    fn update_cgroups(&mut self, t: Instant) {
+        self.top_cgroups.clear();
+        if !Path::new("/sys/fs/cgroup/cgroup.controllers").exists() {
+            /* Not cgroup v2 */
+            return;
+        }
+
+        for c in self.cgroups.values_mut() {
+            c.2 = Stale(true);
+        }
+
+        let (cgroups, top, knames) = (&mut self.cgroups, &mut self.top_cgroups, &self.knames);
+        self.cgroup_path.clear();
+        self.cgroup_path.push("/sys/fs/cgroup");
+        walk_leaf_cgroups(&mut self.cgroup_path, &mut self.buf2, &mut |path, stat| {
+            let path = match path.to_str() {
+                Some(p) => p,
+                None => return,
+            };
+            let devices: Vec<_> = parse_io_stat(stat).collect();
+            let prev = match cgroups.get_mut(path) {
+                Some(c) => c,
+                None => {
+                    cgroups.insert(String::from(path), (t, devices, Stale(false)));
+                    return;
+                }
+            };
+
+            let (rd, wt, dominant) = io_delta(&prev.1, &devices);
+            let elapsed = t - prev.0;
+            *prev = (t, devices, Stale(false));
+            if rd + wt == 0 {
+                return;
+            }
+
+            let kname = dominant.and_then(|dev| knames.get(&dev).cloned());
+            let rate = |b| Rate::<Bytes>::new(0, b, elapsed).unwrap_or_default().0;
+            let name = path.rsplit('/').next().unwrap_or(path);
+            top.push((String::from(name), rate(rd), rate(wt), kname));
+        });
+
+        self.cgroups.retain(|_, c| c.2 == Stale(false));
+        self.top_cgroups
+            .sort_unstable_by_key(|c| std::cmp::Reverse(c.1 .0 + c.2 .0));
+        self.top_cgroups.truncate(self.settings.io_cgroups.into());
+    }
+
+    /// How many cgroups are shown, none if there aren't at least three columns for them
+    fn cgroup_rows(&self) -> usize {
+        let w = self.settings.colwidth.get() as usize;
+        match self.columns() as usize >= 3 * w + 2 {
+            true => self.top_cgroups.len(),
+            false => 0,
+        }
+    }
 }
 
 impl<'a> StatBlock<'a> for BlockDeviceStats<'a> {
@@ -41,19 +316,31 @@ impl<'a> StatBlock<'a> for BlockDeviceStats<'a> {
         let mut bdev = BlockDeviceStats {
             settings: s,
             devices: BTreeMap::new(),
+            totals: BTreeMap::new(),
+            loops: BTreeMap::new(),
+            stall: None,
+            knames: BTreeMap::new(),
+            cgroups: BTreeMap::new(),
+            top_cgroups: Vec::new(),
+            cgroup_path: PathBuf::new(),
             buf: String::new(),
+            buf2: String::new(),
         };
-        bdev.update();
+        bdev.update(&FrameClock::now());
         bdev
     }
 
-    fn update(&mut self) {
-        match read_to_string("/proc/diskstats", &mut self.buf) {
+    fn update(&mut self, clock: &FrameClock) {
+        match self
+            .settings
+            .proc_root
+            .read_to_string("/proc/diskstats", &mut self.buf)
+        {
             Ok(_) => (),
             _ => return,
         }
 
-        let t = Instant::now();
+        let t = clock.instant();
 
         for bdev in self.devices.values_mut() {
             bdev.2 = Stale(true);
@@ -62,12 +349,32 @@ impl<'a> StatBlock<'a> for BlockDeviceStats<'a> {
         /* https://www.kernel.org/doc/Documentation/iostats.txt */
         for bdev in self.buf.lines() {
             let mut bdev = bdev.split_ascii_whitespace();
-            let kname = bdev.nth(2).unwrap();
+            let (major, minor) = (bdev.next().unwrap(), bdev.next().unwrap());
+            let kname = bdev.next().unwrap();
+
+            /* io.stat names devices by number, including the ones not shown here */
+            if self.settings.io_cgroups > 0 {
+                if let (Ok(major), Ok(minor)) = (major.parse(), minor.parse()) {
+                    if self.knames.get(&(major, minor)).map(|k| k.as_str()) != Some(kname) {
+                        self.knames.insert((major, minor), String::from(kname));
+                    }
+                }
+            }
 
             /* XXX: make this user-configurable */
-            if kname.starts_with("dm-") || kname.starts_with("loop") {
+            if kname.starts_with("dm-") {
                 continue;
             }
+            if kname.starts_with("loop") {
+                if !self.settings.disk_loops {
+                    continue;
+                }
+                /* Unused loop devices never did any I/O: no reads or writes completed */
+                let mut counters = bdev.clone();
+                if counters.next() == Some("0") && counters.nth(3) == Some("0") {
+                    continue;
+                }
+            }
 
             /* Filter out partitions */
             if kname.starts_with("sd") || kname.starts_with("hd") {
@@ -86,6 +393,17 @@ impl<'a> StatBlock<'a> for BlockDeviceStats<'a> {
             let mut ent = match self.devices.get_mut(kname) {
                 Some(v) => v,
                 _ => {
+                    /* XXX: a device detached and reattached to another file without going away
+                     * keeps its old label */
+                    if kname.starts_with("loop") {
+                        let root = &self.settings.proc_root;
+                        match loop_backing_file(root, Path::new("/sys/block"), kname) {
+                            Ok(l) => self.loops.insert(String::from(kname), l),
+                            /* Not read from the remote machine yet, show it next time */
+                            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                            Err(_) => self.loops.remove(kname),
+                        };
+                    }
                     let z = DevStats {
                         t,
                         read: Bytes(0),
@@ -106,16 +424,25 @@ impl<'a> StatBlock<'a> for BlockDeviceStats<'a> {
                 wrt: bdev.nth(3).unwrap().parse::<u64>().unwrap(),
             };
             ent.2 = Stale(false);
+            add_totals(&mut self.totals, kname, &ent.0, &ent.1);
         }
 
         self.devices.retain(|_, v| v.2 == Stale(false));
+        let devices = &self.devices;
+        self.loops.retain(|k, _| devices.contains_key(k));
+
+        if self.settings.io_cgroups > 0 {
+            self.update_cgroups(t);
+        }
     }
 
     fn columns(&self) -> u16 {
         if self.devices.is_empty() {
             0
         } else {
-            4 * self.settings.colwidth.get() + 3
+            let n =
+                self.settings.disk_fields.len() as u16 + 2 * self.settings.disk_totals.get() as u16;
+            (n + 1) * self.settings.colwidth.get() + n
         }
     }
 
@@ -123,7 +450,11 @@ impl<'a> StatBlock<'a> for BlockDeviceStats<'a> {
         if self.devices.is_empty() {
             0
         } else {
-            2 + self.devices.len() as u16
+            let cgroups = match self.cgroup_rows() {
+                0 => 0,
+                n => 1 + n as u16,
+            };
+            2 + self.devices.len() as u16 + self.stall.is_some() as u16 + cgroups
         }
     }
 }
@@ -136,41 +467,335 @@ impl<'a> fmt::Display for BlockDeviceStats<'a> {
 
         let newline = MaybeSmart(Newline(), self.settings);
         let w = self.settings.colwidth.get().into();
-        write!(
-            f,
-            "{} {} {} {}{}",
-            MaybeSmart(Heading("DEVICE"), self.settings),
-            MaybeSmart(Heading("READ/s"), self.settings),
-            MaybeSmart(Heading("WRITE/s"), self.settings),
-            MaybeSmart(Heading("PRESSURE"), self.settings),
-            newline
-        )?;
+        write!(f, "{}", MaybeSmart(Heading::new("DEVICE"), self.settings))?;
+        for field in self.settings.disk_fields.iter() {
+            write!(f, " {}", MaybeSmart(field.heading(), self.settings))?;
+        }
+        if self.settings.disk_totals.get() {
+            write!(
+                f,
+                " {} {}",
+                MaybeSmart(Heading::with_short("RD_TOTAL", "RD_TOT"), self.settings),
+                MaybeSmart(Heading::with_short("WR_TOTAL", "WR_TOT"), self.settings)
+            )?;
+        }
+        write!(f, "{}", newline)?;
 
         for (kname, s) in self.devices.iter() {
-            let t = (s.1.t - s.0.t).as_millis() as u64;
-            if t == 0 {
-                /* Device was just added */
-                continue;
-            }
-            let rd = Bytes(1000 * (s.1.read.0 - s.0.read.0) / t);
-            let wt = Bytes(1000 * (s.1.written.0 - s.0.written.0) / t);
+            let (rd, wt, p) = match rates(s) {
+                Some(r) => r,
+                /* Device was just added, still print it: it is counted in rows() */
+                None => (Rate::default(), Rate::default(), Percentage(0.0)),
+            };
             let p = Threshold {
-                val: Percentage(100.0 * ((s.1.wrt - s.0.wrt) as f32) / (t as f32)),
+                val: p,
                 med: Percentage(50.0),
                 high: Percentage(80.0),
                 crit: Percentage(200.0),
             };
+            match self.loops.get(kname) {
+                Some(l) => self.fmt_label(f, w, kname, l)?,
+                None => write!(f, "{:>w$.w$}", kname)?,
+            }
+            for field in self.settings.disk_fields.iter() {
+                match field {
+                    DiskField::Read => write!(f, " {:>w$}", rd)?,
+                    DiskField::Write => write!(f, " {:>w$}", wt)?,
+                    DiskField::Pressure => write!(f, " {:>w$}", MaybeSmart(p, self.settings))?,
+                }
+            }
+            if self.settings.disk_totals.get() {
+                let (rd, wt) = self.totals.get(kname).copied().unwrap_or_default();
+                write!(f, " {:>w$} {:>w$}", rd, wt)?;
+            }
+            write!(f, "{}", newline)?
+        }
+
+        if let Some(kname) = &self.stall {
+            let c = self.columns() as usize;
+            let msg = format!("{} (writeback stall?)", kname);
+            match self.settings.smart.get() {
+                false => write!(f, "{:>c$.c$}", msg)?,
+                true => write!(
+                    f,
+                    "{}{:>c$.c$}\x1B[0m",
+                    self.settings.theme.get().crit(),
+                    msg
+                )?,
+            }
+            write!(f, "{}", newline)?;
+        }
+
+        if self.cgroup_rows() > 0 {
+            /* Cgroups take all the columns but the last two */
+            let cw = self.columns() as usize - 2 * w - 2;
             write!(
                 f,
-                "{:>w$.w$} {:>w$} {:>w$} {:>w$}{}",
-                kname,
-                rd,
-                wt,
-                MaybeSmart(p, self.settings),
+                "{:>cw$} {} {}{}",
+                MaybeSmart(Heading::new("CGROUP"), self.settings),
+                MaybeSmart(DiskField::Read.heading(), self.settings),
+                MaybeSmart(DiskField::Write.heading(), self.settings),
                 newline
-            )?
+            )?;
+            for (name, rd, wt, kname) in self.top_cgroups.iter() {
+                match kname {
+                    Some(k) => self.fmt_label(f, cw, k, name)?,
+                    None => write!(f, "{:>cw$.cw$}", name)?,
+                }
+                write!(f, " {:>w$} {:>w$}{}", rd, wt, newline)?;
+            }
         }
 
         write!(f, "{}", newline)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn sample(t: Instant, read: u64, written: u64, wrt: u64) -> DevStats {
+        DevStats {
+            t,
+            read: Bytes(read),
+            written: Bytes(written),
+            wrt,
+        }
+    }
+
+    #[test]
+    fn io_stat() {
+        let stat = "259:0 rbytes=1234 wbytes=5678 rios=1 wios=2 dbytes=0 dios=0\n\
+                    8:16 rbytes=0 wbytes=4096 rios=0 wios=1 dbytes=0 dios=0\n";
+        assert_eq!(
+            parse_io_stat(stat).collect::<Vec<_>>(),
+            [((259, 0), 1234, 5678), ((8, 16), 0, 4096)]
+        );
+        /* A cgroup with the io controller that did no I/O yet */
+        assert_eq!(parse_io_stat("").count(), 0);
+        /* Newer kernels may add or reorder keys */
+        assert_eq!(
+            parse_io_stat("253:1 wbytes=2 cost.usage=5 rbytes=1").collect::<Vec<_>>(),
+            [((253, 1), 1, 2)]
+        );
+        /* Lines that don't parse are skipped, the others kept */
+        let stat = "8:0 rbytes=1\n\
+                    sda rbytes=1 wbytes=2\n\
+                    8:x rbytes=1 wbytes=2\n\
+                    8:1 rbytes=-1 wbytes=2\n\
+                    8:2\n\
+                    \n\
+                    8:3 rbytes=3 wbytes=4\n";
+        assert_eq!(parse_io_stat(stat).collect::<Vec<_>>(), [((8, 3), 3, 4)]);
+    }
+
+    #[test]
+    fn io_deltas() {
+        let prev = [((259, 0), 1000, 2000), ((8, 0), 500, 0)];
+        /* All on one device */
+        assert_eq!(
+            io_delta(&prev, &[((259, 0), 1000, 9000), ((8, 0), 500, 0)]),
+            (0, 7000, Some((259, 0)))
+        );
+        /* Split between two */
+        assert_eq!(
+            io_delta(&prev, &[((259, 0), 2000, 2000), ((8, 0), 1000, 0)]),
+            (1500, 0, None)
+        );
+        /* Just at the threshold */
+        assert_eq!(
+            io_delta(&prev, &[((259, 0), 1900, 2000), ((8, 0), 600, 0)]),
+            (1000, 0, Some((259, 0)))
+        );
+        /* Nothing new */
+        assert_eq!(io_delta(&prev, &prev), (0, 0, None));
+        /* A new device counts as zero, like a device whose counters went backwards */
+        assert_eq!(
+            io_delta(
+                &prev,
+                &[((259, 0), 10, 20), ((8, 0), 600, 0), ((8, 16), 1 << 30, 0)]
+            ),
+            (100, 0, Some((8, 0)))
+        );
+    }
+
+    #[test]
+    fn leaf_cgroups() {
+        let root = std::env::temp_dir().join(format!("hitome-cgroups-{}", std::process::id()));
+        let slice = root.join("system.slice");
+        for (dir, stat) in [
+            (&root, Some("8:0 rbytes=100 wbytes=100\n")),
+            (&slice, Some("8:0 rbytes=90 wbytes=90\n")),
+            (
+                &slice.join("nginx.service"),
+                Some("8:0 rbytes=80 wbytes=70\n"),
+            ),
+            (&slice.join("cron.service"), Some("")),
+            /* No io controller */
+            (&root.join("init.scope"), None),
+        ] {
+            std::fs::create_dir_all(dir).unwrap();
+            if let Some(s) = stat {
+                std::fs::write(dir.join("io.stat"), s).unwrap();
+            }
+        }
+        std::fs::write(root.join("cgroup.procs"), "1\n").unwrap();
+
+        let mut found = Vec::new();
+        let mut dir = root.clone();
+        walk_leaf_cgroups(&mut dir, &mut String::new(), &mut |p, stat| {
+            let name = p.strip_prefix(&root).unwrap().to_str().unwrap();
+            found.push((String::from(name), parse_io_stat(stat).collect::<Vec<_>>()));
+        });
+        found.sort();
+        assert_eq!(
+            found,
+            [
+                (String::from("system.slice/cron.service"), vec![]),
+                (
+                    String::from("system.slice/nginx.service"),
+                    vec![((8, 0), 80, 70)]
+                ),
+            ]
+        );
+        assert_eq!(dir, root);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// A fake /sys/block with the given loop devices: (kname, backing file if attached)
+    fn sys_block(name: &str, loops: &[(&str, Option<&str>)]) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("hitome-sys-block-{}-{}", name, std::process::id()));
+        for (kname, backing) in loops {
+            std::fs::create_dir_all(root.join(kname)).unwrap();
+            if let Some(b) = backing {
+                std::fs::create_dir_all(root.join(kname).join("loop")).unwrap();
+                std::fs::write(
+                    root.join(kname).join("loop/backing_file"),
+                    format!("{}\n", b),
+                )
+                .unwrap();
+            }
+        }
+        root
+    }
+
+    #[test]
+    fn loop_names() {
+        let root = sys_block(
+            "names",
+            &[
+                ("loop0", Some("/var/lib/snapd/snaps/core22_1380.snap")),
+                ("loop1", None),
+                ("loop2", Some("/debian.iso")),
+                ("loop3", Some("test.img")),
+            ],
+        );
+        let backing = |k| loop_backing_file(&ProcRoot::default(), &root, k).ok();
+        assert_eq!(backing("loop0").as_deref(), Some("snaps/core22_1380.snap"));
+        /* Detached, or gone */
+        assert_eq!(backing("loop1"), None);
+        assert_eq!(backing("loop9"), None);
+        assert_eq!(backing("loop2").as_deref(), Some("/debian.iso"));
+        assert_eq!(backing("loop3").as_deref(), Some("test.img"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// fmt_label() in w columns
+    struct Label<'b>(&'b BlockDeviceStats<'b>, usize, &'b str);
+
+    impl<'b> fmt::Display for Label<'b> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            self.0.fmt_label(f, self.1, "loop0", self.2)
+        }
+    }
+
+    #[test]
+    fn loop_labels() {
+        let s = Settings::default();
+        let bd = BlockDeviceStats::new(&s);
+        let label = |w, l| format!("{}", Label(&bd, w, l));
+        assert_eq!(label(16, "a/b.iso"), "   a/b.iso loop0");
+        assert_eq!(label(13, "a/b.iso"), "a/b.iso loop0");
+        /* No room for the kname, keep the end of the file name */
+        assert_eq!(label(12, "a/b.iso"), "     a/b.iso");
+        assert_eq!(label(6, "snaps/core.snap"), "e.snap");
+
+        s.smart.set(true);
+        assert_eq!(label(14, "a/b.iso"), " a/b.iso \x1B[2mloop0\x1B[0m");
+    }
+
+    /// (read/s, written/s, pressure) as plain numbers
+    fn plain(s: (DevStats, DevStats)) -> Option<(u64, u64, f32)> {
+        rates(&(s.0, s.1, Stale(false))).map(|(r, w, p)| (r.0 .0, w.0 .0, p.0))
+    }
+
+    #[test]
+    fn totals_across_disappearance() {
+        let t = Instant::now();
+        let at = |s: u64| t + Duration::from_secs(s);
+        let mut totals = BTreeMap::new();
+        let mut add = |prev: DevStats, cur: DevStats| {
+            add_totals(&mut totals, "sdb", &prev, &cur);
+            totals.get("sdb").map(|(r, w): &(Bytes, Bytes)| (r.0, w.0))
+        };
+
+        /* Just added: nothing to compare with yet */
+        let z = sample(at(0), 0, 0, 0);
+        assert_eq!(add(z, sample(at(0), 5000, 7000, 0)), None);
+        assert_eq!(
+            add(sample(at(0), 5000, 7000, 0), sample(at(1), 6000, 9000, 0)),
+            Some((1000, 2000))
+        );
+        assert_eq!(
+            add(sample(at(1), 6000, 9000, 0), sample(at(2), 6500, 9000, 0)),
+            Some((1500, 2000))
+        );
+
+        /* Gone from diskstats over a suspend, back with its counters reset */
+        let z = sample(at(10), 0, 0, 0);
+        assert_eq!(add(z, sample(at(10), 100, 50, 0)), Some((1500, 2000)));
+        assert_eq!(
+            add(sample(at(10), 100, 50, 0), sample(at(11), 400, 250, 0)),
+            Some((1800, 2200))
+        );
+
+        /* Recreated without going away: one refresh counted as zero */
+        assert_eq!(
+            add(sample(at(11), 400, 250, 0), sample(at(12), 30, 20, 0)),
+            Some((1800, 2200))
+        );
+        assert_eq!(
+            add(sample(at(12), 30, 20, 0), sample(at(13), 130, 20, 0)),
+            Some((1900, 2200))
+        );
+    }
+
+    #[test]
+    fn first_sample() {
+        let t = Instant::now();
+        let s = sample(t, 1 << 20, 1 << 20, 100);
+        assert_eq!(plain((s, s)), None);
+    }
+
+    #[test]
+    fn steady() {
+        let t = Instant::now();
+        let (a, b) = (
+            sample(t, 1 << 20, 0, 100),
+            sample(t + Duration::from_millis(500), 2 << 20, 1 << 19, 350),
+        );
+        assert_eq!(plain((a, b)), Some((2 << 20, 1 << 20, 50.0)));
+    }
+
+    #[test]
+    fn recreated_device() {
+        let t = Instant::now();
+        let (a, b) = (
+            sample(t, 1 << 30, 1 << 30, 5000),
+            sample(t + Duration::from_secs(1), 1 << 20, 1 << 31, 10),
+        );
+        assert_eq!(plain((a, b)), Some((0, 1 << 30, 0.0)));
+    }
+}
@@ -13,22 +13,42 @@
  * limitations under the License.
  */
 
+use crate::blockdev::DiskField;
+use crate::cpu::CpuLimits;
+use crate::fs::FsField;
+use crate::network::NetField;
+use crate::procroot::ProcRoot;
+use crate::tasks::{TaskField, TaskSortKey};
 use argh::FromArgs;
+use std::borrow::Cow;
 use std::cell::Cell;
 use std::cmp::Ordering;
 use std::fmt::{Alignment, Display, Formatter, Result};
 use std::fs::File;
 use std::io::Read;
+use std::ops::{Deref, DerefMut};
+use std::time::{Duration, Instant};
 
 const SMART_NEWLINE: &str = "\x1B[0K";
 
-#[derive(FromArgs)]
+pub const MIN_COL_WIDTH: u16 = 8;
+/// Enough for 8 columns, eg. the memory block
+pub const MIN_COLUMNS: u16 = 8 * MIN_COL_WIDTH + 7;
+pub const MIN_ROWS: u16 = 24;
+
+#[derive(FromArgs, Default)]
 /// A very simple, non-interactive system monitor
 pub struct Cli {
     #[argh(option, short = 'c')]
-    /// true/false: use colour and other fancy escape sequences (defaults to guessing based on $TERM)
+    /// true/false: use colour and other fancy escape sequences (defaults to guessing based on $TERM,
+    /// while stdout is a terminal)
     pub colour: Option<bool>,
 
+    #[argh(option)]
+    /// dark/light: the background of the terminal, to pick readable colours (if omitted, ask the
+    /// terminal)
+    pub theme: Option<String>,
+
     #[argh(option)]
     /// width of the terminal window, in characters (if omitted, guess)
     pub columns: Option<u16>,
@@ -37,6 +57,11 @@ pub struct Cli {
     /// height of the terminal window, in lines (if omitted, guess)
     pub rows: Option<u16>,
 
+    #[argh(option)]
+    /// always show this many tasks, whatever the height of the terminal (the output scrolls if
+    /// it doesn't fit)
+    pub tasks: Option<u16>,
+
     #[argh(option, short = 'w')]
     /// the width of columns, in characters
     pub column_width: Option<u16>,
@@ -44,83 +69,832 @@ pub struct Cli {
     #[argh(option, short = 'i', default = "2000")]
     /// refresh interval in milliseconds
     pub refresh_interval: u64,
+
+    #[argh(switch)]
+    /// always show space reserved for root in the filesystem block
+    pub fs_verbose: bool,
+
+    #[argh(switch)]
+    /// panic if a block doesn't print as many rows and columns as it should
+    pub check_layout: bool,
+
+    #[argh(switch)]
+    /// count warnings and errors logged by the kernel (needs access to /dev/kmsg)
+    pub kmsg: bool,
+
+    #[argh(switch)]
+    /// print timestamps in UTC instead of local time
+    pub utc: bool,
+
+    #[argh(option)]
+    /// warn when more tasks than this are in D state for two refreshes (defaults to the number
+    /// of CPUs hitome's cgroup may use)
+    pub dstate_threshold: Option<u16>,
+
+    #[argh(option)]
+    /// comma separated columns of the block device table (read,write,pressure)
+    pub disk_fields: Option<String>,
+
+    #[argh(option, default = "0")]
+    /// show the cgroups (eg. systemd services) reading and writing the most under the block
+    /// devices, at most this many (cgroup v2 only)
+    pub io_cgroups: u16,
+
+    #[argh(option)]
+    /// comma separated columns of the network table (rx,tx)
+    pub net_fields: Option<String>,
+
+    #[argh(option, default = "0")]
+    /// show the remote addresses with the most established TCP connections, at most this many
+    pub net_talkers: u16,
+
+    #[argh(option)]
+    /// comma separated columns of the filesystem table (usage,used,avail)
+    pub fs_fields: Option<String>,
+
+    #[argh(option)]
+    /// comma separated columns of the task table
+    /// (pid,user,tty,state,cpu,throttle,lastcpu,wait,rss,gpu,age,oom,threads,csw,read,write,cmd)
+    pub task_fields: Option<String>,
+
+    #[argh(switch)]
+    /// show how much each task reads and writes per second (only readable for your own tasks
+    /// without CAP_SYS_PTRACE), same as adding read,write to --task-fields
+    pub task_io: bool,
+
+    #[argh(switch)]
+    /// show how many times per second each task leaves a CPU, waiting or preempted, same as
+    /// adding csw to --task-fields
+    pub task_csw: bool,
+
+    #[argh(option)]
+    /// state/cpu/mem: what to sort tasks by, state puts tasks in D state first then sorts by cpu
+    /// (defaults to state; 's', 'c' and 'm' change it)
+    pub sort_tasks: Option<String>,
+
+    #[argh(switch)]
+    /// use denser versions of the memory, pressure, cpu, hwmon and filesystem blocks, for small
+    /// terminals
+    pub compact: bool,
+
+    #[argh(switch)]
+    /// also show how much kernel samepage merging shares, and how often it scans, in the memory
+    /// block
+    pub mem_detail: bool,
+
+    #[argh(switch)]
+    /// also show time spent handling hardware and software interrupts in the cpu block
+    pub cpu_detail: bool,
+
+    #[argh(option)]
+    /// ascii/blocks: how to draw the usage of each CPU, blocks uses Unicode block elements when
+    /// colours are on (defaults to ascii)
+    pub cpu_style: Option<String>,
+
+    #[argh(switch)]
+    /// separate thousands with commas in long byte and power values, when they fit
+    pub group_digits: bool,
+
+    #[argh(switch)]
+    /// time how long each block takes to update and print, show the slowest one on the last row
+    /// ('p' or quitting shows all of them)
+    pub profile: bool,
+
+    #[argh(switch)]
+    /// show how much was read from and written to block devices since hitome started ('t'
+    /// toggles)
+    pub disk_totals: bool,
+
+    #[argh(switch)]
+    /// show loop devices in the block device table, labeled by their backing file
+    pub disk_loops: bool,
+
+    #[argh(switch)]
+    /// start with kernel threads hidden from the task list ('k' toggles)
+    pub hide_kthreads: bool,
+
+    #[argh(switch)]
+    /// hide hitome itself from the task list, and show its CPU usage in the task list heading
+    /// instead
+    pub hide_self: bool,
+
+    #[argh(switch)]
+    /// don't colour PIDs by owner: dim for root, cyan for users other than yours
+    pub no_task_colours: bool,
+
+    #[argh(switch)]
+    /// show task CPU% relative to all CPUs instead of a single one
+    pub cpu_total: bool,
+
+    #[argh(option)]
+    /// comma separated task CPU% above which to colour it in yellow, red and magenta (defaults
+    /// to 40,60,80)
+    pub task_cpu_thresholds: Option<String>,
+
+    #[argh(option)]
+    /// only show tasks whose name or command line contains this string ('/' edits)
+    pub task_filter: Option<String>,
+
+    #[argh(option)]
+    /// always show the task with this PID in the task list, even when idle (can be repeated)
+    pub watch_pid: Vec<u32>,
+
+    #[argh(option)]
+    /// show the value of this environment variable before the command line of tasks, eg. to
+    /// tell apart services running the same program
+    pub task_tag: Option<String>,
+
+    #[argh(switch)]
+    /// show the cgroup of tasks instead of their command line, eg. their systemd unit, or the
+    /// container they run in before their command line
+    pub show_unit: bool,
+
+    #[argh(switch)]
+    /// always list zombie tasks at the bottom of the task list, with their parent PID
+    pub show_zombies: bool,
+
+    #[argh(switch)]
+    /// show tasks sharing the same name as one row, with their summed CPU usage
+    pub group_tasks: bool,
+
+    #[argh(switch)]
+    /// show tasks under their parent, with the parents of several of them, like a pruned pstree
+    pub task_tree: bool,
+
+    #[argh(switch)]
+    /// let long command lines continue on a second row in the task list
+    pub task_wrap: bool,
+
+    #[argh(switch)]
+    /// show wear and spare capacity of NVMe drives (needs root, and the nvme-health feature)
+    pub nvme_health: bool,
+
+    #[argh(switch)]
+    /// print a one line summary for tmux's status-right and exit
+    pub tmux: bool,
+
+    #[argh(option)]
+    /// monitor this host instead, eg. user@host, over ssh (nothing needs to be installed there)
+    pub remote: Option<String>,
+
+    #[argh(option)]
+    /// lower hitome's own CPU priority to this niceness
+    pub nice: Option<i32>,
+
+    #[argh(switch)]
+    /// only let hitome run when the CPUs have nothing else to do (SCHED_IDLE)
+    pub idle_sched: bool,
+
+    #[argh(option)]
+    /// only let hitome run on these CPUs, eg. 0-3,8
+    pub affinity: Option<String>,
+
+    #[argh(switch)]
+    /// only let hitome do I/O when the disks have nothing else to do (idle I/O class)
+    pub idle_io: bool,
+
+    #[argh(option, short = 'n')]
+    /// exit after this many refreshes
+    pub iterations: Option<u64>,
+
+    #[argh(switch)]
+    /// with --iterations, browse the printed refreshes once done
+    pub review: bool,
+
+    #[argh(option)]
+    /// when exiting, write peaks, totals and alerts of the session as JSON to this file (- for
+    /// stderr)
+    pub summary: Option<String>,
+
+    #[argh(switch)]
+    /// print the fields written by --summary as JSON and exit
+    pub describe: bool,
+}
+
+/// How the CPU block draws the usage of each CPU: X/O/o/. or ▁ to █
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd)]
+pub enum CpuStyle {
+    Ascii,
+    Blocks,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Theme {
+    Dark,
+    Light,
+    /// tmux's #[...] markup instead of escape sequences, for --tmux
+    Tmux,
+}
+
+impl Theme {
+    /// Pick a theme from the relative luminance of the background, between 0 and 1
+    pub fn for_luminance(l: f32) -> Theme {
+        match l > 0.5 {
+            true => Theme::Light,
+            false => Theme::Dark,
+        }
+    }
+
+    /// Escape sequence for values worth a look; bright yellow is unreadable on light backgrounds
+    pub fn med(self) -> &'static str {
+        match self {
+            Theme::Dark => "\x1B[1;93m",
+            Theme::Light => "\x1B[1;33m",
+            Theme::Tmux => "#[fg=yellow,bold]",
+        }
+    }
+
+    pub fn high(self) -> &'static str {
+        match self {
+            Theme::Dark => "\x1B[1;91m",
+            Theme::Light => "\x1B[1;31m",
+            Theme::Tmux => "#[fg=red,bold]",
+        }
+    }
+
+    pub fn crit(self) -> &'static str {
+        match self {
+            Theme::Dark => "\x1B[1;95m",
+            Theme::Light => "\x1B[1;35m",
+            Theme::Tmux => "#[fg=magenta,bold]",
+        }
+    }
+
+    /// Escape sequence for things that just appeared, eg. new tasks
+    pub fn fresh(self) -> &'static str {
+        match self {
+            Theme::Dark => "\x1B[1;92m",
+            Theme::Light => "\x1B[1;32m",
+            Theme::Tmux => "#[fg=green,bold]",
+        }
+    }
+
+    /// Go back to the default colours after any of the above
+    pub fn reset(self) -> &'static str {
+        match self {
+            Theme::Dark | Theme::Light => "\x1B[0m",
+            Theme::Tmux => "#[default]",
+        }
+    }
 }
 
 pub struct Settings {
-    pub smart: bool,
+    pub smart: Cell<bool>,
+    /// Guessed from $TERM: smart is then only used while stdout is a terminal, which can change
+    /// eg. when reattaching a tmux session from elsewhere
+    pub auto_smart: bool,
     pub refresh: u64,
+    pub fs_verbose: bool,
+    pub check_layout: bool,
+    pub kmsg: bool,
+    pub utc: bool,
+    pub dstate_threshold: u16,
+    pub disk_fields: Vec<DiskField>,
+    pub io_cgroups: u16,
+    pub net_fields: Vec<NetField>,
+    pub net_talkers: u16,
+    pub fs_fields: Vec<FsField>,
+    pub task_fields: Vec<TaskField>,
+    pub sort_tasks: TaskSortKey,
+    pub task_wrap: bool,
+    pub task_tag: Option<String>,
+    pub show_unit: bool,
+    pub show_zombies: bool,
+    pub hide_kthreads: bool,
+    pub hide_self: bool,
+    /// Colour PIDs by owner, unless --no-task-colours
+    pub task_colours: bool,
+    pub cpu_total: bool,
+    /// Task CPU% colour thresholds: (med, high, crit)
+    pub task_cpu_thresholds: (u16, u16, u16),
+    pub task_filter: Option<String>,
+    pub watch_pids: Vec<u32>,
+    pub group_tasks: bool,
+    pub task_tree: bool,
+    pub nvme_health: bool,
+    pub compact: bool,
+    /// KSM breakdown rows in the memory block
+    pub mem_detail: bool,
+    /// IRQ and SIRQ rows in the CPU block
+    pub cpu_detail: bool,
+    pub cpu_style: CpuStyle,
+    pub group_digits: bool,
+    pub profile: bool,
     pub auto_colwidth: bool,
     pub auto_maxcols: bool,
     pub auto_maxrows: bool,
+    pub auto_theme: bool,
+    /// With --tasks, the task list doesn't shrink or grow with the terminal
+    pub tasks: Option<u16>,
     /* These settings can change at runtime, usage of a Cell required to provide interior
      * mutability */
     pub maxcols: Cell<u16>,
+    pub disk_totals: Cell<bool>,
+    pub disk_loops: bool,
     pub maxrows: Cell<u16>,
     pub colwidth: Cell<u16>,
+    /// Guessed after raw input is enabled, unless --theme was given
+    pub theme: Cell<Theme>,
+    /// Measured time between the last two refreshes, longer than refresh when hitome can't keep up
+    pub frame_interval: Cell<Duration>,
+    /// Where /proc and /sys are read from, see --remote
+    pub proc_root: ProcRoot,
+}
+
+/// Parse a --*-fields option, or record an error message listing valid fields
+fn fields_or_error<F: Field>(
+    option: &str,
+    list: &Option<String>,
+    errors: &mut Vec<String>,
+) -> Vec<F> {
+    match list {
+        None => all_fields(),
+        Some(list) => parse_fields(list).unwrap_or_else(|e| {
+            errors.push(format!("{}: {}", option, e));
+            all_fields()
+        }),
+    }
+}
+
+/// Parse three increasing comma separated numbers, up to max
+fn thresholds(list: &str, max: u16) -> std::result::Result<(u16, u16, u16), String> {
+    let v = list
+        .split(',')
+        .map(|t| t.trim().parse::<u16>())
+        .collect::<std::result::Result<Vec<u16>, _>>()
+        .map_err(|e| format!("{} in {}", e, list))?;
+    match v[..] {
+        [med, high, crit] if med < high && high < crit && crit <= max => Ok((med, high, crit)),
+        [_, _, _] => Err(format!("{} must be increasing, and at most {}", list, max)),
+        _ => Err(format!("expected three values, got {}", list)),
+    }
+}
+
+impl Settings {
+    /// Check the command line for values and combinations that can't work, and build Settings from
+    /// it. Options that are merely useless together are pushed to warnings.
+    pub fn from_cli(
+        cli: &Cli,
+        warnings: &mut Vec<String>,
+    ) -> std::result::Result<Settings, Vec<String>> {
+        let mut errors = Vec::new();
+
+        if let Some(c) = cli.columns.filter(|c| *c < MIN_COLUMNS) {
+            errors.push(format!(
+                "--columns: {} is too narrow, hitome needs at least {}",
+                c, MIN_COLUMNS
+            ));
+        }
+        if let Some(r) = cli.rows.filter(|r| *r < MIN_ROWS) {
+            errors.push(format!(
+                "--rows: {} is too short, hitome needs at least {}",
+                r, MIN_ROWS
+            ));
+        }
+        if let Some(w) = cli.column_width.filter(|w| *w < MIN_COL_WIDTH) {
+            errors.push(format!(
+                "--column-width: {} is too narrow, use at least {}",
+                w, MIN_COL_WIDTH
+            ));
+        }
+        if let (Some(c), Some(w)) = (cli.columns, cli.column_width) {
+            if 8 * w + 7 > c {
+                warnings.push(format!(
+                    "--column-width: 8 columns of {} don't fit in --columns {}",
+                    w, c
+                ));
+            }
+        }
+        let sort_tasks = match cli.sort_tasks.as_deref() {
+            None | Some("state") => TaskSortKey::State,
+            Some("cpu") => TaskSortKey::Cpu,
+            Some("mem") => TaskSortKey::Memory,
+            Some(k) => {
+                errors.push(format!(
+                    "--sort-tasks: unknown key {}, use state, cpu or mem",
+                    k
+                ));
+                TaskSortKey::State
+            }
+        };
+        let theme = match cli.theme.as_deref() {
+            _ if cli.tmux => Theme::Tmux,
+            None | Some("dark") => Theme::Dark,
+            Some("light") => Theme::Light,
+            Some(t) => {
+                errors.push(format!("--theme: unknown theme {}, use dark or light", t));
+                Theme::Dark
+            }
+        };
+        let cpu_style = match cli.cpu_style.as_deref() {
+            None | Some("ascii") => CpuStyle::Ascii,
+            Some("blocks") => CpuStyle::Blocks,
+            Some(s) => {
+                errors.push(format!(
+                    "--cpu-style: unknown style {}, use ascii or blocks",
+                    s
+                ));
+                CpuStyle::Ascii
+            }
+        };
+        if cli.tasks == Some(0) {
+            errors.push(String::from("--tasks: must be at least 1"));
+        }
+        if cli.refresh_interval == 0 {
+            errors.push(String::from("--refresh-interval: must be at least 1ms"));
+        }
+        if cli.iterations == Some(0) {
+            errors.push(String::from("--iterations: must be at least 1"));
+        }
+        if cli.review && cli.iterations.is_none() {
+            errors.push(String::from(
+                "--review: also needs --iterations, or there is nothing to review",
+            ));
+        }
+        /* With --cpu-total, 100% is all CPUs; the CPUs of a remote host aren't known yet */
+        let max_cpu = match (cli.cpu_total, &cli.remote) {
+            (true, _) => 100,
+            (false, None) => online_cpus().saturating_mul(100),
+            (false, Some(_)) => u16::MAX,
+        };
+        let task_cpu_thresholds = match &cli.task_cpu_thresholds {
+            None => (40, 60, 80),
+            Some(list) => thresholds(list, max_cpu).unwrap_or_else(|e| {
+                errors.push(format!("--task-cpu-thresholds: {}", e));
+                (40, 60, 80)
+            }),
+        };
+        if cli.nvme_health && !cfg!(feature = "nvme-health") {
+            errors.push(String::from(
+                "--nvme-health: hitome was built without the nvme-health feature",
+            ));
+        }
+        if cli.remote.is_some() {
+            let local = [
+                ("--kmsg", cli.kmsg),
+                ("--io-cgroups", cli.io_cgroups > 0),
+                ("--nvme-health", cli.nvme_health),
+                ("--tmux", cli.tmux),
+                ("--hide-self", cli.hide_self),
+            ];
+            for (option, _) in local.iter().filter(|(_, set)| *set) {
+                errors.push(format!(
+                    "{}: only works on this machine, not with --remote",
+                    option
+                ));
+            }
+        }
+
+        let disk_fields = fields_or_error("--disk-fields", &cli.disk_fields, &mut errors);
+        let net_fields = fields_or_error("--net-fields", &cli.net_fields, &mut errors);
+        let fs_fields: Vec<FsField> = fields_or_error("--fs-fields", &cli.fs_fields, &mut errors);
+        let mut task_fields: Vec<TaskField> =
+            fields_or_error("--task-fields", &cli.task_fields, &mut errors);
+        if cli.task_fields.is_some() && task_fields.iter().any(|f| !f.available()) {
+            warnings.push(String::from(
+                "--task-fields: wait needs a kernel with CONFIG_SCHED_INFO, hiding it",
+            ));
+        }
+        task_fields.retain(|f| f.available());
+        if cli.task_fields.is_none() && !cli.task_io {
+            task_fields.retain(|f| !f.io());
+        }
+        if cli.task_fields.is_none() {
+            task_fields.retain(|f| !f.opt_in());
+        }
+        if cli.task_io {
+            let at = task_fields
+                .iter()
+                .position(|f| *f == TaskField::Cmd)
+                .unwrap_or(task_fields.len());
+            for f in [TaskField::Write, TaskField::Read] {
+                if !task_fields.contains(&f) {
+                    task_fields.insert(at, f);
+                }
+            }
+        }
+        if cli.task_csw && !task_fields.contains(&TaskField::Csw) {
+            let at = task_fields
+                .iter()
+                .position(|f| *f == TaskField::Cmd)
+                .unwrap_or(task_fields.len());
+            task_fields.insert(at, TaskField::Csw);
+        }
+        if task_fields.is_empty() {
+            errors.push(String::from("--task-fields: no field left to show"));
+        }
+        if cli.task_wrap && !task_fields.contains(&TaskField::Cmd) {
+            warnings.push(String::from(
+                "--task-wrap: does nothing without the cmd field in --task-fields; remove one",
+            ));
+        }
+        if cli.task_tree && cli.group_tasks {
+            warnings.push(String::from(
+                "--task-tree: does nothing with --group-tasks; remove one",
+            ));
+        }
+        if cli.fs_verbose && !fs_fields.contains(&FsField::Used) {
+            warnings.push(String::from(
+                "--fs-verbose: does nothing without the used field in --fs-fields; remove one",
+            ));
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        /* Without --column-width, it is only guessed when guessing --columns too */
+        let colwidth = match (cli.column_width, cli.columns) {
+            (Some(w), _) => w,
+            (None, Some(c)) => ((c - 7) / 8).clamp(MIN_COL_WIDTH, 10),
+            (None, None) => 0,
+        };
+
+        let smart = cli
+            .colour
+            .unwrap_or_else(|| match std::env::var_os("TERM") {
+                /* tmux doesn't run status commands in a terminal, but understands the markup */
+                _ if cli.tmux => true,
+                Some(val) => val != "dumb",
+                None => false,
+            });
+
+        Ok(Settings {
+            smart: Cell::new(smart),
+            auto_smart: cli.colour.is_none() && smart,
+            refresh: cli.refresh_interval,
+            fs_verbose: cli.fs_verbose,
+            check_layout: cli.check_layout,
+            kmsg: cli.kmsg,
+            utc: cli.utc,
+            dstate_threshold: cli
+                .dstate_threshold
+                .unwrap_or_else(|| CpuLimits::read().effective_cpus()),
+            disk_fields,
+            net_fields,
+            io_cgroups: cli.io_cgroups,
+            net_talkers: cli.net_talkers,
+            fs_fields,
+            task_fields,
+            sort_tasks,
+            task_wrap: cli.task_wrap,
+            task_tag: cli.task_tag.clone(),
+            show_unit: cli.show_unit,
+            show_zombies: cli.show_zombies,
+            hide_kthreads: cli.hide_kthreads,
+            hide_self: cli.hide_self,
+            task_colours: !cli.no_task_colours,
+            cpu_total: cli.cpu_total,
+            task_cpu_thresholds,
+            task_filter: cli.task_filter.clone(),
+            watch_pids: cli.watch_pid.clone(),
+            group_tasks: cli.group_tasks,
+            task_tree: cli.task_tree,
+            nvme_health: cli.nvme_health,
+            compact: cli.compact,
+            mem_detail: cli.mem_detail,
+            cpu_detail: cli.cpu_detail,
+            cpu_style,
+            group_digits: cli.group_digits,
+            profile: cli.profile,
+            auto_colwidth: cli.column_width.is_none(),
+            auto_maxcols: cli.columns.is_none(),
+            auto_maxrows: cli.rows.is_none(),
+            auto_theme: cli.theme.is_none(),
+            tasks: cli.tasks,
+            maxcols: Cell::new(cli.columns.unwrap_or(0)),
+            disk_totals: Cell::new(cli.disk_totals),
+            disk_loops: cli.disk_loops,
+            maxrows: Cell::new(cli.rows.unwrap_or(0)),
+            colwidth: Cell::new(colwidth),
+            theme: Cell::new(theme),
+            frame_interval: Cell::new(Duration::from_millis(cli.refresh_interval)),
+            proc_root: ProcRoot::default(),
+        })
+    }
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Settings {
-            smart: false,
+            smart: Cell::new(false),
+            auto_smart: false,
             refresh: 2000,
+            fs_verbose: false,
+            check_layout: false,
+            kmsg: false,
+            utc: false,
+            dstate_threshold: online_cpus(),
+            disk_fields: all_fields(),
+            net_fields: all_fields(),
+            io_cgroups: 0,
+            net_talkers: 0,
+            fs_fields: all_fields(),
+            task_fields: all_fields()
+                .into_iter()
+                .filter(|f: &TaskField| !f.io() && !f.opt_in())
+                .collect(),
+            sort_tasks: TaskSortKey::State,
+            task_wrap: false,
+            task_tag: None,
+            show_unit: false,
+            show_zombies: false,
+            hide_kthreads: false,
+            hide_self: false,
+            task_colours: true,
+            cpu_total: false,
+            task_cpu_thresholds: (40, 60, 80),
+            task_filter: None,
+            watch_pids: Vec::new(),
+            group_tasks: false,
+            task_tree: false,
+            nvme_health: false,
+            compact: false,
+            mem_detail: false,
+            cpu_detail: false,
+            cpu_style: CpuStyle::Ascii,
+            group_digits: false,
+            profile: false,
             auto_colwidth: false,
             auto_maxcols: false,
             auto_maxrows: false,
+            auto_theme: false,
+            tasks: None,
             maxcols: Cell::new(120),
+            disk_totals: Cell::new(false),
+            disk_loops: false,
             maxrows: Cell::new(50),
             colwidth: Cell::new(10),
+            theme: Cell::new(Theme::Dark),
+            frame_interval: Cell::new(Duration::from_millis(2000)),
+            proc_root: ProcRoot::default(),
         }
     }
 }
 
+/// A column of a table, that can be chosen with the --*-fields options
+pub trait Field: Copy + 'static {
+    /// Every field and its name, in default order
+    const ALL: &'static [(&'static str, Self)];
+
+    fn heading(&self) -> Heading<'static>;
+}
+
+pub fn all_fields<F: Field>() -> Vec<F> {
+    F::ALL.iter().map(|f| f.1).collect()
+}
+
+/// Parse a comma separated list of field names
+pub fn parse_fields<F: Field>(list: &str) -> std::result::Result<Vec<F>, String> {
+    list.split(',')
+        .map(|name| match F::ALL.iter().find(|f| f.0 == name) {
+            Some(f) => Ok(f.1),
+            None => Err(format!(
+                "unknown field '{}', valid fields are: {}",
+                name,
+                F::ALL.iter().map(|f| f.0).collect::<Vec<_>>().join(",")
+            )),
+        })
+        .collect()
+}
+
+/// The number of CPUs currently online
+pub fn online_cpus() -> u16 {
+    unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) }.max(1) as u16
+}
+
 pub trait StatBlock<'a> {
     fn new(s: &'a Settings) -> Self;
-    fn update(&mut self);
+    /// Take new samples, timestamped by the clock of this refresh
+    fn update(&mut self, clock: &FrameClock);
 
     /// The width of any non-empty line that would be printed if this block were Displayed
     fn columns(&self) -> u16;
     /// The number of lines that would be printed if this block were Displayed
     fn rows(&self) -> u16;
+
+    /// The fewest lines this block can be printed in, if fit() is given less than it would like
+    fn min_rows(&self) -> u16 {
+        self.rows()
+    }
+    /// The number of lines this block would be printed in, if given enough room
+    fn preferred_rows(&self) -> u16 {
+        self.rows()
+    }
+    /// Print at most this many lines from now on, never less than min_rows(). Only honoured by
+    /// blocks that can be shrunk.
+    fn fit(&mut self, _rows: u16) {}
+
+    /// Only print this block if it fits beside another one. Only honoured by MergedStatBlock, for
+    /// its right-hand block.
+    fn optional(&self) -> bool {
+        false
+    }
 }
 
-#[derive(PartialEq, PartialOrd, Clone, Copy)]
+#[derive(PartialEq, PartialOrd, Clone, Copy, Default)]
 pub struct Bytes(pub u64);
 
+impl From<u64> for Bytes {
+    fn from(b: u64) -> Self {
+        Bytes(b)
+    }
+}
+
+/// How much a counter increased per second between two samples, printed like T
+#[derive(Clone, Copy, Default)]
+pub struct Rate<T>(pub T);
+
+impl<T: From<u64>> Rate<T> {
+    /// None if no time elapsed (eg. both samples are the first one), or if the counter went
+    /// backwards. A 64 bit counter won't wrap around in practice: it was reset, eg. a device was
+    /// recreated under the same name, and there is no telling how much it increased.
+    pub fn new(prev: u64, cur: u64, elapsed: Duration) -> Option<Rate<T>> {
+        let ns = elapsed.as_nanos();
+        if ns == 0 {
+            return None;
+        }
+        let delta = cur.checked_sub(prev)? as u128;
+        Some(Rate(T::from(
+            (delta * 1_000_000_000 / ns).min(u64::MAX.into()) as u64,
+        )))
+    }
+}
+
+/// Exponential moving average over about n samples: moves prev by 1/n of the way towards cur
+pub fn ema(prev: f32, cur: f32, n: f32) -> f32 {
+    prev + (cur - prev) / n
+}
+
+impl<T: Display> Display for Rate<T> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        self.0.fmt(f)
+    }
+}
+
 impl Display for Bytes {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        let w = f.width().unwrap_or(8) - 1;
+        let w = f.width().unwrap_or(8).max(2) - 1;
         let p = f.precision().unwrap_or(2);
-        let threshold = if p == 0 {
-            if w >= 4 {
-                10000
-            } else {
-                1000
-            }
-        } else {
-            if w - p - 1 >= 4 {
-                10000
-            } else {
-                1000
-            }
-        };
-        if self.0 >= threshold * 1024 * 1024 * 1024 {
-            write!(
-                f,
-                "{:>w$.p$}T",
-                self.0 as f32 / (1024. * 1024. * 1024. * 1024.)
-            )
-        } else if self.0 >= threshold * 1024 * 1024 {
-            write!(f, "{:>w$.p$}G", self.0 as f32 / (1024. * 1024. * 1024.))
-        } else if self.0 >= threshold * 1024 {
-            write!(f, "{:>w$.p$}M", self.0 as f32 / (1024. * 1024.))
-        } else if self.0 > 0 {
-            write!(f, "{:>w$.p$}K", self.0 as f32 / 1024.)
-        } else {
+        if self.0 == 0 {
             let w = w + 1;
-            write!(f, "{:>w$}", ".")
+            return write!(f, "{:>w$}", ".");
+        }
+
+        /* Show 4 digits before the unit if they fit beside the decimals */
+        let int_room = if p == 0 { w } else { w.saturating_sub(p + 1) };
+        let threshold = if int_room >= 4 { 10000 } else { 1000 };
+        let mut unit = 0;
+        while unit < 3 && self.0 >= threshold << (10 * (unit + 1)) {
+            unit += 1;
+        }
+
+        /* Rounding can still add a digit (999.996 -> 1000.00): drop decimals until the number fits,
+         * like df does, and only then use a bigger unit */
+        loop {
+            let v = self.0 as f64 / (1u64 << (10 * (unit + 1))) as f64;
+            for p in (0..=p).rev() {
+                let rounded = (v * 10f64.powi(p as i32)).round() / 10f64.powi(p as i32);
+                let len = rounded.max(1.).log10() as usize + 1 + if p > 0 { p + 1 } else { 0 };
+                if len <= w || (p == 0 && unit == 3) {
+                    let unit = ['K', 'M', 'G', 'T'][unit];
+                    if f.alternate() {
+                        /* Separators are worth more than the last decimals */
+                        for p in (0..=p).rev() {
+                            let g = group_digits(&format!("{:.p$}", v));
+                            if g.len() <= w {
+                                return write!(f, "{:>w$}{}", g, unit);
+                            }
+                        }
+                    }
+                    return write!(f, "{:>w$.p$}{}", v, unit);
+                }
+            }
+            unit += 1;
+        }
+    }
+}
+
+/// Separate thousands with commas in the integer part of a formatted number, eg. "12,345.67". Left
+/// alone under 5 digits: "1,234" isn't any easier to read than "1234". With {:#}, Bytes and Watts
+/// do this if it fits in their width.
+pub fn group_digits(num: &str) -> String {
+    let (int, frac) = match num.find('.') {
+        Some(i) => num.split_at(i),
+        None => (num, ""),
+    };
+    if int.len() < 5 {
+        return String::from(num);
+    }
+    let mut out = String::with_capacity(num.len() + int.len() / 3);
+    for (i, c) in int.chars().enumerate() {
+        if i > 0 && (int.len() - i) % 3 == 0 {
+            out.push(',');
         }
+        out.push(c);
     }
+    out.push_str(frac);
+    out
 }
 
 #[derive(PartialEq, PartialOrd, Clone, Copy)]
@@ -130,6 +904,12 @@ impl Display for Watts {
     fn fmt(&self, f: &mut Formatter) -> Result {
         let w = f.width().unwrap_or(8) - 1;
         let p = f.precision().unwrap_or(0);
+        if f.alternate() {
+            let g = group_digits(&self.0.to_string());
+            if g.len() <= w {
+                return write!(f, "{:>w$}W", g);
+            }
+        }
         write!(f, "{:>w$.p$}W", self.0)
     }
 }
@@ -145,6 +925,29 @@ impl Display for Percentage {
     }
 }
 
+/// A maximum that slowly decays, so an old spike doesn't stay the maximum forever
+#[derive(Clone, Copy, Default)]
+pub struct DecayingMax(pub u64);
+
+impl DecayingMax {
+    /// Shrink the maximum by 1%, then account for a new value
+    pub fn update(&mut self, v: u64) {
+        self.0 = v.max(self.0 - self.0 / 100);
+    }
+
+    /// Colour a value as a fraction of the maximum: >50% is medium, >80% is high. Never colour
+    /// anything if the maximum is below floor.
+    pub fn threshold(&self, val: Bytes, floor: u64) -> Threshold<Bytes> {
+        let m = self.0.max(floor);
+        Threshold {
+            val,
+            med: Bytes(m / 2),
+            high: Bytes(m * 4 / 5),
+            crit: Bytes(u64::MAX),
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct Threshold<T> {
     pub val: T,
@@ -153,38 +956,90 @@ pub struct Threshold<T> {
     pub crit: T,
 }
 
-pub struct Heading<'a>(pub &'a str);
+/// A column heading, with an optional abbreviation for when it doesn't fit
+pub struct Heading<'a>(&'a str, Option<&'a str>);
 pub struct Newline();
+/// A notice that refreshes take longer than they should
+pub struct Overrun();
 
 /// A wrapper type to access settings in fmt::Display
 pub struct MaybeSmart<'a, T>(pub T, pub &'a Settings);
 
+impl<'a> Heading<'a> {
+    pub const fn new(long: &'a str) -> Self {
+        Heading(long, None)
+    }
+
+    pub const fn with_short(long: &'a str, short: &'a str) -> Self {
+        Heading(long, Some(short))
+    }
+
+    /// The text to print in a column of width w; truncation is up to the caller
+    fn fit(&self, w: usize) -> &'a str {
+        match self.1 {
+            Some(short) if self.0.len() > w => short,
+            _ => self.0,
+        }
+    }
+}
+
 impl<'a, 'b> Display for MaybeSmart<'a, Heading<'b>> {
     fn fmt(&self, f: &mut Formatter) -> Result {
         let w = f.width().unwrap_or_else(|| self.1.colwidth.get().into());
         let p = f.precision().unwrap_or(w);
+        let h = self.0.fit(p);
 
         /* XXX: is there a way to not repeat ourselves? */
-        match (self.1.smart, f.align()) {
-            (false, Some(Alignment::Center)) => write!(f, "{:^w$.p$}", self.0 .0),
-            (false, Some(Alignment::Left)) => write!(f, "{:<w$.p$}", self.0 .0),
-            (false, _) => write!(f, "{:>w$.p$}", self.0 .0),
-            (true, Some(Alignment::Center)) => write!(f, "\x1B[1m{:^w$.p$}\x1B[0m", self.0 .0),
-            (true, Some(Alignment::Left)) => write!(f, "\x1B[1m{:<w$.p$}\x1B[0m", self.0 .0),
-            (true, _) => write!(f, "\x1B[1m{:>w$.p$}\x1B[0m", self.0 .0),
+        match (self.1.smart.get(), f.align()) {
+            (false, Some(Alignment::Center)) => write!(f, "{:^w$.p$}", h),
+            (false, Some(Alignment::Left)) => write!(f, "{:<w$.p$}", h),
+            (false, _) => write!(f, "{:>w$.p$}", h),
+            (true, Some(Alignment::Center)) => write!(f, "\x1B[1m{:^w$.p$}\x1B[0m", h),
+            (true, Some(Alignment::Left)) => write!(f, "\x1B[1m{:<w$.p$}\x1B[0m", h),
+            (true, _) => write!(f, "\x1B[1m{:>w$.p$}\x1B[0m", h),
         }
     }
 }
 
 impl<'a> Display for MaybeSmart<'a, Newline> {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        match self.1.smart {
+        match self.1.smart.get() {
             false => writeln!(f),
             true => writeln!(f, "{}", SMART_NEWLINE),
         }
     }
 }
 
+impl<'a> Display for MaybeSmart<'a, Overrun> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let actual = self.1.frame_interval.get().as_secs_f32();
+        let target = self.1.refresh as f32 / 1000.0;
+        match self.1.smart.get() {
+            false => write!(f, "(running {:.1}s behind target {:.1}s)", actual, target),
+            true => write!(
+                f,
+                "\x1B[2m(running {:.1}s behind target {:.1}s)\x1B[0m",
+                actual, target
+            ),
+        }
+    }
+}
+
+/// Format a value with {:#} when set, passing the width and precision along
+struct Grouped<'a, T>(&'a T, bool);
+
+impl<'a, T: Display> Display for Grouped<'a, T> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let w = f.width().unwrap_or(0);
+        match (self.1, f.precision()) {
+            (true, Some(p)) => write!(f, "{:>#w$.p$}", self.0),
+            (true, None) => write!(f, "{:>#w$}", self.0),
+            (false, Some(p)) => write!(f, "{:>w$.p$}", self.0),
+            (false, None) => write!(f, "{:>w$}", self.0),
+        }
+    }
+}
+
 impl<'a, T> Display for MaybeSmart<'a, Threshold<T>>
 where
     T: Display + PartialOrd,
@@ -193,23 +1048,26 @@ where
         let w = f.width().unwrap_or_else(|| self.1.colwidth.get().into());
         let p = f.precision().unwrap_or(2);
         let t = &self.0;
+        /* With --group-digits, Bytes and Watts separate thousands */
+        let val = Grouped(&t.val, self.1.group_digits);
 
-        if !self.1.smart {
-            return write!(f, "{:>w$.p$}", t.val);
+        if !self.1.smart.get() {
+            return write!(f, "{:>w$.p$}", val);
         }
 
+        let theme = self.1.theme.get();
         if t.val.partial_cmp(&t.med) == Some(Ordering::Less) {
             /* < med */
-            write!(f, "{:>w$.p$}", t.val)
+            write!(f, "{:>w$.p$}", val)
         } else if t.val.partial_cmp(&t.high) == Some(Ordering::Less) {
             /* < high: we're med */
-            write!(f, "\x1B[1;93m{:>w$.p$}\x1B[0m", t.val)
+            write!(f, "{}{:>w$.p$}{}", theme.med(), val, theme.reset())
         } else if t.val.partial_cmp(&t.crit) == Some(Ordering::Less) {
             /* < crit: we're high */
-            write!(f, "\x1B[1;91m{:>w$.p$}\x1B[0m", t.val)
+            write!(f, "{}{:>w$.p$}{}", theme.high(), val, theme.reset())
         } else {
             /* crit */
-            write!(f, "\x1B[1;95m{:>w$.p$}\x1B[0m", t.val)
+            write!(f, "{}{:>w$.p$}{}", theme.crit(), val, theme.reset())
         }
     }
 }
@@ -217,113 +1075,318 @@ where
 #[derive(PartialEq, Eq, Clone, Copy)]
 pub struct Stale(pub bool);
 
-/// Read contents of a file to a given String buffer
-/// # Safety
-/// Make sure the file you are reading can never contain bad UTF-8
-pub unsafe fn read_to_string_unchecked<P: AsRef<std::path::Path>>(
-    p: P,
-    s: &mut String,
-) -> std::io::Result<usize> {
-    s.clear();
-    let mut f = File::open(p)?;
-    f.read_to_end(s.as_mut_vec())
-}
-
-/// Read contents of a file and mangle it into valid UTF-8
-pub fn read_to_string<P: AsRef<std::path::Path>>(p: P, s: &mut String) -> std::io::Result<usize> {
-    const REPLACEMENT_CHAR: u8 = b'?';
-
-    fn check_byte(b: Option<&mut u8>) -> Option<&mut u8> {
-        match b {
-            Some(b) if *b <= 0b10111111 => Some(b),
-            Some(b) => {
-                *b = REPLACEMENT_CHAR;
-                None
-            }
-            _ => None,
+/// Read a clock, eg. CLOCK_MONOTONIC
+pub fn clock(id: libc::clockid_t) -> Duration {
+    unsafe {
+        let mut ts = std::mem::MaybeUninit::<libc::timespec>::uninit();
+        if libc::clock_gettime(id, ts.as_mut_ptr()) != 0 {
+            libc_panic("clock_gettime()");
         }
+        let ts = ts.assume_init();
+        Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
     }
+}
 
-    unsafe {
-        let length = read_to_string_unchecked(p, s)?;
-        /* Now s may contain invalid UTF-8, iterate over the bytes and correct that to make a safe
-         * String */
-        /* XXX: would be nice to leverage String::from_utf8_lossy() or OsString::to_string_lossy(),
-         * but they don't work in-place so are not suited here */
-        let mut iter = s.as_mut_vec().iter_mut();
-        #[allow(clippy::while_let_on_iterator)]
-        while let Some(cp) = iter.next() {
-            /* This is very naive, probably buggy and slow */
-            /* https://doc.rust-lang.org/std/primitive.char.html#validity */
-
-            if *cp <= 0b01111111 {
-                /* Was an ASCII code point */
-                continue;
-            }
+/// The clocks at the start of each refresh, to notice what happened in between. Blocks timestamp
+/// their samples with it, so all of them agree on when a refresh happened. Rates are measured with
+/// Instant (CLOCK_MONOTONIC) and unaffected by steps of the wall clock, but timestamps printed
+/// alongside them are.
+#[derive(Default)]
+pub struct FrameClock {
+    /// (monotonic, boottime, realtime) at the last tick
+    last: Option<(Duration, Duration, Duration)>,
+    /// The same tick as an Instant, unless given clock values
+    at: Option<Instant>,
+    /// Monotonic time between the last two ticks
+    pub elapsed: Option<Duration>,
+    /// How long the system was suspended between the last two ticks: CLOCK_BOOTTIME keeps running
+    /// while suspended, CLOCK_MONOTONIC doesn't
+    pub suspended: Option<Duration>,
+    /// How many seconds the wall clock jumped between the last two ticks, eg. after an NTP step
+    /// or a manual change; negative if it went back
+    pub stepped: Option<f64>,
+    /// With tick_remote(), when a sample arrived the soonest after being taken, and the uptime
+    /// it was taken at
+    remote: Option<(Instant, Duration)>,
+}
 
-            if *cp >= 0b11111000 {
-                /* Invalid leader */
-                *cp = REPLACEMENT_CHAR;
-                continue;
-            }
+impl FrameClock {
+    /// Less than this is jitter, not a suspend
+    const MIN_SUSPEND: Duration = Duration::from_secs(1);
+    /// Less than this is jitter (or NTP slewing the clock), not a step
+    const MIN_STEP: f64 = 1.0;
 
-            let a = match check_byte(iter.next()) {
-                Some(a) => a,
-                None => {
-                    *cp = REPLACEMENT_CHAR;
-                    continue;
-                }
-            };
+    /// A clock ticked once, eg. for the first samples of a block
+    pub fn now() -> FrameClock {
+        let mut c = FrameClock::default();
+        c.tick();
+        c
+    }
 
-            if *cp < 0b11100000 {
-                /* Was a 2-byte sequence */
-                continue;
-            }
+    /// Read the clocks, at the start of a refresh
+    pub fn tick(&mut self) {
+        self.at = Some(Instant::now());
+        self.observe(
+            clock(libc::CLOCK_MONOTONIC),
+            clock(libc::CLOCK_BOOTTIME),
+            clock(libc::CLOCK_REALTIME),
+        )
+    }
 
-            let b = match check_byte(iter.next()) {
-                Some(b) => b,
-                None => {
-                    *cp = REPLACEMENT_CHAR;
-                    *a = REPLACEMENT_CHAR;
-                    continue;
-                }
-            };
+    /// Same as tick(), with given clock values
+    pub fn observe(&mut self, monotonic: Duration, boottime: Duration, realtime: Duration) {
+        self.suspended = None;
+        self.stepped = None;
+        self.elapsed = None;
+        let (m, b, r) = match self.last.replace((monotonic, boottime, realtime)) {
+            Some(l) => l,
+            None => return,
+        };
+        self.elapsed = Some(monotonic.saturating_sub(m));
 
-            if *cp < 0b11110000 {
-                /* Was a 3-byte sequence */
+        let suspended = boottime
+            .saturating_sub(b)
+            .saturating_sub(monotonic.saturating_sub(m));
+        if suspended >= Self::MIN_SUSPEND {
+            self.suspended = Some(suspended);
+        }
 
-                /* Check for 0xD800..0xE000 codepoint */
-                let first_byte = ((*cp & 0b00001111) << 4) | ((*a & 0b00111100) >> 2);
-                if (0xD8..0xE0).contains(&first_byte) {
-                    *cp = REPLACEMENT_CHAR;
-                    *a = REPLACEMENT_CHAR;
-                    *b = REPLACEMENT_CHAR;
-                }
+        /* Boottime counts suspends too, the wall clock should have moved as much */
+        let step =
+            (realtime.as_secs_f64() - r.as_secs_f64()) - boottime.saturating_sub(b).as_secs_f64();
+        if step.abs() >= Self::MIN_STEP {
+            self.stepped = Some(step);
+        }
+    }
 
-                continue;
-            }
+    /// Same as tick(), for samples taken on another machine when its /proc/uptime was this. The
+    /// tick is placed as long after the soonest arrived sample as the uptimes say, so rates don't
+    /// depend on how late each sample is. XXX: suspends and clock steps over there go unnoticed.
+    pub fn tick_remote(&mut self, uptime: Duration) {
+        let now = Instant::now();
+        let at = match self.remote {
+            Some((i, u)) if uptime >= u => i + (uptime - u),
+            _ => now,
+        };
+        /* Sooner than ever, or the other machine rebooted */
+        if at >= now {
+            self.remote = Some((now, uptime));
+        }
+        self.at = Some(at.min(now));
+        self.observe(uptime, uptime, uptime);
+    }
 
-            /* Is a 4-byte sequence */
+    /// When the last tick happened, what samples taken during this refresh are timestamped with
+    pub fn instant(&self) -> Instant {
+        self.at.unwrap_or_else(Instant::now)
+    }
 
-            let c = match check_byte(iter.next()) {
-                Some(c) => c,
-                None => {
-                    *cp = REPLACEMENT_CHAR;
-                    *a = REPLACEMENT_CHAR;
-                    *b = REPLACEMENT_CHAR;
-                    continue;
+    /// Time since boot at the last tick, suspends included, like /proc/uptime
+    pub fn boottime(&self) -> Duration {
+        self.last.map_or(Duration::ZERO, |l| l.1)
+    }
+}
+
+/// How many terminal cells a character takes: 0 for combining marks and other zero-width
+/// characters, 2 for wide East Asian characters and most emoji, 1 otherwise. A rough wcwidth(),
+/// enough to keep columns aligned; XXX: ignores ambiguous width characters and emoji sequences.
+pub fn char_width(c: char) -> usize {
+    match c as u32 {
+        0x0300..=0x036F
+        | 0x1AB0..=0x1AFF
+        | 0x1DC0..=0x1DFF
+        | 0x200B..=0x200F
+        | 0x20D0..=0x20FF
+        | 0xFE00..=0xFE0F
+        | 0xFE20..=0xFE2F => 0,
+        0x1100..=0x115F
+        | 0x2E80..=0x303E
+        | 0x3041..=0x33FF
+        | 0x3400..=0x4DBF
+        | 0x4E00..=0x9FFF
+        | 0xA000..=0xA4CF
+        | 0xAC00..=0xD7A3
+        | 0xF900..=0xFAFF
+        | 0xFE30..=0xFE4F
+        | 0xFF00..=0xFF60
+        | 0xFFE0..=0xFFE6
+        | 0x1F300..=0x1F64F
+        | 0x1F900..=0x1F9FF
+        | 0x20000..=0x3FFFD => 2,
+        _ => 1,
+    }
+}
+
+/// How many terminal cells a string takes
+pub fn str_width(s: &str) -> usize {
+    s.chars().map(char_width).sum()
+}
+
+/// The longest start of s that takes at most w cells, never splitting a character
+pub fn cells_prefix(s: &str, w: usize) -> &str {
+    let mut used = 0;
+    for (i, c) in s.char_indices() {
+        used += char_width(c);
+        if used > w {
+            return &s[..i];
+        }
+    }
+    s
+}
+
+/// A string left-aligned, and truncated or padded to the width of the format in terminal cells,
+/// where {:<w$.w$} would count characters
+pub struct Cells<'a>(pub &'a str);
+
+impl<'a> Display for Cells<'a> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let w = match f.width() {
+            Some(w) => w,
+            None => return f.write_str(self.0),
+        };
+        let s = cells_prefix(self.0, w);
+        write!(f, "{}{:pad$}", s, "", pad = w - str_width(s))
+    }
+}
+
+/// A duration, displayed as eg. 34m12s or 3d04h; right-aligned in the given width, if any
+pub struct HumanDuration(pub Duration);
+
+impl Display for HumanDuration {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let s = self.0.as_secs();
+        let (major, major_unit, minor, minor_unit) = match s {
+            0..=59 => (s, 's', None, ' '),
+            60..=3599 => (s / 60, 'm', Some(s % 60), 's'),
+            3600..=86399 => (s / 3600, 'h', Some(s / 60 % 60), 'm'),
+            8640000.. => (s / 86400, 'd', None, ' '),
+            _ => (s / 86400, 'd', Some(s / 3600 % 24), 'h'),
+        };
+        let len = major.checked_ilog10().unwrap_or(0) as usize + 2 + minor.map_or(0, |_| 3);
+        write!(
+            f,
+            "{:>w$}",
+            "",
+            w = f.width().unwrap_or(0).saturating_sub(len)
+        )?;
+        write!(f, "{}{}", major, major_unit)?;
+        match minor {
+            Some(m) => write!(f, "{:02}{}", m, minor_unit),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Wall clock time, displayed as ISO 8601
+pub struct Timestamp {
+    /// Seconds since the epoch
+    secs: libc::time_t,
+    /// Offset from UTC in seconds, or None to display in UTC
+    offset: Option<libc::time_t>,
+}
+
+impl Timestamp {
+    pub fn now(utc: bool) -> Timestamp {
+        unsafe {
+            let mut ts = std::mem::MaybeUninit::<libc::timespec>::uninit();
+            if libc::clock_gettime(libc::CLOCK_REALTIME, ts.as_mut_ptr()) != 0 {
+                libc_panic("clock_gettime()");
+            }
+            let ts = ts.assume_init();
+
+            let offset = if utc {
+                None
+            } else {
+                let mut tm = std::mem::MaybeUninit::<libc::tm>::uninit();
+                if libc::localtime_r(&ts.tv_sec, tm.as_mut_ptr()).is_null() {
+                    None
+                } else {
+                    Some(tm.assume_init().tm_gmtoff as libc::time_t)
                 }
             };
 
-            /* Check for 0x110000.. codepoint */
-            let first_byte = ((*cp & 0b00000111) << 2) | ((*a & 0b00110000) >> 4);
-            if first_byte >= 0x11 {
-                *cp = REPLACEMENT_CHAR;
-                *a = REPLACEMENT_CHAR;
-                *b = REPLACEMENT_CHAR;
-                *c = REPLACEMENT_CHAR;
+            Timestamp {
+                secs: ts.tv_sec,
+                offset,
             }
         }
+    }
+}
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let secs = self.secs + self.offset.unwrap_or(0);
+        let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+        /* http://howardhinnant.github.io/date_algorithms.html#civil_from_days */
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let d = doy - (153 * mp + 2) / 5 + 1;
+        let m = if mp < 10 { mp + 3 } else { mp - 9 };
+        let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            y,
+            m,
+            d,
+            secs / 3600,
+            secs % 3600 / 60,
+            secs % 60
+        )?;
+
+        match self.offset {
+            None => write!(f, "Z"),
+            Some(o) => write!(
+                f,
+                "{}{:02}:{:02}",
+                if o < 0 { '-' } else { '+' },
+                o.abs() / 3600,
+                o.abs() % 3600 / 60
+            ),
+        }
+    }
+}
+
+/// Read contents of a file to a given String buffer
+/// # Safety
+/// Make sure the file you are reading can never contain bad UTF-8
+pub unsafe fn read_to_string_unchecked<P: AsRef<std::path::Path>>(
+    p: P,
+    s: &mut String,
+) -> std::io::Result<usize> {
+    s.clear();
+    let mut f = File::open(p)?;
+    f.read_to_end(s.as_mut_vec())
+}
+
+/// Replace each byte of invalid UTF-8 sequences with '?', in place
+pub fn mangle_utf8(v: &mut [u8]) {
+    const REPLACEMENT_CHAR: u8 = b'?';
+
+    /* Let std find the invalid sequences, the common all-valid case is a single fast pass */
+    let mut start = 0;
+    while let Err(e) = std::str::from_utf8(&v[start..]) {
+        let bad = start + e.valid_up_to();
+        /* None means the input ends in the middle of a sequence */
+        let end = e.error_len().map_or(v.len(), |l| bad + l);
+        v[bad..end].fill(REPLACEMENT_CHAR);
+        start = end;
+    }
+}
+
+/// Read contents of a file and mangle it into valid UTF-8
+pub fn read_to_string<P: AsRef<std::path::Path>>(p: P, s: &mut String) -> std::io::Result<usize> {
+    unsafe {
+        let length = read_to_string_unchecked(p, s)?;
+        /* Now s may contain invalid UTF-8, correct that to make a safe String.
+         * String::from_utf8_lossy() would do, but doesn't work in-place. */
+        mangle_utf8(s.as_mut_vec());
         Ok(length)
     }
 }
@@ -361,6 +1424,27 @@ where
         self.pad_length_to_columns(self.t.columns()) + self.u.columns()
             < self.settings.maxcols.get()
     }
+
+    pub fn left(&self) -> &T {
+        &self.t
+    }
+
+    pub fn right(&self) -> &U {
+        &self.u
+    }
+
+    pub fn left_mut(&mut self) -> &mut T {
+        &mut self.t
+    }
+
+    pub fn right_mut(&mut self) -> &mut U {
+        &mut self.u
+    }
+
+    /// Is u optional, with nothing to be printed beside?
+    fn hide_u(&self) -> bool {
+        self.u.optional() && (self.t.columns() == 0 || !self.can_merge())
+    }
 }
 
 impl<'a, T, U> StatBlock<'a> for MergedStatBlock<'a, T, U>
@@ -378,14 +1462,14 @@ where
         }
     }
 
-    fn update(&mut self) {
+    fn update(&mut self, clock: &FrameClock) {
         use std::fmt::Write;
 
-        self.t.update();
+        self.t.update(clock);
         self.tbuf.clear();
         write!(self.tbuf, "{}", self.t).unwrap();
 
-        self.u.update();
+        self.u.update(clock);
         self.ubuf.clear();
         write!(self.ubuf, "{}", self.u).unwrap()
     }
@@ -394,6 +1478,10 @@ where
         let tc = self.t.columns();
         let tu = self.u.columns();
 
+        if self.hide_u() {
+            return tc;
+        }
+
         /* Space separator only needed if t and u print something */
         if self.can_merge() && tc > 0 && tu > 0 {
             self.pad_length_to_columns(tc) + tu + 1
@@ -403,12 +1491,13 @@ where
     }
 
     fn rows(&self) -> u16 {
-        if self.can_merge() {
+        if self.hide_u() {
+            self.t.rows()
+        } else if self.can_merge() {
             self.t.rows().max(self.u.rows())
         } else {
-            /* If t or u prints nothing, we can merge; control flow reaches here only if t and u
-             * print something, so the newline separator is always there */
-            self.t.rows() + self.u.rows() + 1
+            /* Each block already ends with an empty line, no need for a separator */
+            self.t.rows() + self.u.rows()
         }
     }
 }
@@ -419,6 +1508,9 @@ where
     U: StatBlock<'a> + Display,
 {
     fn fmt(&self, f: &mut Formatter) -> Result {
+        if self.hide_u() {
+            return write!(f, "{}", self.tbuf);
+        }
         if self.tbuf.is_empty() {
             return write!(f, "{}", self.ubuf);
         }
@@ -427,7 +1519,24 @@ where
         }
 
         if !self.can_merge() {
-            return write!(f, "{}{}", self.tbuf, self.ubuf);
+            /* Print one block under the other, pad the narrowest one so all lines have the same
+             * width */
+            let c = self.columns() as usize;
+            let newline = MaybeSmart(Newline(), self.settings);
+            for (buf, bc) in [
+                (&self.tbuf, self.t.columns()),
+                (&self.ubuf, self.u.columns()),
+            ] {
+                let padding = c - bc as usize;
+                for line in buf.lines() {
+                    if line.is_empty() || line == SMART_NEWLINE {
+                        write!(f, "{}", newline)?
+                    } else {
+                        write!(f, "{}{:padding$}{}", line, "", newline)?
+                    }
+                }
+            }
+            return Ok(());
         }
 
         let tc = self.t.columns();
@@ -464,8 +1573,1127 @@ where
     }
 }
 
+/// Wrap a StatBlock to remember when it was last updated. Printed more than two refresh intervals
+/// after that, eg. because the blocks updated after it took that long, it says how old its data is
+/// at the end of its first line. Only in the blanks that line already ends with, so columns() is
+/// unchanged.
+pub struct Aged<'a, T> {
+    block: T,
+    settings: &'a Settings,
+    updated: Instant,
+}
+
+impl<'a, T> Aged<'a, T> {
+    /// How old the data is, if old enough to say so
+    fn stale(&self) -> Option<Duration> {
+        let age = self.updated.elapsed();
+        (age > Duration::from_millis(2 * self.settings.refresh)).then_some(age)
+    }
+}
+
+impl<'a, T> Deref for Aged<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.block
+    }
+}
+
+impl<'a, T> DerefMut for Aged<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.block
+    }
+}
+
+impl<'a, T: StatBlock<'a>> StatBlock<'a> for Aged<'a, T> {
+    fn new(s: &'a Settings) -> Self {
+        Aged {
+            block: T::new(s),
+            settings: s,
+            updated: Instant::now(),
+        }
+    }
+
+    fn update(&mut self, clock: &FrameClock) {
+        self.block.update(clock);
+        self.updated = clock.instant();
+    }
+
+    fn columns(&self) -> u16 {
+        self.block.columns()
+    }
+
+    fn rows(&self) -> u16 {
+        self.block.rows()
+    }
+
+    fn min_rows(&self) -> u16 {
+        self.block.min_rows()
+    }
+
+    fn preferred_rows(&self) -> u16 {
+        self.block.preferred_rows()
+    }
+
+    fn fit(&mut self, rows: u16) {
+        self.block.fit(rows)
+    }
+
+    fn optional(&self) -> bool {
+        self.block.optional()
+    }
+}
+
+impl<'a, T: Display> Display for Aged<'a, T> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let age = match self.stale() {
+            Some(age) => age,
+            None => return write!(f, "{}", self.block),
+        };
+
+        let out = self.block.to_string();
+        let (first, rest) = match out.split_once('\n') {
+            Some(lines) => lines,
+            None => return f.write_str(&out),
+        };
+        let (line, eol) = match first.strip_suffix(SMART_NEWLINE) {
+            Some(line) => (line, SMART_NEWLINE),
+            None => (first, ""),
+        };
+        let tag = format!("({} old)", HumanDuration(age));
+        let tw = visible_width(&tag);
+        /* Keep a blank between the line and the tag */
+        let blanks = line.len() - line.trim_end_matches(' ').len();
+        if blanks <= tw {
+            return f.write_str(&out);
+        }
+
+        let line = &line[..(line.len() - tw)];
+        match self.settings.smart.get() {
+            false => write!(f, "{}{}{}\n{}", line, tag, eol, rest),
+            true => write!(f, "{}\x1B[2m{}\x1B[0m{}\n{}", line, tag, eol, rest),
+        }
+    }
+}
+
+/// Parse a fixed point number as printed by the kernel, eg. "10000000.42", in hundredths. Digits
+/// past the second decimal are truncated. Avoids floats, an f32 only has ~7 significant digits.
+pub fn parse_hundredths(s: &str) -> Option<u64> {
+    let (int, frac) = s.split_once('.').unwrap_or((s, ""));
+    let frac = frac.as_bytes();
+    if frac.iter().any(|d| !d.is_ascii_digit()) {
+        return None;
+    }
+    let digit = |i: usize| frac.get(i).map_or(0, |d| (d - b'0') as u64);
+    int.parse::<u64>()
+        .ok()?
+        .checked_mul(100)?
+        .checked_add(digit(0) * 10 + digit(1))
+}
+
+/// Undo the octal escaping of paths in /proc files such as mountstats or swaps, eg. "My\040Disk"
+/// becomes "My Disk"
+pub fn unescape_octal(s: &str) -> Cow<'_, str> {
+    if !s.contains('\\') {
+        return Cow::Borrowed(s);
+    }
+
+    let b = s.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        match b.get(i..i + 4) {
+            Some([b'\\', o @ ..]) if o.iter().all(|d| (b'0'..=b'7').contains(d)) => {
+                out.push(o.iter().fold(0u8, |v, d| (v << 3) | (d - b'0')));
+                i += 4;
+            }
+            _ => {
+                out.push(b[i]);
+                i += 1;
+            }
+        }
+    }
+    Cow::Owned(String::from_utf8_lossy(&out).into_owned())
+}
+
+/// Count the columns a string would take on a terminal, skipping escape sequences
+pub fn visible_width(s: &str) -> usize {
+    let mut w = 0;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1B' {
+            w += char_width(c);
+            continue;
+        }
+
+        /* Skip CSI sequences up to and including their final byte */
+        if chars.next() == Some('[') {
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+    w
+}
+
+/// Check that a block prints exactly as many rows and columns as it says it does. Returns a
+/// description of the first mismatch.
+pub fn check_layout<'a, T>(b: &T, buf: &mut String) -> std::result::Result<(), String>
+where
+    T: StatBlock<'a> + Display,
+{
+    use std::fmt::Write;
+
+    buf.clear();
+    write!(buf, "{}", b).unwrap();
+
+    let mut rows = 0;
+    for (i, line) in buf.lines().enumerate() {
+        rows += 1;
+        let w = visible_width(line);
+        if w > 0 && w != b.columns() as usize {
+            return Err(format!(
+                "line {} is {} columns wide, expected {}: {:?}",
+                i + 1,
+                w,
+                b.columns(),
+                line
+            ));
+        }
+    }
+
+    if rows != b.rows() {
+        return Err(format!("printed {} rows, expected {}", rows, b.rows()));
+    }
+
+    Ok(())
+}
+
+pub struct FileDescriptor(pub libc::c_int);
+
+impl Drop for FileDescriptor {
+    fn drop(&mut self) {
+        if self.0 == -1 {
+            return;
+        }
+        unsafe {
+            if libc::close(self.0) != 0 {
+                libc_panic("close()");
+            }
+        }
+    }
+}
+
 pub fn libc_panic(msg: &'static str) -> ! {
     let msg = std::ffi::CString::new(msg).unwrap();
     unsafe { libc::perror(msg.as_ptr()) };
     panic!();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Values around where Bytes changes units or gains a digit, up to 10000T
+    fn byte_boundaries() -> Vec<u64> {
+        let mut v = vec![0, 1, 512, 1023];
+        for unit in 0..4 {
+            let base = 1u64 << (10 * (unit + 1));
+            for k in [1, 9, 10, 99, 100, 999, 1000, 1023, 1024, 9999, 10000] {
+                if unit == 3 && k > 9999 {
+                    continue;
+                }
+                let x = k * base;
+                /* Just below, at, and just above where rounding to 0, 1 and 2 decimals carries */
+                for d in [base / 200, base / 2000, base / 20000, 1, 0] {
+                    v.push(x - d);
+                    v.push(x + d);
+                }
+            }
+        }
+        v
+    }
+
+    #[test]
+    fn bytes_width_sweep() {
+        for b in byte_boundaries() {
+            for w in 6..=12 {
+                for p in 0..=2 {
+                    let s = format!("{:w$.p$}", Bytes(b));
+                    assert_eq!(s.len(), w, "{} at {}.{}: {:?}", b, w, p, s);
+                    if b == 0 {
+                        continue;
+                    }
+
+                    /* Within rounding of the real value */
+                    let unit = match s.chars().last().unwrap() {
+                        'K' => 1,
+                        'M' => 2,
+                        'G' => 3,
+                        'T' => 4,
+                        c => panic!("unit {} in {:?}", c, s),
+                    };
+                    let shown: f64 = s[..w - 1].trim().parse().unwrap();
+                    let real = b as f64 / (1u64 << (10 * unit)) as f64;
+                    let decimals = s.split_once('.').map_or(0, |(_, d)| d.len() - 1);
+                    assert!(decimals <= p, "{:?}", s);
+                    assert!(
+                        (shown - real).abs() <= 0.5 / 10f64.powi(decimals as i32) + 1e-9,
+                        "{} at {}.{}: {:?}",
+                        b,
+                        w,
+                        p,
+                        s
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bytes_examples() {
+        assert_eq!(format!("{}", Bytes(0)), "       .");
+        assert_eq!(format!("{}", Bytes(1_049_000_000)), "1000.40M");
+        assert_eq!(format!("{}", Bytes(10_485_000_000)), "9999.28M");
+        assert_eq!(format!("{}", Bytes(10_486_000_000)), "   9.77G");
+        assert_eq!(format!("{:6.2}", Bytes(1_049_000_000)), " 0.98G");
+        assert_eq!(format!("{:6.2}", Bytes(999 * 1024)), "999.0K");
+        assert_eq!(format!("{:6.2}", Bytes(1024 * 1024 - 1)), " 1.00M");
+        assert_eq!(format!("{:10.2}", Bytes(12345 << 20)), "    12.06G");
+    }
+
+    fn utc(secs: libc::time_t) -> String {
+        Timestamp { secs, offset: None }.to_string()
+    }
+
+    #[test]
+    fn timestamp_leap_years() {
+        assert_eq!(utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(utc(-1), "1969-12-31T23:59:59Z");
+        assert_eq!(utc(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(utc(951868800), "2000-03-01T00:00:00Z");
+        assert_eq!(utc(1709210096), "2024-02-29T12:34:56Z");
+        assert_eq!(utc(4107542399), "2100-02-28T23:59:59Z");
+        assert_eq!(utc(4107542400), "2100-03-01T00:00:00Z");
+        assert_eq!(utc(-2203891200), "1900-03-01T00:00:00Z");
+    }
+
+    #[test]
+    fn timestamp_every_day() {
+        /* Walk the calendar the slow way from 1900 to 2400 */
+        let leap = |y: i64| y % 4 == 0 && (y % 100 != 0 || y % 400 == 0);
+        let (mut y, mut m, mut d) = (1900i64, 1i64, 1i64);
+        let mut days = -25567i64;
+        while y < 2400 {
+            let secs = days * 86400 + 86399;
+            let want = format!("{:04}-{:02}-{:02}T23:59:59Z", y, m, d);
+            assert_eq!(utc(secs as libc::time_t), want);
+
+            let len = match m {
+                2 if leap(y) => 29,
+                2 => 28,
+                4 | 6 | 9 | 11 => 30,
+                _ => 31,
+            };
+            d += 1;
+            if d > len {
+                d = 1;
+                m += 1;
+            }
+            if m > 12 {
+                m = 1;
+                y += 1;
+            }
+            days += 1;
+        }
+    }
+
+    #[test]
+    fn timestamp_offsets() {
+        let at = |offset| {
+            Timestamp {
+                secs: 951868800,
+                offset: Some(offset),
+            }
+            .to_string()
+        };
+        assert_eq!(at(0), "2000-03-01T00:00:00+00:00");
+        assert_eq!(at(3600), "2000-03-01T01:00:00+01:00");
+        /* Crossing back over a leap day */
+        assert_eq!(at(-19800), "2000-02-29T18:30:00-05:30");
+        assert_eq!(at(45900), "2000-03-01T12:45:00+12:45");
+    }
+
+    #[test]
+    fn unescape_octal_paths() {
+        assert!(matches!(
+            unescape_octal("/mnt/usb"),
+            Cow::Borrowed("/mnt/usb")
+        ));
+        assert_eq!(
+            unescape_octal("/run/media/user/My\\040Disk"),
+            "/run/media/user/My Disk"
+        );
+        assert_eq!(unescape_octal("a\\011b\\012c"), "a\tb\nc");
+        assert_eq!(unescape_octal("back\\134slash\\134040"), "back\\slash\\040");
+        assert_eq!(unescape_octal("/swap\\040file\\040"), "/swap file ");
+        /* Not escapes, kept as is */
+        assert_eq!(unescape_octal("a\\9b"), "a\\9b");
+        assert_eq!(unescape_octal("a\\04"), "a\\04");
+        assert_eq!(unescape_octal("trailing\\"), "trailing\\");
+        /* Escaped UTF-8 bytes */
+        assert_eq!(unescape_octal("caf\\303\\251"), "café");
+    }
+
+    #[test]
+    fn hundredths() {
+        assert_eq!(parse_hundredths("10000000.42"), Some(1_000_000_042));
+        assert_eq!(parse_hundredths("0.00"), Some(0));
+        assert_eq!(parse_hundredths("12.3"), Some(1230));
+        assert_eq!(parse_hundredths("12"), Some(1200));
+        assert_eq!(parse_hundredths("12."), Some(1200));
+        assert_eq!(parse_hundredths("1.999"), Some(199));
+        assert_eq!(parse_hundredths("100.00"), Some(10000));
+        assert_eq!(parse_hundredths("184467440737095516.15"), Some(u64::MAX));
+        assert_eq!(parse_hundredths("184467440737095516.16"), None);
+        assert_eq!(parse_hundredths("184467440737095517"), None);
+        assert_eq!(parse_hundredths(""), None);
+        assert_eq!(parse_hundredths(".5"), None);
+        assert_eq!(parse_hundredths("1.-5"), None);
+        assert_eq!(parse_hundredths("1,5"), None);
+    }
+
+    #[test]
+    fn group_digits_thousands() {
+        assert_eq!(group_digits("1234"), "1234");
+        assert_eq!(group_digits("1234.56"), "1234.56");
+        assert_eq!(group_digits("12345"), "12,345");
+        assert_eq!(group_digits("12345.67"), "12,345.67");
+        assert_eq!(group_digits("123456"), "123,456");
+        assert_eq!(group_digits("1234567.8"), "1,234,567.8");
+        assert_eq!(
+            group_digits("18446744073709551615"),
+            "18,446,744,073,709,551,615"
+        );
+    }
+
+    #[test]
+    fn bytes_grouped_width_sweep() {
+        for b in byte_boundaries() {
+            for w in 6..=12 {
+                for p in 0..=2 {
+                    let plain = format!("{:w$.p$}", Bytes(b));
+                    let s = format!("{:#w$.p$}", Bytes(b));
+                    assert_eq!(s.len(), w, "{} at {}.{}: {:?}", b, w, p, s);
+                    /* Same unit, and the same digits bar separators and dropped decimals */
+                    assert_eq!(s.chars().last(), plain.chars().last());
+                    let digits = |s: &str| s.trim().replace(',', "");
+                    let (g, u) = (digits(&s[..w - 1]), digits(&plain[..w - 1]));
+                    assert!(
+                        u.starts_with(g.trim_end_matches(['.', '0']))
+                            || g.parse::<f64>().unwrap() == u.parse::<f64>().unwrap().round(),
+                        "{:?} vs {:?}",
+                        s,
+                        plain
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bytes_grouped_examples() {
+        /* Only terabytes go past 4 digits */
+        let v = (12345 << 40) + (1 << 39);
+        assert_eq!(format!("{:#12.2}", Bytes(v)), "  12,345.50T");
+        /* Trades decimals for separators */
+        assert_eq!(format!("{:#9.2}", Bytes(v)), "12,345.5T");
+        assert_eq!(format!("{:#8.2}", Bytes(v)), " 12,346T");
+        assert_eq!(format!("{:#7.2}", Bytes(v)), "12,346T");
+        assert_eq!(format!("{:#6.2}", Bytes(v)), "12346T");
+        assert_eq!(format!("{:#12}", Bytes(12345 << 20)), "      12.06G");
+        assert_eq!(format!("{:#8}", Bytes(1234 << 20)), "1234.00M");
+        assert_eq!(format!("{:#8}", Watts(123456)), "123,456W");
+        assert_eq!(format!("{:#7}", Watts(123456)), "123456W");
+    }
+
+    #[test]
+    fn threshold_grouped() {
+        let s = Settings {
+            group_digits: true,
+            ..Default::default()
+        };
+        let t = Threshold {
+            val: Bytes(12345 << 40),
+            med: Bytes(1),
+            high: Bytes(u64::MAX),
+            crit: Bytes(u64::MAX),
+        };
+        assert_eq!(format!("{:10.0}", MaybeSmart(t, &s)), "   12,345T");
+        s.smart.set(true);
+        let out = format!("{:10.0}", MaybeSmart(t, &s));
+        assert!(out.starts_with(s.theme.get().med()), "{:?}", out);
+        assert!(out.ends_with("   12,345T\x1B[0m"), "{:?}", out);
+        assert_eq!(visible_width(&out), 10);
+    }
+
+    fn rate(prev: u64, cur: u64, ms: u64) -> Option<u64> {
+        Rate::<Bytes>::new(prev, cur, Duration::from_millis(ms)).map(|r| r.0 .0)
+    }
+
+    #[test]
+    fn rate_edge_cases() {
+        assert_eq!(rate(1000, 3000, 1000), Some(2000));
+        assert_eq!(rate(0, 1000, 250), Some(4000));
+        assert_eq!(rate(0, 1, 3000), Some(0));
+        /* First sample, compared with itself */
+        assert_eq!(rate(5000, 5000, 0), None);
+        assert_eq!(rate(5000, 6000, 0), None);
+        /* Counter reset, or wrapped */
+        assert_eq!(rate(5000, 4999, 1000), None);
+        assert_eq!(rate(u64::MAX - 10, 5, 1000), None);
+        assert_eq!(rate(0, 0, 1000), Some(0));
+        /* No overflow on huge deltas over tiny intervals */
+        assert_eq!(
+            Rate::<Bytes>::new(0, u64::MAX, Duration::from_nanos(1)).map(|r| r.0 .0),
+            Some(u64::MAX)
+        );
+        assert_eq!(rate(0, u64::MAX, 2000), Some(u64::MAX / 2));
+        assert_eq!(format!("{:6.1}", Rate(Bytes(3 << 20))), "  3.0M");
+    }
+
+    #[test]
+    fn heading_short_form() {
+        let s = Settings::default();
+        let h = |w: u16, heading| {
+            s.colwidth.set(w);
+            format!("{}", MaybeSmart(heading, &s))
+        };
+        assert_eq!(
+            h(10, Heading::with_short("INACTIVE", "INACT")),
+            "  INACTIVE"
+        );
+        assert_eq!(h(8, Heading::with_short("INACTIVE", "INACT")), "INACTIVE");
+        assert_eq!(h(7, Heading::with_short("INACTIVE", "INACT")), "  INACT");
+        /* Truncated as a last resort */
+        assert_eq!(h(4, Heading::with_short("INACTIVE", "INACT")), "INAC");
+        assert_eq!(h(7, Heading::new("PRESSURE")), "PRESSUR");
+
+        s.colwidth.set(8);
+        let heading = Heading::with_short("COMMAND", "CMD");
+        assert_eq!(format!("{:<5}", MaybeSmart(heading, &s)), "CMD  ");
+        let heading = Heading::with_short("COMMAND", "CMD");
+        assert_eq!(format!("{:^9}", MaybeSmart(heading, &s)), " COMMAND ");
+        s.smart.set(true);
+        let heading = Heading::with_short("INACTIVE", "INACT");
+        assert_eq!(
+            format!("{:6}", MaybeSmart(heading, &s)),
+            "\x1B[1m INACT\x1B[0m"
+        );
+    }
+
+    /// Every heading string literal in the sources: (long, short)
+    fn source_headings() -> Vec<(&'static str, Option<&'static str>)> {
+        let sources = [
+            include_str!("blockdev.rs"),
+            include_str!("common.rs"),
+            include_str!("cpu.rs"),
+            include_str!("fs.rs"),
+            include_str!("hwmon.rs"),
+            include_str!("kmsg.rs"),
+            include_str!("mem.rs"),
+            include_str!("network.rs"),
+            include_str!("pressure.rs"),
+            include_str!("tasks.rs"),
+        ];
+        let literal = |s: &'static str| s.split_once('"').map(|(l, _)| l);
+        let mut headings = Vec::new();
+        for src in sources {
+            /* Up to the tests of common.rs, which use made up headings */
+            let src = src.split("fn heading_short_form").next().unwrap();
+            for part in src.split("Heading::new(\"").skip(1) {
+                headings.push((literal(part).unwrap(), None));
+            }
+            for part in src.split("Heading::with_short(\"").skip(1) {
+                let (long, rest) = part.split_once('"').unwrap();
+                headings.push((long, rest.split_once('"').and_then(|(_, s)| literal(s))));
+            }
+        }
+        headings
+    }
+
+    #[test]
+    fn headings_fit_min_col_width() {
+        let headings = source_headings();
+        assert!(headings.len() > 50, "{}", headings.len());
+        assert!(headings.contains(&("INACTIVE", Some("INACT"))));
+
+        let s = Settings::default();
+        s.colwidth.set(MIN_COL_WIDTH);
+        for (long, short) in headings {
+            /* Printed over the address columns, wider than one */
+            if long == "TCP_REMOTE" {
+                continue;
+            }
+            let h = match short {
+                Some(short) => Heading::with_short(long, short),
+                None => Heading::new(long),
+            };
+            let out = format!("{}", MaybeSmart(h, &s));
+            assert!(
+                out.trim_start() == long || Some(out.trim_start()) == short,
+                "{:?} truncated to {:?}",
+                long,
+                out
+            );
+        }
+    }
+
+    #[test]
+    fn frame_clock_suspend() {
+        let secs = Duration::from_secs_f64;
+        let mut c = FrameClock::default();
+        /* (monotonic, boottime, realtime) */
+        let mut tick = |m: f64, b: f64, r: f64| {
+            c.observe(secs(m), secs(b), secs(r));
+            (c.suspended, c.stepped)
+        };
+
+        assert_eq!(tick(100.0, 150.0, 1e9), (None, None));
+        assert_eq!(tick(102.0, 152.0, 1e9 + 2.0), (None, None));
+        /* Jitter between reading the clocks */
+        assert_eq!(tick(104.0, 154.5, 1e9 + 4.5), (None, None));
+        /* Suspended for 34m12s between two refreshes 2s apart */
+        assert_eq!(
+            tick(106.0, 2208.5, 1e9 + 2058.5),
+            (Some(Duration::from_secs(2052)), None)
+        );
+        assert_eq!(
+            HumanDuration(Duration::from_secs(2052)).to_string(),
+            "34m12s"
+        );
+        assert_eq!(tick(108.0, 2210.5, 1e9 + 2060.5), (None, None));
+        assert_eq!(c.elapsed, Some(Duration::from_secs(2)));
+        assert_eq!(c.boottime(), Duration::from_secs_f64(2210.5));
+    }
+
+    #[test]
+    fn frame_clock_now() {
+        let mut c = FrameClock::now();
+        assert_eq!(c.elapsed, None);
+        let (at, boot) = (c.instant(), c.boottime());
+        assert!(boot > Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(20));
+        /* Blocks updated later in the same refresh still get the time it started */
+        assert!(c.instant() == at);
+        c.tick();
+        assert!(c.instant() >= at + Duration::from_millis(20));
+        assert!(c.boottime() >= boot + Duration::from_millis(20));
+        assert!(c.elapsed.unwrap() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn frame_clock_remote() {
+        let mut c = FrameClock::default();
+        c.tick_remote(Duration::from_secs(1000));
+        let at = c.instant();
+        assert_eq!(c.boottime(), Duration::from_secs(1000));
+        /* Arrived late: placed by uptime, not by arrival */
+        std::thread::sleep(Duration::from_millis(30));
+        c.tick_remote(Duration::from_millis(1_000_010));
+        assert!(c.instant() == at + Duration::from_millis(10));
+        assert_eq!(c.elapsed, Some(Duration::from_millis(10)));
+        assert_eq!((c.suspended, c.stepped), (None, None));
+        /* Never in the future */
+        c.tick_remote(Duration::from_secs(1100));
+        assert!(c.instant() <= Instant::now());
+    }
+
+    #[test]
+    fn frame_clock_steps() {
+        let secs = Duration::from_secs_f64;
+        let mut c = FrameClock::default();
+        let mut tick = |m: f64, b: f64, r: f64| {
+            c.observe(secs(m), secs(b), secs(r));
+            (c.suspended, c.stepped.map(|s| s.round() as i64))
+        };
+
+        assert_eq!(tick(10.0, 10.0, 5000.0), (None, None));
+        /* NTP step forward, then back */
+        assert_eq!(tick(12.0, 12.0, 5062.0), (None, Some(60)));
+        assert_eq!(tick(14.0, 14.0, 4064.0), (None, Some(-1000)));
+        /* Slewing */
+        assert_eq!(tick(16.0, 16.0, 4066.5), (None, None));
+        /* A suspend is not a step, but both can happen at once */
+        assert_eq!(
+            tick(18.0, 618.0, 4668.5),
+            (Some(Duration::from_secs(600)), None)
+        );
+        assert_eq!(
+            tick(20.0, 920.0, 4900.5),
+            (Some(Duration::from_secs(300)), Some(-70))
+        );
+    }
+
+    /// The command line without any option
+    fn cli() -> Cli {
+        Cli {
+            refresh_interval: 1000,
+            ..Default::default()
+        }
+    }
+
+    /// The errors, or the warnings if there are none
+    fn check(cli: &Cli) -> std::result::Result<Vec<String>, Vec<String>> {
+        let mut warnings = Vec::new();
+        Settings::from_cli(cli, &mut warnings).map(|_| warnings)
+    }
+
+    fn error(cli: &Cli, msg: &str) {
+        match check(cli) {
+            Err(e) => assert_eq!(e, [msg]),
+            Ok(_) => panic!("no error, expected {}", msg),
+        }
+    }
+
+    fn warning(cli: &Cli, msg: &str) {
+        match check(cli) {
+            Ok(w) => assert_eq!(w, [msg]),
+            Err(e) => panic!("errors {:?}, expected warning {}", e, msg),
+        }
+    }
+
+    #[test]
+    fn cli_defaults() {
+        assert_eq!(check(&cli()), Ok(vec![]));
+    }
+
+    #[test]
+    fn cli_errors() {
+        error(
+            &Cli {
+                columns: Some(70),
+                ..cli()
+            },
+            "--columns: 70 is too narrow, hitome needs at least 71",
+        );
+        error(
+            &Cli {
+                rows: Some(10),
+                ..cli()
+            },
+            "--rows: 10 is too short, hitome needs at least 24",
+        );
+        error(
+            &Cli {
+                column_width: Some(5),
+                ..cli()
+            },
+            "--column-width: 5 is too narrow, use at least 8",
+        );
+        error(
+            &Cli {
+                sort_tasks: Some(String::from("pid")),
+                ..cli()
+            },
+            "--sort-tasks: unknown key pid, use state, cpu or mem",
+        );
+        error(
+            &Cli {
+                theme: Some(String::from("solarized")),
+                ..cli()
+            },
+            "--theme: unknown theme solarized, use dark or light",
+        );
+        error(
+            &Cli {
+                cpu_style: Some(String::from("braille")),
+                ..cli()
+            },
+            "--cpu-style: unknown style braille, use ascii or blocks",
+        );
+        error(
+            &Cli {
+                tasks: Some(0),
+                ..cli()
+            },
+            "--tasks: must be at least 1",
+        );
+        error(
+            &Cli {
+                refresh_interval: 0,
+                ..cli()
+            },
+            "--refresh-interval: must be at least 1ms",
+        );
+        error(
+            &Cli {
+                iterations: Some(0),
+                review: true,
+                ..cli()
+            },
+            "--iterations: must be at least 1",
+        );
+        error(
+            &Cli {
+                review: true,
+                ..cli()
+            },
+            "--review: also needs --iterations, or there is nothing to review",
+        );
+        error(
+            &Cli {
+                task_cpu_thresholds: Some(String::from("60,40,80")),
+                cpu_total: true,
+                ..cli()
+            },
+            "--task-cpu-thresholds: 60,40,80 must be increasing, and at most 100",
+        );
+        error(
+            &Cli {
+                task_cpu_thresholds: Some(String::from("40,60")),
+                ..cli()
+            },
+            "--task-cpu-thresholds: expected three values, got 40,60",
+        );
+        error(
+            &Cli {
+                disk_fields: Some(String::from("read,bogus")),
+                ..cli()
+            },
+            "--disk-fields: unknown field 'bogus', valid fields are: read,write,pressure",
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "nvme-health"))]
+    fn cli_nvme_health() {
+        error(
+            &Cli {
+                nvme_health: true,
+                ..cli()
+            },
+            "--nvme-health: hitome was built without the nvme-health feature",
+        );
+    }
+
+    #[test]
+    fn cli_remote() {
+        error(
+            &Cli {
+                remote: Some(String::from("host")),
+                kmsg: true,
+                ..cli()
+            },
+            "--kmsg: only works on this machine, not with --remote",
+        );
+        assert!(check(&Cli {
+            remote: Some(String::from("host")),
+            ..cli()
+        })
+        .is_ok());
+    }
+
+    #[test]
+    fn cli_all_errors() {
+        let e = check(&Cli {
+            tasks: Some(0),
+            theme: Some(String::from("x")),
+            rows: Some(1),
+            ..cli()
+        })
+        .unwrap_err();
+        assert_eq!(e.len(), 3);
+    }
+
+    #[test]
+    fn cli_warnings() {
+        warning(
+            &Cli {
+                columns: Some(100),
+                column_width: Some(12),
+                ..cli()
+            },
+            "--column-width: 8 columns of 12 don't fit in --columns 100",
+        );
+        warning(
+            &Cli {
+                task_wrap: true,
+                task_fields: Some(String::from("pid,cpu")),
+                ..cli()
+            },
+            "--task-wrap: does nothing without the cmd field in --task-fields; remove one",
+        );
+        warning(
+            &Cli {
+                task_tree: true,
+                group_tasks: true,
+                ..cli()
+            },
+            "--task-tree: does nothing with --group-tasks; remove one",
+        );
+        warning(
+            &Cli {
+                fs_verbose: true,
+                fs_fields: Some(String::from("usage,avail")),
+                ..cli()
+            },
+            "--fs-verbose: does nothing without the used field in --fs-fields; remove one",
+        );
+    }
+
+    /// Two lines of 20 columns, the first one ending with blanks after its heading
+    struct Fixed<'a>(&'a Settings, &'static str);
+
+    impl<'a> StatBlock<'a> for Fixed<'a> {
+        fn new(s: &'a Settings) -> Self {
+            Fixed(s, "HEAD")
+        }
+        fn update(&mut self, _: &FrameClock) {}
+        fn columns(&self) -> u16 {
+            20
+        }
+        fn rows(&self) -> u16 {
+            2
+        }
+    }
+
+    impl<'a> Display for Fixed<'a> {
+        fn fmt(&self, f: &mut Formatter) -> Result {
+            let newline = MaybeSmart(Newline(), self.0);
+            write!(f, "{:20}{}{:>20}{}", self.1, newline, "0123456789", newline)
+        }
+    }
+
+    fn aged(s: &Settings, age: Duration) -> Aged<'_, Fixed<'_>> {
+        let mut b = Aged::<Fixed>::new(s);
+        b.updated = Instant::now() - age;
+        b
+    }
+
+    #[test]
+    fn aged_fresh() {
+        let s = Settings {
+            refresh: 1000,
+            ..Default::default()
+        };
+        s.smart.set(false);
+        let b = aged(&s, Duration::from_millis(1500));
+        assert_eq!(b.to_string(), format!("{}", b.block));
+    }
+
+    #[test]
+    fn aged_stale() {
+        let s = Settings {
+            refresh: 1000,
+            ..Default::default()
+        };
+        for smart in [false, true] {
+            s.smart.set(smart);
+            let b = aged(&s, Duration::from_secs(12));
+            let mut buf = String::new();
+            check_layout(&b, &mut buf).unwrap();
+            let first = buf.lines().next().unwrap();
+            match smart {
+                false => assert_eq!(first, "HEAD       (12s old)"),
+                true => assert_eq!(first, "HEAD       \x1B[2m(12s old)\x1B[0m\x1B[0K"),
+            }
+        }
+    }
+
+    #[test]
+    fn aged_no_room() {
+        let s = Settings {
+            refresh: 1000,
+            ..Default::default()
+        };
+        s.smart.set(false);
+        let mut b = aged(&s, Duration::from_secs(100));
+        assert_eq!(
+            b.to_string().lines().next().unwrap(),
+            "HEAD     (1m40s old)"
+        );
+        /* A blank is kept before the tag */
+        b.1 = "HEADING.";
+        assert_eq!(
+            b.to_string().lines().next().unwrap(),
+            "HEADING. (1m40s old)"
+        );
+        b.1 = "HEADING..";
+        assert_eq!(
+            b.to_string().lines().next().unwrap(),
+            "HEADING..           "
+        );
+    }
+
+    #[test]
+    fn visible_width_counts_columns() {
+        assert_eq!(visible_width(""), 0);
+        assert_eq!(visible_width("abc"), 3);
+        assert_eq!(visible_width("\x1B[1;32mabc\x1B[0m"), 3);
+        assert_eq!(visible_width("日本"), 4);
+        assert_eq!(visible_width("\x1B[2m日本\x1B[0m x"), 6);
+        assert_eq!(visible_width("e\u{0301}"), 1);
+    }
+
+    /// Collapse each run of replacement characters, from either mangle_utf8() or
+    /// String::from_utf8_lossy(), into a single '?'
+    fn normalize(s: &str) -> String {
+        let mut out = String::with_capacity(s.len());
+        for c in s.chars().map(|c| if c == '\u{FFFD}' { '?' } else { c }) {
+            if c != '?' || !out.ends_with('?') {
+                out.push(c);
+            }
+        }
+        out
+    }
+
+    fn check_mangle(v: &[u8]) {
+        let mut m = v.to_vec();
+        mangle_utf8(&mut m);
+        let m = String::from_utf8(m).expect("mangle_utf8 left invalid UTF-8");
+        assert_eq!(m.len(), v.len());
+        assert_eq!(
+            normalize(&m),
+            normalize(&String::from_utf8_lossy(v)),
+            "{:x?}",
+            v
+        );
+    }
+
+    #[test]
+    fn mangle_utf8_edge_cases() {
+        for v in [
+            &b""[..],
+            b"plain ascii",
+            "valid: \u{e9}\u{20ac}\u{1f600}".as_bytes(),
+            /* Surrogates, overlong encodings, above U+10FFFF */
+            b"\xED\xA0\x80",
+            b"\xE0\x80\x80",
+            b"\xC0\xAF",
+            b"\xF4\x90\x80\x80",
+            b"\xF5\x80\x80\x80",
+            /* Truncated 3 and 4 byte sequences, in the middle and at the end */
+            b"a\xE2\x82b",
+            b"a\xF0\x9F\x98b",
+            b"a\xE2\x82",
+            b"a\xF0\x9F\x98",
+            b"\xF0\x9F\x98\xF0\x9F\x98\x80",
+            b"\x80\xBF\xFE\xFF",
+        ] {
+            check_mangle(v);
+        }
+    }
+
+    #[test]
+    fn mangle_utf8_random() {
+        /* Bytes likely to start, continue or break multi-byte sequences, '?' left out so it can
+         * stand for any replacement */
+        const BYTES: &[u8] =
+            b"a \x7F\x80\x8F\x90\x9F\xA0\xBF\xC0\xC1\xC2\xDF\xE0\xE1\xED\xEF\xF0\xF1\xF4\xF5\xFF";
+        let mut x: u64 = 0x9E37_79B9_7F4A_7C15;
+        let mut next = || {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        };
+        let mut v = Vec::new();
+        for _ in 0..20000 {
+            v.clear();
+            let len = next() % 12;
+            for _ in 0..len {
+                v.push(BYTES[(next() % BYTES.len() as u64) as usize]);
+            }
+            check_mangle(&v);
+        }
+    }
+
+    /// The whole frame of the fixture machine on an 80x24 terminal with --compact: blocks built
+    /// and updated like in main.rs, rows shared like fit_blocks!() does
+    #[test]
+    fn compact_frame_fits() {
+        use crate::blockdev::BlockDeviceStats;
+        use crate::cpu::CpuStats;
+        use crate::fs::FilesystemStats;
+        use crate::hwmon::HwmonStats;
+        use crate::mem::MemoryStats;
+        use crate::network::NetworkStats;
+        use crate::pressure::PressureStats;
+        use crate::tasks::{TaskStateSummary, TaskStats};
+
+        let s = Settings {
+            compact: true,
+            ..Default::default()
+        };
+        s.maxcols.set(80);
+        s.maxrows.set(24);
+        s.colwidth.set(9);
+        s.proc_root.inject_fixture(0);
+
+        let mut mem = MemoryStats::new(&s);
+        let mut psi = PressureStats::new(&s);
+        let mut cpu_net =
+            MergedStatBlock::<MergedStatBlock<CpuStats, NetworkStats>, TaskStateSummary>::new(&s);
+        let mut bdev_fs = MergedStatBlock::<BlockDeviceStats, FilesystemStats>::new(&s);
+        let mut hwmon = HwmonStats::new(&s);
+        let mut tasks = TaskStats::new(&s);
+        let mut left = 0;
+        /* A refresh per second of the fixture's uptime */
+        let (start, mut clock) = (Instant::now(), FrameClock::default());
+        for step in 1..3 {
+            s.proc_root.inject_fixture(step);
+            let uptime = Duration::from_secs(1000 + step);
+            clock.observe(uptime, uptime, uptime);
+            clock.at = Some(start + Duration::from_secs(step));
+            mem.update(&clock);
+            psi.update(&clock);
+            cpu_net.update(&clock);
+            bdev_fs.update(&clock);
+            hwmon.update(&clock);
+
+            /* The rows left for hwmon and tasks, minus the last one */
+            left = 24 - mem.rows() - psi.rows() - cpu_net.rows() - bdev_fs.rows() - 1;
+            hwmon.fit(match left >= hwmon.preferred_rows() + tasks.min_rows() {
+                true => hwmon.preferred_rows(),
+                false => hwmon.min_rows(),
+            });
+            tasks.fit(left - hwmon.rows());
+            tasks.update(&clock);
+            cpu_net.right_mut().set_states(tasks.state_counts());
+            cpu_net.left_mut().left_mut().set_hot_cpu(tasks.hot_cpu());
+        }
+
+        let mut buf = String::new();
+        check_layout(&mem, &mut buf).unwrap();
+        check_layout(&psi, &mut buf).unwrap();
+        check_layout(&cpu_net, &mut buf).unwrap();
+        /* CPUs, interfaces and task states side by side */
+        assert_eq!(cpu_net.rows(), 5);
+        check_layout(&bdev_fs, &mut buf).unwrap();
+        /* Drives and filesystems too */
+        assert_eq!(bdev_fs.rows(), 5);
+        check_layout(&hwmon, &mut buf).unwrap();
+        check_layout(&tasks, &mut buf).unwrap();
+        assert!(buf.contains("cc1"), "{}", buf);
+
+        for (block, columns) in [
+            ("mem", mem.columns()),
+            ("psi", psi.columns()),
+            ("cpu_net", cpu_net.columns()),
+            ("bdev_fs", bdev_fs.columns()),
+            ("hwmon", hwmon.columns()),
+            ("tasks", tasks.columns()),
+        ] {
+            assert!(columns <= 80, "{} is {} columns wide", block, columns);
+        }
+        /* Room for the fewest tasks the list is ever shown with, not just the few there are */
+        assert!(
+            hwmon.rows() + tasks.rows().max(tasks.min_rows()) <= left,
+            "{} rows left",
+            left
+        );
+    }
+}
@@ -0,0 +1,148 @@
+/* Copyright 2022 Romain "Artefact2" Dal Maso <romain.dalmaso@artefact2.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *	   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/* Task creation and exit events from the proc connector, see include/uapi/linux/cn_proc.h and
+ * include/uapi/linux/connector.h. The events queue up in the socket between refreshes, no thread
+ * is needed to listen to them. */
+
+use crate::common::FileDescriptor;
+
+const NETLINK_CONNECTOR: libc::c_int = 11;
+const CN_IDX_PROC: u32 = 1;
+const CN_VAL_PROC: u32 = 1;
+const PROC_CN_MCAST_LISTEN: u32 = 1;
+const PROC_EVENT_FORK: u32 = 0x00000001;
+const PROC_EVENT_EXIT: u32 = 0x80000000;
+
+/// sizeof(struct nlmsghdr)
+const NLMSG_HDRLEN: usize = 16;
+/// sizeof(struct cn_msg)
+const CN_MSG_LEN: usize = 20;
+/// Offset of event_data in struct proc_event
+const EVENT_DATA: usize = NLMSG_HDRLEN + CN_MSG_LEN + 16;
+
+/// Ask for a big receive buffer, so a burst of forks between two refreshes isn't lost
+const RCVBUF: libc::c_int = 1 << 20;
+
+pub enum TaskEvent {
+    /// (tid, tgid)
+    Fork(u32, u32),
+    /// tid
+    Exit(u32),
+}
+
+pub struct ProcConnector {
+    fd: FileDescriptor,
+    buf: [u8; 256],
+}
+
+impl ProcConnector {
+    /// None if the socket can't be opened or subscribed, usually because this needs
+    /// CAP_NET_ADMIN
+    pub fn new() -> Option<ProcConnector> {
+        unsafe {
+            let fd = libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                NETLINK_CONNECTOR,
+            );
+            if fd == -1 {
+                return None;
+            }
+            let fd = FileDescriptor(fd);
+
+            let mut addr: libc::sockaddr_nl = std::mem::zeroed();
+            addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            addr.nl_groups = CN_IDX_PROC;
+            if libc::bind(
+                fd.0,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            ) != 0
+            {
+                return None;
+            }
+
+            /* Not fatal, the default buffer is only more likely to overflow */
+            libc::setsockopt(
+                fd.0,
+                libc::SOL_SOCKET,
+                libc::SO_RCVBUF,
+                &RCVBUF as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            );
+
+            /* struct nlmsghdr, struct cn_msg, then enum proc_cn_mcast_op */
+            let mut msg = [0u8; NLMSG_HDRLEN + CN_MSG_LEN + 4];
+            let len = msg.len() as u32;
+            msg[0..4].copy_from_slice(&len.to_ne_bytes());
+            msg[4..6].copy_from_slice(&(libc::NLMSG_DONE as u16).to_ne_bytes());
+            msg[12..16].copy_from_slice(&(libc::getpid() as u32).to_ne_bytes());
+            msg[16..20].copy_from_slice(&CN_IDX_PROC.to_ne_bytes());
+            msg[20..24].copy_from_slice(&CN_VAL_PROC.to_ne_bytes());
+            msg[32..34].copy_from_slice(&4u16.to_ne_bytes());
+            msg[36..40].copy_from_slice(&PROC_CN_MCAST_LISTEN.to_ne_bytes());
+            if libc::send(fd.0, msg.as_ptr() as *const libc::c_void, msg.len(), 0)
+                != msg.len() as isize
+            {
+                return None;
+            }
+
+            Some(ProcConnector { fd, buf: [0; 256] })
+        }
+    }
+
+    /// Call f for each event received since the last call. Returns false if some events were
+    /// lost (the receive buffer overflowed), then the tasks must be looked up again from /proc.
+    pub fn drain<F>(&mut self, mut f: F) -> bool
+    where
+        F: FnMut(TaskEvent),
+    {
+        let u32_at =
+            |buf: &[u8], off: usize| u32::from_ne_bytes(buf[off..off + 4].try_into().unwrap());
+        loop {
+            let n = unsafe {
+                libc::recv(
+                    self.fd.0,
+                    self.buf.as_mut_ptr() as *mut libc::c_void,
+                    self.buf.len(),
+                    0,
+                )
+            };
+            if n == -1 {
+                return match unsafe { *libc::__errno_location() } {
+                    libc::EAGAIN => true,
+                    libc::EINTR => continue,
+                    /* ENOBUFS */
+                    _ => false,
+                };
+            }
+
+            /* One event per message */
+            let msg = &self.buf[..n as usize];
+            if msg.len() < EVENT_DATA + 16 {
+                continue;
+            }
+            match u32_at(msg, NLMSG_HDRLEN + CN_MSG_LEN) {
+                PROC_EVENT_FORK => f(TaskEvent::Fork(
+                    u32_at(msg, EVENT_DATA + 8),
+                    u32_at(msg, EVENT_DATA + 12),
+                )),
+                PROC_EVENT_EXIT => f(TaskEvent::Exit(u32_at(msg, EVENT_DATA))),
+                _ => (),
+            }
+        }
+    }
+}
@@ -14,59 +14,313 @@
  */
 
 use super::common::*;
+use crate::procroot::ProcRoot;
 use std::collections::BTreeMap;
 use std::fmt;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default)]
 struct CpuTicks {
     user: u64,
     nice: u64,
     system: u64,
     iowait: u64,
     idle: u64,
+    /// Time the hypervisor ran something else while this (virtual) CPU wanted to run
+    steal: u64,
+    irq: u64,
+    softirq: u64,
     total: u64,
 }
 
+impl CpuTicks {
+    /// Did any counter go backwards since prev, eg. after resuming from suspend or CPU hotplug?
+    /// Not iowait, which decreases every now and then anyway.
+    fn reset_since(&self, prev: &CpuTicks) -> bool {
+        self.user < prev.user
+            || self.nice < prev.nice
+            || self.system < prev.system
+            || self.idle < prev.idle
+            || self.steal < prev.steal
+            || self.irq < prev.irq
+            || self.softirq < prev.softirq
+    }
+}
+
+/// Only look at /proc/softirqs when softirqs take more than this share of CPU time
+const SOFTIRQ_THRESHOLD: f32 = 0.05;
+/// Show this many softirq types, the most frequent ones
+const SOFTIRQ_SHOWN: usize = 3;
+
+/// Share of time in a category, between 0 and 1, and how to draw it. All glyphs are one cell
+/// wide.
 #[derive(PartialEq, PartialOrd)]
-struct CpuUsage(f32);
+struct CpuUsage(f32, CpuStyle);
 
 impl fmt::Display for CpuUsage {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
             "{}",
-            match self.0 {
-                x if x >= 0.6 => 'X',
-                x if x >= 0.2 => 'O',
-                x if x >= 0.1 => 'o',
-                x if x >= 0.01 => '.',
-                _ => ' ',
+            match (self.0, self.1) {
+                (x, _) if x < 0.01 => ' ',
+                /* One eighth per step, rounded up so any usage shows */
+                (x, CpuStyle::Blocks) => {
+                    let i = ((x * 8.0).ceil() as usize).clamp(1, 8);
+                    ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'][i - 1]
+                }
+                (x, CpuStyle::Ascii) if x >= 0.6 => 'X',
+                (x, CpuStyle::Ascii) if x >= 0.2 => 'O',
+                (x, CpuStyle::Ascii) if x >= 0.1 => 'o',
+                (_, CpuStyle::Ascii) => '.',
             }
         )
     }
 }
 
+/// Colour the usage of a CPU by how little idle time it had, whatever it was busy with
+fn coloured(usage: f32, idle: f32, style: CpuStyle) -> Threshold<CpuUsage> {
+    let trs = match idle {
+        x if x <= 0.2 => (0.0, 0.0, 0.0),
+        x if x <= 0.4 => (0.0, 0.0, 1.0),
+        x if x <= 0.6 => (0.0, 1.0, 1.0),
+        _ => (1.0, 1.0, 1.0),
+    };
+    Threshold {
+        val: CpuUsage(usage, style),
+        med: CpuUsage(trs.0, style),
+        high: CpuUsage(trs.1, style),
+        crit: CpuUsage(trs.2, style),
+    }
+}
+
+/// Shares of time a CPU was busy (as in the compact BUSY row) and idle, between 0 and 1
+#[derive(Clone, Copy, Default)]
+pub struct Load {
+    pub busy: f32,
+    pub idle: f32,
+}
+
+/// The usage of CPUs in at most this many glyphs, averaging neighbours as needed, eg. for
+/// --tmux
+pub struct Glyphs<'a>(pub &'a [Load], pub usize);
+
+impl<'a, 'b> fmt::Display for MaybeSmart<'a, Glyphs<'b>> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Glyphs(loads, n) = self.0;
+        let style = match self.1.smart.get() {
+            true => self.1.cpu_style,
+            false => CpuStyle::Ascii,
+        };
+        let n = n.min(loads.len());
+        for i in 0..n {
+            let group = &loads[(i * loads.len() / n)..((i + 1) * loads.len() / n)];
+            let len = group.len() as f32;
+            let busy = group.iter().map(|l| l.busy).sum::<f32>() / len;
+            let idle = group.iter().map(|l| l.idle).sum::<f32>() / len;
+            write!(f, "{}", MaybeSmart(coloured(busy, idle, style), self.1))?;
+        }
+        Ok(())
+    }
+}
+
+/// The CPUs our cgroup is allowed to use, so a container limited to a few host CPUs doesn't
+/// always look idle. Only cgroup v2 is supported; without restrictions, nothing changes.
+#[derive(Default)]
+pub struct CpuLimits {
+    /// From cpuset.cpus.effective, indexed by CPU number; empty if unrestricted
+    allowed: Vec<bool>,
+    /// From cpu.max, in CPUs
+    quota: Option<f32>,
+    /// Online CPUs, of the machine the files are read from
+    online: u16,
+}
+
+/// Parse a cpuset list, eg "0-3,8,10-11"
+pub fn parse_cpu_list(list: &str, allowed: &mut Vec<bool>) {
+    allowed.clear();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let (a, b) = range.split_once('-').unwrap_or((range, range));
+        let (a, b) = match (a.parse::<usize>(), b.parse::<usize>()) {
+            (Ok(a), Ok(b)) if a <= b => (a, b),
+            _ => continue,
+        };
+        if allowed.len() <= b {
+            allowed.resize(b + 1, false);
+        }
+        allowed[a..=b].iter_mut().for_each(|c| *c = true);
+    }
+}
+
+impl CpuLimits {
+    pub fn read() -> CpuLimits {
+        let mut limits = CpuLimits::default();
+        limits.update(&ProcRoot::default(), &mut String::new());
+        limits
+    }
+
+    /// Read the limits of our cgroup again, they can be changed at any time
+    pub fn update(&mut self, root: &ProcRoot, buf: &mut String) {
+        self.allowed.clear();
+        self.quota = None;
+        self.online = root.machine().cpus;
+
+        /* We don't run in a cgroup of a remote machine */
+        if !root.is_local() {
+            return;
+        }
+        /* The cgroup v2 hierarchy is the "0::/path" line */
+        if read_to_string("/proc/self/cgroup", buf).is_err() {
+            return;
+        }
+        let dir = match buf.lines().find_map(|l| l.strip_prefix("0::")) {
+            /* The root cgroup has no limits */
+            Some(p) if p != "/" => format!("/sys/fs/cgroup{}", p),
+            _ => return,
+        };
+
+        /* XXX: cpu.max of parent cgroups is ignored, cpuset.cpus.effective already accounts for
+         * them */
+        if read_to_string(format!("{}/cpuset.cpus.effective", dir), buf).is_ok() {
+            let mut allowed = std::mem::take(&mut self.allowed);
+            parse_cpu_list(buf, &mut allowed);
+            self.allowed = allowed;
+        }
+        if read_to_string(format!("{}/cpu.max", dir), buf).is_ok() {
+            let mut fields = buf.split_ascii_whitespace();
+            if let (Some(Ok(quota)), Some(Ok(period))) = (
+                fields.next().map(|q| q.parse::<f32>()),
+                fields.next().map(|p| p.parse::<f32>()),
+            ) {
+                self.quota = Some(quota / period);
+            }
+        }
+    }
+
+    /// Can we run on this CPU?
+    pub fn allows(&self, cpu: usize) -> bool {
+        self.allowed.is_empty() || self.allowed.get(cpu) == Some(&true)
+    }
+
+    /// How many CPUs worth of time we can use, rounded up
+    pub fn effective_cpus(&self) -> u16 {
+        let online = self.online.max(1);
+        let allowed = match self.allowed.iter().filter(|c| **c).count() as u16 {
+            0 => online,
+            n => n.min(online),
+        };
+        match self.quota {
+            Some(q) => allowed.min(q.ceil() as u16).max(1),
+            None => allowed,
+        }
+    }
+}
+
+/// Parse /proc/softirqs into (type, count summed over all CPUs). There is a column per CPU, going
+/// by the header.
+fn parse_softirqs(softirqs: &str) -> impl Iterator<Item = (&str, u64)> {
+    let mut lines = softirqs.lines();
+    let cpus = lines
+        .next()
+        .map_or(0, |header| header.split_ascii_whitespace().count());
+    lines.filter_map(move |line| {
+        let (name, counts) = line.split_once(':')?;
+        let n = counts
+            .split_ascii_whitespace()
+            .take(cpus)
+            .filter_map(|c| c.parse::<u64>().ok())
+            .sum::<u64>();
+        Some((name.trim_start(), n))
+    })
+}
+
 pub struct CpuStats<'a> {
     settings: &'a Settings,
-    /* Use a BTreeMap to keep CPUs in a deterministic order */
+    limits: CpuLimits,
+    /* Use a BTreeMap to keep CPUs in a deterministic order. Offline CPUs are stale, and keep
+     * their column. */
     state: BTreeMap<usize, (CpuTicks, CpuTicks, Stale)>,
+    /// Softirqs handled by all CPUs so far, by type; only read above SOFTIRQ_THRESHOLD
+    softirqs: Vec<(String, u64)>,
+    /// Share of the softirqs of each type since the last refresh, in percent, most frequent first
+    breakdown: Vec<(String, u8)>,
+    /// Did any CPU ever have time stolen? Always false on bare metal.
+    steal: bool,
+    /// The CPU the busiest shown task last ran on, if busy, as given by set_hot_cpu()
+    hot_cpu: Option<u16>,
     buf: String,
 }
 
+impl<'a> CpuStats<'a> {
+    /// Underline this CPU, from TaskStats::hot_cpu()
+    pub fn set_hot_cpu(&mut self, cpu: Option<u16>) {
+        self.hot_cpu = cpu;
+    }
+
+    /// The load of each CPU we can use since the last update, in order; empty until two updates
+    pub fn loads(&self) -> Vec<Load> {
+        let allowed = self
+            .state
+            .iter()
+            .filter(|(id, cpu)| self.limits.allows(**id) && cpu.2 == Stale(false));
+        allowed
+            .filter(|(_, cpu)| cpu.1.total > cpu.0.total)
+            .map(|(_, cpu)| {
+                let busy = |c: CpuTicks| c.user + c.nice + c.system + c.iowait;
+                let total = (cpu.1.total - cpu.0.total) as f32;
+                Load {
+                    busy: busy(cpu.1).saturating_sub(busy(cpu.0)) as f32 / total,
+                    idle: cpu.1.idle.saturating_sub(cpu.0.idle) as f32 / total,
+                }
+            })
+            .collect()
+    }
+
+    /// Time spent by the CPUs we can use in user, nice or system, in percent of the effective CPU
+    /// count; None until two updates
+    pub fn busy(&self) -> Option<f32> {
+        let allowed = self
+            .state
+            .iter()
+            .filter(|(id, cpu)| self.limits.allows(**id) && cpu.2 == Stale(false));
+        let (busy, total, n) = allowed.fold((0, 0, 0), |(b, t, n), (_, cpu)| {
+            let busy = |c: CpuTicks| c.user + c.nice + c.system;
+            (
+                b + busy(cpu.1).saturating_sub(busy(cpu.0)),
+                t + cpu.1.total.saturating_sub(cpu.0.total),
+                n + 1,
+            )
+        });
+        /* With a quota, using all of it on fewer CPUs than allowed is 100% */
+        let scale = n as f32 / self.limits.effective_cpus() as f32;
+        match total {
+            0 => None,
+            t => Some((100.0 * scale * busy as f32 / t as f32).min(100.0)),
+        }
+    }
+}
+
 impl<'a> StatBlock<'a> for CpuStats<'a> {
     fn new(s: &'a Settings) -> CpuStats {
         let mut cpu = CpuStats {
             settings: s,
+            limits: Default::default(),
             state: Default::default(),
+            softirqs: Vec::new(),
+            breakdown: Vec::new(),
+            steal: false,
+            hot_cpu: None,
             buf: String::new(),
         };
-        cpu.update();
+        cpu.update(&FrameClock::now());
         cpu
     }
 
-    fn update(&mut self) {
+    fn update(&mut self, _: &FrameClock) {
+        let root = &self.settings.proc_root;
+        self.limits.update(root, &mut self.buf);
+
         /* /proc/stats never contains arbitrary user data */
-        match unsafe { read_to_string_unchecked("/proc/stat", &mut self.buf) } {
+        match unsafe { root.read_to_string_unchecked("/proc/stat", &mut self.buf) } {
             Ok(_) => (),
             _ => {
                 self.state.clear();
@@ -90,14 +344,7 @@ impl<'a> StatBlock<'a> for CpuStats<'a> {
             let mut ent = match self.state.get_mut(&cpuid) {
                 Some(ent) => ent,
                 _ => {
-                    let z = CpuTicks {
-                        user: 0,
-                        nice: 0,
-                        system: 0,
-                        iowait: 0,
-                        idle: 0,
-                        total: 0,
-                    };
+                    let z = CpuTicks::default();
                     self.state.insert(cpuid, (z, z, Stale(false)));
                     self.state.get_mut(&cpuid).unwrap()
                 }
@@ -107,7 +354,7 @@ impl<'a> StatBlock<'a> for CpuStats<'a> {
             ent.1.total = 0;
             ent.2 = Stale(false);
 
-            for j in 0..=4 {
+            for j in 0..=6 {
                 let t = fields.next().unwrap().parse::<u64>().unwrap();
 
                 /* https://docs.kernel.org/filesystems/proc.html#miscellaneous-kernel-statistics-in-proc-stat */
@@ -117,14 +364,53 @@ impl<'a> StatBlock<'a> for CpuStats<'a> {
                     2 => ent.1.system = t,
                     3 => ent.1.idle = t,
                     4 => ent.1.iowait = t,
+                    5 => ent.1.irq = t,
+                    6 => ent.1.softirq = t,
                     _ => unreachable!(),
                 }
 
                 ent.1.total += t;
             }
+
+            /* Missing before Linux 2.6.11. The guest and guest_nice fields that follow are already
+             * counted in user and nice, don't count them twice. */
+            let steal = fields
+                .next()
+                .and_then(|t| t.parse::<u64>().ok())
+                .unwrap_or(0);
+            ent.1.steal = steal;
+            ent.1.total += steal;
+
+            /* Start over from the new values, this CPU is blank until the next refresh */
+            if ent.1.reset_since(&ent.0) {
+                ent.0 = ent.1;
+            }
+        }
+
+        /* Offline CPUs are missing from /proc/stat. Keep a column for the ones that went offline
+         * and the ones below the highest id (offline from the start), so columns don't shift. */
+        if let Some(&max) = self.state.keys().next_back() {
+            for id in 0..max {
+                let z = CpuTicks::default();
+                self.state.entry(id).or_insert((z, z, Stale(true)));
+            }
+        }
+        for s in self.state.values_mut().filter(|s| s.2 == Stale(true)) {
+            s.0 = s.1;
         }
+        self.steal = self.state.values().any(|s| s.1.steal > 0);
 
-        self.state.retain(|_, s| s.2 == Stale(false));
+        let (softirq, total) = self.state.values().fold((0, 0), |(s, t), cpu| {
+            let softirq = cpu.1.softirq.saturating_sub(cpu.0.softirq);
+            (s + softirq, t + cpu.1.total.saturating_sub(cpu.0.total))
+        });
+        match total > 0 && softirq as f32 / total as f32 > SOFTIRQ_THRESHOLD {
+            true => self.update_softirqs(),
+            false => {
+                self.softirqs.clear();
+                self.breakdown.clear();
+            }
+        }
     }
 
     fn columns(&self) -> u16 {
@@ -136,14 +422,85 @@ impl<'a> StatBlock<'a> for CpuStats<'a> {
     }
 
     fn rows(&self) -> u16 {
+        let softirqs = !self.breakdown.is_empty() as u16;
         if self.state.is_empty() {
             0
+        } else if self.settings.compact {
+            2 + softirqs
         } else {
-            5
+            5 + self.steal as u16 + 2 * self.settings.cpu_detail as u16 + softirqs
         }
     }
 }
 
+impl<'a> CpuStats<'a> {
+    /// Find out which softirqs keep the CPUs busy since the last call
+    fn update_softirqs(&mut self) {
+        /* /proc/softirqs never contains arbitrary user data */
+        let root = &self.settings.proc_root;
+        if unsafe { root.read_to_string_unchecked("/proc/softirqs", &mut self.buf) }.is_err() {
+            return;
+        }
+        let prev = std::mem::take(&mut self.softirqs);
+        let mut deltas = Vec::new();
+        for (name, n) in parse_softirqs(&self.buf) {
+            if let Some((_, p)) = prev.iter().find(|(k, _)| k == name) {
+                deltas.push((name, n.saturating_sub(*p)));
+            }
+            self.softirqs.push((String::from(name), n));
+        }
+
+        let total = deltas.iter().map(|(_, d)| d).sum::<u64>();
+        self.breakdown.clear();
+        if total == 0 {
+            return;
+        }
+        deltas.sort_by_key(|d| std::cmp::Reverse(d.1));
+        for (name, d) in deltas.into_iter().take(SOFTIRQ_SHOWN) {
+            self.breakdown
+                .push((String::from(name), (100 * d / total) as u8));
+        }
+    }
+
+    /// "SOFTIRQ NET_RX 61% TIMER 22% RCU 9%", with as many types as fit
+    fn fmt_softirqs(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let c = self.columns() as usize;
+        let mut w = 0;
+        for (i, (name, pc)) in self.breakdown.iter().enumerate() {
+            let len = name.len() + 2 + digits(*pc);
+            if i == 0 && c >= 8 + len {
+                write!(
+                    f,
+                    "{:7}",
+                    MaybeSmart(Heading::new("SOFTIRQ"), self.settings)
+                )?;
+                w += 7;
+            }
+            if w + (w > 0) as usize + len > c {
+                break;
+            }
+            if w > 0 {
+                f.write_str(" ")?;
+                w += 1;
+            }
+            write!(f, "{} {}%", name, pc)?;
+            w += len;
+        }
+        write!(
+            f,
+            "{:p$}{}",
+            "",
+            MaybeSmart(Newline(), self.settings),
+            p = c - w
+        )
+    }
+}
+
+/// Number of digits of a percentage
+fn digits(pc: u8) -> usize {
+    pc.checked_ilog10().unwrap_or(0) as usize + 1
+}
+
 impl<'a> fmt::Display for CpuStats<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         if self.state.is_empty() {
@@ -151,51 +508,225 @@ impl<'a> fmt::Display for CpuStats<'a> {
         }
 
         let newline = MaybeSmart(Newline(), self.settings);
+        /* Underline the column of the CPU the busiest task runs on */
+        let hot = match self.settings.smart.get() {
+            true => self.hot_cpu,
+            false => None,
+        };
 
-        for cat in ["IOWAIT", "SYSTEM", "USER", "NICE"].iter() {
-            write!(f, "{} ", MaybeSmart(Heading(cat), self.settings))?;
+        /* Block elements along with colours only, the terminal may not be able to show them */
+        let style = match self.settings.smart.get() {
+            true => self.settings.cpu_style,
+            false => CpuStyle::Ascii,
+        };
+
+        /* In compact mode, fold all categories into one row */
+        let cats: &[&str] = match self.settings.compact {
+            true => &["BUSY"],
+            false => &["STEAL", "IRQ", "SIRQ", "IOWAIT", "SYSTEM", "USER", "NICE"],
+        };
+        let shown = |cat: &&&str| match **cat {
+            "STEAL" => self.steal,
+            "IRQ" | "SIRQ" => self.settings.cpu_detail,
+            _ => true,
+        };
+
+        for cat in cats.iter().filter(shown) {
+            write!(f, "{} ", MaybeSmart(Heading::new(cat), self.settings))?;
 
             /* XXX: this doesn't feel like the best way */
             let get = |c: CpuTicks| match *cat {
+                "STEAL" => c.steal,
+                "IRQ" => c.irq,
+                "SIRQ" => c.softirq,
                 "IOWAIT" => c.iowait,
                 "SYSTEM" => c.system,
                 "USER" => c.user,
                 "NICE" => c.nice,
+                "BUSY" => c.user + c.nice + c.system + c.iowait,
                 _ => unreachable!(),
             };
 
-            for (_, cpu) in self.state.iter() {
+            for (id, cpu) in self.state.iter() {
+                if cpu.2 == Stale(true) {
+                    write!(f, "-")?;
+                    continue;
+                }
+                /* Nothing to compare with after a counter reset */
+                if cpu.1.total <= cpu.0.total {
+                    write!(f, " ")?;
+                    continue;
+                }
+
+                let usage = CpuUsage(
+                    /* Use a saturating sub, the iowait counters occasionally decrease(!). */
+                    (get(cpu.1).saturating_sub(get(cpu.0)) as f32)
+                        / ((cpu.1.total - cpu.0.total) as f32),
+                    style,
+                );
+
+                /* Dim CPUs outside of our cpuset, their load isn't ours to worry about */
+                if !self.limits.allows(*id) {
+                    match self.settings.smart.get() {
+                        true => write!(f, "\x1B[2m{}\x1B[0m", usage)?,
+                        false => write!(f, "{}", usage)?,
+                    }
+                    continue;
+                }
+
                 /* Set thresholds for colouring based on idle% */
-                let trs = match ((cpu.1.idle - cpu.0.idle) as f32)
-                    / ((cpu.1.total - cpu.0.total) as f32)
-                {
-                    x if x <= 0.2 => (0.0, 0.0, 0.0),
-                    x if x <= 0.4 => (0.0, 0.0, 1.0),
-                    x if x <= 0.6 => (0.0, 1.0, 1.0),
-                    _ => (1.0, 1.0, 1.0),
-                };
-
-                /* XXX: figure out if the counters ever wrap */
+                let idle =
+                    ((cpu.1.idle - cpu.0.idle) as f32) / ((cpu.1.total - cpu.0.total) as f32);
+
+                if hot == Some(*id as u16) {
+                    write!(f, "\x1B[4m")?;
+                }
                 write!(
                     f,
                     "{}",
-                    MaybeSmart(Threshold {
-                        /* Use a saturating sub, the iowait counters occasionally decrease(!). */
-                        val: CpuUsage(
-                            (get(cpu.1).saturating_sub(get(cpu.0)) as f32)
-                                / ((cpu.1.total - cpu.0.total) as f32)
-                        ),
-                        med: CpuUsage(trs.0),
-                        high: CpuUsage(trs.1),
-                        crit: CpuUsage(trs.2),
-                    }, self.settings)
+                    MaybeSmart(coloured(usage.0, idle, style), self.settings)
                 )
                 .unwrap();
+                if hot == Some(*id as u16) {
+                    write!(f, "\x1B[24m")?;
+                }
             }
 
             write!(f, "{}", newline)?
         }
 
+        if !self.breakdown.is_empty() {
+            self.fmt_softirqs(f)?;
+        }
         write!(f, "{}", newline)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_lists() {
+        let mut allowed = vec![true; 3];
+        let cpus = |allowed: &Vec<bool>| {
+            let on = allowed.iter().enumerate().filter(|(_, a)| **a);
+            on.map(|(c, _)| c).collect::<Vec<_>>()
+        };
+        parse_cpu_list("0-3,8\n", &mut allowed);
+        assert_eq!(cpus(&allowed), [0, 1, 2, 3, 8]);
+        assert_eq!(allowed.len(), 9);
+        parse_cpu_list("", &mut allowed);
+        assert!(allowed.is_empty());
+        /* What doesn't parse is skipped, reversed ranges too */
+        parse_cpu_list("5-2,x,1,,6-", &mut allowed);
+        assert_eq!(cpus(&allowed), [1]);
+    }
+
+    #[test]
+    fn compact_layout() {
+        let mut buf = String::new();
+        let settings = |compact| {
+            let s = Settings {
+                compact,
+                ..Default::default()
+            };
+            s.maxcols.set(80);
+            s.maxrows.set(24);
+            s.colwidth.set(9);
+            s
+        };
+        let (s, full) = (settings(true), settings(false));
+        s.proc_root.inject_fixture(0);
+        full.proc_root.inject_fixture(0);
+        let (mut c, mut f) = (CpuStats::new(&s), CpuStats::new(&full));
+        for step in 1..3 {
+            s.proc_root.inject_fixture(step);
+            full.proc_root.inject_fixture(step);
+            c.update(&FrameClock::now());
+            f.update(&FrameClock::now());
+        }
+        check_layout(&c, &mut buf).unwrap();
+        check_layout(&f, &mut buf).unwrap();
+        /* One busy row folding the four categories, plus softirqs */
+        assert_eq!((c.rows(), f.rows()), (3, 6));
+        assert_eq!(c.columns(), 9 + 1 + 8);
+    }
+
+    #[test]
+    fn glyphs() {
+        let s = Settings::default();
+        let loads: Vec<_> = [0.7, 0.7, 0.0, 0.0, 0.15, 0.15, 0.25, 0.25]
+            .into_iter()
+            .map(|busy| Load { busy, idle: 0.0 })
+            .collect();
+        assert_eq!(MaybeSmart(Glyphs(&loads, 4), &s).to_string(), "X oO");
+        assert_eq!(MaybeSmart(Glyphs(&loads[..3], 4), &s).to_string(), "XX ");
+        assert_eq!(MaybeSmart(Glyphs(&[], 4), &s).to_string(), "");
+    }
+
+    const SOFTIRQS_4: &str = "                    CPU0       CPU1       CPU2       CPU3
+          HI:          0          1          0          0
+       TIMER:    1023412     985012    1000321     990000
+      NET_TX:         12          3          4          5
+      NET_RX:     500000      20000      30000      40000
+       BLOCK:       1000       2000       3000       4000
+    IRQ_POLL:          0          0          0          0
+     TASKLET:         17          0          0          0
+       SCHED:     800000     700000     600000     500000
+     HRTIMER:          0          0          0          0
+         RCU:     300000     310000     320000     330000
+";
+
+    /// A machine with 64 CPUs: CPU n handled n+1 softirqs of each type, times the type's rank
+    fn softirqs_64() -> String {
+        let mut s = String::from("      ");
+        for cpu in 0..64 {
+            s.push_str(&format!(" {:>10}", format!("CPU{}", cpu)));
+        }
+        s.push('\n');
+        for (rank, name) in ["HI", "TIMER", "NET_RX", "RCU"].iter().enumerate() {
+            s.push_str(&format!("{:>12}:", name));
+            for cpu in 0..64u64 {
+                s.push_str(&format!(" {:>10}", (cpu + 1) * (rank as u64 + 1)));
+            }
+            s.push('\n');
+        }
+        s
+    }
+
+    #[test]
+    fn softirqs_4_cpus() {
+        let p: Vec<_> = parse_softirqs(SOFTIRQS_4).collect();
+        assert_eq!(p.len(), 10);
+        assert_eq!(p[0], ("HI", 1));
+        assert_eq!(p[1], ("TIMER", 1023412 + 985012 + 1000321 + 990000));
+        assert_eq!(p[3], ("NET_RX", 590000));
+        assert_eq!(p[9], ("RCU", 1260000));
+    }
+
+    #[test]
+    fn softirqs_64_cpus() {
+        let s = softirqs_64();
+        let p: Vec<_> = parse_softirqs(&s).collect();
+        /* 1 + 2 + ... + 64 */
+        assert_eq!(
+            p,
+            [
+                ("HI", 2080),
+                ("TIMER", 2 * 2080),
+                ("NET_RX", 3 * 2080),
+                ("RCU", 4 * 2080)
+            ]
+        );
+    }
+
+    #[test]
+    fn softirqs_extra_columns() {
+        /* Anything past the last CPU column is not a count */
+        let s = "       CPU0 CPU1\n  TIMER: 10 20 30 extra\nNET_RX: 1\n\n";
+        let p: Vec<_> = parse_softirqs(s).collect();
+        assert_eq!(p, [("TIMER", 30), ("NET_RX", 1)]);
+        assert_eq!(parse_softirqs("").count(), 0);
+    }
+}
@@ -14,79 +14,189 @@
  */
 
 use crate::common::*;
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::CString;
 use std::fmt;
 
 struct FSUsage {
     size: Bytes,
+    /// Free blocks, including those reserved for root
+    free: Bytes,
+    /// Free blocks available to unprivileged users
     avail: Bytes,
 }
 
+impl FSUsage {
+    fn reserved(&self) -> Bytes {
+        Bytes(self.free.0.saturating_sub(self.avail.0))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FsField {
+    Usage,
+    Used,
+    Avail,
+}
+
+impl Field for FsField {
+    const ALL: &'static [(&'static str, Self)] = &[
+        ("usage", FsField::Usage),
+        ("used", FsField::Used),
+        ("avail", FsField::Avail),
+    ];
+
+    fn heading(&self) -> Heading<'static> {
+        match self {
+            FsField::Usage => Heading::new("USED%"),
+            FsField::Used => Heading::new("USED"),
+            FsField::Avail => Heading::new("AVAIL"),
+        }
+    }
+}
+
+/// Width of the "(+XXXX resv)" suffix appended to the USED column
+const RESV_WIDTH: u16 = 12;
+
 pub struct FilesystemStats<'a> {
     settings: &'a Settings,
     /* XXX: use PathBuf as key? OsString? otoh we don't really need portability */
     /* XXX: we need the CString for statvfs; find way to avoid duplicate data */
     /// Mountpoint -> (_, Mountpoint, _)
     filesystems: BTreeMap<String, (FSUsage, CString, Stale)>,
+    /// Block device, as seen in mountstats -> (canonical path, _)
+    canon: HashMap<String, (String, Stale)>,
     buf: String,
 }
 
+/// Parse a line of /proc/self/mountstats, returns (bdev, mountpoint) for mounts backed by a block
+/// device. Both are still escaped, see unescape_octal().
+pub fn parse_mount(mount: &str) -> Option<(&str, &str)> {
+    let (bdev, mountpoint) = mount
+        .strip_prefix("device ")
+        .unwrap()
+        .split_once(" mounted on ")
+        .unwrap();
+
+    if !bdev.starts_with('/') {
+        /* Not interested in these kind of mounts */
+        return None;
+    }
+
+    let (mountpoint, _) = mountpoint.rsplit_once(" with fstype ").unwrap();
+    Some((bdev, mountpoint))
+}
+
+/// Several mounts can share a block device, could be eg bind mounts or btrfs subvolumes. Only keep
+/// the one closest to the root of the filesystem, which is hopefully the one with the shortest
+/// mountpoint; the first one listed if several are as short. Returns canonical bdev ->
+/// mountpoint, still escaped.
+fn best_mounts<'b>(
+    mountstats: &'b str,
+    canon: &'b HashMap<String, (String, Stale)>,
+) -> HashMap<&'b str, &'b str> {
+    /* XXX: keep instance in self and blank it when we're done? don't know how to work around
+     * lifetime stuff */
+    let mut best = HashMap::<&str, &str>::new();
+    for mount in mountstats.lines() {
+        let (bdev, mountpoint) = match parse_mount(mount) {
+            Some(m) => m,
+            None => continue,
+        };
+
+        /* Compare what the paths will look like, "\040" is a single space */
+        let bdev = canon[bdev].0.as_str();
+        match best.get_mut(bdev) {
+            Some(m) if unescape_octal(m).len() > unescape_octal(mountpoint).len() => {
+                *m = mountpoint
+            }
+            Some(_) => (),
+            None => {
+                best.insert(bdev, mountpoint);
+            }
+        }
+    }
+    best
+}
+
+impl<'a> FilesystemStats<'a> {
+    /// Should the root-reserved space of this filesystem be shown next to its used space? Not
+    /// with --compact unless asked for, it makes the block too wide to fit beside the devices.
+    fn show_reserved(&self, u: &FSUsage) -> bool {
+        let r = u.reserved().0;
+        r > 0
+            && match (self.settings.fs_verbose, self.settings.compact) {
+                (true, _) => true,
+                (false, true) => false,
+                (false, false) => r > u.size.0 / 100,
+            }
+    }
+
+    /// Is the reserved space suffix shown for any filesystem?
+    fn any_reserved(&self) -> bool {
+        self.settings.fs_fields.contains(&FsField::Used)
+            && self.filesystems.values().any(|v| self.show_reserved(&v.0))
+    }
+}
+
+impl<'a> FilesystemStats<'a> {
+    /// Mountpoints of the filesystems shown, unescaped
+    pub fn mountpoints(&self) -> impl Iterator<Item = &str> {
+        self.filesystems.keys().map(String::as_str)
+    }
+}
+
 impl<'a> StatBlock<'a> for FilesystemStats<'a> {
     fn new(s: &'a Settings) -> FilesystemStats {
         FilesystemStats {
             settings: s,
             filesystems: BTreeMap::new(),
+            canon: HashMap::new(),
             buf: String::new(),
         }
     }
 
-    fn update(&mut self) {
-        match read_to_string("/proc/self/mountstats", &mut self.buf) {
+    fn update(&mut self, _: &FrameClock) {
+        let root = &self.settings.proc_root;
+        match root.read_to_string("/proc/self/mountstats", &mut self.buf) {
             Ok(_) => (),
             _ => return,
         }
 
-        /* XXX: keep instance in self and blank it when we're done? don't know how to work around
-         * lifetime stuff */
-        /* XXX: use PathBuf? OsString? */
-        let mut seen = HashSet::<&str>::with_capacity(self.filesystems.len());
-
         for v in self.filesystems.values_mut() {
             v.2 = Stale(true);
         }
+        for v in self.canon.values_mut() {
+            v.1 = Stale(true);
+        }
 
+        /* Canonicalize block devices: for instance /dev/disk/by-label/foo and /dev/disk/by-id/bar
+         * can refer to the same block device */
         for mount in self.buf.lines() {
-            let (bdev, mountpoint) = mount
-                .strip_prefix("device ")
-                .unwrap()
-                .split_once(" mounted on ")
-                .unwrap();
-
-            if !bdev.starts_with('/') {
-                /* Not interested in these kind of mounts */
-                continue;
-            }
+            let (bdev, _) = match parse_mount(mount) {
+                Some(m) => m,
+                None => continue,
+            };
 
-            /* XXX: find a way to canonicalize bdev: for instance /dev/disk/by-label/foo and
-             * /dev/disk/by-id/bar can refer to the same block device */
-            //let canon_bdev = std::fs::canonicalize(bdev).unwrap();
-            //let canon_bdev_lossy = canon_bdev.to_string_lossy().into_owned();
-            //let bdev = canon_bdev_lossy.as_str();
-            //           ^ value needs to live as long as seen
-            /* maybe maintain canon: HashMap<String, (String, Stale)> in self? */
-
-            if seen.contains(bdev) {
-                /* Another fs on the same block device, could be eg bind mount or btrfs subvolume...
-                 * skip them */
-                /* XXX: are there any edge cases? */
-                continue;
+            match self.canon.get_mut(bdev) {
+                Some(c) => c.1 = Stale(false),
+                None => {
+                    let c = match root.canonicalize(&*unescape_octal(bdev)) {
+                        Ok(c) => c.to_string_lossy().into_owned(),
+                        _ => String::from(bdev),
+                    };
+                    self.canon.insert(String::from(bdev), (c, Stale(false)));
+                }
             }
-            seen.insert(bdev);
-
-            let (mountpoint, _) = mountpoint.rsplit_once(" with fstype ").unwrap();
+        }
+        self.canon.retain(|_, v| v.1 == Stale(false));
 
-            let mut ent = match self.filesystems.get_mut(mountpoint) {
+        let best = best_mounts(&self.buf, &self.canon);
+        for mountpoint in best.into_values() {
+            /* Spaces and such are escaped, so the splitting in parse_mount() is safe, but
+             * statvfs() needs the real path */
+            let mountpoint = &*unescape_octal(mountpoint);
+            let ent = match self.filesystems.get_mut(mountpoint) {
                 Some(v) => v,
                 _ => {
                     self.filesystems.insert(
@@ -94,6 +204,7 @@ impl<'a> StatBlock<'a> for FilesystemStats<'a> {
                         (
                             FSUsage {
                                 size: Bytes(0),
+                                free: Bytes(0),
                                 avail: Bytes(0),
                             },
                             CString::new(mountpoint).unwrap(),
@@ -104,18 +215,15 @@ impl<'a> StatBlock<'a> for FilesystemStats<'a> {
                 }
             };
 
-            unsafe {
-                use std::mem::MaybeUninit;
-                let mut vfs: MaybeUninit<libc::statvfs64> = MaybeUninit::uninit();
-
-                if libc::statvfs64(ent.1.as_ptr() as *const libc::c_char, vfs.as_mut_ptr()) != 0 {
-                    panic!("statvfs64({}) returned non-zero", mountpoint);
-                }
-
-                let vfs = vfs.assume_init();
-                ent.0.size.0 = vfs.f_blocks * vfs.f_frsize;
-                ent.0.avail.0 = vfs.f_bavail * vfs.f_bsize;
-            }
+            let usage = match root.usage(&ent.1) {
+                Ok(u) => u,
+                /* Not sent by the remote machine (yet), try again next time */
+                Err(_) if !root.is_local() => continue,
+                Err(e) => panic!("statvfs64({}) failed: {}", mountpoint, e),
+            };
+            ent.0.size.0 = usage.size;
+            ent.0.free.0 = usage.free;
+            ent.0.avail.0 = usage.avail;
 
             ent.2 = Stale(false);
         }
@@ -125,9 +233,15 @@ impl<'a> StatBlock<'a> for FilesystemStats<'a> {
 
     fn columns(&self) -> u16 {
         if self.filesystems.is_empty() {
-            0
+            return 0;
+        }
+
+        let n = self.settings.fs_fields.len() as u16;
+        let c = (n + 1) * self.settings.colwidth.get() + n;
+        if self.any_reserved() {
+            c + RESV_WIDTH
         } else {
-            4 * self.settings.colwidth.get() + 3
+            c
         }
     }
 
@@ -148,42 +262,140 @@ impl<'a> fmt::Display for FilesystemStats<'a> {
 
         let newline = MaybeSmart(Newline(), self.settings);
         let w = self.settings.colwidth.get().into();
-        write!(
-            f,
-            "{} {} {} {}{}",
-            MaybeSmart(Heading("FS"), self.settings),
-            MaybeSmart(Heading("USED%"), self.settings),
-            MaybeSmart(Heading("USED"), self.settings),
-            MaybeSmart(Heading("AVAIL"), self.settings),
-            newline
-        )?;
+        let any_reserved = self.any_reserved();
+        let rw = RESV_WIDTH as usize;
+        write!(f, "{}", MaybeSmart(Heading::new("FS"), self.settings))?;
+        for field in self.settings.fs_fields.iter() {
+            write!(f, " {}", MaybeSmart(field.heading(), self.settings))?;
+            /* Above the value, not the reserved suffix */
+            if *field == FsField::Used && any_reserved {
+                write!(f, "{:rw$}", "")?;
+            }
+        }
+        write!(f, "{}", newline)?;
 
         for (k, v) in self.filesystems.iter() {
             write!(
                 f,
-                "{:>w$.w$} {:>w$} {:>w$} {:>w$}{}",
+                "{:>w$.w$}",
                 if k == "/" {
                     k
                 } else {
                     k.rsplit_once('/').unwrap().1
-                },
-                MaybeSmart(
-                    Threshold {
-                        val: Percentage(
-                            100.0 * ((v.0.size.0 - v.0.avail.0) as f32) / (v.0.size.0 as f32)
-                        ),
-                        med: Percentage(80.0),
-                        high: Percentage(90.0),
-                        crit: Percentage(95.0),
-                    },
-                    self.settings
-                ),
-                Bytes(v.0.size.0 - v.0.avail.0),
-                v.0.avail,
-                newline
+                }
             )?;
+            for field in self.settings.fs_fields.iter() {
+                match field {
+                    FsField::Usage => write!(
+                        f,
+                        " {:>w$}",
+                        MaybeSmart(
+                            Threshold {
+                                val: Percentage(
+                                    100.0 * ((v.0.size.0 - v.0.avail.0) as f32)
+                                        / (v.0.size.0 as f32)
+                                ),
+                                med: Percentage(80.0),
+                                high: Percentage(90.0),
+                                crit: Percentage(95.0),
+                            },
+                            self.settings
+                        )
+                    )?,
+                    /* Counting the blocks reserved for root as used, only when they are shown
+                     * apart */
+                    FsField::Used => match self.show_reserved(&v.0) {
+                        true => write!(
+                            f,
+                            " {:>w$}(+{:4.0} resv)",
+                            Bytes(v.0.size.0 - v.0.free.0),
+                            v.0.reserved()
+                        )?,
+                        false => {
+                            write!(f, " {:>w$}", Bytes(v.0.size.0 - v.0.avail.0))?;
+                            if any_reserved {
+                                write!(f, "{:rw$}", "")?;
+                            }
+                        }
+                    },
+                    FsField::Avail => write!(f, " {:>w$}", v.0.avail)?,
+                }
+            }
+            write!(f, "{}", newline)?;
         }
 
         write!(f, "{}", newline)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTSTATS: &str = "\
+device sysfs mounted on /sys with fstype sysfs
+device proc mounted on /proc with fstype proc
+device /dev/disk/by-uuid/4f6c mounted on /var/lib/docker with fstype btrfs
+device /dev/nvme0n1p2 mounted on / with fstype btrfs
+device /dev/nvme0n1p2 mounted on /home with fstype btrfs
+device /dev/nvme0n1p2 mounted on /var/log with fstype btrfs
+device /dev/nvme0n1p1 mounted on /boot/efi with fstype vfat
+device /dev/sdb1 mounted on /mnt/abcdef with fstype ext4
+device /dev/sdb1 mounted on /mnt/a\\040b with fstype ext4
+device /dev/sdc1 mounted on /media/usb\\040stick with fstype exfat
+device server:/export mounted on /net/export with fstype nfs4 statvers=1.1
+";
+
+    /// Canonical names for the block devices of MOUNTSTATS, as canonicalize() would give
+    fn canon() -> HashMap<String, (String, Stale)> {
+        [
+            ("/dev/disk/by-uuid/4f6c", "/dev/nvme0n1p2"),
+            ("/dev/nvme0n1p2", "/dev/nvme0n1p2"),
+            ("/dev/nvme0n1p1", "/dev/nvme0n1p1"),
+            ("/dev/sdb1", "/dev/sdb1"),
+            ("/dev/sdc1", "/dev/sdc1"),
+        ]
+        .into_iter()
+        .map(|(b, c)| (String::from(b), (String::from(c), Stale(false))))
+        .collect()
+    }
+
+    #[test]
+    fn one_mount_per_device() {
+        let canon = canon();
+        let best = best_mounts(MOUNTSTATS, &canon);
+        assert_eq!(best.len(), 4);
+        /* Btrfs subvolumes, and a bind mount listed first under another name of the device */
+        assert_eq!(best["/dev/nvme0n1p2"], "/");
+        assert_eq!(best["/dev/nvme0n1p1"], "/boot/efi");
+        /* Shorter once unescaped */
+        assert_eq!(best["/dev/sdb1"], "/mnt/a\\040b");
+        assert_eq!(best["/dev/sdc1"], "/media/usb\\040stick");
+        assert_eq!(unescape_octal(best["/dev/sdc1"]), "/media/usb stick");
+    }
+
+    #[test]
+    fn first_of_equals() {
+        let canon = canon();
+        let best = best_mounts(
+            "device /dev/sdb1 mounted on /srv/b with fstype ext4\n\
+             device /dev/sdb1 mounted on /srv/a with fstype ext4\n",
+            &canon,
+        );
+        assert_eq!(best["/dev/sdb1"], "/srv/b");
+    }
+
+    #[test]
+    fn escaped_fstype_separator() {
+        assert_eq!(
+            parse_mount(
+                "device /dev/sdb1 mounted on /mnt/x\\040with\\040fstype\\040y with fstype ext4"
+            ),
+            Some(("/dev/sdb1", "/mnt/x\\040with\\040fstype\\040y"))
+        );
+        assert_eq!(
+            parse_mount("device tmpfs mounted on /tmp with fstype tmpfs"),
+            None
+        );
+    }
+}
@@ -14,13 +14,29 @@
  */
 
 use crate::common::*;
+#[cfg(feature = "nvme-health")]
+use crate::nvme;
 use std::collections::BTreeMap;
 use std::fmt;
 use std::fmt::Write;
 use std::path::PathBuf;
+#[cfg(feature = "nvme-health")]
+use std::time::{Duration, Instant};
+
+/// The NVMe health log changes very slowly, don't bother reading it at every refresh
+#[cfg(feature = "nvme-health")]
+const NVME_HEALTH_INTERVAL: Duration = Duration::from_secs(600);
+
+/// NVMe controller (eg. nvme0) -> when its health was last read, and the result
+#[cfg(feature = "nvme-health")]
+type NvmeHealthCache = fnv::FnvHashMap<String, (Instant, Option<nvme::NvmeHealth>)>;
+
+/// When the block is given too few rows, only show this many sensors, the hottest ones, on a
+/// single line
+const TIGHT_SENSORS: usize = 4;
 
 #[derive(Copy, Clone, PartialEq, PartialOrd)]
-pub struct Celsius(f32);
+pub struct Celsius(pub f32);
 
 impl fmt::Display for Celsius {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -30,6 +46,38 @@ impl fmt::Display for Celsius {
     }
 }
 
+/// How a temperature changed since the previous refresh
+#[derive(Copy, Clone)]
+enum Trend {
+    Stable,
+    Rising,
+    Falling,
+}
+
+impl Trend {
+    /// Ignore changes of 1C or less, most sensors jitter that much
+    fn new(prev: &DataKind, cur: Celsius) -> Self {
+        match prev {
+            DataKind::Temperature(p, _, _) if cur.0 - p.0 > 1.0 => Trend::Rising,
+            DataKind::Temperature(p, _, _) if p.0 - cur.0 > 1.0 => Trend::Falling,
+            _ => Trend::Stable,
+        }
+    }
+}
+
+impl<'a> fmt::Display for MaybeSmart<'a, Trend> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let c = match (self.0, self.1.smart.get()) {
+            (Trend::Stable, _) => ' ',
+            (Trend::Rising, true) => '↑',
+            (Trend::Falling, true) => '↓',
+            (Trend::Rising, false) => '+',
+            (Trend::Falling, false) => '-',
+        };
+        write!(f, "{}", c)
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 enum KeyKind {
     Hwmon(usize),
@@ -38,19 +86,99 @@ enum KeyKind {
 
 #[derive(Copy, Clone)]
 enum DataKind {
-    Temperature(Celsius),
+    Temperature(Celsius, Option<Celsius>, Trend), /* input, critical, _ */
     Percentage(Percentage),
     Bytes(Bytes, Option<Bytes>), /* used, total */
     Watts(Watts, Option<Watts>), /* used, total */
+    #[cfg(feature = "nvme-health")]
+    Wear(Percentage),
+    #[cfg(feature = "nvme-health")]
+    Spare(Percentage, Percentage), /* available, threshold */
+    /// GPU memory used by the process with the most, labeled with its comm
+    GpuProcess(Bytes),
     Nothing,
 }
 
+/// A temperature coloured against its critical temperature if known
+pub fn temperature(c: Celsius, crit: Option<Celsius>) -> Threshold<Celsius> {
+    match crit {
+        Some(Celsius(crit)) => Threshold {
+            val: c,
+            med: Celsius(crit - 25.0),
+            high: Celsius(crit - 15.0),
+            crit: Celsius(crit - 5.0),
+        },
+        None => Threshold {
+            val: c,
+            med: Celsius(50.0),
+            high: Celsius(70.0),
+            crit: Celsius(90.0),
+        },
+    }
+}
+
+/// In compact mode, the label of the only sensor shown for a device: its hottest one
+fn hottest(sensors: &BTreeMap<String, (DataKind, Stale)>) -> Option<&str> {
+    sensors
+        .iter()
+        .filter_map(|(k, v)| match v.0 {
+            DataKind::Temperature(c, _, _) => Some((k, c)),
+            _ => None,
+        })
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(k, _)| k.as_str())
+}
+
+impl<'a> HwmonStats<'a> {
+    /// The highest temperature of all sensors, with its thresholds
+    pub fn hottest(&self) -> Option<Threshold<Celsius>> {
+        self.state
+            .values()
+            .flat_map(|(_, sensors, _)| sensors.values())
+            .filter_map(|v| match v.0 {
+                DataKind::Temperature(c, crit, _) => Some(temperature(c, crit)),
+                _ => None,
+            })
+            .reduce(|a, b| if b.val > a.val { b } else { a })
+    }
+
+    /// GPU memory used by each pid, as of the last update
+    pub fn gpu_memory(&self) -> &fnv::FnvHashMap<u32, u64> {
+        &self.gpu_memory
+    }
+}
+
+/// What we learned about a hwmonX when it first appeared
+struct Chip {
+    /// Contents of hwmonX/name, eg. k10temp
+    name: String,
+    /// Canonical path of hwmonX/device
+    parent: Option<PathBuf>,
+    /// The row this chip's sensors are shown on, shared with the other chips of the same device
+    group: KeyKind,
+    /// Label of the row, unique unlike name
+    label: String,
+    stale: Stale,
+}
+
 pub struct HwmonStats<'a> {
     settings: &'a Settings,
-    /// hwmonX -> label, (label, value)...
+    /// hwmonX of the first chip of a device -> label, (label, value)...
     state: BTreeMap<KeyKind, (String, BTreeMap<String, (DataKind, Stale)>, Stale)>,
+    /// hwmonX -> _
+    chips: fnv::FnvHashMap<usize, Chip>,
     nvml: Option<nvml_wrapper::Nvml>,
+    /// pid -> GPU memory used, as reported by NVML
+    gpu_memory: fnv::FnvHashMap<u32, u64>,
+    /// None if disabled, or if we don't have permission to read health logs
+    #[cfg(feature = "nvme-health")]
+    nvme_health: Option<NvmeHealthCache>,
+    /// As given to fit()
+    max_rows: u16,
     // internal buffers re-used in update()
+    /// The X of each hwmonX in p, sorted
+    monitors: Vec<usize>,
+    /// Starts as /sys/class/hwmon
     p: PathBuf,
     sb: String,
     sb2: String,
@@ -61,61 +189,80 @@ impl<'a> StatBlock<'a> for HwmonStats<'a> {
         Self {
             settings: s,
             state: Default::default(),
-            nvml: nvml_wrapper::Nvml::init().ok(),
+            chips: Default::default(),
+            /* NVML only knows about the GPUs of this machine */
+            nvml: match s.proc_root.is_local() {
+                true => nvml_wrapper::Nvml::init().ok(),
+                false => None,
+            },
+            gpu_memory: Default::default(),
+            #[cfg(feature = "nvme-health")]
+            nvme_health: match s.nvme_health {
+                true => Some(Default::default()),
+                false => None,
+            },
+            max_rows: u16::MAX,
+            monitors: Vec::new(),
             p: PathBuf::from("/sys/class/hwmon"),
             sb: Default::default(),
             sb2: Default::default(),
         }
     }
 
-    fn update(&mut self) {
-        for (_, s) in self.state.iter_mut() {
+    /* The clock only times NVMe health reads */
+    fn update(&mut self, _clock: &FrameClock) {
+        for (k, s) in self.state.iter_mut() {
             s.2 = Stale(true);
+            if let KeyKind::Hwmon(_) = k {
+                for v in s.1.values_mut() {
+                    v.1 = Stale(true);
+                }
+            }
+        }
+        for c in self.chips.values_mut() {
+            c.stale = Stale(true);
         }
 
-        if let Ok(monitors) = std::fs::read_dir("/sys/class/hwmon") {
-            for m in monitors {
-                let m = match m {
-                    Ok(m) => m,
-                    _ => continue,
-                };
+        /* From a remote machine, get the sensors along with the list of chips, rather than each
+         * temperature after the previous one */
+        let settings = self.settings;
+        let root = &settings.proc_root;
+        root.prefetch("/sys/class/hwmon/hwmon*/name");
+        root.prefetch("/sys/class/hwmon/hwmon*/temp*");
+        if let Ok(monitors) = root.read_dir(&self.p) {
+            /* In numerical order: which chip of a device is discovered first, and gets the row and
+             * the plain sensor labels, doesn't depend on the directory order */
+            let mut xs = std::mem::take(&mut self.monitors);
+            xs.clear();
+            xs.extend(
+                monitors
+                    .iter()
+                    .filter_map(|m| m.strip_prefix("hwmon")?.parse::<usize>().ok()),
+            );
+            xs.sort_unstable();
 
-                /* XXX: feels clunky */
-                let x = match m.file_name().to_str().unwrap()[5..].parse::<usize>() {
-                    Ok(k) => KeyKind::Hwmon(k),
-                    _ => continue,
-                };
+            for &x in xs.iter() {
+                self.p.push(format!("hwmon{}", x));
 
-                let ent = match self.state.get_mut(&x) {
-                    Some(ent) => ent,
-                    None => {
-                        let z = (String::new(), Default::default(), Stale(false));
-                        self.state.insert(x, z);
-                        self.state.get_mut(&x).unwrap()
-                    }
-                };
-                ent.2 = Stale(false);
-
-                for (_, v) in ent.1.iter_mut() {
-                    v.1 = Stale(true);
+                if !self.chips.contains_key(&x) && !self.discover_chip(x) {
+                    self.p.pop();
+                    continue;
                 }
+                let chip = self.chips.get_mut(&x).unwrap();
+                chip.stale = Stale(false);
 
-                self.p.push(m.file_name());
-
-                // Update name
-                // XXX: not very descriptive, esp. for nvme
-                self.p.push("name");
-                ent.0.clear();
-                unsafe { read_to_string_unchecked(&self.p, &mut ent.0) }.unwrap();
-                self.p.pop();
-                ent.0.pop(); // Remove terminating \n
+                let ent = self
+                    .state
+                    .entry(chip.group)
+                    .or_insert_with(|| (chip.label.clone(), Default::default(), Stale(false)));
+                ent.2 = Stale(false);
 
                 // Read /sys/class/hwmonX/tempY_{label,input} while they exist
                 let mut y = 1;
                 loop {
                     self.sb2.clear();
                     self.p.push(format!("temp{}_input", y));
-                    let input = unsafe { read_to_string_unchecked(&self.p, &mut self.sb2) };
+                    let input = unsafe { root.read_to_string_unchecked(&self.p, &mut self.sb2) };
                     self.p.pop();
                     let input = match input {
                         Ok(_) => {
@@ -126,9 +273,22 @@ impl<'a> StatBlock<'a> for HwmonStats<'a> {
                         _ => break,
                     };
 
+                    // Use the critical temperature when the driver knows better than our defaults,
+                    // eg. amdgpu junction is rated for 110C. Ignore nonsensical values.
+                    self.sb2.clear();
+                    self.p.push(format!("temp{}_crit", y));
+                    let crit = unsafe { root.read_to_string_unchecked(&self.p, &mut self.sb2) };
+                    self.p.pop();
+                    let crit = match crit.map(|_| self.sb2.trim_end().parse::<f32>()) {
+                        Ok(Ok(c)) if (40000.0..=150000.0).contains(&c) => {
+                            Some(Celsius(c / 1000f32))
+                        }
+                        _ => None,
+                    };
+
                     self.sb.clear();
                     self.p.push(format!("temp{}_label", y));
-                    let label = unsafe { read_to_string_unchecked(&self.p, &mut self.sb) };
+                    let label = unsafe { root.read_to_string_unchecked(&self.p, &mut self.sb) };
                     self.p.pop();
                     if !label.is_ok() {
                         // No label, this is OK
@@ -138,6 +298,11 @@ impl<'a> StatBlock<'a> for HwmonStats<'a> {
                         // Remove terminating \n
                         self.sb.pop();
                     }
+                    if ent.1.get(&self.sb).is_some_and(|v| v.1 == Stale(false)) {
+                        // Another chip of the same device already has a sensor with this label
+                        self.sb.insert(0, ' ');
+                        self.sb.insert_str(0, &chip.name);
+                    }
 
                     let ent = match ent.1.get_mut(&self.sb) {
                         Some(ent) => ent,
@@ -147,20 +312,72 @@ impl<'a> StatBlock<'a> for HwmonStats<'a> {
                             ent.1.get_mut(&self.sb).unwrap()
                         }
                     };
-                    ent.0 = DataKind::Temperature(Celsius(input / 1000f32));
+                    let c = Celsius(input / 1000f32);
+                    ent.0 = DataKind::Temperature(c, crit, Trend::new(&ent.0, c));
                     ent.1 = Stale(false);
 
                     y += 1;
                 }
 
-                if ent.0 == "amdgpu" {
+                #[cfg(feature = "nvme-health")]
+                if chip.name == "nvme" && self.nvme_health.is_some() {
+                    /* hwmonX/device links to the controller, eg. ../../nvme0 */
+                    self.p.push("device");
+                    let ctrl = std::fs::read_link(&self.p);
+                    self.p.pop();
+                    let ctrl = ctrl
+                        .ok()
+                        .and_then(|l| l.file_name().map(|n| n.to_string_lossy().into_owned()));
+
+                    let cache = self.nvme_health.as_mut().unwrap();
+                    let health = match ctrl {
+                        None => None,
+                        Some(ctrl) => match cache.get(&ctrl) {
+                            Some((t, h)) if _clock.instant() - *t < NVME_HEALTH_INTERVAL => *h,
+                            _ => match nvme::read_health(&ctrl) {
+                                Err(libc::EACCES) | Err(libc::EPERM) => {
+                                    /* Not root, don't try again */
+                                    self.nvme_health = None;
+                                    None
+                                }
+                                h => {
+                                    let h = h.ok();
+                                    cache.insert(ctrl, (_clock.instant(), h));
+                                    h
+                                }
+                            },
+                        },
+                    };
+
+                    if let Some(h) = health {
+                        for (k, v) in [
+                            ("wear", DataKind::Wear(Percentage(h.percentage_used as f32))),
+                            (
+                                "spare",
+                                DataKind::Spare(
+                                    Percentage(h.available_spare as f32),
+                                    Percentage(h.spare_threshold as f32),
+                                ),
+                            ),
+                        ] {
+                            match ent.1.get_mut(k) {
+                                Some(ent) => *ent = (v, Stale(false)),
+                                None => {
+                                    ent.1.insert(k.to_string(), (v, Stale(false)));
+                                }
+                            }
+                        }
+                    }
+                }
+
+                if chip.name == "amdgpu" {
                     self.sb.clear();
                     self.sb2.clear();
                     self.p.push("power1_average");
-                    let input = unsafe { read_to_string_unchecked(&self.p, &mut self.sb) };
+                    let input = unsafe { root.read_to_string_unchecked(&self.p, &mut self.sb) };
                     self.p.pop();
                     self.p.push("power1_cap");
-                    let input2 = unsafe { read_to_string_unchecked(&self.p, &mut self.sb2) };
+                    let input2 = unsafe { root.read_to_string_unchecked(&self.p, &mut self.sb2) };
                     self.p.pop();
                     if input.is_ok() && input2.is_ok() {
                         self.sb.pop();
@@ -184,10 +401,10 @@ impl<'a> StatBlock<'a> for HwmonStats<'a> {
                     self.sb2.clear();
                     self.p.push("device");
                     self.p.push("mem_info_vram_used");
-                    let input = unsafe { read_to_string_unchecked(&self.p, &mut self.sb) };
+                    let input = unsafe { root.read_to_string_unchecked(&self.p, &mut self.sb) };
                     self.p.pop();
                     self.p.push("mem_info_vram_total");
-                    let input2 = unsafe { read_to_string_unchecked(&self.p, &mut self.sb2) };
+                    let input2 = unsafe { root.read_to_string_unchecked(&self.p, &mut self.sb2) };
                     self.p.pop();
                     if input.is_ok() && input2.is_ok() {
                         self.sb.pop();
@@ -208,16 +425,36 @@ impl<'a> StatBlock<'a> for HwmonStats<'a> {
                     }
 
                     self.p.pop();
-                }
 
-                if ent.1.len() != y {
-                    ent.1.retain(|_, v| v.1 == Stale(false));
+                    self.sb.clear();
+                    self.p.push("pwm1");
+                    let input = unsafe { root.read_to_string_unchecked(&self.p, &mut self.sb) };
+                    self.p.pop();
+                    if input.is_ok() {
+                        self.sb.pop();
+                        let ent = match ent.1.get_mut("fan") {
+                            Some(ent) => ent,
+                            None => {
+                                ent.1
+                                    .insert("fan".to_string(), (DataKind::Nothing, Stale(false)));
+                                ent.1.get_mut("fan").unwrap()
+                            }
+                        };
+                        /* pwm1 goes from 0 to 255 */
+                        ent.0 = DataKind::Percentage(Percentage(
+                            100f32 * self.sb.parse::<f32>().unwrap() / 255f32,
+                        ));
+                        ent.1 = Stale(false);
+                    }
                 }
 
                 self.p.pop();
             }
+            self.monitors = xs;
         }
 
+        self.gpu_memory.clear();
+
         if let Some(nvml) = &self.nvml {
             if let Ok(n) = nvml.device_count() {
                 for i in 0..n {
@@ -248,7 +485,10 @@ impl<'a> StatBlock<'a> for HwmonStats<'a> {
                     v.0 = match device
                         .temperature(nvml_wrapper::enum_wrappers::device::TemperatureSensor::Gpu)
                     {
-                        Ok(t) => DataKind::Temperature(Celsius(t as f32)),
+                        Ok(t) => {
+                            let c = Celsius(t as f32);
+                            DataKind::Temperature(c, None, Trend::new(&v.0, c))
+                        }
                         _ => DataKind::Nothing,
                     };
 
@@ -267,37 +507,233 @@ impl<'a> StatBlock<'a> for HwmonStats<'a> {
                         }
                         _ => DataKind::Nothing,
                     };
+
+                    /* A process can be listed as both compute and graphics, with the same memory */
+                    let mut top: Option<(u32, u64)> = None;
+                    ent.1.retain(|_, v| !matches!(v.0, DataKind::GpuProcess(_)));
+                    for p in [
+                        device.running_compute_processes(),
+                        device.running_graphics_processes(),
+                    ]
+                    .into_iter()
+                    .flat_map(|p| p.unwrap_or_default())
+                    {
+                        let used = match p.used_gpu_memory {
+                            nvml_wrapper::enums::device::UsedGpuMemory::Used(b) => b,
+                            _ => continue,
+                        };
+                        let m = self.gpu_memory.entry(p.pid).or_default();
+                        *m = (*m).max(used);
+                        if used > top.map_or(0, |t| t.1) {
+                            top = Some((p.pid, used));
+                        }
+                    }
+
+                    if let Some((pid, used)) = top {
+                        /* NVML may report processes that already exited, ignore them */
+                        if read_to_string(format!("/proc/{}/comm", pid), &mut self.sb).is_ok() {
+                            self.sb.pop();
+                            ent.1.insert(
+                                self.sb.clone(),
+                                (DataKind::GpuProcess(Bytes(used)), Stale(false)),
+                            );
+                        }
+                    }
                 }
             }
         }
 
+        self.chips.retain(|_, c| c.stale == Stale(false));
         self.state.retain(|_, s| s.2 == Stale(false));
+        for (k, s) in self.state.iter_mut() {
+            if let KeyKind::Hwmon(_) = k {
+                s.1.retain(|_, v| v.1 == Stale(false));
+            }
+        }
     }
 
     fn columns(&self) -> u16 {
         if self.state.is_empty() {
             0
         } else {
-            8
+            8 * self.settings.colwidth.get() + 7
         }
     }
 
     fn rows(&self) -> u16 {
+        match self.tight() {
+            true => self.min_rows(),
+            false => self.preferred_rows(),
+        }
+    }
+
+    fn min_rows(&self) -> u16 {
+        match self.state.is_empty() {
+            true => 0,
+            false => 2,
+        }
+    }
+
+    fn preferred_rows(&self) -> u16 {
         if self.state.is_empty() {
             return 0;
         }
 
-        let two_cols = self.state.values().all(|v| v.1.len() <= 3);
+        let two_cols = self.two_cols();
         if two_cols {
-            1 + (self.state.len() as u16 + 1) / 2
+            let n = self.state.values().filter(|v| !v.1.is_empty()).count() as u16;
+            1 + (n + 1) / 2
         } else {
             let mut cols = 1;
             for v in self.state.values() {
-                cols += (v.1.len() as u16 + 6) / 7
+                cols += (self.shown_sensors(&v.1) as u16 + 6) / 7
             }
             cols
         }
     }
+
+    fn fit(&mut self, rows: u16) {
+        self.max_rows = rows;
+    }
+}
+
+impl<'a> HwmonStats<'a> {
+    /// Find out which device a new hwmonX (in self.p) belongs to, and how to label it. False if
+    /// its name wasn't read from the remote machine yet.
+    fn discover_chip(&mut self, x: usize) -> bool {
+        let root = &self.settings.proc_root;
+        let mut name = String::new();
+        self.p.push("name");
+        /* Not unchecked: it is printed, and drivers can put anything in there */
+        let read = root.read_to_string(&self.p, &mut name);
+        self.p.pop();
+        match read {
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return false,
+            Err(_) => name.clear(),
+            Ok(_) => (),
+        }
+        name.truncate(name.trim_end().len()); // Remove terminating \n, if any
+        if name.is_empty() {
+            name = format!("hwmon{}", x);
+        }
+
+        self.p.push("device");
+        let parent = root.canonicalize(&self.p).ok();
+        self.p.pop();
+
+        /* Chips of the same device, eg. a PCI device, share a row */
+        let group = match parent
+            .as_ref()
+            .and_then(|p| self.chips.values().find(|c| c.parent.as_ref() == Some(p)))
+        {
+            Some(c) => c.group,
+            None => KeyKind::Hwmon(x),
+        };
+
+        let label = match parent.as_ref().and_then(|p| p.file_name()?.to_str()) {
+            /* The device is the controller, eg. nvme0 */
+            Some(f) if name == "nvme" && f.starts_with("nvme") => String::from(f),
+            _ => {
+                /* Number devices with identical names, eg. k10temp, k10temp1 */
+                let n = self
+                    .chips
+                    .iter()
+                    .filter(|(k, c)| c.name == name && c.group == KeyKind::Hwmon(**k))
+                    .count();
+                match n {
+                    0 => name.clone(),
+                    n => format!("{}{}", name, n),
+                }
+            }
+        };
+
+        self.chips.insert(
+            x,
+            Chip {
+                name,
+                parent,
+                group,
+                label,
+                stale: Stale(false),
+            },
+        );
+        true
+    }
+
+    /// Number of sensors printed for a device
+    fn shown_sensors(&self, sensors: &BTreeMap<String, (DataKind, Stale)>) -> usize {
+        match self.settings.compact && hottest(sensors).is_some() {
+            true => 1,
+            false => sensors.len(),
+        }
+    }
+
+    /// Print two devices per line?
+    fn two_cols(&self) -> bool {
+        self.state.values().all(|v| self.shown_sensors(&v.1) <= 3)
+    }
+
+    /// Only print the hottest sensors of all devices, on a single line?
+    fn tight(&self) -> bool {
+        self.max_rows < self.preferred_rows()
+    }
+
+    /// The single line printed when tight(): device and sensor labels, then temperature, of the
+    /// hottest sensors
+    fn fmt_tight(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let w = self.settings.colwidth.get().into();
+        let mut temps: Vec<_> = self
+            .state
+            .values()
+            .flat_map(|(dev, sensors, _)| sensors.iter().map(move |(k, v)| (dev, k, v.0)))
+            .filter_map(|(dev, k, v)| match v {
+                DataKind::Temperature(c, crit, trend) => Some((dev, k, c, crit, trend)),
+                _ => None,
+            })
+            .collect();
+        temps.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(std::cmp::Ordering::Equal));
+
+        let mut used_cols = 0;
+        for (dev, k, c, crit, trend) in temps.into_iter().take(TIGHT_SENSORS) {
+            if used_cols > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{:>w$.w$}", dev)?;
+            self.fmt_temperature(f, k, c, crit, trend)?;
+            used_cols += 2;
+        }
+        if used_cols == 0 {
+            write!(f, "{:>w$.w$}", "")?;
+            used_cols = 1;
+        }
+        for _ in used_cols..8 {
+            write!(f, " {:>w$.w$}", "")?;
+        }
+        write!(f, "{}", MaybeSmart(Newline(), self.settings))
+    }
+
+    /// A sensor label and its temperature, coloured against its critical temperature if known
+    fn fmt_temperature(
+        &self,
+        f: &mut fmt::Formatter,
+        label: &str,
+        c: Celsius,
+        crit: Option<Celsius>,
+        trend: Trend,
+    ) -> fmt::Result {
+        let w: usize = self.settings.colwidth.get().into();
+        let label = MaybeSmart(Heading::new(label), self.settings);
+        let value = MaybeSmart(temperature(c, crit), self.settings);
+        if w > 10 {
+            let w = w - 7;
+            write!(f, " {:>w$.w$}{:>6.1}", label, value)?;
+        } else {
+            let w = w - 6;
+            write!(f, " {:>w$.w$}{:>5.0}", label, value)?;
+        }
+        /* Always reserve room for the trend, so columns don't move around */
+        write!(f, "{}", MaybeSmart(trend, self.settings))
+    }
 }
 
 impl<'a> fmt::Display for HwmonStats<'a> {
@@ -305,10 +741,14 @@ impl<'a> fmt::Display for HwmonStats<'a> {
         if self.state.is_empty() {
             return Ok(());
         }
+        if self.tight() {
+            self.fmt_tight(f)?;
+            return write!(f, "{}", MaybeSmart(Newline(), self.settings));
+        }
 
         let newline = MaybeSmart(Newline(), self.settings);
         let w = self.settings.colwidth.get().into();
-        let two_cols = self.state.values().all(|v| v.1.len() <= 3);
+        let two_cols = self.two_cols();
         let mut used_cols = 0;
 
         for v in self.state.values() {
@@ -319,36 +759,30 @@ impl<'a> fmt::Display for HwmonStats<'a> {
             used_cols += 1;
             write!(f, "{:>w$.w$}", v.0)?;
 
+            let hottest = match self.settings.compact {
+                true => hottest(&v.1),
+                false => None,
+            };
+
             let mut i = 0;
             for (k, vv) in v.1.iter() {
+                if hottest.is_some_and(|h| h != k) {
+                    continue;
+                }
+
                 if i > 0 && i % 7 == 0 {
                     write!(f, "{}{:>w$.w$}", newline, v.0)?;
                     used_cols = 1;
                 }
 
-                let label = MaybeSmart(Heading(k), self.settings);
+                let label = MaybeSmart(Heading::new(k), self.settings);
                 match vv.0 {
                     DataKind::Nothing => {
                         let w = w - 4;
                         write!(f, " {:>w$.w$} n/a", label)?;
                     }
-                    DataKind::Temperature(c) => {
-                        let value = MaybeSmart(
-                            Threshold {
-                                val: c,
-                                med: Celsius(50.0),
-                                high: Celsius(70.0),
-                                crit: Celsius(90.0),
-                            },
-                            self.settings,
-                        );
-                        if w > 10 {
-                            let w = w - 6;
-                            write!(f, " {:>w$.w$}{:>6.1}", label, value)?;
-                        } else {
-                            let w = w - 6;
-                            write!(f, " {:>w$.w$}{:>6.0}", label, value)?;
-                        }
+                    DataKind::Temperature(c, crit, trend) => {
+                        self.fmt_temperature(f, k, c, crit, trend)?;
                     }
                     DataKind::Percentage(p) => {
                         let value = MaybeSmart(
@@ -380,6 +814,36 @@ impl<'a> fmt::Display for HwmonStats<'a> {
                         let w = w - 6;
                         write!(f, " {:>w$.w$}{:>6.1}", label, v)?;
                     }
+                    #[cfg(feature = "nvme-health")]
+                    DataKind::Wear(p) => {
+                        let value = MaybeSmart(
+                            Threshold {
+                                val: p,
+                                med: Percentage(80.0),
+                                high: Percentage(90.0),
+                                crit: Percentage(100.0),
+                            },
+                            self.settings,
+                        );
+                        let w = w - 4;
+                        write!(f, " {:>w$.w$}{:>4.0}", label, value)?;
+                    }
+                    #[cfg(feature = "nvme-health")]
+                    DataKind::Spare(p, threshold) => {
+                        let w = w - 4;
+                        write!(f, " {:>w$.w$}", label)?;
+                        /* Lower is worse, can't use Threshold */
+                        match self.settings.smart.get() && p < threshold {
+                            false => write!(f, "{:>4.0}", p)?,
+                            true => {
+                                write!(f, "{}{:>4.0}\x1B[0m", self.settings.theme.get().crit(), p)?
+                            }
+                        }
+                    }
+                    DataKind::GpuProcess(b) => {
+                        let w = w - 6;
+                        write!(f, " {:>w$.w$}{:>6.1}", label, b)?;
+                    }
                     DataKind::Watts(v, None) => {
                         let w = w - 6;
                         write!(f, " {:>w$.w$}{:>6.1}", label, v)?;
@@ -426,9 +890,227 @@ impl<'a> fmt::Display for HwmonStats<'a> {
             }
         }
 
-        for _ in used_cols..8 {
-            write!(f, " {:>w$.w$}", "")?;
+        if used_cols > 0 {
+            /* Only in two column mode, with an odd number of devices; the separator after the
+             * last device has already been written */
+            write!(f, "{:>w$.w$}", "")?;
+            for _ in (used_cols + 1)..8 {
+                write!(f, " {:>w$.w$}", "")?;
+            }
+            write!(f, "{}", newline)?;
+        }
+        write!(f, "{}", newline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp(c: f32) -> (DataKind, Stale) {
+        (
+            DataKind::Temperature(Celsius(c), None, Trend::Stable),
+            Stale(false),
+        )
+    }
+
+    /// (hwmonX, name, device under devices/, [(label, millidegrees)])
+    type FakeChip<'s> = (usize, &'s str, Option<&'s str>, &'s [(&'s str, i32)]);
+
+    /// A fake /sys/class/hwmon with the given chips
+    fn sys_fixture(test: &str, chips: &[FakeChip]) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("hitome-hwmon-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        for (x, name, device, temps) in chips {
+            let hwmon = root.join("class/hwmon").join(format!("hwmon{}", x));
+            std::fs::create_dir_all(&hwmon).unwrap();
+            std::fs::write(hwmon.join("name"), format!("{}\n", name)).unwrap();
+            if let Some(d) = device {
+                std::fs::create_dir_all(root.join("devices").join(d)).unwrap();
+                let target = PathBuf::from("../../../devices").join(d);
+                std::os::unix::fs::symlink(target, hwmon.join("device")).unwrap();
+            }
+            for (y, (label, temp)) in temps.iter().enumerate() {
+                let y = y + 1;
+                std::fs::write(
+                    hwmon.join(format!("temp{}_input", y)),
+                    format!("{}\n", temp),
+                )
+                .unwrap();
+                if !label.is_empty() {
+                    std::fs::write(
+                        hwmon.join(format!("temp{}_label", y)),
+                        format!("{}\n", label),
+                    )
+                    .unwrap();
+                }
+            }
         }
-        write!(f, "{}{}", newline, newline)
+        root.join("class/hwmon")
+    }
+
+    #[test]
+    fn chips_grouped_by_device() {
+        let root = sys_fixture(
+            "group",
+            &[
+                (
+                    0,
+                    "nvme",
+                    Some("pci/0000:01:00.0/nvme/nvme0"),
+                    &[("Composite", 41850), ("Sensor 1", 52850)],
+                ),
+                (
+                    1,
+                    "nvme",
+                    Some("pci/0000:02:00.0/nvme/nvme1"),
+                    &[("Composite", 38850)],
+                ),
+                /* A second chip of the first drive */
+                (
+                    2,
+                    "nvme",
+                    Some("pci/0000:01:00.0/nvme/nvme0"),
+                    &[("Composite", 45000), ("Sensor 2", 40000)],
+                ),
+                (3, "k10temp", Some("pci/0000:00:18.3"), &[("Tctl", 61000)]),
+                (4, "k10temp", Some("pci/0000:00:19.3"), &[("Tctl", 59000)]),
+                (10, "acpitz", None, &[("", 27800)]),
+            ],
+        );
+        let s = Settings::default();
+        let mut h = HwmonStats::new(&s);
+        h.nvml = None;
+        h.p = root;
+        h.update(&FrameClock::now());
+
+        let rows: Vec<(&str, Vec<&str>)> = h
+            .state
+            .values()
+            .map(|(label, sensors, _)| {
+                (label.as_str(), sensors.keys().map(|k| k.as_str()).collect())
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                (
+                    "nvme0",
+                    vec!["Composite", "Sensor 1", "Sensor 2", "nvme Composite"]
+                ),
+                ("nvme1", vec!["Composite"]),
+                ("k10temp", vec!["Tctl"]),
+                ("k10temp1", vec!["Tctl"]),
+                ("acpitz", vec!["Temp1"]),
+            ]
+        );
+
+        /* Labels are kept on the next refresh */
+        h.update(&FrameClock::now());
+        assert_eq!(h.state.len(), 5);
+        assert_eq!(h.state.values().next().unwrap().1.len(), 4);
+        s.colwidth.set(10);
+        let mut buf = String::new();
+        check_layout(&h, &mut buf).unwrap();
+        let lines: Vec<&str> = buf.lines().map(str::trim_end).collect();
+        assert_eq!(
+            lines,
+            [
+                "     nvme0 Comp  42C  Sens  53C  Sens  40C  nvme  45C",
+                "     nvme1 Comp  39C",
+                "   k10temp Tctl  61C",
+                "  k10temp1 Tctl  59C",
+                "    acpitz Temp  28C",
+                "",
+            ]
+        );
+    }
+
+    #[test]
+    fn trend_threshold() {
+        let trend = |prev: f32, cur: f32| {
+            let prev = DataKind::Temperature(Celsius(prev), None, Trend::Stable);
+            match Trend::new(&prev, Celsius(cur)) {
+                Trend::Stable => ' ',
+                Trend::Rising => '+',
+                Trend::Falling => '-',
+            }
+        };
+        assert_eq!(trend(60.0, 61.5), '+');
+        assert_eq!(trend(60.0, 61.0), ' ');
+        assert_eq!(trend(60.0, 59.0), ' ');
+        assert_eq!(trend(60.0, 58.5), '-');
+        assert_eq!(trend(60.0, 60.0), ' ');
+        /* First reading, or a sensor that used to report something else */
+        assert!(matches!(
+            Trend::new(&DataKind::Nothing, Celsius(80.0)),
+            Trend::Stable
+        ));
+    }
+
+    /// One temperature cell, as printed by fmt_temperature()
+    struct TempCell<'h, 'a>(&'h HwmonStats<'a>, f32, Trend);
+
+    impl fmt::Display for TempCell<'_, '_> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            self.0
+                .fmt_temperature(f, "Tctl", Celsius(self.1), None, self.2)
+        }
+    }
+
+    #[test]
+    fn trend_keeps_width() {
+        let s = Settings::default();
+        let h = HwmonStats::new(&s);
+        for w in [8, 10, 12] {
+            s.colwidth.set(w);
+            for smart in [false, true] {
+                s.smart.set(smart);
+                let cells: Vec<String> = [Trend::Stable, Trend::Rising, Trend::Falling]
+                    .into_iter()
+                    .map(|t| TempCell(&h, 78.0, t).to_string())
+                    .collect();
+                for c in &cells {
+                    assert_eq!(visible_width(c), w as usize + 1, "{:?}", c);
+                }
+                let glyphs: Vec<char> = cells.iter().map(|c| c.chars().last().unwrap()).collect();
+                match smart {
+                    false => assert_eq!(glyphs, [' ', '+', '-']),
+                    true => assert_eq!(glyphs, [' ', '↑', '↓']),
+                }
+            }
+        }
+
+        s.colwidth.set(8);
+        s.smart.set(false);
+        assert_eq!(TempCell(&h, 78.0, Trend::Rising).to_string(), " Tc  78C+");
+        s.colwidth.set(10);
+        assert_eq!(
+            TempCell(&h, 78.0, Trend::Falling).to_string(),
+            " Tctl  78C-"
+        );
+        s.colwidth.set(12);
+        assert_eq!(
+            TempCell(&h, 78.0, Trend::Stable).to_string(),
+            "  Tctl 78.0C "
+        );
+    }
+
+    #[test]
+    fn compact_hottest() {
+        let mut sensors = BTreeMap::new();
+        assert_eq!(hottest(&sensors), None);
+
+        sensors.insert(
+            String::from("fan"),
+            (DataKind::Percentage(Percentage(90.0)), Stale(false)),
+        );
+        assert_eq!(hottest(&sensors), None);
+
+        sensors.insert(String::from("Composite"), temp(41.0));
+        sensors.insert(String::from("Sensor 1"), temp(52.5));
+        sensors.insert(String::from("Sensor 2"), temp(38.0));
+        assert_eq!(hottest(&sensors), Some("Sensor 1"));
     }
 }
@@ -0,0 +1,252 @@
+/* Copyright 2022 Romain "Artefact2" Dal Maso <romain.dalmaso@artefact2.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *	   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+pub const ESCAPE: u8 = 0x1B;
+pub const BACKSPACE: u8 = 0x7F;
+
+/// The terminal settings before raw input was enabled, restored when exiting
+static mut SAVED_TERMIOS: Option<libc::termios> = None;
+
+/// Set when a signal asked us to exit, see catch_signals()
+static QUIT: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn restore_and_reraise(sig: libc::c_int) {
+    restore();
+    unsafe {
+        libc::signal(sig, libc::SIG_DFL);
+        libc::raise(sig);
+    }
+}
+
+extern "C" fn request_quit(sig: libc::c_int) {
+    /* Don't get stuck if the main loop doesn't notice */
+    if QUIT.swap(true, Ordering::Relaxed) {
+        restore_and_reraise(sig);
+    }
+}
+
+/// Instead of exiting right away on SIGINT, SIGTERM or SIGHUP, let the main loop exit cleanly;
+/// see quit_requested(). Overrides the handlers set by enable().
+pub fn catch_signals() {
+    for sig in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        unsafe {
+            libc::signal(
+                sig,
+                request_quit as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+        }
+    }
+}
+
+/// Was a signal caught by catch_signals()?
+pub fn quit_requested() -> bool {
+    QUIT.load(Ordering::Relaxed)
+}
+
+/// Like thread::sleep(), but returns early if interrupted by a signal
+pub fn sleep(timeout: Duration) {
+    unsafe {
+        libc::poll(std::ptr::null_mut(), 0, timeout.as_millis() as libc::c_int);
+    }
+}
+
+/// Put the terminal in non-canonical mode without echo, so key presses can be read one by one.
+/// Returns false if stdin is not a terminal. Can be called again after restore().
+pub fn enable() -> bool {
+    unsafe {
+        if libc::isatty(libc::STDIN_FILENO) != 1 {
+            return false;
+        }
+
+        let mut t = std::mem::MaybeUninit::<libc::termios>::uninit();
+        if libc::tcgetattr(libc::STDIN_FILENO, t.as_mut_ptr()) != 0 {
+            return false;
+        }
+        /* Don't save our own raw mode if already enabled */
+        let t = match SAVED_TERMIOS {
+            Some(saved) => saved,
+            None => t.assume_init(),
+        };
+        SAVED_TERMIOS = Some(t);
+
+        /* Keep ISIG, so ^C still works; restore the terminal on the way out */
+        for sig in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            libc::signal(
+                sig,
+                restore_and_reraise as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+        }
+
+        let mut raw = t;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        raw.c_cc[libc::VMIN] = 0;
+        raw.c_cc[libc::VTIME] = 0;
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) == 0
+    }
+}
+
+/// Restore the terminal to how it was before enable() was called. Async-signal-safe.
+pub fn restore() {
+    unsafe {
+        if let Some(t) = SAVED_TERMIOS {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &t);
+        }
+    }
+}
+
+/// Wait at most timeout for a key press
+pub fn read_key(timeout: Duration) -> Option<u8> {
+    let mut fds = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let mut c = 0u8;
+    unsafe {
+        if libc::poll(&mut fds, 1, timeout.as_millis() as libc::c_int) != 1 {
+            return None;
+        }
+        if libc::read(
+            libc::STDIN_FILENO,
+            &mut c as *mut u8 as *mut libc::c_void,
+            1,
+        ) != 1
+        {
+            /* Don't spin if stdin was closed */
+            std::thread::sleep(timeout);
+            return None;
+        }
+    }
+    Some(c)
+}
+
+/// Parse the reply to an OSC 11 query, eg. "\x1B]11;rgb:RRRR/GGGG/BBBB\x07" (terminated by BEL or
+/// ST), into the relative luminance of the background. Components have 1 to 4 hex digits.
+pub fn parse_background(reply: &[u8]) -> Option<f32> {
+    let reply = std::str::from_utf8(reply).ok()?;
+    let (_, rgb) = reply.split_once("]11;")?;
+    let rgb = rgb.trim_end_matches(['\x07', '\\', ESCAPE as char]);
+    /* Some terminals (eg. rxvt) also send an alpha component, ignore it */
+    let rgb = rgb
+        .strip_prefix("rgb:")
+        .or_else(|| rgb.strip_prefix("rgba:"))?;
+
+    let mut c = [0f32; 3];
+    let mut parts = rgb.split('/');
+    for c in c.iter_mut() {
+        let hex = parts.next()?;
+        /* from_str_radix() would take a sign */
+        if hex.is_empty() || hex.len() > 4 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let max = (1u32 << (4 * hex.len())) - 1;
+        *c = u32::from_str_radix(hex, 16).ok()? as f32 / max as f32;
+    }
+    /* Rec. 709 weights, ignoring gamma: close enough to tell dark from light */
+    Some(0.2126 * c[0] + 0.7152 * c[1] + 0.0722 * c[2])
+}
+
+/// Ask the terminal for its background colour, see parse_background(). Needs enable(). Gives up
+/// after timeout, as many terminals never answer.
+pub fn query_background(timeout: Duration) -> Option<f32> {
+    unsafe {
+        if libc::isatty(libc::STDOUT_FILENO) != 1 {
+            return None;
+        }
+    }
+    let mut out = std::io::stdout();
+    out.write_all(b"\x1B]11;?\x07").ok()?;
+    out.flush().ok()?;
+
+    /* XXX: a reply arriving after the timeout will be read as key presses */
+    let deadline = Instant::now() + timeout;
+    let mut reply = Vec::new();
+    while reply.len() < 64 {
+        let c = read_key(deadline.saturating_duration_since(Instant::now()))?;
+        reply.push(c);
+        if c == 0x07 || reply.ends_with(b"\x1B\\") {
+            return parse_background(&reply);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Theme;
+
+    fn luminance(reply: &[u8]) -> f32 {
+        parse_background(reply).unwrap()
+    }
+
+    #[test]
+    fn xterm() {
+        /* xterm answers with the terminator of the query */
+        assert_eq!(luminance(b"\x1B]11;rgb:ffff/ffff/ffff\x07"), 1.0);
+        assert_eq!(luminance(b"\x1B]11;rgb:ffff/ffff/ffff\x1B\\"), 1.0);
+        assert_eq!(luminance(b"\x1B]11;rgb:0000/0000/0000\x07"), 0.0);
+    }
+
+    #[test]
+    fn kitty() {
+        let l = luminance(b"\x1B]11;rgb:0000/0000/0000\x1B\\");
+        assert!(Theme::for_luminance(l) == Theme::Dark);
+        let l = luminance(b"\x1B]11;rgb:fafa/f4f4/eded\x1B\\");
+        assert!(Theme::for_luminance(l) == Theme::Light);
+    }
+
+    #[test]
+    fn foot() {
+        let l = luminance(b"\x1B]11;rgb:1e1e/1e1e/2e2e\x1B\\");
+        assert!((l - 0.1222).abs() < 0.001, "{}", l);
+        assert!(Theme::for_luminance(l) == Theme::Dark);
+        let l = luminance(b"\x1B]11;rgb:ffff/ffff/dddd\x07");
+        assert!(Theme::for_luminance(l) == Theme::Light);
+    }
+
+    #[test]
+    fn digits() {
+        /* The same colour with 2 and 4 digits per channel, and rxvt's alpha */
+        let l4 = luminance(b"\x1B]11;rgb:8080/4040/c0c0\x07");
+        let l2 = luminance(b"\x1B]11;rgb:80/40/c0\x07");
+        assert!((l4 - l2).abs() < 0.001, "{} {}", l4, l2);
+        assert_eq!(luminance(b"\x1B]11;rgb:f/f/f\x07"), 1.0);
+        assert_eq!(luminance(b"\x1B]11;rgb:fff/fff/fff\x07"), 1.0);
+        assert_eq!(luminance(b"\x1B]11;rgba:ffff/ffff/ffff/0000\x1B\\"), 1.0);
+    }
+
+    #[test]
+    fn garbage() {
+        for reply in [
+            &b""[..],
+            b"\x07",
+            b"\x1B]10;rgb:ffff/ffff/ffff\x07",
+            b"\x1B]11;rgb:ffff/ffff\x07",
+            b"\x1B]11;rgb:fffff/ffff/ffff\x07",
+            b"\x1B]11;rgb://ffff/ffff\x07",
+            b"\x1B]11;rgb:+fff/ffff/ffff\x07",
+            b"\x1B]11;rgb:gggg/ffff/ffff\x07",
+            b"\x1B]11;#ffffff\x07",
+            b"\x1B]11;rgb:\xff\xff/ffff/ffff\x07",
+        ] {
+            assert_eq!(parse_background(reply), None, "{:?}", reply);
+        }
+    }
+}
@@ -0,0 +1,239 @@
+/* Copyright 2022 Romain "Artefact2" Dal Maso <romain.dalmaso@artefact2.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *	   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::common::*;
+use std::fmt;
+
+/// Syslog levels, see include/linux/kern_levels.h
+const LOGLEVEL_ERR: u8 = 3;
+const LOGLEVEL_WARNING: u8 = 4;
+
+enum Kmsg {
+    /// Not opted in, print nothing
+    Disabled,
+    /// Couldn't open /dev/kmsg; how many times update() was called since. Only say so in the first
+    /// frame.
+    NoAccess(u8),
+    Open(FileDescriptor),
+}
+
+/// Number of records at warning level or worse, with the worst level seen
+#[derive(Clone, Copy)]
+struct Burst(u32, u8);
+
+impl<'a> fmt::Display for MaybeSmart<'a, Burst> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let w = f.width().unwrap_or(8) - 4;
+        let n = self.0 .0;
+
+        if !self.1.smart.get() || n == 0 {
+            return write!(f, "{:>w$} err", n);
+        }
+
+        let theme = self.1.theme.get();
+        let colour = match self.0 .1 {
+            l if l < LOGLEVEL_ERR => theme.crit(),
+            LOGLEVEL_ERR => theme.high(),
+            _ => theme.med(),
+        };
+        write!(f, "{}{:>w$} err\x1B[0m", colour, n)
+    }
+}
+
+pub struct KmsgStats<'a> {
+    settings: &'a Settings,
+    kmsg: Kmsg,
+    burst: Burst,
+    /// The text of the most recent record at warning level or worse
+    last: String,
+    buf: [u8; 8192],
+}
+
+/// Parse a record read from /dev/kmsg, returns (level, message). See
+/// Documentation/ABI/testing/dev-kmsg.
+fn parse_record(rec: &str) -> Option<(u8, &str)> {
+    let (header, msg) = rec.split_once(';')?;
+    let prio = header.split(',').next()?.parse::<u32>().ok()?;
+    /* Skip continuation lines, they start with a space and contain KEY=value pairs */
+    let msg = msg.lines().next().unwrap_or("");
+    Some(((prio & 7) as u8, msg))
+}
+
+impl<'a> StatBlock<'a> for KmsgStats<'a> {
+    fn new(s: &'a Settings) -> Self {
+        let kmsg = if !s.kmsg {
+            Kmsg::Disabled
+        } else {
+            let fd =
+                unsafe { libc::open(c"/dev/kmsg".as_ptr(), libc::O_RDONLY | libc::O_NONBLOCK) };
+            if fd == -1 {
+                Kmsg::NoAccess(0)
+            } else {
+                /* Only interested in records logged from now on */
+                unsafe { libc::lseek(fd, 0, libc::SEEK_END) };
+                Kmsg::Open(FileDescriptor(fd))
+            }
+        };
+
+        KmsgStats {
+            settings: s,
+            kmsg,
+            burst: Burst(0, LOGLEVEL_WARNING),
+            last: String::new(),
+            buf: [0; 8192],
+        }
+    }
+
+    fn update(&mut self, _: &FrameClock) {
+        let fd = match &mut self.kmsg {
+            Kmsg::Open(fd) => fd.0,
+            Kmsg::NoAccess(n) => {
+                *n = n.saturating_add(1);
+                return;
+            }
+            Kmsg::Disabled => return,
+        };
+
+        self.burst = Burst(0, LOGLEVEL_WARNING);
+
+        /* Every read() returns exactly one record */
+        loop {
+            let ret = unsafe {
+                libc::read(
+                    fd,
+                    self.buf.as_mut_ptr() as *mut libc::c_void,
+                    self.buf.len(),
+                )
+            };
+            if ret == -1 {
+                if unsafe { *libc::__errno_location() } == libc::EPIPE {
+                    /* Some records were overwritten before we could read them, skip them */
+                    continue;
+                }
+                /* Most likely EAGAIN, no more records */
+                break;
+            }
+            if ret == 0 {
+                break;
+            }
+
+            let (level, msg) = match std::str::from_utf8(&self.buf[..(ret as usize)])
+                .ok()
+                .and_then(parse_record)
+            {
+                Some(r) => r,
+                None => continue,
+            };
+            if level > LOGLEVEL_WARNING {
+                continue;
+            }
+
+            self.burst.0 += 1;
+            self.burst.1 = self.burst.1.min(level);
+            self.last.clear();
+            self.last.push_str(msg);
+        }
+    }
+
+    fn columns(&self) -> u16 {
+        match self.kmsg {
+            Kmsg::Disabled => 0,
+            Kmsg::NoAccess(n) if n > 1 => 0,
+            _ => self.settings.maxcols.get(),
+        }
+    }
+
+    fn rows(&self) -> u16 {
+        match self.kmsg {
+            Kmsg::Disabled => 0,
+            Kmsg::NoAccess(n) if n > 1 => 0,
+            _ => 2,
+        }
+    }
+}
+
+impl<'a> fmt::Display for KmsgStats<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let newline = MaybeSmart(Newline(), self.settings);
+        let w = self.settings.colwidth.get().into();
+        let len = self.settings.maxcols.get().into();
+
+        match self.kmsg {
+            Kmsg::Disabled => Ok(()),
+            Kmsg::NoAccess(n) if n > 1 => Ok(()),
+            Kmsg::NoAccess(_) => match self.settings.smart.get() {
+                false => write!(f, "{:<len$.len$}{}{}", "kmsg: no access", newline, newline),
+                true => write!(
+                    f,
+                    "\x1B[2m{:<len$.len$}\x1B[0m{}{}",
+                    "kmsg: no access", newline, newline
+                ),
+            },
+            Kmsg::Open(_) => {
+                let len = len.saturating_sub(2 * w + 2);
+                write!(
+                    f,
+                    "{} {:w$} {:<len$.len$}{}{}",
+                    MaybeSmart(Heading::new("KMSG"), self.settings),
+                    MaybeSmart(self.burst, self.settings),
+                    self.last,
+                    newline,
+                    newline
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records() {
+        assert_eq!(
+            parse_record("3,1234,5678901,-;nvme0: I/O error\n"),
+            Some((3, "nvme0: I/O error"))
+        );
+        /* Facility bits above the level */
+        assert_eq!(
+            parse_record("28,1,2,-;usb 1-1: reset"),
+            Some((4, "usb 1-1: reset"))
+        );
+        assert_eq!(
+            parse_record("4,1,2,c;oom: killed\n SUBSYSTEM=memory\n DEVICE=+memory:1\n"),
+            Some((4, "oom: killed"))
+        );
+        assert_eq!(parse_record("6,1,2,-;"), Some((6, "")));
+        assert_eq!(parse_record("no header"), None);
+        assert_eq!(parse_record("x,1,2,-;msg"), None);
+    }
+
+    #[test]
+    fn narrow_terminal() {
+        let s: Settings = Default::default();
+        s.smart.set(false);
+        s.maxcols.set(10);
+        s.colwidth.set(8);
+        let k = KmsgStats {
+            settings: &s,
+            kmsg: Kmsg::Open(FileDescriptor(-1)),
+            burst: Burst(2, LOGLEVEL_ERR),
+            last: String::from("nvme0: I/O error"),
+            buf: [0; 8192],
+        };
+        assert_eq!(k.to_string(), "    KMSG    2 err \n\n");
+    }
+}
@@ -1,9 +1,23 @@
 pub mod blockdev;
 pub mod common;
+pub mod connector;
 pub mod cpu;
 pub mod fs;
 pub mod hwmon;
+pub mod input;
+pub mod kmsg;
 pub mod mem;
 pub mod network;
+#[cfg(feature = "nvme-health")]
+pub mod nvme;
 pub mod pressure;
+pub mod procroot;
+pub mod profile;
+pub mod remote;
+pub mod review;
+pub mod sched;
+pub mod session;
 pub mod tasks;
+pub mod tmux;
+#[cfg(feature = "io-uring")]
+pub mod uring;
@@ -18,24 +18,62 @@ use hitome::common::*;
 use hitome::cpu::CpuStats;
 use hitome::fs::FilesystemStats;
 use hitome::hwmon::HwmonStats;
+use hitome::input;
+use hitome::kmsg::KmsgStats;
 use hitome::mem::MemoryStats;
 use hitome::network::NetworkStats;
 use hitome::pressure::PressureStats;
-use hitome::tasks::TaskStats;
-use std::cell::Cell;
+use hitome::profile::{Phase, Profile, Slowest};
+use hitome::remote::Remote;
+use hitome::review::{self, Frame};
+use hitome::sched;
+use hitome::session::SessionStats;
+use hitome::tasks::{TaskSortKey, TaskStateSummary, TaskStats};
+use hitome::tmux::StatusLine;
 use std::io::{self, BufWriter, Write};
 use std::thread;
 use std::time::{Duration, Instant};
 
-// XXX: find a better place for these
-const MIN_COL_WIDTH: u16 = 8;
-const MIN_COLUMNS: u16 = 8 * MIN_COL_WIDTH + 7;
-const MIN_ROWS: u16 = 24;
+/// Warn after this many consecutive refreshes took more than 120% of the refresh interval
+const MAX_OVERRUNS: u8 = 3;
 
-/// A function-like macro that .update()s all of its arguments
+/// A function-like macro that evaluates an expression, and records how long it took if profiling
+/// (an Option<Profile>) is enabled
+macro_rules! timed {
+    ($profile:expr, $name:expr, $phase:expr, $e:expr) => {
+        match &mut $profile {
+            None => $e,
+            Some(p) => {
+                let t = Instant::now();
+                let r = $e;
+                p.record($name, $phase, t.elapsed());
+                r
+            }
+        }
+    };
+}
+
+/// A function-like macro that .update()s all of its arguments with the clock of this refresh
 macro_rules! update {
-    ($( $x:expr ),*) => {
-        $($x.update();)*
+    ($profile:expr, $clock:expr, $( $x:expr ),*) => {
+        $(timed!($profile, stringify!($x), Phase::Update, $x.update(&$clock));)*
+    }
+}
+
+/// A function-like macro that prints all of its arguments
+macro_rules! print_blocks {
+    ($w:expr, $profile:expr, $( $x:expr ),*) => {
+        $(timed!($profile, stringify!($x), Phase::Fmt, write!($w, "{}", $x).unwrap());)*
+    }
+}
+
+/// A function-like macro that panics if any of its arguments doesn't print exactly as many rows and
+/// columns as it says it does
+macro_rules! check_layout {
+    ($buf:expr, $( $x:expr ),*) => {
+        $(if let Err(e) = check_layout(&$x, &mut $buf) {
+            panic!("layout check failed for {}: {}", stringify!($x), e);
+        })*
     }
 }
 
@@ -44,7 +82,8 @@ struct TermDimensions {
     cols: u16,
 }
 
-fn get_term_dimensions() -> Option<TermDimensions> {
+/// Ask the terminal on stdout for its dimensions, fails if stdout isn't a terminal
+fn ioctl_term_dimensions() -> Option<TermDimensions> {
     unsafe {
         let mut w = std::mem::MaybeUninit::<libc::winsize>::uninit();
         /* This isn't very portable, but neither is Hitome */
@@ -56,6 +95,13 @@ fn get_term_dimensions() -> Option<TermDimensions> {
             });
         }
     }
+    None
+}
+
+fn get_term_dimensions() -> Option<TermDimensions> {
+    if let Some(d) = ioctl_term_dimensions() {
+        return Some(d);
+    }
 
     /* As a fallback, get dimensions from the environment */
     if let Some(lines) = std::env::var_os("LINES") {
@@ -74,6 +120,14 @@ fn get_term_dimensions() -> Option<TermDimensions> {
     None
 }
 
+/// With a guessed --colour, stop using escape sequences while stdout isn't a terminal, and use them
+/// again once it is. draw!() clears the whole screen anyway, so nothing is left over from dumb mode.
+fn update_smart(s: &Settings) {
+    if s.auto_smart {
+        s.smart.set(ioctl_term_dimensions().is_some());
+    }
+}
+
 fn update_term_dimensions(s: &Settings) {
     if !s.auto_maxcols && !s.auto_maxrows {
         return;
@@ -96,32 +150,96 @@ fn update_term_dimensions(s: &Settings) {
     assert!(s.colwidth.get() >= MIN_COL_WIDTH);
 }
 
+/// Busier than this (the PRESSURE column), and writing less than this per second: the device isn't
+/// keeping up
+const STALL_PRESSURE: f32 = 80.0;
+const STALL_WRITE_RATE: u64 = 1024 * 1024;
+
+/// Guess which device is holding up writeback, one slow drive can wedge it for all of them. When
+/// there are enough dirty pages for background writeback, it is the only device that is busy while
+/// writing little.
+fn writeback_stall<'a>(
+    dirty_high: bool,
+    devices: impl Iterator<Item = (&'a str, Percentage, Bytes)>,
+) -> Option<&'a str> {
+    if !dirty_high {
+        return None;
+    }
+    let mut stalled = devices.filter(|(_, p, wt)| p.0 >= STALL_PRESSURE && wt.0 < STALL_WRITE_RATE);
+    match (stalled.next(), stalled.next()) {
+        (Some((kname, _, _)), None) => Some(kname),
+        _ => None,
+    }
+}
+
+/// Write the session summary to a file, or stderr for "-"
+fn write_summary(path: &str, session: &SessionStats) {
+    let r = match path {
+        "-" => write!(io::stderr(), "{}", session),
+        p => std::fs::write(p, session.to_string()),
+    };
+    if let Err(e) = r {
+        eprintln!("--summary: {}: {}", path, e);
+    }
+}
+
 fn main() {
     if !cfg!(target_os = "linux") {
         eprintln!("Hitome only works by reading Linux-specific /proc interfaces, sorry.");
         return;
     }
 
-    let settings;
+    let mut settings;
+    let iterations;
+    let summary;
+    let review;
+    let mut remote = None;
     {
         let cli: Cli = argh::from_env();
-        if cli.columns == None || cli.rows == None {}
-        settings = Settings {
-            smart: cli
-                .colour
-                .unwrap_or_else(|| match std::env::var_os("TERM") {
-                    Some(val) => val != "dumb",
-                    None => false,
-                }),
-            auto_maxcols: cli.columns == None,
-            auto_maxrows: cli.rows == None,
-            auto_colwidth: cli.column_width == None,
-            maxcols: Cell::new(cli.columns.unwrap_or(0)),
-            maxrows: Cell::new(cli.rows.unwrap_or(0)),
-            colwidth: Cell::new(cli.column_width.unwrap_or(0)),
-            refresh: cli.refresh_interval,
+        let mut warnings = Vec::new();
+        settings = match Settings::from_cli(&cli, &mut warnings) {
+            Ok(s) => s,
+            Err(errors) => {
+                for e in errors {
+                    eprintln!("{}", e);
+                }
+                std::process::exit(2);
+            }
         };
+        for w in warnings {
+            eprintln!("warning: {}", w);
+        }
+        if cli.describe {
+            println!("{}", hitome::session::describe());
+            return;
+        }
+        if let Err(errors) = sched::apply(&cli) {
+            for e in errors {
+                eprintln!("{}", e);
+            }
+            std::process::exit(1);
+        }
+        if cli.tmux {
+            println!("{}", StatusLine::sample(&settings));
+            return;
+        }
+        if let Some(destination) = &cli.remote {
+            match Remote::connect(destination, &settings.proc_root) {
+                Ok(r) => remote = Some(r),
+                Err(e) => {
+                    eprintln!("--remote: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            if cli.dstate_threshold.is_none() {
+                settings.dstate_threshold = settings.proc_root.machine().cpus;
+            }
+        }
+        update_smart(&settings);
         update_term_dimensions(&settings);
+        iterations = cli.iterations;
+        summary = cli.summary;
+        review = cli.review;
         /* Let cli drop out of scope, it has lived its usefulness */
     }
 
@@ -129,49 +247,402 @@ fn main() {
      * crashes. This allows us to see Rust errors. */
     let mut w = std::mem::ManuallyDrop::new(BufWriter::new(io::stdout()));
 
-    let mut mem = MemoryStats::new(&settings);
-    let mut psi = PressureStats::new(&settings);
-    let mut cpu_net = MergedStatBlock::<CpuStats, NetworkStats>::new(&settings);
-    let mut bdev_fs = MergedStatBlock::<BlockDeviceStats, FilesystemStats>::new(&settings);
-    let mut hwmon = HwmonStats::new(&settings);
-    let mut tasks = TaskStats::new(&settings);
+    /* Each block says how old its data is when other blocks are slow to update */
+    let mut kmsg = Aged::<KmsgStats>::new(&settings);
+    let mut mem = Aged::<MemoryStats>::new(&settings);
+    let mut psi = Aged::<PressureStats>::new(&settings);
+    /* The task states shown next to cpu and network are given after tasks are updated */
+    let mut cpu_net = Aged::<
+        MergedStatBlock<MergedStatBlock<CpuStats, NetworkStats>, TaskStateSummary>,
+    >::new(&settings);
+    let mut bdev_fs = Aged::<MergedStatBlock<BlockDeviceStats, FilesystemStats>>::new(&settings);
+    let mut hwmon = Aged::<HwmonStats>::new(&settings);
+    let mut tasks = Aged::<TaskStats>::new(&settings);
+
+    /* Only read key presses if we can draw the prompt on the last row */
+    let interactive = settings.smart.get() && input::enable();
+    if interactive && settings.auto_theme {
+        if let Some(l) = input::query_background(Duration::from_millis(100)) {
+            settings.theme.set(Theme::for_luminance(l));
+        }
+    }
+    if summary.is_some() {
+        input::catch_signals();
+    }
+    /* The filter being edited, if any */
+    let mut prompt: Option<String> = None;
+    let mut layout_buf = String::new();
+    /* When the last refresh started, and how many refreshes in a row were late */
+    let mut last_frame: Option<Instant> = None;
+    let mut overruns = 0u8;
+    let mut profile = match settings.profile {
+        true => Some(Profile::default()),
+        false => None,
+    };
+    let mut session = summary.as_ref().map(|_| SessionStats::default());
+    let mut frames = 0u64;
+    let mut clock = FrameClock::default();
+    /* Only kept for --review */
+    let mut printed = Vec::<Frame>::new();
+    /* Was a D state pileup shown in the last refresh? */
+    let mut pileup = false;
+
+    /// With --remote, ask for the files blocks read last time and wait up to a refresh for them.
+    /// None if they are late; exits if ssh did.
+    macro_rules! fetch {
+        ($r:expr) => {
+            match $r
+                .request(&settings.proc_root)
+                .and_then(|_| $r.receive(Duration::from_millis(settings.refresh)))
+            {
+                Ok(sample) => sample,
+                Err(e) => {
+                    input::restore();
+                    if settings.smart.get() {
+                        write!(w, "\x1B[{};1H\x1B[0J", settings.maxrows.get()).unwrap();
+                        w.flush().unwrap();
+                    }
+                    eprintln!("--remote: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        };
+    }
 
     println!("Hitome will now wait a while to collect statistics...");
+    /* Blocks only find out which files they need from the first ones they read */
+    if let Some(r) = &mut remote {
+        for _ in 0..2 {
+            if let Some(sample) = fetch!(r) {
+                clock.tick_remote(sample.uptime);
+                settings.proc_root.inject(sample.snapshot);
+            }
+            update!(profile, clock, kmsg, mem, psi, cpu_net, bdev_fs, hwmon);
+            update!(profile, clock, tasks);
+        }
+    }
     thread::sleep(Duration::from_millis(settings.refresh));
 
+    /// Share the rows left by the fixed size blocks between hwmon and the task list. Hwmon gets
+    /// the rows it would like, unless that leaves the task list with less than its minimum, or
+    /// than --tasks.
+    macro_rules! fit_blocks {
+        () => {
+            let mut remaining_rows = settings.maxrows.get() as i16
+                - kmsg.rows() as i16
+                - mem.rows() as i16
+                - psi.rows() as i16
+                - cpu_net.rows() as i16
+                - bdev_fs.rows() as i16
+                - (prompt.is_some() || profile.is_some()) as i16
+                - (settings.smart.get() && overruns >= MAX_OVERRUNS) as i16
+                - 1;
+            let preferred = hwmon.preferred_rows() as i16;
+            /* With --tasks, the task list takes its rows whether they fit or not, two per task
+             * with --task-wrap */
+            let task_rows = match settings.tasks {
+                Some(n) => n as i16 * (1 + settings.task_wrap as i16) + 1,
+                None => tasks.min_rows() as i16,
+            };
+            hwmon.fit(match remaining_rows - preferred >= task_rows {
+                true => preferred as u16,
+                false => hwmon.min_rows(),
+            });
+            remaining_rows -= hwmon.rows() as i16;
+            match settings.tasks {
+                Some(n) => tasks.set_max_tasks(n),
+                None => tasks.fit(remaining_rows.max(0) as u16),
+            }
+        };
+    }
+
+    macro_rules! draw {
+        () => {
+            if settings.smart.get() {
+                /* Move cursor to top-left */
+                write!(w, "\x1B[1;1H\x1B[0J").unwrap();
+                if overruns >= MAX_OVERRUNS {
+                    write!(
+                        w,
+                        "{}{}",
+                        MaybeSmart(Overrun(), &settings),
+                        MaybeSmart(Newline(), &settings)
+                    )
+                    .unwrap();
+                }
+            } else if overruns >= MAX_OVERRUNS {
+                writeln!(
+                    w,
+                    "---------- {} {}",
+                    Timestamp::now(settings.utc),
+                    MaybeSmart(Overrun(), &settings)
+                )
+                .unwrap();
+            } else {
+                writeln!(w, "---------- {}", Timestamp::now(settings.utc)).unwrap();
+            }
+
+            print_blocks!(w, profile, kmsg, mem, psi, cpu_net, bdev_fs, hwmon, tasks);
+
+            if let (Some(p), true) = (&prompt, settings.smart.get()) {
+                /* Line editor on the bottom row */
+                write!(w, "\x1B[{};1H/{}", settings.maxrows.get(), p).unwrap();
+            } else if let Some(p) = &profile {
+                if settings.smart.get() {
+                    write!(w, "\x1B[{};1H", settings.maxrows.get()).unwrap();
+                }
+                write!(w, "{}", MaybeSmart(Slowest(p), &settings)).unwrap();
+                if !settings.smart.get() {
+                    writeln!(w).unwrap();
+                }
+            }
+
+            if settings.smart.get() {
+                /* Erase from cursor to end */
+                write!(w, "\x1B[0J").unwrap();
+            }
+
+            w.flush().unwrap();
+        };
+    }
+
+    /// Restore the terminal, print the profile and write the summary, then exit (after browsing
+    /// frames with --review)
+    macro_rules! quit {
+        () => {
+            input::restore();
+            if settings.smart.get() {
+                write!(w, "\x1B[{};1H\x1B[0J", settings.maxrows.get()).unwrap();
+            }
+            if let Some(p) = &profile {
+                write!(w, "{}", p).unwrap();
+            }
+            w.flush().unwrap();
+            if let (Some(path), Some(s)) = (&summary, &session) {
+                write_summary(path, s);
+            }
+            if review && iterations == Some(frames) {
+                review::review(&printed, settings.maxrows.get());
+            }
+            return;
+        };
+    }
+
     loop {
-        let t = Instant::now();
+        if input::quit_requested() {
+            quit!();
+        }
+
+        match &mut remote {
+            None => clock.tick(),
+            Some(r) => match fetch!(r) {
+                Some(sample) => {
+                    clock.tick_remote(sample.uptime);
+                    settings.proc_root.inject(sample.snapshot);
+                }
+                /* Skip this refresh rather than mix files read at different times */
+                None => continue,
+            },
+        }
+        let t = clock.instant();
+        if let Some(s) = clock.stepped {
+            /* Rates are fine, but timestamps before and after don't line up anymore */
+            let msg = format!("clock stepped {:+.1}s", s);
+            if !settings.smart.get() {
+                writeln!(w, "== {} ==", msg).unwrap();
+            }
+            if let Some(s) = &mut session {
+                s.alert(Timestamp::now(settings.utc), &msg);
+            }
+        }
+        if let Some(d) = clock.suspended {
+            /* Rates over a suspend make no sense: take new baselines, and only draw after a whole
+             * refresh. Without this marker, the gap in logs would look like hitome hung. */
+            if !settings.smart.get() {
+                writeln!(w, "== suspended for {} ==", HumanDuration(d)).unwrap();
+                w.flush().unwrap();
+            }
+            if let Some(s) = &mut session {
+                let msg = format!("suspended for {}", HumanDuration(d));
+                s.alert(Timestamp::now(settings.utc), &msg);
+            }
+            update!(profile, clock, kmsg, mem, psi, cpu_net, bdev_fs, hwmon);
+            tasks.set_gpu_memory(hwmon.gpu_memory());
+            tasks.set_mountpoints(bdev_fs.right().mountpoints());
+            update!(profile, clock, tasks);
+            last_frame = None;
+            overruns = 0;
+            input::sleep(Duration::from_millis(settings.refresh));
+            continue;
+        }
 
-        if settings.smart {
-            /* Move cursor to top-left */
-            write!(w, "\x1B[1;1H\x1B[0J").unwrap();
-        } else {
-            writeln!(w, "----------").unwrap();
+        if let Some(last) = last_frame {
+            let interval = t - last;
+            settings.frame_interval.set(interval);
+            match interval > Duration::from_millis(settings.refresh * 6 / 5) {
+                true => overruns = overruns.saturating_add(1),
+                false => overruns = 0,
+            }
         }
+        last_frame = Some(t);
 
+        update_smart(&settings);
         update_term_dimensions(&settings);
-        update!(mem, psi, cpu_net, bdev_fs, hwmon);
-        let remaining_rows = settings.maxrows.get() as i16
-            - mem.rows() as i16
-            - psi.rows() as i16
-            - cpu_net.rows() as i16
-            - bdev_fs.rows() as i16
-            - hwmon.rows() as i16
-            - 2;
-        tasks.set_max_tasks(remaining_rows.max(5) as u16);
-        update!(tasks);
-        write!(w, "{}{}{}{}{}{}", mem, psi, cpu_net, bdev_fs, hwmon, tasks).unwrap();
-
-        if settings.smart {
-            /* Erase from cursor to end */
-            write!(w, "\x1B[0J").unwrap();
-        }
-
-        w.flush().unwrap();
-        thread::sleep(Duration::from_millis(
-            settings
-                .refresh
-                .saturating_sub(t.elapsed().as_millis() as u64),
-        ));
+        update!(profile, clock, kmsg, mem, psi, cpu_net, bdev_fs, hwmon);
+        let stall = writeback_stall(mem.dirty_above_background(), bdev_fs.left().writes())
+            .map(String::from);
+        bdev_fs.left_mut().set_writeback_stall(stall);
+        fit_blocks!();
+        tasks.set_gpu_memory(hwmon.gpu_memory());
+        tasks.set_mountpoints(bdev_fs.right().mountpoints());
+        update!(profile, clock, tasks);
+        cpu_net.right_mut().set_states(tasks.state_counts());
+        cpu_net.left_mut().left_mut().set_hot_cpu(tasks.hot_cpu());
+        if let Some(s) = &mut session {
+            s.frame();
+            s.cpu(cpu_net.left().left().busy());
+            s.memory_available(mem.available());
+            s.pressure(psi.avg10());
+            s.interfaces(cpu_net.left().right().counters());
+            s.device_totals(bdev_fs.left().totals());
+            /* Only log when the alerts start, not on every refresh they are shown */
+            if let Some((n, comm, wchan)) = tasks.pileup() {
+                if !pileup {
+                    let mut msg = format!("{} tasks in uninterruptible sleep, mostly {}", n, comm);
+                    if !wchan.is_empty() {
+                        msg.push_str(&format!(" [{}]", wchan));
+                    }
+                    s.alert(Timestamp::now(settings.utc), &msg);
+                }
+            }
+            if overruns == MAX_OVERRUNS {
+                let msg = format!(
+                    "refreshes running {:.1}s behind target {:.1}s",
+                    settings.frame_interval.get().as_secs_f32(),
+                    settings.refresh as f32 / 1000.0
+                );
+                s.alert(Timestamp::now(settings.utc), &msg);
+            }
+        }
+        pileup = tasks.pileup().is_some();
+        if settings.check_layout {
+            check_layout!(layout_buf, kmsg, mem, psi, cpu_net, bdev_fs, hwmon, tasks);
+        }
+        draw!();
+
+        frames += 1;
+        if review {
+            printed.push(Frame {
+                timestamp: Timestamp::now(settings.utc).to_string(),
+                text: format!(
+                    "{}{}{}{}{}{}{}",
+                    kmsg, mem, psi, cpu_net, bdev_fs, hwmon, tasks
+                ),
+            });
+        }
+        if iterations == Some(frames) {
+            quit!();
+        }
+
+        let refresh = Duration::from_millis(settings.refresh);
+        while let Some(remaining) = refresh.checked_sub(t.elapsed()) {
+            if input::quit_requested() {
+                quit!();
+            }
+
+            if !interactive || !settings.smart.get() {
+                input::sleep(remaining);
+                continue;
+            }
+
+            let key = match input::read_key(remaining) {
+                Some(k) => k,
+                None => continue,
+            };
+
+            match (&mut prompt, key) {
+                (None, b's') => tasks.set_sort_key(TaskSortKey::State),
+                (None, b'c') => tasks.set_sort_key(TaskSortKey::Cpu),
+                (None, b'm') => tasks.set_sort_key(TaskSortKey::Memory),
+                (None, b'k') => tasks.toggle_kernel_threads(),
+                (None, b't') => settings.disk_totals.set(!settings.disk_totals.get()),
+                (None, b'/') => prompt = Some(String::from(tasks.filter())),
+                (None, b'p') => {
+                    /* Shown until the next refresh */
+                    if let Some(p) = &profile {
+                        write!(w, "\x1B[1;1H\x1B[0J{}", p).unwrap();
+                        w.flush().unwrap();
+                    }
+                    continue;
+                }
+                (None, b'q') => {
+                    quit!();
+                }
+                (None, _) => continue,
+                (Some(_), input::ESCAPE) => {
+                    tasks.set_filter("");
+                    prompt = None;
+                }
+                (Some(p), b'\n') => {
+                    tasks.set_filter(p);
+                    prompt = None;
+                }
+                (Some(p), input::BACKSPACE) => {
+                    p.pop();
+                }
+                (Some(p), k) if k.is_ascii_graphic() || k == b' ' => p.push(k as char),
+                (Some(_), _) => continue,
+            }
+
+            fit_blocks!();
+            tasks.select_tasks();
+            cpu_net.left_mut().left_mut().set_hot_cpu(tasks.hot_cpu());
+            draw!();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (dirty above background, [(kname, pressure, written/s)]) -> suspected device
+    fn stall(dirty: bool, devices: &[(&'static str, f32, u64)]) -> Option<&'static str> {
+        writeback_stall(
+            dirty,
+            devices
+                .iter()
+                .map(|(k, p, w)| (*k, Percentage(*p), Bytes(*w))),
+        )
+    }
+
+    #[test]
+    fn writeback_stall_decisions() {
+        const MB: u64 = 1024 * 1024;
+        let usb = ("sdc", 100.0, 200 * 1024);
+        let nvme_idle = ("nvme0n1", 2.0, 0);
+        let nvme_busy = ("nvme0n1", 95.0, 800 * MB);
+        let hdd_slow = ("sda", 90.0, 512 * 1024);
+
+        /* Dirty pages below the background threshold: never a stall */
+        assert_eq!(stall(false, &[usb, nvme_idle]), None);
+        /* One slow and busy device */
+        assert_eq!(stall(true, &[nvme_idle, usb]), Some("sdc"));
+        assert_eq!(stall(true, &[nvme_busy, usb]), Some("sdc"));
+        /* Busy but keeping up */
+        assert_eq!(stall(true, &[nvme_busy]), None);
+        /* Two candidates: can't tell which one */
+        assert_eq!(stall(true, &[usb, hdd_slow, nvme_idle]), None);
+        /* At the limits */
+        assert_eq!(
+            stall(true, &[("sdc", STALL_PRESSURE, STALL_WRITE_RATE - 1)]),
+            Some("sdc")
+        );
+        assert_eq!(
+            stall(true, &[("sdc", STALL_PRESSURE, STALL_WRITE_RATE)]),
+            None
+        );
+        assert_eq!(stall(true, &[("sdc", STALL_PRESSURE - 0.1, 0)]), None);
+        assert_eq!(stall(true, &[]), None);
     }
 }
@@ -14,17 +14,149 @@
  */
 
 use super::common::*;
+use crate::procroot::ProcRoot;
 use std::fmt;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+const KSM_DIR: &str = "/sys/kernel/mm/ksm";
+
+/// Swapping out this many bytes per second is worth a look, or a sign of trouble
+const SWAP_OUT_MED: u64 = 1024 * 1024;
+const SWAP_OUT_HIGH: u64 = 10 * 1024 * 1024;
+
+/// Over this percentage of swap used is worth a look, but never more than that without swapping
+/// out
+const SWAP_USED_MED: u64 = 50;
+
+/// How many refreshes the swap out rate is smoothed over
+const SWAP_OUT_SMOOTHING: f32 = 3.0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum SwapLevel {
+    Quiet,
+    Med,
+    High,
+}
+
+/// Swap usage, coloured by how fast pages are swapped out: lots of swap used is fine if it stays
+/// there, swapping out all the time isn't
+#[derive(Clone, Copy)]
+struct Swap {
+    used: Bytes,
+    /// Summed size of all swap areas
+    size: Bytes,
+    /// Smoothed swap out rate, in bytes per second
+    out_rate: f32,
+}
+
+impl Swap {
+    /// Feed the swap out rate of the last refresh, in bytes per second
+    fn swapped_out(&mut self, rate: u64) {
+        /* Exponential moving average, so one short burst isn't flagged */
+        self.out_rate = ema(self.out_rate, rate as f32, SWAP_OUT_SMOOTHING);
+    }
+
+    fn level(&self) -> SwapLevel {
+        match self.out_rate as u64 {
+            r if r >= SWAP_OUT_HIGH => SwapLevel::High,
+            r if r >= SWAP_OUT_MED => SwapLevel::Med,
+            _ if self.used.0 * 100 > self.size.0 * SWAP_USED_MED => SwapLevel::Med,
+            _ => SwapLevel::Quiet,
+        }
+    }
+}
+
+impl<'a> fmt::Display for MaybeSmart<'a, Swap> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let w = f.width().unwrap_or(8);
+        let used = self.0.used;
+        let theme = self.1.theme.get();
+        let colour = match self.0.level() {
+            _ if !self.1.smart.get() => None,
+            SwapLevel::High => Some(theme.high()),
+            SwapLevel::Med => Some(theme.med()),
+            SwapLevel::Quiet => None,
+        };
+        match colour {
+            None => write!(f, "{:>w$}", used),
+            Some(c) => write!(f, "{}{:>w$}\x1B[0m", c, used),
+        }
+    }
+}
 
 struct Memory {
+    /// Anonymous memory, only used in compact mode
+    anon: Bytes,
     active: Bytes,
     inactive: Bytes,
     cached: Bytes,
     free: Bytes,
+    /// MemAvailable from /proc/meminfo: the kernel's estimate of what can be allocated without
+    /// swapping, None on kernels older than 3.14
+    available: Option<Bytes>,
     dirty: Threshold<Bytes>,
     writeback: Threshold<Bytes>,
-    swap: Bytes,
+    swap: Swap,
+    /// Pages swapped out since boot, and when it was read
+    pswpout: Option<(u64, Instant)>,
     zram: Bytes,
+    /// Kernel samepage merging counters, if it is running
+    ksm: Option<Ksm>,
+    /// The first full_scans read since KSM runs, and when
+    ksm_first_scans: Option<(u64, Instant)>,
+    /// Full scans per minute since then
+    ksm_scans: f32,
+}
+
+/// Kernel samepage merging counters, in pages. See https://docs.kernel.org/admin-guide/mm/ksm.html
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+struct Ksm {
+    shared: u64,
+    sharing: u64,
+    unshared: u64,
+    volatile: u64,
+    full_scans: u64,
+}
+
+impl Ksm {
+    /// pages_shared pages are deduplicated into pages_sharing, so the saving is the difference
+    fn saved(&self, pagesize: u64) -> Bytes {
+        Bytes(self.sharing.saturating_sub(self.shared) * pagesize)
+    }
+}
+
+/// Read a sysfs file containing a single number
+fn read_number(root: &ProcRoot, path: &str, buf: &mut String) -> Option<u64> {
+    unsafe { root.read_to_string_unchecked(path, buf) }.ok()?;
+    buf.trim_end().parse::<u64>().ok()
+}
+
+/// Read the KSM counters of a directory like /sys/kernel/mm/ksm, None if KSM isn't running
+fn read_ksm(root: &ProcRoot, dir: &str, path: &mut String, buf: &mut String) -> Option<Ksm> {
+    let mut read = |name| {
+        path.clear();
+        write!(path, "{}/{}", dir, name).unwrap();
+        read_number(root, path, buf)
+    };
+    if read("run")? != 1 {
+        return None;
+    }
+    Some(Ksm {
+        shared: read("pages_shared")?,
+        sharing: read("pages_sharing")?,
+        unshared: read("pages_unshared")?,
+        volatile: read("pages_volatile")?,
+        full_scans: read("full_scans")?,
+    })
+}
+
+/// How many times a counter increased per minute
+fn per_minute(first: u64, cur: u64, elapsed: Duration) -> f32 {
+    match elapsed.as_secs_f32() {
+        s if s > 0.0 => cur.saturating_sub(first) as f32 * 60.0 / s,
+        _ => 0.0,
+    }
 }
 
 pub struct MemoryStats<'a> {
@@ -32,6 +164,56 @@ pub struct MemoryStats<'a> {
     pagesize: u64,
     state: Memory,
     buf: String,
+    buf2: String,
+}
+
+impl<'a> MemoryStats<'a> {
+    /// MemAvailable, or free memory plus caches (a bit optimistic) on old kernels
+    pub fn available(&self) -> Bytes {
+        self.state
+            .available
+            .unwrap_or(Bytes(self.state.free.0 + self.state.cached.0))
+    }
+
+    /// Are there enough dirty pages for background writeback to kick in?
+    pub fn dirty_above_background(&self) -> bool {
+        self.state.dirty.val >= self.state.dirty.high
+    }
+
+    /// Only show KSM when it runs, and when there is room for a 9th column
+    fn show_ksm(&self) -> bool {
+        let w = self.settings.colwidth.get();
+        !self.settings.compact
+            && self.state.ksm.is_some()
+            && self.settings.maxcols.get() >= 9 * w + 8
+    }
+
+    /// With --mem-detail, two more rows with the KSM breakdown
+    fn show_ksm_detail(&self) -> bool {
+        self.settings.mem_detail && !self.settings.compact && self.state.ksm.is_some()
+    }
+
+    fn update_ksm(&mut self, now: Instant) {
+        let s = &mut self.state;
+        s.ksm = read_ksm(
+            &self.settings.proc_root,
+            KSM_DIR,
+            &mut self.buf2,
+            &mut self.buf,
+        );
+
+        /* Scans of big hosts take minutes, average since the first one seen rather than over the
+         * last refresh. Start over if KSM was stopped, or the counter went backwards. */
+        s.ksm_first_scans = match (s.ksm, s.ksm_first_scans) {
+            (None, _) => None,
+            (Some(k), Some((first, t))) if k.full_scans >= first => Some((first, t)),
+            (Some(k), _) => Some((k.full_scans, now)),
+        };
+        s.ksm_scans = match (s.ksm, s.ksm_first_scans) {
+            (Some(k), Some((first, t))) => per_minute(first, k.full_scans, now - t),
+            _ => 0.0,
+        };
+    }
 }
 
 impl<'a> StatBlock<'a> for MemoryStats<'a> {
@@ -44,57 +226,54 @@ impl<'a> StatBlock<'a> for MemoryStats<'a> {
         };
         MemoryStats {
             settings: s,
-            pagesize: unsafe { libc::sysconf(libc::_SC_PAGE_SIZE) } as u64,
+            pagesize: s.proc_root.machine().pagesize,
             state: Memory {
+                anon: Bytes(0),
                 active: Bytes(0),
                 inactive: Bytes(0),
                 cached: Bytes(0),
                 free: Bytes(0),
+                available: None,
                 dirty: z,
                 writeback: z,
-                swap: Bytes(0),
+                swap: Swap {
+                    used: Bytes(0),
+                    size: Bytes(0),
+                    out_rate: 0.0,
+                },
+                pswpout: None,
                 zram: Bytes(0),
+                ksm: None,
+                ksm_first_scans: None,
+                ksm_scans: 0.0,
             },
             buf: String::new(),
+            buf2: String::new(),
         }
     }
 
-    fn update(&mut self) {
+    fn update(&mut self, clock: &FrameClock) {
+        let now = clock.instant();
+        self.update_ksm(now);
+
+        let root = &self.settings.proc_root;
         let s = &mut self.state;
-        s.swap.0 = 0;
+        s.swap.used.0 = 0;
+        s.swap.size.0 = 0;
         s.zram.0 = 0;
 
-        /* /proc/swaps doesn't contain arbitrary user data */
-        if unsafe { read_to_string_unchecked("/proc/swaps", &mut self.buf) }.is_ok() {
-            for line in self.buf.lines().skip(1) {
-                s.swap.0 += line
-                    .split_ascii_whitespace()
-                    .nth(3)
-                    .unwrap()
-                    .parse::<u64>()
-                    .unwrap()
-                    * 1024;
-            }
+        if root.read_to_string("/proc/swaps", &mut self.buf).is_ok() {
+            (s.swap.used.0, s.swap.size.0) = parse_swaps(&self.buf);
         }
 
-        for bdev in std::fs::read_dir("/sys/block").unwrap() {
-            let bdev = match bdev {
-                Ok(s) => s,
-                _ => continue,
-            };
-
-            if bdev
-                .file_name()
-                .to_str()
-                .map_or(true, |s| !s.starts_with("zram"))
-            {
+        for bdev in root.read_dir("/sys/block").unwrap_or_default() {
+            if !bdev.starts_with("zram") {
                 continue;
             }
 
-            let mut mm = bdev.path();
-            mm.push("mm_stat");
+            let mm = format!("/sys/block/{}/mm_stat", bdev);
             /* /sys/block/zramN/mm_stat only contains space separated numeric fields */
-            if unsafe { read_to_string_unchecked(mm, &mut self.buf) }.is_ok() {
+            if unsafe { root.read_to_string_unchecked(mm, &mut self.buf) }.is_ok() {
                 /* https://docs.kernel.org/admin-guide/blockdev/zram.html */
                 s.zram.0 += self
                     .buf
@@ -106,12 +285,20 @@ impl<'a> StatBlock<'a> for MemoryStats<'a> {
             }
         }
 
+        /* No arbitrary strings in /proc/meminfo either */
+        s.available = match unsafe { root.read_to_string_unchecked("/proc/meminfo", &mut self.buf) }
+        {
+            Ok(_) => parse_mem_available(&self.buf).map(Bytes),
+            Err(_) => None,
+        };
+
         /* No arbitrary strings in /proc/vmstat */
-        match unsafe { read_to_string_unchecked("/proc/vmstat", &mut self.buf) } {
+        match unsafe { root.read_to_string_unchecked("/proc/vmstat", &mut self.buf) } {
             Ok(_) => (),
             _ => return,
         };
 
+        s.anon.0 = 0;
         s.active.0 = 0;
         s.inactive.0 = 0;
         s.cached.0 = 0;
@@ -121,13 +308,21 @@ impl<'a> StatBlock<'a> for MemoryStats<'a> {
             let k = iter.next().unwrap();
             let mut val = || iter.next().unwrap().parse::<u64>().unwrap() * self.pagesize;
             match k {
-                "nr_active_anon" => s.active.0 += val(),
+                "nr_active_anon" => {
+                    let v = val();
+                    s.active.0 += v;
+                    s.anon.0 += v
+                }
                 "nr_active_file" => {
                     let v = val();
                     s.active.0 += v;
                     s.cached.0 += v
                 }
-                "nr_inactive_anon" => s.inactive.0 += val(),
+                "nr_inactive_anon" => {
+                    let v = val();
+                    s.inactive.0 += v;
+                    s.anon.0 += v
+                }
                 "nr_inactive_file" => {
                     let v = val();
                     s.inactive.0 += v;
@@ -140,7 +335,7 @@ impl<'a> StatBlock<'a> for MemoryStats<'a> {
                     let v = val();
                     s.cached.0 += v;
                     /* Swap is already filled, should be ok to substract without wrapping around */
-                    s.swap.0 -= v;
+                    s.swap.used.0 -= v;
                 }
                 "nr_free_pages" => s.free.0 = val(),
                 "nr_dirty" => s.dirty.val.0 = val(),
@@ -151,17 +346,32 @@ impl<'a> StatBlock<'a> for MemoryStats<'a> {
                     s.dirty.high.0 = v
                 }
                 "nr_writeback" => s.writeback.val.0 = val(),
+                "pswpout" => {
+                    let v = (val(), now);
+                    if let Some((prev, t)) = s.pswpout.replace(v) {
+                        let Rate(Bytes(r)) = Rate::new(prev, v.0, v.1 - t).unwrap_or_default();
+                        s.swap.swapped_out(r);
+                    }
+                }
                 _ => continue,
             };
         }
     }
 
     fn columns(&self) -> u16 {
-        8 * self.settings.colwidth.get() + 7
+        match self.settings.compact {
+            /* MEM, then 3 times " {value} {label:5}" */
+            true => 4 * self.settings.colwidth.get() + 3 * 7,
+            false if self.show_ksm() => 9 * self.settings.colwidth.get() + 8,
+            false => 8 * self.settings.colwidth.get() + 7,
+        }
     }
 
     fn rows(&self) -> u16 {
-        3
+        match self.settings.compact {
+            true => 2,
+            false => 3 + 2 * self.show_ksm_detail() as u16,
+        }
     }
 }
 
@@ -171,17 +381,40 @@ impl<'a> fmt::Display for MemoryStats<'a> {
         let s = &self.state;
         let se = &self.settings;
         let newline = MaybeSmart(Newline(), se);
+
+        if se.compact {
+            return write!(
+                f,
+                "{} {:>w$} used  {:>w$} avail {:>w$} swap {}{}",
+                MaybeSmart(Heading::new("MEM"), se),
+                s.anon,
+                self.available(),
+                MaybeSmart(s.swap, se),
+                newline,
+                newline
+            );
+        }
+
+        let (ksm_heading, ksm) = match (self.show_ksm(), s.ksm) {
+            (true, Some(k)) => (
+                format!(" {}", MaybeSmart(Heading::new("KSM"), se)),
+                format!(" {:>w$}", k.saved(self.pagesize)),
+            ),
+            _ => (String::new(), String::new()),
+        };
+
         write!(
             f,
-            "{} {} {} {} {} {} {} {}{}{:>w$} {:>w$} {:>w$} {:>w$} {:>w$} {:>w$} {:>w$} {:>w$}{}{}",
-            MaybeSmart(Heading("ACTIVE"), se),
-            MaybeSmart(Heading("INACTIVE"), se),
-            MaybeSmart(Heading("CACHED"), se),
-            MaybeSmart(Heading("FREE"), se),
-            MaybeSmart(Heading("DIRTY"), se),
-            MaybeSmart(Heading("W_BACK"), se),
-            MaybeSmart(Heading("SWAP"), se),
-            MaybeSmart(Heading("ZRAM"), se),
+            "{} {} {} {} {} {} {} {}{}{}{:>w$} {:>w$} {:>w$} {:>w$} {:>w$} {:>w$} {:>w$} {:>w$}{}{}",
+            MaybeSmart(Heading::new("ACTIVE"), se),
+            MaybeSmart(Heading::with_short("INACTIVE", "INACT"), se),
+            MaybeSmart(Heading::new("CACHED"), se),
+            MaybeSmart(Heading::new("FREE"), se),
+            MaybeSmart(Heading::new("DIRTY"), se),
+            MaybeSmart(Heading::new("W_BACK"), se),
+            MaybeSmart(Heading::new("SWAP"), se),
+            MaybeSmart(Heading::new("ZRAM"), se),
+            ksm_heading,
             newline,
             s.active,
             s.inactive,
@@ -189,10 +422,299 @@ impl<'a> fmt::Display for MemoryStats<'a> {
             s.free,
             MaybeSmart(s.dirty, self.settings),
             MaybeSmart(s.writeback, self.settings),
-            s.swap,
+            MaybeSmart(s.swap, self.settings),
             s.zram,
-            newline,
+            ksm,
             newline
-        )
+        )?;
+
+        if let Some(k) = s.ksm.filter(|_| self.show_ksm_detail()) {
+            let ps = self.pagesize;
+            let pad = self.columns() as usize - (6 * w + 5);
+            write!(
+                f,
+                "{} {} {} {} {} {}{:pad$}{}",
+                MaybeSmart(Heading::with_short("KSM_SAVED", "SAVED"), se),
+                MaybeSmart(Heading::with_short("SHARED", "SHRD"), se),
+                MaybeSmart(Heading::with_short("SHARING", "SHRNG"), se),
+                MaybeSmart(Heading::with_short("UNSHARED", "UNSHR"), se),
+                MaybeSmart(Heading::with_short("VOLATILE", "VOLAT"), se),
+                MaybeSmart(Heading::with_short("SCANS/M", "SCN/M"), se),
+                "",
+                newline
+            )?;
+            write!(
+                f,
+                "{:>w$} {:>w$} {:>w$} {:>w$} {:>w$} {:>w$.1}{:pad$}{}",
+                k.saved(ps),
+                Bytes(k.shared * ps),
+                Bytes(k.sharing * ps),
+                Bytes(k.unshared * ps),
+                Bytes(k.volatile * ps),
+                s.ksm_scans,
+                "",
+                newline
+            )?;
+        }
+
+        write!(f, "{}", newline)
+    }
+}
+
+/// MemAvailable from /proc/meminfo, in bytes
+fn parse_mem_available(meminfo: &str) -> Option<u64> {
+    /* "MemAvailable:   12345678 kB" */
+    let kb = meminfo
+        .lines()
+        .find_map(|l| l.strip_prefix("MemAvailable:"))?
+        .trim()
+        .strip_suffix(" kB")?;
+    kb.trim().parse::<u64>().ok().map(|kb| kb * 1024)
+}
+
+/// Sum the (used, size) of all swaps in /proc/swaps, in bytes
+fn parse_swaps(swaps: &str) -> (u64, u64) {
+    /* The filename in the first column is user data, and may contain (escaped) spaces; only
+     * trust the fixed columns on the right: Filename Type Size Used Priority */
+    swaps.lines().skip(1).fold((0, 0), |(used, size), line| {
+        let mut cols = line.split_ascii_whitespace().rev().skip(1);
+        let mut kib = || cols.next().unwrap().parse::<u64>().unwrap() * 1024;
+        (used + kib(), size + kib())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1024 * 1024;
+
+    /// A fake /sys/kernel/mm/ksm, with the given files
+    fn ksm_dir(name: &str, files: &[(&str, &str)]) -> String {
+        let dir = std::env::temp_dir().join(format!("hitome-ksm-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (f, v) in files {
+            std::fs::write(dir.join(f), v).unwrap();
+        }
+        dir.into_os_string().into_string().unwrap()
+    }
+
+    const KSM_FILES: [(&str, &str); 6] = [
+        ("run", "1\n"),
+        ("pages_shared", "1000\n"),
+        ("pages_sharing", "25000\n"),
+        ("pages_unshared", "300\n"),
+        ("pages_volatile", "42\n"),
+        ("full_scans", "17\n"),
+    ];
+
+    #[test]
+    fn swaps_with_spaces() {
+        let swaps = "\
+Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority
+/dev/zram0                              partition\t8388604\t\t1024\t\t100
+/swap\\040file                           file\t\t2097148\t\t0\t\t-2
+/mnt/My\\040Disk/a\\011b\\134c\\040file\\0401   file\t\t1048576\t\t512\t\t-3
+";
+        assert_eq!(
+            parse_swaps(swaps),
+            ((1024 + 512) * 1024, (8388604 + 2097148 + 1048576) * 1024)
+        );
+        assert_eq!(parse_swaps("Filename Type Size Used Priority\n"), (0, 0));
+    }
+
+    #[test]
+    fn mem_available() {
+        let meminfo = "\
+MemTotal:       32596244 kB
+MemFree:         1802924 kB
+MemAvailable:   20417388 kB
+Buffers:          712476 kB
+";
+        assert_eq!(parse_mem_available(meminfo), Some(20417388 * 1024));
+        /* Kernels older than 3.14 */
+        assert_eq!(
+            parse_mem_available("MemTotal: 1024 kB\nMemFree: 512 kB\n"),
+            None
+        );
+        assert_eq!(parse_mem_available("MemAvailable: x kB\n"), None);
+    }
+
+    /// An 80x24 terminal: 9 wide columns
+    fn small_terminal(compact: bool) -> Settings {
+        let s = Settings {
+            compact,
+            ..Default::default()
+        };
+        s.maxcols.set(80);
+        s.maxrows.set(24);
+        s.colwidth.set(9);
+        s
+    }
+
+    #[test]
+    fn compact_layout() {
+        let mut buf = String::new();
+        let (s, full) = (small_terminal(true), small_terminal(false));
+        s.proc_root.inject_fixture(0);
+        full.proc_root.inject_fixture(0);
+        let (mut m, mut f) = (MemoryStats::new(&s), MemoryStats::new(&full));
+        for step in 1..3 {
+            s.proc_root.inject_fixture(step);
+            full.proc_root.inject_fixture(step);
+            m.update(&FrameClock::now());
+            f.update(&FrameClock::now());
+        }
+        check_layout(&m, &mut buf).unwrap();
+        /* Anonymous memory, MemAvailable, and swap used but not cached */
+        assert_eq!(
+            buf,
+            "      MEM  7031.25M used     19.53G avail  1020.09M swap \n\n"
+        );
+        check_layout(&f, &mut buf).unwrap();
+        /* KSM only fits from 89 columns */
+        assert_eq!((m.rows(), f.rows()), (2, 3));
+        assert!(m.columns() <= 80 && f.columns() <= 80);
+    }
+
+    /// The colour of a swap, after being fed a swap out rate at each refresh
+    fn level(used: u64, size: u64, rates: &[u64]) -> SwapLevel {
+        let mut s = Swap {
+            used: Bytes(used),
+            size: Bytes(size),
+            out_rate: 0.0,
+        };
+        for r in rates {
+            s.swapped_out(*r);
+        }
+        s.level()
+    }
+
+    #[test]
+    fn swap_quiet() {
+        assert_eq!(level(0, 0, &[]), SwapLevel::Quiet);
+        assert_eq!(level(1024 * MB, 8192 * MB, &[0, 0, 0]), SwapLevel::Quiet);
+        /* Swapping in doesn't count */
+        assert_eq!(level(1024 * MB, 8192 * MB, &[MB / 2; 10]), SwapLevel::Quiet);
+    }
+
+    #[test]
+    fn swap_occupancy_never_high() {
+        assert_eq!(level(6000 * MB, 8192 * MB, &[]), SwapLevel::Med);
+        assert_eq!(level(8192 * MB, 8192 * MB, &[0; 10]), SwapLevel::Med);
+        assert_eq!(level(8192 * MB, 8192 * MB, &[2 * MB; 10]), SwapLevel::Med);
+    }
+
+    #[test]
+    fn swap_sustained_out() {
+        assert_eq!(level(0, 8192 * MB, &[2 * MB; 5]), SwapLevel::Med);
+        assert_eq!(level(0, 8192 * MB, &[50 * MB; 5]), SwapLevel::High);
+        assert_eq!(level(8192 * MB, 8192 * MB, &[50 * MB; 5]), SwapLevel::High);
+    }
+
+    #[test]
+    fn swap_burst_smoothed() {
+        /* One refresh at 20M/s averages to less than 10M/s */
+        assert_eq!(level(0, 8192 * MB, &[0, 0, 20 * MB]), SwapLevel::Med);
+        assert_eq!(level(0, 8192 * MB, &[0, 0, 2 * MB]), SwapLevel::Quiet);
+        /* And fades away once swapping stops */
+        let mut rates = vec![50 * MB, 50 * MB, 0, 0, 0];
+        assert_eq!(level(0, 8192 * MB, &rates), SwapLevel::Med);
+        rates.extend([0; 7]);
+        assert_eq!(level(0, 8192 * MB, &rates), SwapLevel::Quiet);
+    }
+
+    #[test]
+    fn ksm_saved() {
+        let k = Ksm {
+            shared: 1000,
+            sharing: 25000,
+            ..Default::default()
+        };
+        assert_eq!(k.saved(4096).0, 24000 * 4096);
+        assert_eq!(Ksm::default().saved(4096).0, 0);
+        /* Never seen, but don't wrap around */
+        let k = Ksm {
+            shared: 10,
+            sharing: 5,
+            ..Default::default()
+        };
+        assert_eq!(k.saved(4096).0, 0);
+    }
+
+    #[test]
+    fn ksm_scans_per_minute() {
+        assert_eq!(per_minute(17, 17, Duration::from_secs(30)), 0.0);
+        assert_eq!(per_minute(17, 20, Duration::from_secs(90)), 2.0);
+        assert_eq!(per_minute(17, 20, Duration::ZERO), 0.0);
+        assert_eq!(per_minute(20, 17, Duration::from_secs(60)), 0.0);
+    }
+
+    #[test]
+    fn ksm_missing() {
+        let (mut p, mut b) = (String::new(), String::new());
+        assert_eq!(
+            read_ksm(&ProcRoot::default(), "/nonexistent/ksm", &mut p, &mut b),
+            None
+        );
+        /* Older kernels without some of the files */
+        let dir = ksm_dir("partial", &KSM_FILES[..3]);
+        assert_eq!(read_ksm(&ProcRoot::default(), &dir, &mut p, &mut b), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ksm_stopped() {
+        let (mut p, mut b) = (String::new(), String::new());
+        let dir = ksm_dir("stopped", &KSM_FILES);
+        std::fs::write(format!("{}/run", dir), "0\n").unwrap();
+        assert_eq!(read_ksm(&ProcRoot::default(), &dir, &mut p, &mut b), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ksm_running() {
+        let (mut p, mut b) = (String::new(), String::new());
+        let dir = ksm_dir("running", &KSM_FILES);
+        assert_eq!(
+            read_ksm(&ProcRoot::default(), &dir, &mut p, &mut b),
+            Some(Ksm {
+                shared: 1000,
+                sharing: 25000,
+                unshared: 300,
+                volatile: 42,
+                full_scans: 17,
+            })
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ksm_detail_layout() {
+        let s = Settings {
+            mem_detail: true,
+            ..Default::default()
+        };
+        s.smart.set(false);
+        s.maxcols.set(80);
+        s.colwidth.set(8);
+        let mut m = MemoryStats::new(&s);
+        m.state.ksm = Some(Ksm {
+            shared: 1000,
+            sharing: 25000,
+            ..Default::default()
+        });
+        assert_eq!(m.rows(), 5);
+        let mut buf = String::new();
+        check_layout(&m, &mut buf).unwrap();
+        assert!(buf
+            .lines()
+            .nth(2)
+            .unwrap()
+            .starts_with("   SAVED   SHARED  SHARING"));
+
+        m.state.ksm = None;
+        assert_eq!(m.rows(), 3);
+        check_layout(&m, &mut buf).unwrap();
     }
 }
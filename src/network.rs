@@ -16,6 +16,7 @@
 use crate::common::*;
 use std::collections::BTreeMap;
 use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::time::Instant;
 
 #[derive(Clone, Copy)]
@@ -25,39 +26,193 @@ struct IfaceStats {
     tx: Bytes,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum NetField {
+    Rx,
+    Tx,
+}
+
+impl Field for NetField {
+    const ALL: &'static [(&'static str, Self)] = &[("rx", NetField::Rx), ("tx", NetField::Tx)];
+
+    fn heading(&self) -> Heading<'static> {
+        match self {
+            NetField::Rx => Heading::new("RX/s"),
+            NetField::Tx => Heading::new("TX/s"),
+        }
+    }
+}
+
+/// TCP_ESTABLISHED, see include/net/tcp_states.h
+const TCP_ESTABLISHED: &str = "01";
+
+/// Parse an address from /proc/net/tcp or /proc/net/tcp6: the in-memory representation of the
+/// address, printed as hex u32s in host byte order, eg. "0100007F" for 127.0.0.1 on little endian
+pub fn parse_tcp_address(hex: &str) -> Option<IpAddr> {
+    /* from_str_radix() would take a sign */
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let word = |i: usize| {
+        let w = hex.get(8 * i..8 * (i + 1))?;
+        u32::from_str_radix(w, 16).ok().map(u32::to_ne_bytes)
+    };
+    match hex.len() {
+        8 => Some(IpAddr::V4(Ipv4Addr::from(word(0)?))),
+        32 => {
+            let mut b = [0u8; 16];
+            for i in 0..4 {
+                b[4 * i..4 * (i + 1)].copy_from_slice(&word(i)?);
+            }
+            let a = Ipv6Addr::from(b);
+            Some(match a.to_ipv4_mapped() {
+                Some(v4) => IpAddr::V4(v4),
+                None => IpAddr::V6(a),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Don't colour rates on interfaces that never saw more than this many bytes per second
+const MIN_RATE_CEILING: u64 = 64 * 1024;
+
 pub struct NetworkStats<'a> {
     settings: &'a Settings,
-    /// kname (eg. enp6s0) -> ...; use a BTreeMap for deterministic in-order traversal
-    ifaces: BTreeMap<String, (IfaceStats, IfaceStats, Stale)>,
+    /// kname (eg. enp6s0) -> (_, _, _, [max rx/s, max tx/s]); use a BTreeMap for deterministic
+    /// in-order traversal
+    ifaces: BTreeMap<String, (IfaceStats, IfaceStats, Stale, [DecayingMax; 2])>,
+    /// With --net-talkers, remote address -> number of established TCP connections
+    talkers: fnv::FnvHashMap<IpAddr, u16>,
+    /// The busiest talkers, in order
+    top_talkers: Vec<(IpAddr, u16)>,
+    /// Length of the longest address in top_talkers
+    talkers_width: u16,
     buf: String,
 }
 
+impl<'a> NetworkStats<'a> {
+    /// (interface, received, transmitted) since boot
+    pub fn counters(&self) -> impl Iterator<Item = (&str, Bytes, Bytes)> {
+        self.ifaces
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.1.rx, v.1.tx))
+    }
+
+    /// Bytes received and transmitted per second by all interfaces but loopback
+    pub fn rates(&self) -> (Bytes, Bytes) {
+        self.ifaces.iter().filter(|(k, _)| k.as_str() != "lo").fold(
+            (Bytes(0), Bytes(0)),
+            |(rx, tx), (_, s)| {
+                let elapsed = s.1.t - s.0.t;
+                let Rate(Bytes(r)) = Rate::new(s.0.rx.0, s.1.rx.0, elapsed).unwrap_or_default();
+                let Rate(Bytes(t)) = Rate::new(s.0.tx.0, s.1.tx.0, elapsed).unwrap_or_default();
+                (Bytes(rx.0 + r), Bytes(tx.0 + t))
+            },
+        )
+    }
+
+    /// Count established TCP connections by remote address, ignoring loopback. No reverse DNS,
+    /// it would be slow and leak what is being looked at.
+    fn update_talkers(&mut self) {
+        self.talkers.clear();
+        let root = &self.settings.proc_root;
+        for path in ["/proc/net/tcp", "/proc/net/tcp6"] {
+            /* Only numeric fields, no need to check for UTF-8 */
+            if unsafe { root.read_to_string_unchecked(path, &mut self.buf) }.is_err() {
+                continue;
+            }
+            /* sl local_address rem_address st ... */
+            for line in self.buf.lines().skip(1) {
+                let mut fields = line.split_ascii_whitespace().skip(2);
+                let (remote, state) = match (fields.next(), fields.next()) {
+                    (Some(r), Some(s)) => (r, s),
+                    _ => continue,
+                };
+                if state != TCP_ESTABLISHED {
+                    continue;
+                }
+                let addr = match remote
+                    .split_once(':')
+                    .and_then(|(a, _)| parse_tcp_address(a))
+                {
+                    Some(a) if !a.is_loopback() => a,
+                    _ => continue,
+                };
+                *self.talkers.entry(addr).or_default() += 1;
+            }
+        }
+        self.rank_talkers();
+    }
+
+    /// Keep the addresses with the most connections, in order
+    fn rank_talkers(&mut self) {
+        self.top_talkers.clear();
+        self.top_talkers
+            .extend(self.talkers.iter().map(|(a, n)| (*a, *n)));
+        self.top_talkers
+            .sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        self.top_talkers.truncate(self.settings.net_talkers.into());
+        self.talkers_width = self
+            .top_talkers
+            .iter()
+            .map(|(a, _)| a.to_string().len() as u16)
+            .max()
+            .unwrap_or(0);
+    }
+
+    /// Width of the interface table
+    fn ifaces_columns(&self) -> u16 {
+        let n = self.settings.net_fields.len() as u16;
+        (n + 1) * self.settings.colwidth.get() + n
+    }
+}
+
 impl<'a> StatBlock<'a> for NetworkStats<'a> {
     fn new(s: &'a Settings) -> NetworkStats {
         let mut ns = NetworkStats {
             settings: s,
             ifaces: Default::default(),
+            talkers: Default::default(),
+            top_talkers: Vec::new(),
+            talkers_width: 0,
             buf: String::new(),
         };
-        ns.update();
+        ns.update(&FrameClock::now());
         ns
     }
 
-    fn update(&mut self) {
-        match read_to_string("/proc/net/dev", &mut self.buf) {
+    fn update(&mut self, clock: &FrameClock) {
+        match self
+            .settings
+            .proc_root
+            .read_to_string("/proc/net/dev", &mut self.buf)
+        {
             Ok(_) => (),
             _ => return,
         }
 
-        let t = Instant::now();
+        let t = clock.instant();
 
         for iface in self.ifaces.values_mut() {
             iface.2 = Stale(true);
         }
 
         for dev in self.buf.lines().skip(2) {
+            /* Split on the colon first: older kernels put no space between it and the first
+             * counter when it is long */
+            let (kname, dev) = match dev.split_once(':') {
+                Some((k, d)) => (k.trim(), d),
+                None => continue,
+            };
             let mut dev = dev.split_ascii_whitespace();
-            let kname = dev.next().unwrap().strip_suffix(':').unwrap();
+            let (rx, tx) = match (dev.next(), dev.nth(7)) {
+                (Some(r), Some(t)) => match (r.parse(), t.parse()) {
+                    (Ok(r), Ok(t)) => (Bytes(r), Bytes(t)),
+                    _ => continue,
+                },
+                _ => continue,
+            };
 
             /* XXX: make this user-configurable */
             if kname.starts_with("br") {
@@ -72,29 +227,43 @@ impl<'a> StatBlock<'a> for NetworkStats<'a> {
                         rx: Bytes(0),
                         tx: Bytes(0),
                     };
-                    self.ifaces
-                        .insert(String::from(kname), (z, z, Stale(false)));
+                    self.ifaces.insert(
+                        String::from(kname),
+                        (z, z, Stale(false), Default::default()),
+                    );
                     self.ifaces.get_mut(kname).unwrap()
                 }
             };
 
             ent.0 = ent.1;
-            ent.1 = IfaceStats {
-                t,
-                rx: Bytes(dev.next().unwrap().parse().unwrap()),
-                tx: Bytes(dev.nth(7).unwrap().parse().unwrap()),
-            };
+            ent.1 = IfaceStats { t, rx, tx };
             ent.2 = Stale(false);
+
+            let elapsed = ent.1.t - ent.0.t;
+            if let (Some(Rate(Bytes(rx))), Some(Rate(Bytes(tx)))) = (
+                Rate::new(ent.0.rx.0, ent.1.rx.0, elapsed),
+                Rate::new(ent.0.tx.0, ent.1.tx.0, elapsed),
+            ) {
+                ent.3[0].update(rx);
+                ent.3[1].update(tx);
+            }
         }
 
         self.ifaces.retain(|_, v| v.2 == Stale(false));
+
+        if self.settings.net_talkers > 0 {
+            self.update_talkers();
+        }
     }
 
     fn columns(&self) -> u16 {
-        if self.ifaces.is_empty() {
-            0
-        } else {
-            3 * self.settings.colwidth.get() + 2
+        match (self.ifaces.is_empty(), self.top_talkers.is_empty()) {
+            (true, _) => 0,
+            (false, true) => self.ifaces_columns(),
+            /* Never cut addresses short, a truncated one looks like another valid one */
+            (false, false) => self
+                .ifaces_columns()
+                .max(self.talkers_width + 1 + self.settings.colwidth.get()),
         }
     }
 
@@ -102,7 +271,11 @@ impl<'a> StatBlock<'a> for NetworkStats<'a> {
         if self.ifaces.is_empty() {
             0
         } else {
-            2 + self.ifaces.len() as u16
+            let talkers = match self.top_talkers.len() {
+                0 => 0,
+                n => 1 + n as u16,
+            };
+            2 + self.ifaces.len() as u16 + talkers
         }
     }
 }
@@ -115,24 +288,187 @@ impl<'a> fmt::Display for NetworkStats<'a> {
 
         let newline = MaybeSmart(Newline(), self.settings);
         let w = self.settings.colwidth.get().into();
-        write!(
-            f,
-            "{} {} {}{}",
-            MaybeSmart(Heading("IFACE"), self.settings),
-            MaybeSmart(Heading("RX/s"), self.settings),
-            MaybeSmart(Heading("TX/s"), self.settings),
-            newline
-        )?;
+        /* Wide addresses below make the block wider than the interface table */
+        let pad = (self.columns() - self.ifaces_columns()) as usize;
+        write!(f, "{}", MaybeSmart(Heading::new("IFACE"), self.settings))?;
+        for field in self.settings.net_fields.iter() {
+            write!(f, " {}", MaybeSmart(field.heading(), self.settings))?;
+        }
+        write!(f, "{:pad$}{}", "", newline)?;
 
         for (kname, s) in self.ifaces.iter() {
             /* From https://github.com/torvalds/linux/blob/master/include/uapi/linux/if_link.h, the
-             * stats reported will wrap at either u32::MAX or (more likely) u64::MAX. */
-            let t = (s.1.t - s.0.t).as_millis() as u64;
-            let rx = Bytes(1000 * (s.1.rx.0.wrapping_sub(s.0.rx.0)) / t);
-            let tx = Bytes(1000 * (s.1.tx.0.wrapping_sub(s.0.tx.0)) / t);
-            write!(f, "{:>w$.w$} {:>w$} {:>w$}{}", kname, rx, tx, newline)?
+             * stats reported will wrap at either u32::MAX or (more likely) u64::MAX. Either way,
+             * show nothing for one refresh, like for just added interfaces. */
+            let elapsed = s.1.t - s.0.t;
+            let Rate(rx) = Rate::new(s.0.rx.0, s.1.rx.0, elapsed).unwrap_or_default();
+            let Rate(tx) = Rate::new(s.0.tx.0, s.1.tx.0, elapsed).unwrap_or_default();
+            write!(f, "{:>w$.w$}", kname)?;
+            for field in self.settings.net_fields.iter() {
+                let (rate, max) = match field {
+                    NetField::Rx => (rx, &s.3[0]),
+                    NetField::Tx => (tx, &s.3[1]),
+                };
+                write!(
+                    f,
+                    " {:>w$}",
+                    MaybeSmart(max.threshold(rate, MIN_RATE_CEILING), self.settings)
+                )?;
+            }
+            write!(f, "{:pad$}{}", "", newline)?
+        }
+
+        if !self.top_talkers.is_empty() {
+            /* Remote addresses take all the columns but the last one */
+            let aw = self.columns() as usize - w - 1;
+            write!(
+                f,
+                "{:>aw$} {:>w$}{}",
+                MaybeSmart(Heading::new("TCP_REMOTE"), self.settings),
+                MaybeSmart(Heading::new("CONNS"), self.settings),
+                newline
+            )?;
+            for (addr, n) in self.top_talkers.iter() {
+                write!(f, "{:>aw$} {:>w$}{}", addr.to_string(), n, newline)?;
+            }
         }
 
         write!(f, "{}", newline)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Print an address like the kernel does: each 4 bytes as a u32 in host byte order
+    fn kernel_hex(addr: &[u8]) -> String {
+        addr.chunks(4)
+            .map(|w| format!("{:08X}", u32::from_ne_bytes(w.try_into().unwrap())))
+            .collect()
+    }
+
+    #[test]
+    fn tcp_ipv4() {
+        for a in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.0.254",
+            "0.0.0.0",
+            "255.255.255.255",
+        ] {
+            let a: Ipv4Addr = a.parse().unwrap();
+            let hex = kernel_hex(&a.octets());
+            assert_eq!(parse_tcp_address(&hex), Some(IpAddr::V4(a)));
+            assert_eq!(parse_tcp_address(&hex.to_lowercase()), Some(IpAddr::V4(a)));
+        }
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn tcp_little_endian() {
+        assert_eq!(
+            parse_tcp_address("0100007F"),
+            Some(IpAddr::V4(Ipv4Addr::LOCALHOST))
+        );
+        assert_eq!(
+            parse_tcp_address("0302010A"),
+            Some(IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3)))
+        );
+        assert_eq!(
+            parse_tcp_address("B80D0120000000000000000001000000"),
+            Some(IpAddr::V6("2001:db8::1".parse().unwrap()))
+        );
+        assert_eq!(
+            parse_tcp_address("0000000000000000FFFF00000100007F"),
+            Some(IpAddr::V4(Ipv4Addr::LOCALHOST))
+        );
+    }
+
+    #[test]
+    fn tcp_ipv6() {
+        for a in ["::1", "2001:db8::1", "fe80::1234:5678:9abc:def0", "::"] {
+            let a: Ipv6Addr = a.parse().unwrap();
+            let hex = kernel_hex(&a.octets());
+            assert_eq!(hex.len(), 32);
+            assert_eq!(parse_tcp_address(&hex), Some(IpAddr::V6(a)));
+        }
+    }
+
+    #[test]
+    fn tcp_ipv4_mapped() {
+        let a: Ipv6Addr = "::ffff:192.0.2.7".parse().unwrap();
+        assert_eq!(
+            parse_tcp_address(&kernel_hex(&a.octets())),
+            Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7)))
+        );
+        /* Only ::ffff:0:0/96 is mapped, the deprecated ::a.b.c.d isn't */
+        let a: Ipv6Addr = "::192.0.2.7".parse().unwrap();
+        assert_eq!(
+            parse_tcp_address(&kernel_hex(&a.octets())),
+            Some(IpAddr::V6(a))
+        );
+    }
+
+    #[test]
+    fn talker_ipv6() {
+        let s = Settings {
+            net_talkers: 2,
+            ..Default::default()
+        };
+        let mut ns = NetworkStats::new(&s);
+        let t = Instant::now();
+        let z = IfaceStats {
+            t,
+            rx: Bytes(0),
+            tx: Bytes(0),
+        };
+        ns.ifaces.clear();
+        ns.ifaces.insert(
+            String::from("eth0"),
+            (z, z, Stale(false), Default::default()),
+        );
+        ns.talkers.clear();
+        ns.talkers
+            .insert("2001:db8:85a3::8a2e:370:7334".parse().unwrap(), 12);
+        ns.talkers.insert("192.0.2.7".parse().unwrap(), 3);
+        ns.rank_talkers();
+
+        let mut buf = String::new();
+        check_layout(&ns, &mut buf).unwrap();
+        let out = ns.to_string();
+        let rows: Vec<&str> = out.lines().collect();
+        assert_eq!(rows[3], "2001:db8:85a3::8a2e:370:7334         12");
+        assert_eq!(rows[4], "                   192.0.2.7          3");
+
+        /* With a single column, still wide enough */
+        let s = Settings {
+            net_talkers: 2,
+            net_fields: vec![NetField::Rx],
+            ..Default::default()
+        };
+        ns.settings = &s;
+        check_layout(&ns, &mut buf).unwrap();
+        assert!(ns
+            .to_string()
+            .contains("2001:db8:85a3::8a2e:370:7334         12"));
+    }
+
+    #[test]
+    fn tcp_bad_lengths() {
+        for hex in [
+            "",
+            "0100007",
+            "0100007F0",
+            "0100007F0100007F",
+            "0000000000000000FFFF00000100007",
+            "0000000000000000FFFF00000100007F0",
+            "0100007G",
+            "+100007F",
+            "0000000000000000FFFF0000-100007F",
+            "é100007",
+        ] {
+            assert_eq!(parse_tcp_address(hex), None, "{:?}", hex);
+        }
+    }
+}
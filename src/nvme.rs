@@ -0,0 +1,183 @@
+/* Copyright 2022 Romain "Artefact2" Dal Maso <romain.dalmaso@artefact2.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *	   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/* Read the SMART / Health Information log page of NVMe drives. See the NVMe base specification,
+ * section "Get Log Page", and include/uapi/linux/nvme_ioctl.h */
+
+use crate::common::FileDescriptor;
+use std::ffi::CString;
+
+/// struct nvme_admin_cmd (alias of struct nvme_passthru_cmd)
+#[repr(C)]
+#[derive(Default)]
+struct AdminCmd {
+    opcode: u8,
+    flags: u8,
+    rsvd1: u16,
+    nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    metadata: u64,
+    addr: u64,
+    metadata_len: u32,
+    data_len: u32,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+    timeout_ms: u32,
+    result: u32,
+}
+
+impl AdminCmd {
+    /// A Get Log Page command for the whole of a log, of all namespaces, read into log
+    fn get_log_page(lid: u32, log: &mut [u8]) -> AdminCmd {
+        /* Number of dwords minus one, split in two halves */
+        let numd = (log.len() / 4 - 1) as u32;
+        AdminCmd {
+            opcode: OPCODE_GET_LOG_PAGE,
+            nsid: NSID_ALL,
+            addr: log.as_mut_ptr() as u64,
+            data_len: log.len() as u32,
+            cdw10: lid | ((numd & 0xFFFF) << 16),
+            cdw11: numd >> 16,
+            ..Default::default()
+        }
+    }
+}
+
+/// _IOWR('N', 0x41, struct nvme_admin_cmd)
+const NVME_IOCTL_ADMIN_CMD: libc::c_ulong = (3 << 30)
+    | ((std::mem::size_of::<AdminCmd>() as libc::c_ulong) << 16)
+    | ((b'N' as libc::c_ulong) << 8)
+    | 0x41;
+
+const OPCODE_GET_LOG_PAGE: u8 = 0x02;
+const LOG_SMART: u32 = 0x02;
+const LOG_SMART_LEN: usize = 512;
+/// The SMART log is controller-wide
+const NSID_ALL: u32 = 0xFFFF_FFFF;
+
+#[derive(Clone, Copy)]
+pub struct NvmeHealth {
+    /// Estimate of the drive life used, can exceed 100
+    pub percentage_used: u8,
+    /// Remaining spare capacity, in percent
+    pub available_spare: u8,
+    /// When available_spare falls below this, the drive raises a critical warning
+    pub spare_threshold: u8,
+}
+
+/// Parse the relevant fields of a SMART / Health Information log page
+pub fn parse_smart_log(log: &[u8; LOG_SMART_LEN]) -> NvmeHealth {
+    /* Byte 0 is critical warning, 1-2 composite temperature */
+    NvmeHealth {
+        available_spare: log[3],
+        spare_threshold: log[4],
+        percentage_used: log[5],
+    }
+}
+
+/// Issue a Get Log Page admin command to a controller, eg. "nvme0". Needs CAP_SYS_ADMIN. Returns
+/// errno on failure.
+pub fn read_health(ctrl: &str) -> Result<NvmeHealth, libc::c_int> {
+    let path = CString::new(format!("/dev/{}", ctrl)).unwrap();
+    let mut log = [0u8; LOG_SMART_LEN];
+    let mut cmd = AdminCmd::get_log_page(LOG_SMART, &mut log);
+
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_RDONLY);
+        if fd == -1 {
+            return Err(*libc::__errno_location());
+        }
+        let fd = FileDescriptor(fd);
+        let ret = libc::ioctl(fd.0, NVME_IOCTL_ADMIN_CMD, &mut cmd as *mut AdminCmd);
+        match ret {
+            0 => Ok(parse_smart_log(&log)),
+            /* Positive values are NVMe status codes */
+            -1 => Err(*libc::__errno_location()),
+            _ => Err(libc::EIO),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::{offset_of, size_of};
+
+    #[test]
+    fn admin_cmd_layout() {
+        /* As laid out by the kernel's struct nvme_passthru_cmd */
+        assert_eq!(size_of::<AdminCmd>(), 72);
+        assert_eq!(offset_of!(AdminCmd, nsid), 4);
+        assert_eq!(offset_of!(AdminCmd, metadata), 16);
+        assert_eq!(offset_of!(AdminCmd, addr), 24);
+        assert_eq!(offset_of!(AdminCmd, metadata_len), 32);
+        assert_eq!(offset_of!(AdminCmd, data_len), 36);
+        assert_eq!(offset_of!(AdminCmd, cdw10), 40);
+        assert_eq!(offset_of!(AdminCmd, timeout_ms), 64);
+        assert_eq!(offset_of!(AdminCmd, result), 68);
+        assert_eq!(NVME_IOCTL_ADMIN_CMD, 0xC048_4E41);
+    }
+
+    #[test]
+    fn get_log_page_dwords() {
+        /* 512 bytes is 128 dwords, sent as 127 in the upper half of cdw10 */
+        let mut log = [0u8; LOG_SMART_LEN];
+        let cmd = AdminCmd::get_log_page(LOG_SMART, &mut log);
+        assert_eq!(
+            (cmd.opcode, cmd.nsid, cmd.data_len),
+            (0x02, 0xFFFF_FFFF, 512)
+        );
+        assert_eq!((cmd.cdw10, cmd.cdw11), (0x007F_0002, 0));
+
+        /* 1M is 0x40000 dwords: 0x3FFFF, the upper half goes in cdw11 */
+        let mut log = vec![0u8; 1 << 20];
+        let cmd = AdminCmd::get_log_page(0x0D, &mut log);
+        assert_eq!((cmd.cdw10, cmd.cdw11), (0xFFFF_000D, 0x0000_0003));
+    }
+
+    #[test]
+    fn smart_log() {
+        let mut log = [0u8; LOG_SMART_LEN];
+        /* Critical warning: spare below threshold; 310 K */
+        log[0] = 0x01;
+        log[1..3].copy_from_slice(&310u16.to_le_bytes());
+        log[3] = 4;
+        log[4] = 10;
+        log[5] = 103;
+        /* Data units read, and everything after: must not be picked up */
+        log[6..].fill(0xFF);
+        let h = parse_smart_log(&log);
+        assert_eq!(
+            (h.available_spare, h.spare_threshold, h.percentage_used),
+            (4, 10, 103)
+        );
+
+        let h = parse_smart_log(&[0; LOG_SMART_LEN]);
+        assert_eq!(
+            (h.available_spare, h.spare_threshold, h.percentage_used),
+            (0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn no_such_controller() {
+        assert_eq!(read_health("nvme-hitome-test").err(), Some(libc::ENOENT));
+    }
+}
@@ -14,6 +14,7 @@
  */
 
 use crate::common::*;
+use crate::procroot::ProcRoot;
 use std::fmt;
 
 /// 10s, 60s, 300s
@@ -32,9 +33,35 @@ pub struct PressureStats<'a> {
 }
 
 impl<'a> PressureStats<'a> {
-    fn update_cat(pa: &str, buf: &mut String, pr: &mut Pressure) {
+    /// The avg10 values of some/full cpu, memory and io, in percent; None if PSI is unavailable
+    pub fn avg10(&self) -> Option<[f32; 6]> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        Some([
+            self.cpu.some[0].val.0,
+            self.cpu.full[0].val.0,
+            self.memory.some[0].val.0,
+            self.memory.full[0].val.0,
+            self.io.some[0].val.0,
+            self.io.full[0].val.0,
+        ])
+    }
+
+    /// The highest avg10 value, with its thresholds; None if PSI is unavailable
+    pub fn worst(&self) -> Option<Threshold<Percentage>> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        [self.cpu, self.memory, self.io]
+            .into_iter()
+            .flat_map(|p| [p.some[0], p.full[0]])
+            .reduce(|a, b| if b.val.0 > a.val.0 { b } else { a })
+    }
+
+    fn update_cat(root: &ProcRoot, pa: &str, buf: &mut String, pr: &mut Pressure) {
         /* /proc/pressure/(*) files can never contain invalid UTF-8 */
-        match unsafe { read_to_string_unchecked(pa, buf) } {
+        match unsafe { root.read_to_string_unchecked(pa, buf) } {
             Ok(_) => (),
             _ => return,
         }
@@ -54,7 +81,7 @@ impl<'a> PressureStats<'a> {
                     Some(("avg300", p)) => (2, p),
                     _ => continue,
                 };
-                pr[idx].val.0 = p.parse::<f32>().unwrap();
+                pr[idx].val.0 = parse_hundredths(p).unwrap() as f32 / 100.0;
             }
         }
     }
@@ -81,10 +108,16 @@ impl<'a> StatBlock<'a> for PressureStats<'a> {
         }
     }
 
-    fn update(&mut self) {
-        PressureStats::update_cat("/proc/pressure/cpu", &mut self.buf, &mut self.cpu);
-        PressureStats::update_cat("/proc/pressure/memory", &mut self.buf, &mut self.memory);
-        PressureStats::update_cat("/proc/pressure/io", &mut self.buf, &mut self.io);
+    fn update(&mut self, _: &FrameClock) {
+        let root = &self.settings.proc_root;
+        PressureStats::update_cat(root, "/proc/pressure/cpu", &mut self.buf, &mut self.cpu);
+        PressureStats::update_cat(
+            root,
+            "/proc/pressure/memory",
+            &mut self.buf,
+            &mut self.memory,
+        );
+        PressureStats::update_cat(root, "/proc/pressure/io", &mut self.buf, &mut self.io);
     }
 
     fn columns(&self) -> u16 {
@@ -98,6 +131,8 @@ impl<'a> StatBlock<'a> for PressureStats<'a> {
     fn rows(&self) -> u16 {
         if self.buf.is_empty() {
             0
+        } else if self.settings.compact {
+            3
         } else {
             5
         }
@@ -117,17 +152,22 @@ impl<'a> fmt::Display for PressureStats<'a> {
         write!(
             f,
             "{} {} {} {} {} {} {}{}",
-            MaybeSmart(Heading("PSI"), s),
-            MaybeSmart(Heading("SOME_CPU"), s),
-            MaybeSmart(Heading("FULL_CPU"), s),
-            MaybeSmart(Heading("SOME_MEM"), s),
-            MaybeSmart(Heading("FULL_MEM"), s),
-            MaybeSmart(Heading("SOME_IO"), s),
-            MaybeSmart(Heading("FULL_IO"), s),
+            MaybeSmart(Heading::new("PSI"), s),
+            MaybeSmart(Heading::with_short("SOME_CPU", "S_CPU"), s),
+            MaybeSmart(Heading::with_short("FULL_CPU", "F_CPU"), s),
+            MaybeSmart(Heading::with_short("SOME_MEM", "S_MEM"), s),
+            MaybeSmart(Heading::with_short("FULL_MEM", "F_MEM"), s),
+            MaybeSmart(Heading::new("SOME_IO"), s),
+            MaybeSmart(Heading::new("FULL_IO"), s),
             newline
         )?;
 
-        for (label, i) in [("avg10", 0), ("avg60", 1), ("avg300", 2)] {
+        let avgs: &[(&str, usize)] = match self.settings.compact {
+            true => &[("avg10", 0)],
+            false => &[("avg10", 0), ("avg60", 1), ("avg300", 2)],
+        };
+
+        for &(label, i) in avgs {
             write!(
                 f,
                 "{:>w$} {:>w$} {:>w$} {:>w$} {:>w$} {:>w$} {:>w$}{}",
@@ -145,3 +185,39 @@ impl<'a> fmt::Display for PressureStats<'a> {
         write!(f, "{}", newline)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_layout() {
+        let mut buf = String::new();
+        let settings = |compact| {
+            let s = Settings {
+                compact,
+                ..Default::default()
+            };
+            s.maxcols.set(80);
+            s.maxrows.set(24);
+            s.colwidth.set(9);
+            s
+        };
+        let (s, full) = (settings(true), settings(false));
+        s.proc_root.inject_fixture(0);
+        full.proc_root.inject_fixture(0);
+        let (mut p, mut f) = (PressureStats::new(&s), PressureStats::new(&full));
+        p.update(&FrameClock::now());
+        f.update(&FrameClock::now());
+        check_layout(&p, &mut buf).unwrap();
+        assert!(buf
+            .lines()
+            .nth(1)
+            .unwrap()
+            .trim_start()
+            .starts_with("avg10"));
+        check_layout(&f, &mut buf).unwrap();
+        assert_eq!((p.rows(), f.rows()), (3, 5));
+        assert!(p.columns() <= 80);
+    }
+}
@@ -0,0 +1,740 @@
+/* Copyright 2022 Romain "Artefact2" Dal Maso <romain.dalmaso@artefact2.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *	   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/* Where blocks read /proc and /sys from: this machine, or contents injected at each refresh, eg.
+ * read on another machine by --remote, or fixtures in tests. Blocks keep using the usual paths
+ * either way. With injected contents, what blocks asked for is noted, so it can be injected next
+ * time. */
+
+use crate::common::{mangle_utf8, online_cpus, read_to_string, read_to_string_unchecked};
+use fnv::{FnvHashMap, FnvHashSet};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::ffi::CStr;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+/// Usage of a filesystem, from statvfs(), in bytes
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct FsUsage {
+    pub size: u64,
+    /// Including the blocks reserved for root
+    pub free: u64,
+    /// Free for unprivileged users
+    pub avail: u64,
+}
+
+/// What blocks need to know about the machine the files come from
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Machine {
+    pub pagesize: u64,
+    /// How many jiffies in a second, as exposed to userspace
+    pub user_hz: u16,
+    /// Online CPUs
+    pub cpus: u16,
+    /// Whose tasks are "yours"
+    pub uid: u32,
+}
+
+impl Machine {
+    pub fn local() -> Machine {
+        unsafe {
+            Machine {
+                pagesize: libc::sysconf(libc::_SC_PAGE_SIZE) as u64,
+                user_hz: libc::sysconf(libc::_SC_CLK_TCK) as u16,
+                cpus: online_cpus(),
+                uid: libc::getuid(),
+            }
+        }
+    }
+}
+
+/// Files of a machine, all read at about the same time, answering requests (see
+/// ProcRoot::requests())
+pub struct Snapshot {
+    machine: Machine,
+    /// The requests answered, anything else can't be told missing yet
+    requests: FnvHashSet<String>,
+    /// Path -> contents, and whether it was asked for by its own path rather than a pattern
+    files: FnvHashMap<String, (String, bool)>,
+    /// Pattern or directory request -> the paths it matched
+    matched: FnvHashMap<String, Vec<String>>,
+    /// Mountpoint -> usage
+    usage: FnvHashMap<String, FsUsage>,
+}
+
+impl Snapshot {
+    pub fn new<'r>(machine: Machine, requests: impl Iterator<Item = &'r str>) -> Snapshot {
+        Snapshot {
+            machine,
+            requests: requests.map(String::from).collect(),
+            files: Default::default(),
+            matched: Default::default(),
+            usage: Default::default(),
+        }
+    }
+
+    /// Add a file read for an "f:" or "l:" request; invalid UTF-8 is mangled, like
+    /// read_to_string() does
+    pub fn add_file(&mut self, request: &str, path: &str, mut contents: Vec<u8>) {
+        mangle_utf8(&mut contents);
+        let contents = String::from_utf8(contents).unwrap();
+        let own = request.get(2..) == Some(path);
+        if !own {
+            self.add_entry(request, path);
+        }
+        self.files.insert(String::from(path), (contents, own));
+    }
+
+    /// Add a path matched by an "l:" or "d:" request
+    pub fn add_entry(&mut self, request: &str, path: &str) {
+        match self.matched.get_mut(request) {
+            Some(m) => m.push(String::from(path)),
+            None => {
+                self.matched
+                    .insert(String::from(request), vec![String::from(path)]);
+            }
+        }
+    }
+
+    /// Add the usage of a filesystem, for an "s:" request
+    pub fn add_usage(&mut self, mountpoint: &str, usage: FsUsage) {
+        self.usage.insert(String::from(mountpoint), usage);
+    }
+}
+
+/// The injected contents, and what was asked for since the last requests()
+struct Injected {
+    snapshot: Snapshot,
+    wanted: BTreeSet<String>,
+}
+
+#[derive(Default)]
+pub struct ProcRoot {
+    /// None while reading the files of this machine
+    injected: RefCell<Option<Injected>>,
+}
+
+impl ProcRoot {
+    /// Read from snapshot instead of this machine, until the next injection
+    pub fn inject(&self, snapshot: Snapshot) {
+        let mut injected = self.injected.borrow_mut();
+        let wanted = injected.take().map(|i| i.wanted).unwrap_or_default();
+        *injected = Some(Injected { snapshot, wanted });
+    }
+
+    /// Are files read from this machine?
+    pub fn is_local(&self) -> bool {
+        self.injected.borrow().is_none()
+    }
+
+    pub fn machine(&self) -> Machine {
+        match &*self.injected.borrow() {
+            Some(i) => i.snapshot.machine,
+            None => Machine::local(),
+        }
+    }
+
+    /// Same as machine().uid, without counting CPUs
+    pub fn uid(&self) -> u32 {
+        match &*self.injected.borrow() {
+            Some(i) => i.snapshot.machine.uid,
+            None => unsafe { libc::getuid() },
+        }
+    }
+
+    /// What blocks asked for since the last call, to be answered by the next snapshot: "f:path"
+    /// for a whole file, "l:pattern" for the first line of each file matching a shell pattern,
+    /// "d:dir" for the entries of a directory, and "s:mountpoint" for the usage of a filesystem.
+    /// Never contains whitespace.
+    pub fn requests(&self) -> Vec<String> {
+        match &mut *self.injected.borrow_mut() {
+            Some(i) => std::mem::take(&mut i.wanted).into_iter().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Note a request, if contents are injected. XXX: paths with whitespace can't be asked for.
+    fn want(injected: &mut Injected, kind: &str, path: &str) {
+        if !path.contains(char::is_whitespace) {
+            injected.wanted.insert(format!("{}:{}", kind, path));
+        }
+    }
+
+    /// The error for something that wasn't injected: NotFound if it was asked for, WouldBlock if
+    /// it might be there next time
+    fn missing(injected: &Injected, kind: &str, path: &str) -> Error {
+        let request = format!("{}:{}", kind, path);
+        match injected.snapshot.requests.contains(&request) {
+            true => Error::from(ErrorKind::NotFound),
+            false => Error::from(ErrorKind::WouldBlock),
+        }
+    }
+
+    /// Same as common::read_to_string(). With injected contents, a file that wasn't asked for yet
+    /// is a WouldBlock error.
+    pub fn read_to_string<P: AsRef<Path>>(&self, p: P, s: &mut String) -> Result<usize> {
+        match &mut *self.injected.borrow_mut() {
+            None => read_to_string(p, s),
+            Some(i) => Self::read_injected(i, p.as_ref(), s),
+        }
+    }
+
+    /// Same as common::read_to_string_unchecked()
+    /// # Safety
+    /// Make sure the file you are reading can never contain bad UTF-8
+    pub unsafe fn read_to_string_unchecked<P: AsRef<Path>>(
+        &self,
+        p: P,
+        s: &mut String,
+    ) -> Result<usize> {
+        match &mut *self.injected.borrow_mut() {
+            None => read_to_string_unchecked(p, s),
+            Some(i) => Self::read_injected(i, p.as_ref(), s),
+        }
+    }
+
+    fn read_injected(injected: &mut Injected, p: &Path, s: &mut String) -> Result<usize> {
+        s.clear();
+        let path = p.to_str().ok_or(ErrorKind::NotFound)?;
+        match injected.snapshot.files.get(path) {
+            Some((contents, own)) => {
+                s.push_str(contents);
+                /* Files matched by a pattern are sent anyway */
+                if *own {
+                    Self::want(injected, "f", path);
+                }
+                Ok(s.len())
+            }
+            None => {
+                Self::want(injected, "f", path);
+                Err(Self::missing(injected, "f", path))
+            }
+        }
+    }
+
+    /// The names in a directory, in no particular order
+    pub fn read_dir<P: AsRef<Path>>(&self, p: P) -> Result<Vec<String>> {
+        let mut injected = self.injected.borrow_mut();
+        let injected = match &mut *injected {
+            None => {
+                return Ok(std::fs::read_dir(p)?
+                    .filter_map(|e| e.ok()?.file_name().into_string().ok())
+                    .collect())
+            }
+            Some(i) => i,
+        };
+
+        let dir = p.as_ref().to_str().ok_or(ErrorKind::NotFound)?;
+        Self::want(injected, "d", dir);
+        /* What was listed, and what is known to be in there */
+        let s = &injected.snapshot;
+        let listed = s.matched.get(&format!("d:{}", dir)).into_iter().flatten();
+        let names: BTreeSet<&str> = listed
+            .chain(s.files.keys())
+            .filter_map(|f| f.strip_prefix(dir)?.strip_prefix('/')?.split('/').next())
+            .filter(|n| !n.is_empty())
+            .collect();
+        match names.is_empty() {
+            true => Err(Self::missing(injected, "d", dir)),
+            false => Ok(names.into_iter().map(String::from).collect()),
+        }
+    }
+
+    /// With injected contents, ask for the first line of all the files matching a shell pattern
+    /// at once, eg. the stat files of all tasks, rather than one by one as they are found. Files
+    /// read this way aren't asked for again by their own path.
+    pub fn prefetch(&self, pattern: &str) {
+        if let Some(i) = &mut *self.injected.borrow_mut() {
+            Self::want(i, "l", pattern);
+        }
+    }
+
+    /// With injected contents, the paths that matched a pattern given to prefetch(), which is
+    /// called too. Always empty for the files of this machine: walk them instead.
+    pub fn matches(&self, pattern: &str) -> Vec<String> {
+        self.prefetch(pattern);
+        match &*self.injected.borrow() {
+            Some(i) => i
+                .snapshot
+                .matched
+                .get(&format!("l:{}", pattern))
+                .cloned()
+                .unwrap_or_default(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Same as std::fs::canonicalize(). XXX: symbolic links are not followed in injected contents
+    pub fn canonicalize<P: AsRef<Path>>(&self, p: P) -> Result<PathBuf> {
+        match self.is_local() {
+            true => std::fs::canonicalize(p),
+            false => Err(Error::from(ErrorKind::Unsupported)),
+        }
+    }
+
+    /// Same as std::fs::read_link(), see canonicalize()
+    pub fn read_link<P: AsRef<Path>>(&self, p: P) -> Result<PathBuf> {
+        match self.is_local() {
+            true => std::fs::read_link(p),
+            false => Err(Error::from(ErrorKind::Unsupported)),
+        }
+    }
+
+    /// The usage of the filesystem mounted there, from statvfs()
+    pub fn usage(&self, mountpoint: &CStr) -> Result<FsUsage> {
+        let mut injected = self.injected.borrow_mut();
+        let injected = match &mut *injected {
+            None => unsafe {
+                let mut vfs = std::mem::MaybeUninit::<libc::statvfs64>::uninit();
+                if libc::statvfs64(mountpoint.as_ptr(), vfs.as_mut_ptr()) != 0 {
+                    return Err(Error::last_os_error());
+                }
+                let vfs = vfs.assume_init();
+                return Ok(FsUsage {
+                    size: vfs.f_blocks * vfs.f_frsize,
+                    free: vfs.f_bfree * vfs.f_bsize,
+                    avail: vfs.f_bavail * vfs.f_bsize,
+                });
+            },
+            Some(i) => i,
+        };
+
+        let mountpoint = mountpoint.to_str().map_err(|_| ErrorKind::NotFound)?;
+        Self::want(injected, "s", mountpoint);
+        match injected.snapshot.usage.get(mountpoint) {
+            Some(u) => Ok(*u),
+            None => Err(Self::missing(injected, "s", mountpoint)),
+        }
+    }
+}
+
+/// A line of /proc/[pid]/task/[tid]/stat, shaped like the kernel's: cpu is the one last ran on
+#[cfg(test)]
+fn task_stat(pid: u32, comm: &str, state: char, jiffies: u64, rss: u64, cpu: u16) -> String {
+    format!(
+        "{pid} ({comm}) {state} 1 {pid} {pid} 0 -1 4194560 100 0 0 0 {jiffies} 0 0 0 20 0 1 0 100 \
+         100000000 {rss} 18446744073709551615 1 1 0 0 0 0 0 0 0 0 0 0 17 {cpu} 0 0 0 0 0 0 0 0 0 0 \
+         0 0 0\n",
+    )
+}
+
+/// The files of a made up machine, for tests: 8 CPUs, busy with softirqs, KSM and swap on zram,
+/// 3 network interfaces, a NVMe and a SATA drive, 3 filesystems, 4 sensor chips and a few tasks.
+/// Counters grow with step, like they would from one refresh to the next. Answers requests, what
+/// blocks asked for the step before.
+#[cfg(test)]
+pub fn fixture(step: u64, requests: &[String]) -> Snapshot {
+    let machine = Machine {
+        pagesize: 4096,
+        user_hz: 100,
+        cpus: 8,
+        uid: 1000,
+    };
+    let mut files = Vec::<(String, String)>::new();
+    let mut add = |path: &str, contents: String| files.push((String::from(path), contents));
+
+    /* Each CPU spends 100 jiffies per step: more of them in user the higher its number, and 10
+     * in softirqs */
+    let mut stat = String::from("cpu  8000 0 4000 80000 0 0 800 0 0 0\n");
+    for c in 0..8 {
+        stat.push_str(&format!(
+            "cpu{} {} 0 {} {} 0 0 {} 0 0 0\n",
+            c,
+            1000 + step * (10 + 10 * c),
+            500 + step * 5,
+            10000 + step * (75 - 10 * c),
+            100 + step * 10
+        ));
+    }
+    stat.push_str("intr 123456 0 0\nctxt 654321\nbtime 1700000000\nprocesses 4242\n");
+    add("/proc/stat", stat);
+
+    let mut softirqs = String::from("          ");
+    for c in 0..8 {
+        softirqs.push_str(&format!(" {:>10}", format!("CPU{}", c)));
+    }
+    softirqs.push('\n');
+    for (name, rate) in [
+        ("HI", 0),
+        ("TIMER", 40),
+        ("NET_TX", 1),
+        ("NET_RX", 120),
+        ("BLOCK", 30),
+        ("IRQ_POLL", 0),
+        ("TASKLET", 2),
+        ("SCHED", 50),
+        ("HRTIMER", 0),
+        ("RCU", 20),
+    ] {
+        softirqs.push_str(&format!("{:>9}:", name));
+        for _ in 0..8 {
+            softirqs.push_str(&format!(" {:>10}", 1000 * rate + step * rate));
+        }
+        softirqs.push('\n');
+    }
+    add("/proc/softirqs", softirqs);
+
+    add(
+        "/proc/meminfo",
+        String::from(
+            "MemTotal:       32768000 kB\nMemFree:         2048000 kB\n\
+             MemAvailable:   20480000 kB\n",
+        ),
+    );
+    add(
+        "/proc/vmstat",
+        format!(
+            "nr_free_pages 512000\nnr_inactive_anon 600000\nnr_active_anon 1200000\n\
+             nr_inactive_file 2000000\nnr_active_file 1500000\nnr_slab_reclaimable 200000\n\
+             nr_slab_unreclaimable 80000\nnr_kernel_misc_reclaimable 0\nnr_dirty {}\n\
+             nr_writeback 0\nnr_swapcached 1000\nnr_dirty_threshold 400000\n\
+             nr_dirty_background_threshold 200000\npswpout {}\n",
+            2000 + 100 * step,
+            10000 + 50 * step
+        ),
+    );
+    add(
+        "/proc/swaps",
+        String::from(
+            "Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority\n\
+             /dev/zram0                              partition\t8388604\t\t1048576\t\t100\n",
+        ),
+    );
+    add(
+        "/sys/block/zram0/mm_stat",
+        String::from("1073741824 268435456 285212672 0 285212672 12 0 0 0\n"),
+    );
+    for (name, value) in [
+        ("run", 1),
+        ("pages_shared", 10000),
+        ("pages_sharing", 50000),
+        ("pages_unshared", 20000),
+        ("pages_volatile", 3000),
+        ("full_scans", 40 + step),
+    ] {
+        add(
+            &format!("/sys/kernel/mm/ksm/{}", name),
+            format!("{}\n", value),
+        );
+    }
+
+    add(
+        "/proc/pressure/cpu",
+        String::from(
+            "some avg10=12.50 avg60=8.20 avg300=4.10 total=912345678\n\
+             full avg10=0.00 avg60=0.00 avg300=0.00 total=0\n",
+        ),
+    );
+    add(
+        "/proc/pressure/memory",
+        String::from(
+            "some avg10=0.50 avg60=0.30 avg300=0.10 total=1234567\n\
+             full avg10=0.20 avg60=0.10 avg300=0.00 total=456789\n",
+        ),
+    );
+    add(
+        "/proc/pressure/io",
+        String::from(
+            "some avg10=3.10 avg60=2.40 avg300=1.90 total=87654321\n\
+             full avg10=1.40 avg60=1.10 avg300=0.80 total=45678901\n",
+        ),
+    );
+
+    let mut dev = String::from(
+        "Inter-|   Receive                                                |  Transmit\n \
+         face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs \
+         drop fifo colls carrier compressed\n",
+    );
+    for (name, rx, tx) in [
+        ("lo", 4096, 4096),
+        ("enp5s0", 2_500_000, 180_000),
+        ("wlan0", 0, 0),
+    ] {
+        dev.push_str(&format!(
+            "{:>6}: {} 1000 0 0 0 0 0 0 {} 1000 0 0 0 0 0 0\n",
+            name,
+            100_000_000 + rx * step,
+            50_000_000 + tx * step
+        ));
+    }
+    add("/proc/net/dev", dev);
+
+    /* Partitions, device mapper and loop devices aren't shown */
+    let mut diskstats = String::new();
+    for (major, minor, name, read, written, busy) in [
+        (259, 0, "nvme0n1", 4096, 20480, 150),
+        (259, 1, "nvme0n1p1", 0, 0, 0),
+        (259, 2, "nvme0n1p2", 4096, 20480, 150),
+        (8, 0, "sda", 0, 2048, 900),
+        (8, 1, "sda1", 0, 2048, 900),
+        (252, 0, "zram0", 512, 1024, 0),
+        (253, 0, "dm-0", 4096, 20480, 150),
+        (7, 0, "loop0", 0, 0, 0),
+    ] {
+        /* Sectors so far, done 8 at a time */
+        let (read, written) = (100000 + read * step, 100000 + written * step);
+        diskstats.push_str(&format!(
+            "{:>4} {:>7} {} {} 0 {} 0 {} 0 {} 0 0 {} {} 0 0 0 0\n",
+            major,
+            minor,
+            name,
+            read / 8,
+            read,
+            written / 8,
+            written,
+            busy * step,
+            busy * step
+        ));
+    }
+    add("/proc/diskstats", diskstats);
+
+    add(
+        "/proc/self/mountstats",
+        String::from(
+            "device sysfs mounted on /sys with fstype sysfs\n\
+             device proc mounted on /proc with fstype proc\n\
+             device /dev/nvme0n1p2 mounted on / with fstype ext4\n\
+             device /dev/nvme0n1p2 mounted on /var/lib/docker with fstype ext4\n\
+             device /dev/nvme0n1p3 mounted on /home with fstype ext4\n\
+             device tmpfs mounted on /tmp with fstype tmpfs\n\
+             device /dev/sda1 mounted on /mnt/backup with fstype xfs\n",
+        ),
+    );
+
+    /// (name, [(label, millidegrees, critical millidegrees or 0)])
+    type Chip<'s> = (&'s str, &'s [(&'s str, u32, u32)]);
+    let chips: [Chip; 4] = [
+        ("k10temp", &[("Tctl", 65250, 0), ("Tccd1", 58000, 0)]),
+        (
+            "nvme",
+            &[
+                ("Composite", 44850, 84850),
+                ("Sensor 1", 44850, 0),
+                ("Sensor 2", 51850, 0),
+            ],
+        ),
+        (
+            "amdgpu",
+            &[
+                ("edge", 52000, 100000),
+                ("junction", 61000, 110000),
+                ("mem", 58000, 105000),
+            ],
+        ),
+        ("acpitz", &[("", 27800, 0)]),
+    ];
+    for (x, (name, temps)) in chips.iter().enumerate() {
+        let hwmon = format!("/sys/class/hwmon/hwmon{}", x);
+        add(&format!("{}/name", hwmon), format!("{}\n", name));
+        for (y, (label, temp, crit)) in temps.iter().enumerate() {
+            let y = y + 1;
+            add(&format!("{}/temp{}_input", hwmon, y), format!("{}\n", temp));
+            if !label.is_empty() {
+                add(
+                    &format!("{}/temp{}_label", hwmon, y),
+                    format!("{}\n", label),
+                );
+            }
+            if *crit > 0 {
+                add(&format!("{}/temp{}_crit", hwmon, y), format!("{}\n", crit));
+            }
+        }
+        if *name == "amdgpu" {
+            add(
+                &format!("{}/power1_average", hwmon),
+                String::from("35000000\n"),
+            );
+            add(
+                &format!("{}/power1_cap", hwmon),
+                String::from("186000000\n"),
+            );
+            add(&format!("{}/pwm1", hwmon), String::from("80\n"));
+            add(
+                &format!("{}/device/mem_info_vram_used", hwmon),
+                String::from("1073741824\n"),
+            );
+            add(
+                &format!("{}/device/mem_info_vram_total", hwmon),
+                String::from("8573157376\n"),
+            );
+        }
+    }
+
+    add(
+        "/etc/passwd",
+        String::from(
+            "root:x:0:0:root:/root:/bin/sh\npostgres:x:70:70::/var/lib/postgres:/bin/sh\n\
+             alice:x:1000:1000::/home/alice:/bin/sh\n",
+        ),
+    );
+
+    let mut s = Snapshot::new(machine, requests.iter().map(String::as_str));
+    for (path, contents) in files {
+        s.add_file(&format!("f:{}", path), &path, contents.into_bytes());
+    }
+
+    let postgres = "/usr/bin/postgres\0-D\0/var/lib/postgres/data\0";
+    let cc1 = "/usr/bin/cc1\0-O2\0hitome.c\0";
+    for (pid, tid, uid, comm, cmdline, state, rate, rss, cpu) in [
+        (1, 1, 0, "systemd", "/sbin/init\0splash\0", 'S', 0, 3000, 0),
+        (812, 812, 70, "postgres", postgres, 'S', 2, 40000, 3),
+        (812, 815, 70, "postgres", postgres, 'S', 1, 40000, 5),
+        (1500, 1500, 1000, "cc1", cc1, 'R', 90, 120000, 6),
+    ] {
+        /* Like a remote machine, the stat files of all tasks answer a single pattern */
+        s.add_file(
+            "l:/proc/[0-9]*/task/[0-9]*/stat",
+            &format!("/proc/{}/task/{}/stat", pid, tid),
+            task_stat(tid, comm, state, 100 + rate * step, rss, cpu).into_bytes(),
+        );
+        for (file, contents) in [
+            ("comm", format!("{}\n", comm)),
+            ("cmdline", String::from(cmdline)),
+            (
+                "status",
+                format!("Name:\t{}\nUid:\t{uid}\t{uid}\t{uid}\t{uid}\n", comm),
+            ),
+        ] {
+            /* Threads are also found as /proc/tid, which is where they are read from */
+            let path = format!("/proc/{}/task/{}/{}", tid, tid, file);
+            s.add_file(&format!("f:{}", path), &path, contents.into_bytes());
+        }
+    }
+
+    let gb = 1024 * 1024 * 1024;
+    for (mountpoint, size, free, avail) in [
+        /* ext4 reserves 5% for root */
+        ("/", 250 * gb, 80 * gb, 80 * gb - 12 * gb),
+        ("/home", 1800 * gb, 700 * gb, 700 * gb - 90 * gb),
+        ("/mnt/backup", 4000 * gb, 1200 * gb, 1200 * gb),
+    ] {
+        s.add_usage(mountpoint, FsUsage { size, free, avail });
+    }
+    s
+}
+
+#[cfg(test)]
+impl ProcRoot {
+    /// Read from fixture(step), answering what was asked for since the last injection
+    pub fn inject_fixture(&self, step: u64) {
+        let requests = self.requests();
+        self.inject(fixture(step, &requests));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MACHINE: Machine = Machine {
+        pagesize: 4096,
+        user_hz: 100,
+        cpus: 4,
+        uid: 1000,
+    };
+
+    #[test]
+    fn injected_reads() {
+        let root = ProcRoot::default();
+        assert!(root.is_local());
+        assert!(root.requests().is_empty());
+
+        let mut s = Snapshot::new(MACHINE, ["f:/proc/stat", "f:/proc/gone"].into_iter());
+        s.add_file("f:/proc/stat", "/proc/stat", b"cpu 1 2 3\n".to_vec());
+        s.add_file("l:/proc/*/stat", "/proc/1/stat", b"1 (init) S\n".to_vec());
+        root.inject(s);
+        assert!(!root.is_local());
+        assert_eq!(root.machine(), MACHINE);
+
+        let mut buf = String::new();
+        assert_eq!(root.read_to_string("/proc/stat", &mut buf).unwrap(), 10);
+        assert_eq!(buf, "cpu 1 2 3\n");
+        root.read_to_string("/proc/1/stat", &mut buf).unwrap();
+        assert_eq!(buf, "1 (init) S\n");
+        /* Asked for but not there, or not asked for yet */
+        let kind = |p| {
+            root.read_to_string(p, &mut String::new())
+                .unwrap_err()
+                .kind()
+        };
+        assert_eq!(kind("/proc/gone"), ErrorKind::NotFound);
+        assert_eq!(kind("/proc/meminfo"), ErrorKind::WouldBlock);
+        assert_eq!(kind("/proc/with space"), ErrorKind::WouldBlock);
+
+        /* Files matched by a pattern come with it */
+        assert_eq!(root.matches("/proc/*/stat"), ["/proc/1/stat"]);
+        assert!(root.matches("/proc/*/io").is_empty());
+        assert_eq!(
+            root.requests(),
+            [
+                "f:/proc/gone",
+                "f:/proc/meminfo",
+                "f:/proc/stat",
+                "l:/proc/*/io",
+                "l:/proc/*/stat"
+            ]
+        );
+        assert!(root.requests().is_empty());
+
+        /* Asked for again once injected, even when nothing is read in between */
+        root.read_to_string("/proc/uptime", &mut buf).unwrap_err();
+        root.inject(Snapshot::new(MACHINE, std::iter::empty()));
+        assert_eq!(root.requests(), ["f:/proc/uptime"]);
+    }
+
+    #[test]
+    fn injected_dirs() {
+        let root = ProcRoot::default();
+        let mut s = Snapshot::new(MACHINE, ["d:/sys/block"].into_iter());
+        s.add_entry("d:/sys/block", "/sys/block/sda");
+        s.add_file(
+            "f:/sys/block/zram0/mm_stat",
+            "/sys/block/zram0/mm_stat",
+            b"1 2 3\n".to_vec(),
+        );
+        s.add_usage(
+            "/home",
+            FsUsage {
+                size: 10,
+                free: 5,
+                avail: 4,
+            },
+        );
+        root.inject(s);
+
+        let mut names = root.read_dir("/sys/block").unwrap();
+        names.sort();
+        assert_eq!(names, ["sda", "zram0"]);
+        assert_eq!(root.read_dir("/sys/block/zram0").unwrap(), ["mm_stat"]);
+        assert_eq!(
+            root.read_dir("/sys/class/hwmon").unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+        assert_eq!(root.usage(c"/home").unwrap().avail, 4);
+        assert_eq!(root.usage(c"/").unwrap_err().kind(), ErrorKind::WouldBlock);
+        assert!(root.canonicalize("/dev/sda").is_err());
+        assert_eq!(
+            root.requests(),
+            [
+                "d:/sys/block",
+                "d:/sys/block/zram0",
+                "d:/sys/class/hwmon",
+                "s:/",
+                "s:/home"
+            ]
+        );
+    }
+}
@@ -0,0 +1,112 @@
+/* Copyright 2022 Romain "Artefact2" Dal Maso <romain.dalmaso@artefact2.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *	   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::common::*;
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Clone, Copy)]
+pub enum Phase {
+    Update = 0,
+    Fmt = 1,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Samples {
+    total: Duration,
+    max: Duration,
+    last: Duration,
+    n: u32,
+}
+
+/// How long each block took to update and print, over the whole session
+#[derive(Default)]
+pub struct Profile {
+    /// Block name -> samples for each Phase; there are few blocks, a Vec is good enough
+    blocks: Vec<(&'static str, [Samples; 2])>,
+}
+
+struct Millis(Duration);
+
+impl fmt::Display for Millis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let w = f.width().unwrap_or(8) - 2;
+        write!(f, "{:>w$.2}ms", self.0.as_secs_f64() * 1000.0)
+    }
+}
+
+impl Profile {
+    pub fn record(&mut self, block: &'static str, phase: Phase, d: Duration) {
+        let samples = match self.blocks.iter_mut().find(|b| b.0 == block) {
+            Some(b) => &mut b.1[phase as usize],
+            None => {
+                self.blocks.push((block, Default::default()));
+                &mut self.blocks.last_mut().unwrap().1[phase as usize]
+            }
+        };
+
+        samples.total += d;
+        samples.max = samples.max.max(d);
+        samples.last = d;
+        samples.n += 1;
+    }
+
+    /// The block that took the longest to update and print in the last refresh
+    pub fn slowest(&self) -> Option<(&'static str, Duration)> {
+        self.blocks
+            .iter()
+            .map(|(name, s)| (*name, s[0].last + s[1].last))
+            .max_by_key(|b| b.1)
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>10} {:>10} {:>10} {:>10} {:>10}",
+            "BLOCK", "UPDATE_AVG", "UPDATE_MAX", "FMT_AVG", "FMT_MAX"
+        )?;
+        for (name, s) in self.blocks.iter() {
+            write!(f, "{:>10.10}", name)?;
+            for s in s.iter() {
+                write!(
+                    f,
+                    " {:>10} {:>10}",
+                    Millis(s.total.checked_div(s.n).unwrap_or_default()),
+                    Millis(s.max)
+                )?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// A one line summary of a Profile, for the footer
+pub struct Slowest<'a>(pub &'a Profile);
+
+impl<'a, 'b> fmt::Display for MaybeSmart<'a, Slowest<'b>> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (name, d) = match self.0 .0.slowest() {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        match self.1.smart.get() {
+            false => write!(f, "slowest: {} {:2}", name, Millis(d)),
+            true => write!(f, "\x1B[2mslowest: {} {:2}\x1B[0m", name, Millis(d)),
+        }
+    }
+}
@@ -0,0 +1,401 @@
+/* Copyright 2022 Romain "Artefact2" Dal Maso <romain.dalmaso@artefact2.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *	   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/* --remote: read /proc and /sys of another machine through a single ssh session. A shell loop runs
+ * there, nothing needs to be installed. At each refresh, it is sent the requests of ProcRoot (what
+ * blocks read last time), and answers with the files between marker lines. Markers start with a
+ * random token, so file contents can't pass for them. */
+
+use crate::procroot::{FsUsage, Machine, ProcRoot, Snapshot};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+/// Runs on the remote machine, in sh -c '...' whatever the login shell is: a single line, without
+/// single quotes. It reads the token, then a line of requests (see ProcRoot::requests()) per
+/// refresh, and answers each one with:
+///
+/// TOKEN frame UPTIME
+/// TOKEN f REQUEST PATH, followed by the file, or its first line for l: requests, then a newline
+/// TOKEN fail, right after the above if reading the file failed midway, eg. EIO from sysfs
+/// TOKEN d REQUEST PATH, for each entry of a directory
+/// TOKEN s REQUEST, followed by the fields of stat -f
+/// TOKEN end
+const SCRIPT: &str = concat!(
+    "set -f; read -r t || exit; ",
+    "printf \"%s\\n\" \"$t hello $(getconf PAGESIZE) $(getconf CLK_TCK) ",
+    "$(getconf _NPROCESSORS_ONLN) $(id -u)\"; ",
+    "while read -r ws; do ",
+    "read -r u _ < /proc/uptime; printf \"%s\\n\" \"$t frame $u\"; ",
+    "for w in $ws; do p=${w#??}; case $w in ",
+    "f:*) [ -f \"$p\" ] && [ -r \"$p\" ] && { printf \"%s\\n\" \"$t f $w $p\"; ",
+    "if cat \"$p\"; then echo; else echo; printf \"%s\\n\" \"$t fail\"; fi; };; ",
+    "l:*) set +f; for q in $p; do [ -f \"$q\" ] && [ -r \"$q\" ] && ",
+    "{ l=; IFS= read -r l < \"$q\" || [ -n \"$l\" ]; } && ",
+    "printf \"%s\\n%s\\n\\n\" \"$t f $w $q\" \"$l\"; done; set -f;; ",
+    "d:*) set +f; for q in \"$p\"/*; do [ -e \"$q\" ] && printf \"%s\\n\" \"$t d $w $q\"; done; set -f;; ",
+    "s:*) printf \"%s\\n\" \"$t s $w\"; stat -f -c \"%S %s %b %f %a\" \"$p\"; echo;; ",
+    "esac; done 2>/dev/null; printf \"%s\\n\" \"$t end\"; done"
+);
+
+/// What the remote machine sent for a request
+enum Record {
+    File(String, String, Vec<u8>),
+    Entry(String, String),
+    Usage(String, FsUsage),
+}
+
+/// Everything sent for one line of requests, only passed on once complete
+struct Frame {
+    uptime: Duration,
+    records: Vec<Record>,
+}
+
+/// A refresh worth of files from the remote machine
+pub struct Sample {
+    /// /proc/uptime when the files were read
+    pub uptime: Duration,
+    pub snapshot: Snapshot,
+}
+
+pub struct Remote {
+    ssh: Child,
+    stdin: ChildStdin,
+    frames: Receiver<Frame>,
+    machine: Machine,
+    /// The requests sent, until they are answered
+    pending: Option<Vec<String>>,
+}
+
+/// Parse "12345.67", the first field of /proc/uptime
+fn parse_uptime(u: &str) -> Option<Duration> {
+    let u = u.parse::<f64>().ok()?;
+    Duration::try_from_secs_f64(u).ok()
+}
+
+/// Parse the output of stat -f -c "%S %s %b %f %a"
+fn parse_usage(stat: &[u8]) -> Option<FsUsage> {
+    let stat = std::str::from_utf8(stat).ok()?;
+    let f = stat
+        .split_ascii_whitespace()
+        .map(|f| f.parse::<u64>().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    match f[..] {
+        /* Same as statvfs(): f_frsize, f_bsize, f_blocks, f_bfree, f_bavail */
+        [frsize, bsize, blocks, bfree, bavail] => Some(FsUsage {
+            size: blocks * frsize,
+            free: bfree * bsize,
+            avail: bavail * bsize,
+        }),
+        _ => None,
+    }
+}
+
+/// Split the output of SCRIPT into frames, and pass complete ones on. Returns when ssh exits.
+fn read_frames(
+    token: &str,
+    out: impl BufRead,
+    frames: std::sync::mpsc::Sender<Frame>,
+) -> std::io::Result<()> {
+    let marker = format!("{} ", token);
+    let mut frame: Option<Frame> = None;
+    /* The record being read, and the lines after its marker */
+    let mut record: Option<(String, String, String)> = None;
+    let mut data = Vec::new();
+
+    for line in out.split(b'\n') {
+        let line = line?;
+        let fields = match line.strip_prefix(marker.as_bytes()) {
+            None => {
+                data.extend_from_slice(&line);
+                data.push(b'\n');
+                continue;
+            }
+            Some(m) => String::from_utf8_lossy(m).into_owned(),
+        };
+
+        /* A marker ends the record before it, minus the newline added after it */
+        data.pop();
+        if let (Some((kind, request, path)), Some(f)) = (record.take(), &mut frame) {
+            let data = std::mem::take(&mut data);
+            match kind.as_str() {
+                "f" => f.records.push(Record::File(request, path, data)),
+                _ => {
+                    if let Some(u) = parse_usage(&data) {
+                        let mountpoint = String::from(request.get(2..).unwrap_or_default());
+                        f.records.push(Record::Usage(mountpoint, u));
+                    }
+                }
+            }
+        }
+        data.clear();
+
+        let mut fields = fields.splitn(3, ' ');
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some("frame"), Some(u), None) => {
+                /* Anything unfinished is dropped rather than mixed with this one */
+                frame = parse_uptime(u).map(|uptime| Frame {
+                    uptime,
+                    records: Vec::new(),
+                });
+            }
+            /* The file just sent couldn't be read after all, don't pass it for an empty one */
+            (Some("fail"), None, None) => {
+                if let Some(f) = &mut frame {
+                    f.records.pop();
+                }
+            }
+            (Some("end"), None, None) => {
+                if let Some(f) = frame.take() {
+                    if frames.send(f).is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+            (Some(kind @ ("f" | "s")), Some(request), path) => {
+                let path = String::from(path.unwrap_or_default());
+                record = Some((String::from(kind), String::from(request), path));
+            }
+            (Some("d"), Some(request), Some(path)) => {
+                if let Some(f) = &mut frame {
+                    f.records
+                        .push(Record::Entry(String::from(request), String::from(path)));
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+impl Remote {
+    /// Start ssh to destination, eg. user@host, and wait until the other end is ready. Files are
+    /// read from there by root from now on, starting empty: see receive().
+    pub fn connect(destination: &str, root: &ProcRoot) -> Result<Remote, String> {
+        let mut ssh = Command::new("ssh")
+            .args(["-T", "--", destination, &format!("sh -c '{}'", SCRIPT)])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("can't run ssh: {}", e))?;
+        let mut stdin = ssh.stdin.take().unwrap();
+        let mut stdout = BufReader::new(ssh.stdout.take().unwrap());
+
+        /* Sent rather than given on the command line, where anyone there could see it */
+        let mut token = [0u8; 8];
+        std::fs::File::open("/dev/urandom")
+            .and_then(|mut f| f.read_exact(&mut token))
+            .map_err(|e| format!("/dev/urandom: {}", e))?;
+        let token = token
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        writeln!(stdin, "{}", token).map_err(|_| String::from("ssh exited"))?;
+
+        /* "TOKEN hello PAGESIZE CLK_TCK NPROCESSORS_ONLN UID", guessing from this machine what
+         * getconf didn't say */
+        let mut hello = String::new();
+        stdout
+            .read_line(&mut hello)
+            .map_err(|e| format!("ssh: {}", e))?;
+        let mut fields = hello.split_ascii_whitespace();
+        if fields.next() != Some(&token) || fields.next() != Some("hello") {
+            let _ = ssh.kill();
+            let _ = ssh.wait();
+            return Err(String::from("ssh exited, or the remote shell isn't sh"));
+        }
+        let mut fields = fields.map(|f| f.parse::<u64>().ok());
+        let local = Machine::local();
+        let machine = Machine {
+            pagesize: fields.next().flatten().unwrap_or(local.pagesize),
+            user_hz: fields.next().flatten().map_or(local.user_hz, |h| h as u16),
+            cpus: fields
+                .next()
+                .flatten()
+                .map_or(local.cpus, |c| c.max(1) as u16),
+            uid: fields.next().flatten().map_or(local.uid, |u| u as u32),
+        };
+
+        let (tx, frames) = std::sync::mpsc::channel();
+        std::thread::spawn(move || read_frames(&token, stdout, tx));
+
+        root.inject(Snapshot::new(machine, std::iter::empty()));
+        Ok(Remote {
+            ssh,
+            stdin,
+            frames,
+            machine,
+            pending: None,
+        })
+    }
+
+    /// Ask for what blocks read since the last request, unless the last request is still being
+    /// answered
+    pub fn request(&mut self, root: &ProcRoot) -> Result<(), String> {
+        if self.pending.is_some() {
+            return Ok(());
+        }
+        let requests = root.requests();
+        writeln!(self.stdin, "{}", requests.join(" ")).map_err(|_| String::from("ssh exited"))?;
+        self.pending = Some(requests);
+        Ok(())
+    }
+
+    /// Wait up to timeout for the answer to the last request. None if it is late, it can still
+    /// come with the next call.
+    pub fn receive(&mut self, timeout: Duration) -> Result<Option<Sample>, String> {
+        let frame = match self.frames.recv_timeout(timeout) {
+            Ok(f) => f,
+            Err(RecvTimeoutError::Timeout) => return Ok(None),
+            Err(RecvTimeoutError::Disconnected) => return Err(String::from("ssh exited")),
+        };
+        let requests = self.pending.take().unwrap_or_default();
+        let mut snapshot = Snapshot::new(self.machine, requests.iter().map(String::as_str));
+        for r in frame.records {
+            match r {
+                Record::File(request, path, data) => snapshot.add_file(&request, &path, data),
+                Record::Entry(request, path) => snapshot.add_entry(&request, &path),
+                Record::Usage(mountpoint, usage) => snapshot.add_usage(&mountpoint, usage),
+            }
+        }
+        Ok(Some(Sample {
+            uptime: frame.uptime,
+            snapshot,
+        }))
+    }
+}
+
+impl Drop for Remote {
+    fn drop(&mut self) {
+        let _ = self.ssh.kill();
+        let _ = self.ssh.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run SCRIPT with sh on this machine, as ssh would on the remote one
+    fn run_script(requests: &[&str]) -> Vec<Frame> {
+        let mut sh = Command::new("sh")
+            .args(["-c", SCRIPT])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdin = sh.stdin.take().unwrap();
+        writeln!(stdin, "tok").unwrap();
+        for r in requests {
+            writeln!(stdin, "{}", r).unwrap();
+        }
+        drop(stdin);
+
+        let mut out = BufReader::new(sh.stdout.take().unwrap());
+        let mut hello = String::new();
+        out.read_line(&mut hello).unwrap();
+        assert!(hello.starts_with("tok hello "));
+        let (tx, rx) = std::sync::mpsc::channel();
+        read_frames("tok", out, tx).unwrap();
+        sh.wait().unwrap();
+        rx.into_iter().collect()
+    }
+
+    #[test]
+    fn script() {
+        let dir = std::env::temp_dir().join(format!("hitome-remote-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a"), "one\ntwo\n").unwrap();
+        std::fs::write(dir.join("b"), b"no\0newline").unwrap();
+        std::fs::write(dir.join("with space"), "").unwrap();
+        let d = dir.to_str().unwrap();
+
+        let requests = format!("f:{d}/a f:{d}/b f:{d}/missing l:{d}/[a] d:{d} s:{d}");
+        let frames = run_script(&[&requests, ""]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].uptime > Duration::ZERO);
+        assert!(frames[1].records.is_empty());
+
+        let mut files = Vec::new();
+        let mut entries = Vec::new();
+        let mut usage = None;
+        for r in &frames[0].records {
+            match r {
+                Record::File(request, path, data) => files.push((
+                    request.as_str(),
+                    path.strip_prefix(d).unwrap(),
+                    data.as_slice(),
+                )),
+                Record::Entry(request, path) => {
+                    assert_eq!(request, &format!("d:{}", d));
+                    entries.push(path.strip_prefix(d).unwrap());
+                }
+                Record::Usage(mountpoint, u) => usage = Some((mountpoint.as_str(), *u)),
+            }
+        }
+        let (fa, fb, l) = (format!("f:{d}/a"), format!("f:{d}/b"), format!("l:{d}/[a]"));
+        assert_eq!(
+            files,
+            [
+                (fa.as_str(), "/a", &b"one\ntwo\n"[..]),
+                (fb.as_str(), "/b", &b"no\0newline"[..]),
+                (l.as_str(), "/a", &b"one\n"[..]),
+            ]
+        );
+        entries.sort();
+        assert_eq!(entries, ["/a", "/b", "/sub", "/with space"]);
+        let (mountpoint, u) = usage.unwrap();
+        assert_eq!(mountpoint, d);
+        assert!(u.size > 0 && u.free >= u.avail);
+    }
+
+    #[test]
+    fn unreadable_files() {
+        /* Readable going by its mode, but reading at offset 0 fails with EIO */
+        let frames = run_script(&["f:/proc/self/mem l:/proc/self/me[m] f:/proc/self/stat"]);
+        assert_eq!(frames.len(), 1);
+        match &frames[0].records[..] {
+            [Record::File(request, path, data)] => {
+                assert_eq!(
+                    (request.as_str(), path.as_str()),
+                    ("f:/proc/self/stat", "/proc/self/stat")
+                );
+                assert!(data.ends_with(b"\n"));
+            }
+            _ => panic!("unexpected records"),
+        }
+    }
+
+    #[test]
+    fn incomplete_frames() {
+        let out = "tok frame 12.5\ntok f f:/a /a\nhalf\ntok frame 13.5\ntok f f:/a /a\nfull\n\n\
+                   tok s s:/\n1 2 3 4 5\n\ntok end\ntok frame 14.5\ntok f f:/a /a\nlost\n";
+        let (tx, rx) = std::sync::mpsc::channel();
+        read_frames("tok", out.as_bytes(), tx).unwrap();
+        let frames: Vec<Frame> = rx.into_iter().collect();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].uptime, Duration::from_millis(13500));
+        match &frames[0].records[..] {
+            [Record::File(_, p, data), Record::Usage(m, u)] => {
+                assert_eq!((p.as_str(), data.as_slice()), ("/a", &b"full\n"[..]));
+                assert_eq!(m, "/");
+                assert_eq!((u.size, u.free, u.avail), (3, 8, 10));
+            }
+            _ => panic!("unexpected records"),
+        }
+    }
+}
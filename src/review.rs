@@ -0,0 +1,109 @@
+/* Copyright 2022 Romain "Artefact2" Dal Maso <romain.dalmaso@artefact2.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *	   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/* A tiny pager over the frames printed in dumb mode, see --review */
+
+use crate::input;
+use std::io::{self, Write};
+use std::time::Duration;
+
+/// How many frames PgUp and PgDn skip
+const PAGE: usize = 10;
+
+/// A frame as it was printed, and when
+pub struct Frame {
+    pub timestamp: String,
+    pub text: String,
+}
+
+enum Key {
+    Prev,
+    Next,
+    PageUp,
+    PageDown,
+    Quit,
+    Other,
+}
+
+/// Read a key press, decoding the escape sequences of arrow keys, PgUp and PgDn
+fn read_key() -> Key {
+    let k = match input::read_key(Duration::from_secs(3600)) {
+        Some(k) => k,
+        None => return Key::Other,
+    };
+    if k == b'q' {
+        return Key::Quit;
+    }
+    if k != input::ESCAPE {
+        return Key::Other;
+    }
+
+    /* The rest of the sequence arrives right away; a lone Esc doesn't */
+    let mut seq = [0u8; 3];
+    for c in seq.iter_mut() {
+        match input::read_key(Duration::from_millis(50)) {
+            Some(k) => *c = k,
+            None => break,
+        }
+        if c.is_ascii_alphabetic() || *c == b'~' {
+            break;
+        }
+    }
+    match &seq {
+        [b'[', b'A' | b'D', _] => Key::Prev,
+        [b'[', b'B' | b'C', _] => Key::Next,
+        [b'[', b'5', b'~'] => Key::PageUp,
+        [b'[', b'6', b'~'] => Key::PageDown,
+        _ => Key::Other,
+    }
+}
+
+/// Browse frames until 'q' is pressed, starting from the last one. Does nothing if stdin isn't a
+/// terminal.
+pub fn review(frames: &[Frame], maxrows: u16) {
+    if frames.is_empty() || !input::enable() {
+        return;
+    }
+
+    let mut w = io::stdout().lock();
+    let mut i = frames.len() - 1;
+    loop {
+        let f = &frames[i];
+        write!(
+            w,
+            "\x1B[1;1H\x1B[0J{}\x1B[{};1H\x1B[7mframe {}/{} at {} (arrows, PgUp/PgDn, q to quit)\x1B[0m",
+            f.text,
+            maxrows,
+            i + 1,
+            frames.len(),
+            f.timestamp
+        )
+        .unwrap();
+        w.flush().unwrap();
+
+        i = match read_key() {
+            Key::Prev => i.saturating_sub(1),
+            Key::Next => (i + 1).min(frames.len() - 1),
+            Key::PageUp => i.saturating_sub(PAGE),
+            Key::PageDown => (i + PAGE).min(frames.len() - 1),
+            Key::Quit => break,
+            Key::Other => continue,
+        };
+    }
+
+    write!(w, "\x1B[{};1H\x1B[0J", maxrows).unwrap();
+    w.flush().unwrap();
+    input::restore();
+}
@@ -0,0 +1,178 @@
+/* Copyright 2022 Romain "Artefact2" Dal Maso <romain.dalmaso@artefact2.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *	   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/* Keep hitome out of the way of what it measures: lower its CPU and I/O priorities, or pin it to
+ * some CPUs. See sched(7) and ioprio_set(2). */
+
+use crate::common::Cli;
+use crate::cpu::parse_cpu_list;
+
+/// See include/uapi/linux/ioprio.h
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+/// Turn the return value of a syscall into a message with errno, eg. "--nice: Permission denied"
+fn check(ret: libc::c_long, what: &str) -> Result<(), String> {
+    match ret {
+        -1 => Err(format!("{}: {}", what, std::io::Error::last_os_error())),
+        _ => Ok(()),
+    }
+}
+
+pub fn set_nice(n: i32) -> Result<(), String> {
+    let ret = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, n) };
+    check(ret as libc::c_long, "--nice")
+}
+
+pub fn set_idle_sched() -> Result<(), String> {
+    let param = libc::sched_param { sched_priority: 0 };
+    let ret = unsafe { libc::sched_setscheduler(0, libc::SCHED_IDLE, &param) };
+    check(ret as libc::c_long, "--idle-sched")
+}
+
+/// Only run on the CPUs of a list such as "0-3,8"
+pub fn set_affinity(list: &str) -> Result<(), String> {
+    /* parse_cpu_list() skips what it doesn't understand in kernel files, be stricter with users */
+    let max = libc::CPU_SETSIZE as usize - 1;
+    for range in list.trim().split(',') {
+        let (a, b) = range.split_once('-').unwrap_or((range, range));
+        match (a.parse::<usize>(), b.parse::<usize>()) {
+            (Ok(_), Ok(b)) if b > max => {
+                return Err(format!("--affinity: CPUs above {} are not supported", max));
+            }
+            (Ok(a), Ok(b)) if a <= b => (),
+            _ => return Err(format!("--affinity: invalid CPU list \"{}\"", list)),
+        }
+    }
+    let mut allowed = Vec::new();
+    parse_cpu_list(list, &mut allowed);
+
+    let ret = unsafe {
+        let mut set = std::mem::zeroed::<libc::cpu_set_t>();
+        for (cpu, _) in allowed.iter().enumerate().filter(|(_, a)| **a) {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    check(ret as libc::c_long, "--affinity")
+}
+
+pub fn set_idle_io() -> Result<(), String> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        )
+    };
+    check(ret, "--idle-io")
+}
+
+/// Apply the priority and affinity options, returns all the errors
+pub fn apply(cli: &Cli) -> Result<(), Vec<String>> {
+    let mut errors = Vec::new();
+    let mut try_to = |r: Result<(), String>| {
+        if let Err(e) = r {
+            errors.push(e);
+        }
+    };
+
+    if let Some(n) = cli.nice {
+        try_to(set_nice(n));
+    }
+    if cli.idle_sched {
+        try_to(set_idle_sched());
+    }
+    if let Some(list) = &cli.affinity {
+        try_to(set_affinity(list));
+    }
+    if cli.idle_io {
+        try_to(set_idle_io());
+    }
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The CPUs this thread may run on, as a list
+    fn current_affinity() -> String {
+        let mut cpus = Vec::new();
+        unsafe {
+            let mut set = std::mem::zeroed::<libc::cpu_set_t>();
+            assert_eq!(
+                libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set),
+                0
+            );
+            for cpu in 0..libc::CPU_SETSIZE as usize {
+                if libc::CPU_ISSET(cpu, &set) {
+                    cpus.push(cpu.to_string());
+                }
+            }
+        }
+        cpus.join(",")
+    }
+
+    #[test]
+    fn affinity_invalid() {
+        for list in ["", "foo", "0-3,x", "3-1", "-1", "0,,1", "1-"] {
+            assert_eq!(
+                set_affinity(list),
+                Err(format!("--affinity: invalid CPU list \"{}\"", list))
+            );
+        }
+        assert_eq!(
+            set_affinity("0-99999999999"),
+            Err(String::from(
+                "--affinity: CPUs above 1023 are not supported"
+            ))
+        );
+    }
+
+    #[test]
+    fn affinity_no_such_cpu() {
+        /* Valid, but no such CPU is online: the kernel refuses */
+        assert_eq!(
+            set_affinity("1023"),
+            Err(String::from("--affinity: Invalid argument (os error 22)"))
+        );
+    }
+
+    #[test]
+    fn affinity_unchanged() {
+        /* Only this test's thread is affected */
+        let list = current_affinity();
+        assert_eq!(set_affinity(&list), Ok(()));
+        assert_eq!(current_affinity(), list);
+    }
+
+    #[test]
+    fn check_errno() {
+        assert_eq!(check(0, "--nice"), Ok(()));
+        assert_eq!(check(5, "--nice"), Ok(()));
+        let ret = unsafe { libc::setpriority(libc::PRIO_PROCESS, u32::MAX - 1, 0) };
+        assert_eq!(
+            check(ret as libc::c_long, "--nice"),
+            Err(String::from("--nice: No such process (os error 3)"))
+        );
+    }
+}
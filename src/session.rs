@@ -0,0 +1,492 @@
+/* Copyright 2022 Romain "Artefact2" Dal Maso <romain.dalmaso@artefact2.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *	   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::common::*;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Names of the avg10 pressure values, in the order returned by PressureStats::avg10()
+const PSI_NAMES: [&str; 6] = [
+    "cpu_some",
+    "cpu_full",
+    "memory_some",
+    "memory_full",
+    "io_some",
+    "io_full",
+];
+
+/// Fields of the summary, as (name, type, unit, block). A "*" stands for one field per interface
+/// or device; peak_psi_avg10 has one field per entry of PSI_NAMES.
+/* XXX: there is no per-refresh JSON or Prometheus output yet, so --describe only covers this */
+const FIELDS: [(&str, &str, &str, &str); 9] = [
+    ("frames", "counter", "refreshes", "session"),
+    ("peak_cpu_percent", "gauge", "percent", "cpu"),
+    ("min_mem_available_bytes", "gauge", "bytes", "memory"),
+    ("peak_psi_avg10", "gauge", "percent", "pressure"),
+    ("interfaces.*.rx_bytes", "counter", "bytes", "network"),
+    ("interfaces.*.tx_bytes", "counter", "bytes", "network"),
+    ("devices.*.read_bytes", "counter", "bytes", "blockdev"),
+    ("devices.*.written_bytes", "counter", "bytes", "blockdev"),
+    ("alerts", "event", "", "session"),
+];
+
+/// Counters of a network interface or block device, when first and last seen
+#[derive(Clone, Copy)]
+struct Totals {
+    first: [u64; 2],
+    last: [u64; 2],
+    /// Counted before the counters were reset (eg. an interface was recreated)
+    before_reset: [u64; 2],
+}
+
+impl Totals {
+    fn new(c: [u64; 2]) -> Totals {
+        Totals {
+            first: c,
+            last: c,
+            before_reset: [0, 0],
+        }
+    }
+
+    /// Record new values of the counters. When one goes backwards, it was reset to zero since the
+    /// last refresh: keep what was counted so far, and add the new value to it.
+    fn update(&mut self, c: [u64; 2]) {
+        for (i, v) in c.into_iter().enumerate() {
+            if v < self.last[i] {
+                self.before_reset[i] = self.total(i);
+                self.first[i] = 0;
+            }
+            self.last[i] = v;
+        }
+    }
+
+    fn total(&self, i: usize) -> u64 {
+        self.before_reset[i] + self.last[i] - self.first[i]
+    }
+}
+
+/// Peaks and totals over the whole session, updated once per refresh and written out as JSON when
+/// exiting. Blocks that had nothing to report for a refresh are simply left out.
+#[derive(Default)]
+pub struct SessionStats {
+    frames: u64,
+    peak_cpu: Option<f32>,
+    min_mem_available: Option<u64>,
+    peak_psi: Option<[f32; 6]>,
+    interfaces: BTreeMap<String, Totals>,
+    devices: BTreeMap<String, Totals>,
+    alerts: Vec<String>,
+}
+
+fn record_counters<'a>(
+    map: &mut BTreeMap<String, Totals>,
+    counters: impl Iterator<Item = (&'a str, Bytes, Bytes)>,
+) {
+    for (name, a, b) in counters {
+        let c = [a.0, b.0];
+        match map.get_mut(name) {
+            Some(t) => t.update(c),
+            None => {
+                map.insert(String::from(name), Totals::new(c));
+            }
+        }
+    }
+}
+
+impl SessionStats {
+    pub fn frame(&mut self) {
+        self.frames += 1;
+    }
+
+    pub fn cpu(&mut self, busy: Option<f32>) {
+        if let Some(b) = busy {
+            self.peak_cpu = Some(self.peak_cpu.map_or(b, |p| p.max(b)));
+        }
+    }
+
+    pub fn memory_available(&mut self, avail: Bytes) {
+        self.min_mem_available = Some(self.min_mem_available.map_or(avail.0, |m| m.min(avail.0)));
+    }
+
+    pub fn pressure(&mut self, avg10: Option<[f32; 6]>) {
+        let avg10 = match avg10 {
+            Some(a) => a,
+            None => return,
+        };
+        match &mut self.peak_psi {
+            None => self.peak_psi = Some(avg10),
+            Some(peak) => {
+                for (p, v) in peak.iter_mut().zip(avg10) {
+                    *p = p.max(v);
+                }
+            }
+        }
+    }
+
+    /// Record (interface, rx, tx) counters
+    pub fn interfaces<'a>(&mut self, counters: impl Iterator<Item = (&'a str, Bytes, Bytes)>) {
+        record_counters(&mut self.interfaces, counters);
+    }
+
+    /// Record (device, read, written) totals, as accumulated by BlockDeviceStats
+    pub fn device_totals<'a>(&mut self, totals: impl Iterator<Item = (&'a str, Bytes, Bytes)>) {
+        for (name, rd, wt) in totals {
+            let t = Totals {
+                first: [0, 0],
+                last: [rd.0, wt.0],
+                before_reset: [0, 0],
+            };
+            match self.devices.get_mut(name) {
+                Some(d) => *d = t,
+                None => {
+                    self.devices.insert(String::from(name), t);
+                }
+            }
+        }
+    }
+
+    pub fn alert(&mut self, when: Timestamp, what: &str) {
+        self.alerts.push(format!("{} {}", when, what));
+    }
+}
+
+/// A JSON string literal
+struct JsonStr<'a>(&'a str);
+
+impl<'a> fmt::Display for JsonStr<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"")?;
+        for c in self.0.chars() {
+            match c {
+                '"' => write!(f, "\\\"")?,
+                '\\' => write!(f, "\\\\")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
+
+/// A JSON number, or null
+struct JsonOpt<T>(Option<T>);
+
+impl<T: fmt::Display> fmt::Display for JsonOpt<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            Some(v) => write!(f, "{}", v),
+            None => write!(f, "null"),
+        }
+    }
+}
+
+fn fmt_totals(
+    f: &mut fmt::Formatter,
+    map: &BTreeMap<String, Totals>,
+    keys: [&str; 2],
+) -> fmt::Result {
+    write!(f, "{{")?;
+    for (i, (name, t)) in map.iter().enumerate() {
+        write!(
+            f,
+            "{}{}:{{\"{}\":{},\"{}\":{}}}",
+            if i > 0 { "," } else { "" },
+            JsonStr(name),
+            keys[0],
+            t.total(0),
+            keys[1],
+            t.total(1)
+        )?;
+    }
+    write!(f, "}}")
+}
+
+/// Serialize as a single line of JSON
+impl fmt::Display for SessionStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{{\"frames\":{},\"peak_cpu_percent\":{},\"min_mem_available_bytes\":{},\"peak_psi_avg10\":",
+            self.frames,
+            JsonOpt(self.peak_cpu.map(|p| format!("{:.1}", p))),
+            JsonOpt(self.min_mem_available)
+        )?;
+        match &self.peak_psi {
+            None => write!(f, "null")?,
+            Some(peak) => {
+                write!(f, "{{")?;
+                for (i, (name, p)) in PSI_NAMES.iter().zip(peak).enumerate() {
+                    write!(f, "{}\"{}\":{:.2}", if i > 0 { "," } else { "" }, name, p)?;
+                }
+                write!(f, "}}")?;
+            }
+        }
+        write!(f, ",\"interfaces\":")?;
+        fmt_totals(f, &self.interfaces, ["rx_bytes", "tx_bytes"])?;
+        write!(f, ",\"devices\":")?;
+        fmt_totals(f, &self.devices, ["read_bytes", "written_bytes"])?;
+        write!(f, ",\"alerts\":[")?;
+        for (i, a) in self.alerts.iter().enumerate() {
+            write!(f, "{}{}", if i > 0 { "," } else { "" }, JsonStr(a))?;
+        }
+        writeln!(f, "]}}")
+    }
+}
+
+/// The fields of the summary, with those of PSI_NAMES spelled out
+fn described() -> Vec<(String, &'static str, &'static str, &'static str)> {
+    let mut fields = Vec::new();
+    for (name, kind, unit, block) in FIELDS {
+        match name {
+            "peak_psi_avg10" => {
+                for psi in PSI_NAMES {
+                    fields.push((format!("{}.{}", name, psi), kind, unit, block));
+                }
+            }
+            _ => fields.push((String::from(name), kind, unit, block)),
+        }
+    }
+    fields
+}
+
+/// List the fields of the summary as a single line of JSON, for --describe
+pub fn describe() -> String {
+    let mut out = String::from("{\"summary\":[");
+    for (i, (name, kind, unit, block)) in described().iter().enumerate() {
+        out.push_str(&format!(
+            "{}{{\"name\":{},\"type\":\"{}\",\"unit\":\"{}\",\"block\":\"{}\"}}",
+            if i > 0 { "," } else { "" },
+            JsonStr(name),
+            kind,
+            unit,
+            block
+        ));
+    }
+    out.push_str("]}");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counters<'c>(
+        c: &'c [(&'static str, u64, u64)],
+    ) -> impl Iterator<Item = (&'static str, Bytes, Bytes)> + 'c {
+        c.iter().map(|(n, a, b)| (*n, Bytes(*a), Bytes(*b)))
+    }
+
+    #[test]
+    fn peaks_with_missing_blocks() {
+        let mut s = SessionStats::default();
+        assert_eq!(
+            s.to_string(),
+            "{\"frames\":0,\"peak_cpu_percent\":null,\"min_mem_available_bytes\":null,\
+             \"peak_psi_avg10\":null,\"interfaces\":{},\"devices\":{},\"alerts\":[]}\n"
+        );
+
+        let frames = [
+            (None, 4000, None),
+            (Some(12.5), 3000, Some([1.0, 0.0, 5.0, 2.0, 0.5, 0.25])),
+            (Some(87.25), 5000, None),
+            (None, 2500, Some([3.0, 0.5, 1.0, 0.0, 0.0, 0.0])),
+            (Some(40.0), 6000, None),
+        ];
+        for (cpu, mem, psi) in frames {
+            s.frame();
+            s.cpu(cpu);
+            s.memory_available(Bytes(mem));
+            s.pressure(psi);
+        }
+        assert_eq!(s.frames, 5);
+        assert_eq!(s.peak_cpu, Some(87.25));
+        assert_eq!(s.min_mem_available, Some(2500));
+        assert_eq!(s.peak_psi, Some([3.0, 0.5, 5.0, 2.0, 0.5, 0.25]));
+    }
+
+    #[test]
+    fn interface_totals() {
+        let mut s = SessionStats::default();
+        s.interfaces(counters(&[("eth0", 1000, 50)]));
+        s.interfaces(counters(&[("eth0", 3000, 70), ("wg0", 10, 10)]));
+        /* eth0 gone for a refresh, then back */
+        s.interfaces(counters(&[("wg0", 25, 40)]));
+        s.interfaces(counters(&[("eth0", 4000, 100), ("wg0", 25, 40)]));
+        assert_eq!(
+            (s.interfaces["eth0"].total(0), s.interfaces["eth0"].total(1)),
+            (3000, 50)
+        );
+        assert_eq!(
+            (s.interfaces["wg0"].total(0), s.interfaces["wg0"].total(1)),
+            (15, 30)
+        );
+
+        /* Recreated: counted from zero again, without losing what came before */
+        s.interfaces(counters(&[("eth0", 500, 100)]));
+        s.interfaces(counters(&[("eth0", 800, 130)]));
+        assert_eq!(
+            (s.interfaces["eth0"].total(0), s.interfaces["eth0"].total(1)),
+            (3800, 80)
+        );
+        s.interfaces(counters(&[("eth0", 0, 0)]));
+        s.interfaces(counters(&[("eth0", 200, 20)]));
+        assert_eq!(
+            (s.interfaces["eth0"].total(0), s.interfaces["eth0"].total(1)),
+            (4000, 100)
+        );
+    }
+
+    #[test]
+    fn json_output() {
+        let mut s = SessionStats::default();
+        s.frame();
+        s.cpu(Some(50.04));
+        s.memory_available(Bytes(1 << 30));
+        s.pressure(Some([0.0; 6]));
+        s.interfaces(counters(&[("eth0", 0, 0)]));
+        s.interfaces(counters(&[("eth0", 10, 20)]));
+        s.device_totals(counters(&[("nvme0n1", 512, 4096)]));
+        s.device_totals(counters(&[("nvme0n1", 1024, 8192), ("sd\"a", 0, 0)]));
+        s.alert(Timestamp::now(true), "line\nbreak \\ \"quoted\"");
+        /* "2026-10-16T00:00:00Z" */
+        let when = s.alerts[0][..20].to_string();
+        assert_eq!(
+            s.to_string().replace(&when, "WHEN"),
+            "{\"frames\":1,\"peak_cpu_percent\":50.0,\"min_mem_available_bytes\":1073741824,\
+             \"peak_psi_avg10\":{\"cpu_some\":0.00,\"cpu_full\":0.00,\"memory_some\":0.00,\
+             \"memory_full\":0.00,\"io_some\":0.00,\"io_full\":0.00},\
+             \"interfaces\":{\"eth0\":{\"rx_bytes\":10,\"tx_bytes\":20}},\
+             \"devices\":{\"nvme0n1\":{\"read_bytes\":1024,\"written_bytes\":8192},\
+             \"sd\\\"a\":{\"read_bytes\":0,\"written_bytes\":0}},\
+             \"alerts\":[\"WHEN line\\u000abreak \\\\ \\\"quoted\\\"\"]}\n"
+        );
+    }
+
+    /// The paths of the values of a JSON object, eg. "devices.sda.read_bytes". Arrays are values.
+    fn json_paths(json: &str) -> Vec<String> {
+        fn string(b: &[u8], i: &mut usize) -> String {
+            let start = *i + 1;
+            *i = start;
+            while b[*i] != b'"' {
+                *i += if b[*i] == b'\\' { 2 } else { 1 };
+            }
+            *i += 1;
+            String::from_utf8(b[start..(*i - 1)].to_vec()).unwrap()
+        }
+        fn value(b: &[u8], i: &mut usize, path: &mut Vec<String>, out: &mut Vec<String>) {
+            match b[*i] {
+                b'{' => {
+                    *i += 1;
+                    while b[*i] != b'}' {
+                        path.push(string(b, i));
+                        assert_eq!(b[*i], b':');
+                        *i += 1;
+                        value(b, i, path, out);
+                        path.pop();
+                        if b[*i] == b',' {
+                            *i += 1;
+                        }
+                    }
+                    *i += 1;
+                    return;
+                }
+                b'[' => {
+                    let mut depth = 0;
+                    loop {
+                        match b[*i] {
+                            b'"' => {
+                                string(b, i);
+                                continue;
+                            }
+                            b'[' => depth += 1,
+                            b']' => depth -= 1,
+                            _ => (),
+                        }
+                        *i += 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                }
+                b'"' => {
+                    string(b, i);
+                }
+                _ => {
+                    while !b",}]".contains(&b[*i]) {
+                        *i += 1;
+                    }
+                }
+            }
+            out.push(path.join("."));
+        }
+
+        let mut out = Vec::new();
+        value(
+            json.trim_end().as_bytes(),
+            &mut 0,
+            &mut Vec::new(),
+            &mut out,
+        );
+        out
+    }
+
+    #[test]
+    fn described_fields_emitted() {
+        let mut s = SessionStats::default();
+        s.frame();
+        s.cpu(Some(12.5));
+        s.memory_available(Bytes(1 << 30));
+        s.pressure(Some([1.0; 6]));
+        s.interfaces(counters(&[("eth0", 0, 0), ("wlan0", 0, 0)]));
+        s.interfaces(counters(&[("eth0", 10, 20), ("wlan0", 5, 5)]));
+        s.device_totals(counters(&[("nvme0n1", 512, 4096)]));
+        s.device_totals(counters(&[("nvme0n1", 1024, 8192)]));
+        s.alert(Timestamp::now(true), "[x, \"y\"]");
+
+        let mut emitted: Vec<String> = json_paths(&s.to_string())
+            .into_iter()
+            .map(|p| match p.split_once('.') {
+                Some((block @ ("interfaces" | "devices"), rest)) => {
+                    format!("{}.*.{}", block, rest.rsplit('.').next().unwrap())
+                }
+                _ => p,
+            })
+            .collect();
+        emitted.sort();
+        emitted.dedup();
+        let mut described: Vec<String> = described().into_iter().map(|f| f.0).collect();
+        described.sort();
+        assert_eq!(emitted, described);
+    }
+
+    #[test]
+    fn describe_json() {
+        let d = describe();
+        assert!(d.starts_with(
+            "{\"summary\":[{\"name\":\"frames\",\"type\":\"counter\",\"unit\":\"refreshes\",\
+             \"block\":\"session\"},"
+        ));
+        assert!(d.contains(
+            "{\"name\":\"peak_psi_avg10.io_full\",\"type\":\"gauge\",\"unit\":\"percent\",\
+             \"block\":\"pressure\"}"
+        ));
+        assert!(d.ends_with("\"block\":\"session\"}]}"));
+        assert_eq!(json_paths(&d), ["summary"]);
+        assert_eq!(
+            d.matches("\"name\"").count(),
+            FIELDS.len() - 1 + PSI_NAMES.len()
+        );
+    }
+}
@@ -14,13 +14,18 @@
  */
 
 use crate::common::*;
+use crate::connector::{ProcConnector, TaskEvent};
+use crate::procroot::ProcRoot;
+#[cfg(feature = "io-uring")]
+use crate::uring;
 use fnv::FnvHashMap;
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 use std::fmt;
 use std::fmt::Write;
+use std::io::{ErrorKind, Read};
 use std::path::PathBuf;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 /// Linux PIDs should not go above 2^22, says proc(5)
@@ -33,24 +38,112 @@ struct Jiffies(u64, u64);
 #[derive(Clone, Copy, PartialEq, Eq)]
 struct TaskState(char);
 
+impl TaskState {
+    /// The state standing for a whole group of tasks: D beats R beats S beats the rest
+    fn rank(&self) -> u8 {
+        match self.0 {
+            'D' => 3,
+            'R' => 2,
+            'S' => 1,
+            _ => 0,
+        }
+    }
+}
+
 impl<'a> fmt::Display for MaybeSmart<'a, TaskState> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let w = f.width().unwrap_or(1);
 
-        if !self.1.smart {
+        if !self.1.smart.get() {
             return write!(f, "{:>w$}", self.0 .0);
         }
 
         match self.0 .0 {
-            'R' => write!(f, "\x1B[1;93m{:>w$}\x1B[0m", self.0 .0),
-            'D' => write!(f, "\x1B[1;95m{:>w$}\x1B[0m", self.0 .0),
+            'R' => write!(f, "{}{:>w$}\x1B[0m", self.1.theme.get().med(), self.0 .0),
+            'D' => write!(f, "{}{:>w$}\x1B[0m", self.1.theme.get().crit(), self.0 .0),
             _ => write!(f, "{:>w$}", self.0 .0),
         }
     }
 }
 
+/// A PID coloured by who owns the task: as is for the user running hitome, dim for root, cyan
+/// for other users. New tasks are in green instead.
+struct TaskPid {
+    pid: Pid,
+    fresh: bool,
+    /// Only known for shown tasks
+    uid: Option<u32>,
+}
+
+impl<'a> fmt::Display for MaybeSmart<'a, TaskPid> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let w = f.width().unwrap_or(0);
+        let t = &self.0;
+        if !self.1.smart.get() {
+            return write!(f, "{:>w$}", t.pid.0);
+        }
+
+        let colour = match (t.fresh, t.uid) {
+            (true, _) => self.1.theme.get().fresh(),
+            (false, _) if !self.1.task_colours => "",
+            (false, Some(uid)) if uid == self.1.proc_root.uid() => "",
+            (false, Some(0)) => "\x1B[2m",
+            (false, Some(_)) => "\x1B[36m",
+            (false, None) => "",
+        };
+        match colour {
+            "" => write!(f, "{:>w$}", t.pid.0),
+            c => write!(f, "{}{:>w$}\x1B[0m", c, t.pid.0),
+        }
+    }
+}
+
+/// The name or short id of the container a task runs in, dimmed
+struct Container<'a>(&'a str);
+
+impl<'a, 'b> fmt::Display for MaybeSmart<'a, Container<'b>> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.1.smart.get() {
+            true => write!(f, "\x1B[2m{}\x1B[0m", self.0 .0),
+            false => write!(f, "{}", self.0 .0),
+        }
+    }
+}
+
+/// The policy field of /proc/pid/stat, see sched(7) and include/uapi/linux/sched.h
+#[derive(Clone, Copy)]
+struct SchedPolicy(u32);
+
+impl<'a> fmt::Display for MaybeSmart<'a, SchedPolicy> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let w = f.width().unwrap_or(1);
+        match self.0 .0 {
+            /* SCHED_FIFO, SCHED_RR and SCHED_DEADLINE: a spinning real-time task can hog a CPU
+             * without showing up in nice-based tools */
+            1 | 2 | 6 => match self.1.smart.get() {
+                false => write!(f, "{:<w$}", '!'),
+                true => write!(f, "{}{:<w$}\x1B[0m", self.1.theme.get().high(), '!'),
+            },
+            /* SCHED_IDLE */
+            5 => write!(f, "{:<w$}", 'i'),
+            /* SCHED_OTHER, SCHED_BATCH */
+            _ => write!(f, "{:<w$}", ""),
+        }
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, PartialOrd, Eq, Ord)]
-struct CPUPercentage(u8);
+struct CPUPercentage(u16);
+
+impl CPUPercentage {
+    /// With --cpu-total, 100% means all online CPUs are busy instead of one
+    fn shown(self, settings: &Settings, cpus: u16) -> CPUPercentage {
+        match settings.cpu_total {
+            true => CPUPercentage(self.0 / cpus),
+            false => self,
+        }
+    }
+}
 
 impl fmt::Display for CPUPercentage {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -59,8 +152,41 @@ impl fmt::Display for CPUPercentage {
     }
 }
 
-#[derive(PartialEq, Eq)]
-struct TaskSort(TaskState, CPUPercentage);
+/// What to sort tasks by
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TaskSortKey {
+    /// Tasks in D state first, longest stuck first, then CPU usage
+    State,
+    /// CPU usage since the last refresh
+    Cpu,
+    /// Resident set size
+    Memory,
+}
+
+/// (rank of tasks in D state, sort key, CPU time used so far in jiffies, state). Tasks with the same
+/// sort key, eg. many at 1% CPU, rank by CPU time used, then by pid as the heap holds (TaskSort,
+/// Pid): their order does not shuffle from one refresh to the next. The state itself is not
+/// compared.
+struct TaskSort(u16, u64, u64, TaskState);
+
+impl TaskSort {
+    /// Only sorting by state bubbles up tasks in D state, longest stuck first
+    fn new(by: TaskSortKey, state: TaskState, dstuck: u16, key: u64, jiffies: u64) -> TaskSort {
+        let rank = match (by, state.0) {
+            (TaskSortKey::State, 'D') => dstuck.saturating_add(1),
+            _ => 0,
+        };
+        TaskSort(rank, key, jiffies, state)
+    }
+}
+
+impl PartialEq for TaskSort {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for TaskSort {}
 
 impl PartialOrd for TaskSort {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
@@ -70,11 +196,346 @@ impl PartialOrd for TaskSort {
 
 impl Ord for TaskSort {
     fn cmp(&self, other: &Self) -> Ordering {
-        match (self.0 .0, other.0 .0) {
-            ('D', 'D') => self.1.cmp(&other.1),
-            ('D', _) => Ordering::Greater,
-            (_, 'D') => Ordering::Less,
-            _ => self.1.cmp(&other.1),
+        (self.0, self.1, self.2).cmp(&(other.0, other.1, other.2))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TaskField {
+    Pid,
+    User,
+    Tty,
+    State,
+    Cpu,
+    Throttle,
+    LastCpu,
+    Wait,
+    Rss,
+    Gpu,
+    Age,
+    Oom,
+    Threads,
+    Csw,
+    Read,
+    Write,
+    Cmd,
+}
+
+impl Field for TaskField {
+    const ALL: &'static [(&'static str, Self)] = &[
+        ("pid", TaskField::Pid),
+        ("user", TaskField::User),
+        ("tty", TaskField::Tty),
+        ("state", TaskField::State),
+        ("cpu", TaskField::Cpu),
+        ("throttle", TaskField::Throttle),
+        ("lastcpu", TaskField::LastCpu),
+        ("wait", TaskField::Wait),
+        ("rss", TaskField::Rss),
+        ("gpu", TaskField::Gpu),
+        ("age", TaskField::Age),
+        ("oom", TaskField::Oom),
+        ("threads", TaskField::Threads),
+        ("csw", TaskField::Csw),
+        ("read", TaskField::Read),
+        ("write", TaskField::Write),
+        ("cmd", TaskField::Cmd),
+    ];
+
+    fn heading(&self) -> Heading<'static> {
+        match self {
+            TaskField::Pid => Heading::new("PID"),
+            TaskField::User => Heading::new("USER"),
+            TaskField::Tty => Heading::new("TTY"),
+            TaskField::State => Heading::new("S"),
+            TaskField::Cpu => Heading::new("CPU%"),
+            TaskField::Throttle => Heading::new("THRT%"),
+            TaskField::LastCpu => Heading::new("CPU#"),
+            TaskField::Wait => Heading::new("WAIT%"),
+            TaskField::Rss => Heading::new("RSS"),
+            TaskField::Gpu => Heading::new("GPU"),
+            TaskField::Age => Heading::new("AGE"),
+            TaskField::Oom => Heading::new("OOM"),
+            TaskField::Threads => Heading::new("THR"),
+            TaskField::Csw => Heading::new("CSW/s"),
+            TaskField::Read => Heading::new("READ/s"),
+            TaskField::Write => Heading::new("WRITE/s"),
+            TaskField::Cmd => Heading::with_short("COMMAND", "CMD"),
+        }
+    }
+}
+
+impl TaskField {
+    /// Per-task I/O rates are only shown with --task-io, or when asked for in --task-fields
+    pub fn io(&self) -> bool {
+        matches!(self, TaskField::Read | TaskField::Write)
+    }
+
+    /// Only shown when asked for in --task-fields
+    pub fn opt_in(&self) -> bool {
+        matches!(
+            self,
+            TaskField::Throttle
+                | TaskField::Gpu
+                | TaskField::Age
+                | TaskField::Oom
+                | TaskField::Threads
+                | TaskField::Csw
+        )
+    }
+
+    /// Can this field be shown on this system? WAIT% needs CONFIG_SCHED_INFO.
+    pub fn available(&self) -> bool {
+        match self {
+            TaskField::Wait => std::path::Path::new("/proc/self/schedstat").exists(),
+            _ => true,
+        }
+    }
+
+    /// Width of this column; the command line takes whatever space is left
+    fn width(&self, settings: &Settings) -> usize {
+        match self {
+            TaskField::Pid => settings.colwidth.get().into(),
+            TaskField::User => 8,
+            /* pts/2047 */
+            TaskField::Tty => 8,
+            /* And the scheduling policy */
+            TaskField::State => 2,
+            /* Up to 100% per CPU, eg. 1600% with 16 CPUs */
+            TaskField::Cpu => match settings.cpu_total {
+                true => 4,
+                false => digits(100 * settings.proc_root.machine().cpus as u32) + 1,
+            },
+            TaskField::Throttle => 5,
+            TaskField::LastCpu => 4,
+            TaskField::Wait => 5,
+            TaskField::Age => 6,
+            TaskField::Oom => 4,
+            TaskField::Threads => 4,
+            TaskField::Csw => 6,
+            TaskField::Rss | TaskField::Gpu | TaskField::Read | TaskField::Write => {
+                settings.colwidth.get().into()
+            }
+            TaskField::Cmd => command_width(settings),
+        }
+    }
+}
+
+/// Hide the last CPU and TTY columns below this many columns, the command line needs the room
+/// more
+const WIDE_MIN_COLUMNS: u16 = 100;
+/// Mark the CPU of the busiest shown task in the CPU block when it uses at least this much
+const HOT_TASK_MIN_CPU: u16 = 50;
+
+/// The task fields that fit in maxcols, out of --task-fields
+fn shown_fields(settings: &Settings) -> impl Iterator<Item = &TaskField> {
+    let wide = settings.maxcols.get() >= WIDE_MIN_COLUMNS;
+    settings
+        .task_fields
+        .iter()
+        .filter(move |f| wide || !matches!(f, TaskField::LastCpu | TaskField::Tty))
+}
+
+/// Width of the command line column: all of maxcols minus the other columns and their separators
+fn command_width(settings: &Settings) -> usize {
+    shown_fields(settings)
+        .filter(|f| **f != TaskField::Cmd)
+        .fold(settings.maxcols.get().into(), |w: usize, f| {
+            w.saturating_sub(f.width(settings) + 1)
+        })
+}
+
+/// tty_nr from /proc/pid/stat, a device number: "pts/3", or "-" without a controlling terminal
+#[derive(Clone, Copy)]
+struct Tty(u32);
+
+impl fmt::Display for Tty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        /* See new_encode_dev() in include/linux/kdev_t.h, and
+         * Documentation/admin-guide/devices.txt */
+        let major = (self.0 >> 8) & 0xFFF;
+        let minor = (self.0 & 0xFF) | ((self.0 >> 12) & 0xFFF00);
+        let w = f.width().unwrap_or(0);
+        let mut buf = String::new();
+        match (major, minor) {
+            (0, 0) => return write!(f, "{:<w$}", "-"),
+            (136..=143, n) => write!(buf, "pts/{}", (major - 136) * 256 + n),
+            (4, n @ 0..=63) => write!(buf, "tty{}", n),
+            (4, n) => write!(buf, "ttyS{}", n - 64),
+            (m, n) => write!(buf, "{}:{}", m, n),
+        }?;
+        write!(f, "{:<w$}", buf)
+    }
+}
+
+/// (number of tasks in D state, most common comm, most common wait channel or "")
+struct PileUp<'a>(u16, &'a str, &'a str);
+
+/// (zombies, ppid, parent comm)
+struct Reaper<'a>(u16, u32, &'a str);
+
+impl<'a, 'b> fmt::Display for MaybeSmart<'a, Reaper<'b>> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let len = f.width().unwrap_or(60);
+        let Reaper(n, ppid, comm) = self.0;
+        let msg = format!("{} zombies, parent: {} (pid {})", n, comm, ppid);
+        match self.1.smart.get() {
+            false => write!(f, "{:<len$.len$}", msg),
+            true => write!(f, "{}{:<len$.len$}\x1B[0m", self.1.theme.get().crit(), msg),
+        }
+    }
+}
+
+impl<'a, 'b> fmt::Display for MaybeSmart<'a, PileUp<'b>> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let len = f.width().unwrap_or(60);
+        let PileUp(n, comm, wchan) = self.0;
+        /* Format to a String first, so the whole message can be truncated/padded */
+        let mut msg = format!("{} tasks in uninterruptible sleep, mostly {}", n, comm);
+        if !wchan.is_empty() {
+            write!(msg, " [{}]", wchan).unwrap();
+        }
+        match self.1.smart.get() {
+            false => write!(f, "{:<len$.len$}", msg),
+            true => write!(f, "{}{:<len$.len$}\x1B[0m", self.1.theme.get().crit(), msg),
+        }
+    }
+}
+
+/// Only raise a D state pileup after two refreshes above the threshold in a row, a short burst of
+/// I/O is fine; (previous count, current count)
+fn pileup_raised(dstate: (u16, u16), threshold: u16) -> bool {
+    dstate.0 > threshold && dstate.1 > threshold
+}
+
+fn count_name(counts: &mut FnvHashMap<String, u16>, name: &str) {
+    match counts.get_mut(name) {
+        Some(n) => *n += 1,
+        None => {
+            counts.insert(String::from(name), 1);
+        }
+    }
+}
+
+/// The name or pid counted the most. Ties are broken by the smallest one, so the message doesn't
+/// flicker between refreshes.
+fn most_common<K: Ord + std::hash::Hash>(counts: FnvHashMap<K, u16>) -> Option<K> {
+    counts
+        .into_iter()
+        .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(&a.0)))
+        .map(|(c, _)| c)
+}
+
+/// Number of digits of n
+fn digits(n: u32) -> usize {
+    n.checked_ilog10().unwrap_or(0) as usize + 1
+}
+
+/// Only show how many tasks appeared since the last refresh above this many, eg. a fork bomb
+const FRESH_TASKS_SHOWN: u32 = 20;
+
+/// Number of tasks in some states of interest
+#[derive(Clone, Copy, Default)]
+pub struct TaskStateCounts {
+    /// All tasks, whatever their state
+    pub total: u32,
+    pub running: u16,
+    pub dstate: u16,
+    pub zombie: u16,
+    /// Tasks that appeared since the previous refresh
+    pub fresh: u32,
+    /// Tasks created since the previous refresh, only known with the proc connector
+    pub spawned: Option<u32>,
+}
+
+/// The end of the task list heading: "TASKS 1423 +35  R 5  D 2  40 spawned  3 zombies  self 1%";
+/// (counts, CPUs, CPU usage of hitome with --hide-self)
+struct Counts(TaskStateCounts, u16, Option<CPUPercentage>);
+
+impl Counts {
+    fn zombies(&self) -> &'static str {
+        match self.0.zombie {
+            1 => "zombie",
+            _ => "zombies",
+        }
+    }
+
+    fn width(&self) -> usize {
+        let c = &self.0;
+        let mut w = 14 + digits(c.total) + digits(c.running.into()) + digits(c.dstate.into());
+        if c.fresh > FRESH_TASKS_SHOWN {
+            w += 2 + digits(c.fresh);
+        }
+        if let Some(n) = c.spawned {
+            w += 10 + digits(n);
+        }
+        if c.zombie > 0 {
+            w += 3 + digits(c.zombie.into()) + self.zombies().len();
+        }
+        if let Some(pc) = self.2 {
+            w += 8 + digits(pc.0.into());
+        }
+        w
+    }
+}
+
+impl<'a> fmt::Display for MaybeSmart<'a, Counts> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Counts(c, cpus, selfpc) = self.0;
+        /* Hitome itself is running */
+        let running = Threshold {
+            val: c.running,
+            med: cpus + 1,
+            high: 2 * cpus + 1,
+            crit: 4 * cpus + 1,
+        };
+        let dstate = Threshold {
+            val: c.dstate,
+            med: 1,
+            high: 6,
+            crit: (self.1.dstate_threshold + 1).max(7),
+        };
+        write!(
+            f,
+            "{:5} {}",
+            MaybeSmart(Heading::new("TASKS"), self.1),
+            c.total
+        )?;
+        if c.fresh > FRESH_TASKS_SHOWN {
+            let fresh = Threshold {
+                val: c.fresh,
+                med: 50,
+                high: 200,
+                crit: 1000,
+            };
+            write!(f, " +{:1}", MaybeSmart(fresh, self.1))?;
+        }
+        write!(
+            f,
+            "  R {:1}  D {:1}",
+            MaybeSmart(running, self.1),
+            MaybeSmart(dstate, self.1)
+        )?;
+        if let Some(n) = c.spawned {
+            write!(f, "  {} spawned", n)?;
+        }
+
+        if c.zombie > 0 {
+            match self.1.smart.get() {
+                false => write!(f, "  {} {}", c.zombie, self.0.zombies())?,
+                true => write!(
+                    f,
+                    "  {}{} {}\x1B[0m",
+                    self.1.theme.get().crit(),
+                    c.zombie,
+                    self.0.zombies()
+                )?,
+            }
+        }
+
+        match selfpc {
+            Some(pc) => write!(f, "  self {:1}", pc),
+            None => Ok(()),
         }
     }
 }
@@ -82,38 +543,48 @@ impl Ord for TaskSort {
 /// (tcomm, stripped arg0, args)
 struct CommandLine<'a>(&'a str, &'a str, &'a str);
 
+impl<'a> CommandLine<'a> {
+    /// How many characters of args fit in len, after tcomm and arg0
+    fn args_room(&self, len: usize) -> usize {
+        match self {
+            CommandLine(x, y, _) if y.starts_with(x) => len.saturating_sub(str_width(y) + 1),
+            CommandLine(x, y, _) => len.saturating_sub(str_width(x) + str_width(y) + 4),
+        }
+    }
+}
+
 impl<'a, 'b> fmt::Display for MaybeSmart<'a, CommandLine<'b>> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let len = f.width().unwrap_or(60);
         match self.0 {
             CommandLine(x, y, z) if y.starts_with(x) => {
-                if len >= y.len() + 1 {
-                    let len = len - y.len() - 1;
-                    match self.1.smart {
-                        false => write!(f, "{} {:<len$}", y, z),
-                        true => write!(f, "\x1B[1m{}\x1B[0m {:<len$.len$}", y, z),
+                if len >= str_width(y) + 1 {
+                    let len = self.0.args_room(len);
+                    match self.1.smart.get() {
+                        false => write!(f, "{} {:len$}", y, Cells(z)),
+                        true => write!(f, "\x1B[1m{}\x1B[0m {:len$}", y, Cells(z)),
                     }
                 } else {
                     /* arg0 is too long, can't even show any args */
-                    match self.1.smart {
-                        false => write!(f, "{:<len$.len$}", y),
-                        true => write!(f, "\x1B[1m{:<len$.len$}\x1B[0m", y),
+                    match self.1.smart.get() {
+                        false => write!(f, "{:len$}", Cells(y)),
+                        true => write!(f, "\x1B[1m{:len$}\x1B[0m", Cells(y)),
                     }
                 }
             }
             CommandLine(x, y, z) => {
-                if len >= x.len() + y.len() + 4 {
-                    let len = len - x.len() - y.len() - 4;
-                    match self.1.smart {
-                        false => write!(f, "({}) {} {:<len$}", x, y, z),
-                        true => write!(f, "({}) \x1B[1m{}\x1B[0m {:<len$.len$}", x, y, z),
+                if len >= str_width(x) + str_width(y) + 4 {
+                    let len = self.0.args_room(len);
+                    match self.1.smart.get() {
+                        false => write!(f, "({}) {} {:len$}", x, y, Cells(z)),
+                        true => write!(f, "({}) \x1B[1m{}\x1B[0m {:len$}", x, y, Cells(z)),
                     }
                 } else {
                     /* arg0 is too long, can't even show any args */
-                    let len = len.saturating_sub(x.len() + 3);
-                    match self.1.smart {
-                        false => write!(f, "({}) {:<len$.len$}", x, y),
-                        true => write!(f, "({}) \x1B[1m{:<len$.len$}\x1B[0m", x, y),
+                    let len = len.saturating_sub(str_width(x) + 3);
+                    match self.1.smart.get() {
+                        false => write!(f, "({}) {:len$}", x, Cells(y)),
+                        true => write!(f, "({}) \x1B[1m{:len$}\x1B[0m", x, Cells(y)),
                     }
                 }
             }
@@ -121,63 +592,464 @@ impl<'a, 'b> fmt::Display for MaybeSmart<'a, CommandLine<'b>> {
     }
 }
 
-struct FileDescriptor(libc::c_int);
+struct TaskEntry {
+    /// For /proc/pid/task/pid/stat
+    filedes: Option<FileDescriptor>,
+    jiffies: (Jiffies, Jiffies),
+    /// Resident set size of the whole process, in bytes; the same for all of its threads
+    rss: u64,
+    /// In jiffies after boot, tells apart tasks reusing the same pid
+    start_time: u64,
+    /// Time spent waiting to run in nanoseconds, from /proc/pid/schedstat, only read for shown
+    /// tasks
+    run_delay: Option<(u64, Instant)>,
+    /// Time spent waiting to run since the last refresh, as a percentage of wall time
+    wait: Option<CPUPercentage>,
+    /// Owner of /proc/pid/task/pid, only read for shown tasks, with the user field or coloured
+    /// PIDs
+    uid: Option<u32>,
+    /// With --task-tag, the value of that environment variable; only read once, for shown tasks
+    tag: Option<Option<String>>,
+    /// With --show-unit, the last component of its cgroup; only read once, for shown tasks
+    unit: Option<Option<String>>,
+    /// With --show-unit, the name of its container from HOSTNAME, or its short id, when its
+    /// cgroup is one of a container
+    container: Option<String>,
+    /// Command line (arguments separated by \0) and comm, only read for shown tasks, and tasks
+    /// checked against the filter. The command line is read again when comm changes, eg. after
+    /// exec().
+    command: Option<(String, String)>,
+    state: TaskState,
+    /// Consecutive refreshes spent in D state
+    dstuck: u16,
+    /// Consecutive refreshes without using any CPU time
+    idle: u8,
+    kthread: bool,
+    ppid: u32,
+    /// Controlling terminal
+    tty: Tty,
+    /// The CPU this task last ran on
+    last_cpu: u16,
+    policy: SchedPolicy,
+    /// Is this the main thread of its process?
+    leader: bool,
+    /// Did this task appear since the previous refresh?
+    fresh: bool,
+    /// Its stat file could not be parsed, the fields above come from status instead
+    cpu_unknown: bool,
+    /// The pid of its process, ie. of its main thread
+    process: Pid,
+    /// From stat, for --group-tasks and the filter
+    comm: String,
+    /// read_bytes and write_bytes from /proc/pid/io, only read for shown tasks
+    io: Option<(u64, u64, Instant)>,
+    /// Bytes read and written per second since the last refresh, from io
+    io_rates: Option<(Rate<Bytes>, Rate<Bytes>)>,
+    /// Voluntary and involuntary context switches from /proc/pid/status, only read for shown
+    /// tasks with the csw field
+    csw: Option<(u64, Instant)>,
+    /// Context switches per second since the last refresh, from csw
+    csw_rate: Option<Rate<u64>>,
+    /// With the throttle field, its cgroup v2 path; only read once, for shown tasks
+    cgroup: Option<Option<String>>,
+    /// throttled_usec of its cgroup, None without a CPU limit
+    throttled: Option<(u64, Instant)>,
+    /// Time its cgroup spent throttled since the last refresh, as a percentage of wall time
+    throttle: Option<CPUPercentage>,
+    /// Mountpoint most of the files opened by this task live under, and how many refreshes
+    /// before looking again
+    writes_to: (Option<String>, u8),
+    stale: Stale,
+}
+
+/// What a task's row shows besides its TaskEntry, worked out by TaskStats
+#[derive(Clone, Copy)]
+struct TaskExtras<'a> {
+    cpupc: CPUPercentage,
+    age: Duration,
+    /// Threads of its process
+    threads: u16,
+    /// GPU memory used, from HwmonStats
+    gpu: Option<u64>,
+    /// Depth in the tree with --task-tree
+    depth: usize,
+    user: &'a str,
+}
+
+/// Tasks sharing the same comm, shown as one row. XXX: grouping by cgroup leaf would be better
+/// when available
+struct TaskGroup {
+    /// The busiest member, by sort key
+    leader: (Pid, u64),
+    tasks: u16,
+    /// Only counting main threads
+    processes: u16,
+    state: TaskState,
+    /// The longest any member has been stuck in D state
+    dstuck: u16,
+    cpu: CPUPercentage,
+    /// CPU time used so far by all members, in jiffies
+    jiffies: u64,
+    rss: u64,
+    /// GPU memory used by all members, from NVML
+    gpu: u64,
+}
 
-impl Drop for FileDescriptor {
-    fn drop(&mut self) {
-        if self.0 == -1 {
-            return;
+impl TaskEntry {
+    fn new(filedes: Option<FileDescriptor>) -> TaskEntry {
+        TaskEntry {
+            filedes,
+            jiffies: (Jiffies(0, 0), Jiffies(0, 0)),
+            rss: 0,
+            start_time: 0,
+            run_delay: None,
+            wait: None,
+            uid: None,
+            tag: None,
+            unit: None,
+            container: None,
+            command: None,
+            state: TaskState('?'),
+            dstuck: 0,
+            idle: 0,
+            kthread: false,
+            ppid: 0,
+            tty: Tty(0),
+            fresh: false,
+            cpu_unknown: false,
+            last_cpu: 0,
+            policy: SchedPolicy(0),
+            leader: false,
+            process: Pid(0),
+            comm: String::new(),
+            io: None,
+            io_rates: None,
+            csw: None,
+            csw_rate: None,
+            cgroup: None,
+            throttled: None,
+            throttle: None,
+            writes_to: (None, 0),
+            stale: Stale(false),
         }
-        unsafe {
-            if libc::close(self.0) != 0 {
-                libc_panic("close()");
+    }
+
+    /// 0% if no time passed, or if the counters went backwards (eg. after resuming from suspend)
+    fn cpu_percentage(&self) -> CPUPercentage {
+        let used = self.jiffies.1 .0.saturating_sub(self.jiffies.0 .0);
+        let elapsed = self.jiffies.1 .1.saturating_sub(self.jiffies.0 .1);
+        CPUPercentage(
+            (100 * used)
+                .checked_div(elapsed)
+                .unwrap_or(0)
+                .min(u16::MAX.into()) as u16,
+        )
+    }
+}
+
+/// System uptime in jiffies, from CLOCK_BOOTTIME like /proc/uptime. Integer math all the way, so
+/// it stays exact for uptimes of years.
+fn uptime_jiffies(boottime: Duration, user_hz: u16) -> u64 {
+    (boottime.as_nanos() * user_hz as u128 / 1_000_000_000) as u64
+}
+
+/// Does a comm or command line match the task filter? XXX: a plain substring match, a regex
+/// (behind an optional feature) would go here
+fn filter_matches(filter: &str, haystack: &str) -> bool {
+    haystack.contains(filter)
+}
+
+/// The last component of a cgroup path from /proc/pid/cgroup, eg. "postgresql.service". Prefers
+/// the unified (v2) hierarchy, then the one of systemd on v1 or hybrid systems. None if the task is
+/// in the root cgroup, or the file is empty.
+fn cgroup_leaf(cgroup: &str) -> Option<String> {
+    let mut v2 = None;
+    let mut systemd = None;
+    /* hierarchy-ID:controller-list:cgroup-path */
+    for line in cgroup.lines() {
+        match line.splitn(3, ':').collect::<Vec<_>>()[..] {
+            ["0", "", path] => v2 = Some(path),
+            [_, "name=systemd", path] => systemd = Some(path),
+            _ => continue,
+        }
+    }
+
+    let leaf = |p: &str| {
+        p.rsplit('/')
+            .next()
+            .filter(|l| !l.is_empty())
+            .map(String::from)
+    };
+    v2.and_then(leaf).or_else(|| systemd.and_then(leaf))
+}
+
+/// The short (12 characters) id of a container from the leaf of its cgroup, as named by systemd
+/// (eg. "docker-<id>.scope", "crio-<id>.scope", "libpod-<id>.scope") or by the cgroupfs driver
+/// of docker and kubelet (the bare id). None if this does not look like a container.
+fn container_id(leaf: &str) -> Option<&str> {
+    let id = leaf.strip_suffix(".scope").unwrap_or(leaf);
+    let id = ["docker-", "crio-", "libpod-", "cri-containerd-"]
+        .iter()
+        .find_map(|p| id.strip_prefix(p))
+        .unwrap_or(id);
+    match id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        true => Some(&id[..12]),
+        false => None,
+    }
+}
+
+/// The path of a task in the unified (v2) hierarchy from /proc/pid/cgroup, eg.
+/// "/system.slice/postgresql.service". None on v1 hosts.
+fn cgroup_v2_path(cgroup: &str) -> Option<String> {
+    cgroup
+        .lines()
+        .find_map(|l| l.strip_prefix("0::"))
+        .map(String::from)
+}
+
+/// Truncate --task-tag values to this many characters
+const TAG_MAX_LEN: usize = 12;
+/// Truncate container names to this many characters
+const CONTAINER_MAX_LEN: usize = 20;
+
+/// Look up the name of a user, eg. in /etc/passwd
+fn user_name(uid: u32) -> Option<String> {
+    let mut buf = vec![0u8; 1024];
+    loop {
+        let mut pw = std::mem::MaybeUninit::<libc::passwd>::uninit();
+        let mut res = std::ptr::null_mut();
+        let ret = unsafe {
+            libc::getpwuid_r(
+                uid,
+                pw.as_mut_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+                &mut res,
+            )
+        };
+        match ret {
+            libc::ERANGE if buf.len() < 65536 => buf.resize(buf.len() * 2, 0),
+            0 if !res.is_null() => {
+                let name = unsafe { std::ffi::CStr::from_ptr(pw.assume_init().pw_name) };
+                return Some(name.to_string_lossy().into_owned());
             }
+            /* Not found, or an error */
+            _ => return None,
         }
     }
 }
 
-struct TaskEntry {
-    /// For /proc/pid/task/pid/stat
-    filedes: Option<FileDescriptor>,
-    jiffies: (Jiffies, Jiffies),
+/// Look up the name of a user in the contents of an /etc/passwd, eg. of a remote machine
+fn passwd_name(passwd: &str, uid: u32) -> Option<String> {
+    passwd.lines().find_map(|l| {
+        let mut fields = l.split(':');
+        let name = fields.next()?;
+        match fields.nth(1)?.parse::<u32>() {
+            Ok(u) if u == uid => Some(String::from(name)),
+            _ => None,
+        }
+    })
+}
+
+/// Set in the flags field of /proc/pid/stat for kernel threads, see include/linux/sched.h
+const PF_KTHREAD: u32 = 0x00200000;
+
+/// Give up on stat files longer than this, rather than parse a truncated line
+const STAT_MAX_LEN: usize = 4096;
+
+/// Past max_fds, close the stat files of tasks that did not run for this many refreshes to keep
+/// those of running tasks open instead
+const FD_IDLE_REFRESHES: u8 = 5;
+
+/// With io_uring, how much of each stat file is read in a batch; longer ones are read again
+#[cfg(feature = "io-uring")]
+const STAT_SLOT_LEN: usize = 1024;
+
+/// The fields of /proc/pid/stat after the process name, see
+/// https://www.kernel.org/doc/html/latest/filesystems/proc.html table 1-4 and proc(5)
+struct StatLine {
     state: TaskState,
-    stale: Stale,
+    ppid: u32,
+    tty: u32,
+    flags: u32,
+    used_jiffies: u64,
+    start_time: u64,
+    rss: u64,
+    processor: u16,
+    policy: u32,
+}
+
+impl StatLine {
+    /// Parse what follows the closing parenthesis of the process name. None if any field is
+    /// missing or malformed, instead of reading the wrong ones.
+    fn parse(stat: &str) -> Option<StatLine> {
+        let mut stat = stat.split_ascii_whitespace();
+        let state = TaskState(stat.next()?.chars().next()?);
+        let ppid = stat.next()?.parse().ok()?;
+        /* Printed as a signed int */
+        let tty = stat.nth(2)?.parse::<i32>().ok()? as u32;
+        let flags = stat.nth(1)?.parse().ok()?;
+        let used_jiffies = stat.nth(4)?.parse::<u64>().ok()? + stat.next()?.parse::<u64>().ok()?;
+        let start_time = stat.nth(6)?.parse().ok()?;
+        let rss = stat.nth(1)?.parse().ok()?;
+        let processor = stat.nth(14)?.parse().ok()?;
+        let policy = stat.nth(1)?.parse().ok()?;
+        Some(StatLine {
+            state,
+            ppid,
+            tty,
+            flags,
+            used_jiffies,
+            start_time,
+            rss,
+            processor,
+            policy,
+        })
+    }
+}
+
+/// Look at the files opened by shown tasks writing more than this many bytes per second
+const WRITE_TARGET_MIN_RATE: u64 = 1024 * 1024;
+/// How many refreshes to wait before looking at the opened files of a task again
+const WRITE_TARGET_TTL: u8 = 5;
+/// Only look at this many file descriptors per task
+const WRITE_TARGET_MAX_FDS: usize = 32;
+
+/// Make tasks stand out after this many refreshes in a row in D state, when a hung mount or a
+/// dying disk is more likely than a slow I/O
+const DSTUCK_REFRESHES: u16 = 5;
+
+/// Above this many zombies, their parent is most likely not reaping them: find out which one
+const ZOMBIE_THRESHOLD: u16 = 100;
+
+/// Always show at least this many tasks, even if it overflows the terminal. Other blocks are
+/// shrunk before the task list gets this small.
+const MIN_TASKS: u16 = 5;
+
+/// The mountpoint most of these opened files are under, the longest matching one for each file.
+/// Only regular files count: sockets, pipes and devices say nothing about where writes go.
+fn write_target(files: impl Iterator<Item = PathBuf>, mountpoints: &[String]) -> Option<&str> {
+    let mut counts = vec![0u16; mountpoints.len()];
+    let mut total = 0u16;
+    for f in files {
+        if !std::fs::metadata(&f).is_ok_and(|m| m.is_file()) {
+            continue;
+        }
+        total += 1;
+        if let Some((i, _)) = mountpoints
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| f.starts_with(m))
+            .max_by_key(|(_, m)| m.len())
+        {
+            counts[i] += 1;
+        }
+    }
+    /* Only a guess worth showing if most of the files agree */
+    counts
+        .iter()
+        .position(|c| *c * 2 > total)
+        .map(|i| mountpoints[i].as_str())
 }
 
 pub struct TaskStats<'a> {
     settings: &'a Settings,
     /// How many jiffies in a second, as exposed to userspace
     user_hz: u16,
-    /// System uptime in jiffies
+    /// In bytes, to convert rss from /proc/pid/stat
+    pagesize: u64,
+    /// To tell how many running tasks are too many
+    cpus: u16,
+    /// System uptime in jiffies, at the start of this refresh
     uptime: u64,
-    /// Hopefully near-ish time elapsed since uptime was updated
-    since_uptime: Instant,
+    /// When this refresh started: samples are timestamped with it, and uptime is extrapolated
+    /// from it while tasks are read
+    now: Instant,
     buf: String,
     buf2: String,
     buf3: String,
     bufp: PathBuf,
-    bufstat: [u8; 512],
+    bufstat: Vec<u8>,
+    /// None if the kernel can't do it, then stat files are read one by one
+    #[cfg(feature = "io-uring")]
+    ring: Option<uring::Ring>,
+    /// Tasks whose stat file is to be read by the ring: (pid, taskid, must_close)
+    #[cfg(feature = "io-uring")]
+    batch: Vec<(Pid, Pid, bool)>,
+    #[cfg(feature = "io-uring")]
+    batchfds: Vec<libc::c_int>,
+    /// STAT_SLOT_LEN bytes for each read of the ring
+    #[cfg(feature = "io-uring")]
+    bufring: Vec<u8>,
     tasks: FnvHashMap<Pid, TaskEntry>,
+    /// None without CAP_NET_ADMIN, then /proc is walked at every refresh
+    connector: Option<ProcConnector>,
+    /// With the proc connector, the live tasks and their process: taskid -> pid
+    live: FnvHashMap<Pid, Pid>,
+    /// With the proc connector, how many tasks were created since the last refresh, including the
+    /// ones that already exited
+    spawned: Option<u32>,
+    /// With --hide-self, the pid of hitome and its summed CPU usage since the last refresh
+    self_pid: Pid,
+    self_cpu: Option<CPUPercentage>,
+    /// Number of threads of each process, by pid, counted while walking /proc
+    threads: FnvHashMap<Pid, u16>,
     /// Used to sort tasks by their State/CPU%. Pushing is O(1) and popping is O(log n). Pushing all
     /// the tasks and popping the 10 highest is only O(n + 10 log n) instead of sorting which is O(n
     /// log n).
     sorted: BinaryHeap<(TaskSort, Pid)>,
+    /// The tasks that will be printed, in order
+    shown: Vec<Pid>,
+    /// Tasks of --watch-pid that no longer exist, printed before the shown ones
+    gone: Vec<Pid>,
+    /// With --show-zombies, the zombies appended to shown
+    zombies: Vec<Pid>,
+    /// With --task-tree, how deep each shown task is indented under its parent
+    depth: Vec<u8>,
     /// Formatted and ordered lines, ready to be printed
     relevant: Vec<String>,
+    /// How many of the shown tasks continue on a second line
+    wrapped: u16,
+    /// For --task-tag, only the beginning of /proc/pid/environ
+    bufenv: Box<[u8; 8192]>,
+    /// pid -> GPU memory used, as given by set_gpu_memory()
+    gpu_memory: FnvHashMap<u32, u64>,
+    /// uid -> user name, or the uid itself if it has no name; resolving names can be slow (eg.
+    /// LDAP)
+    users: FnvHashMap<u32, String>,
+    /// With --group-tasks, tasks sharing the same comm; kept to reuse its allocation
+    groups: FnvHashMap<String, TaskGroup>,
     /// How many tasks we can print
     maxtasks: u16,
     /// The maximum number of files we can open concurrently
     max_fds: u64,
+    sort_key: TaskSortKey,
+    show_kthreads: bool,
+    /// Only show tasks whose comm or cmdline contain this string
+    filter: String,
+    /// Mountpoints of filesystems backed by a block device, as given by set_mountpoints()
+    mountpoints: Vec<String>,
+    /// Number of tasks in some states, counted by the last update
+    states: TaskStateCounts,
+    /// The CPU the busiest shown task last ran on, if busy enough
+    hot_cpu: Option<u16>,
+    /// Number of tasks in D state, (previous refresh, this refresh)
+    dstate: (u16, u16),
+    /// Set when too many tasks have been in D state for two refreshes: (number of tasks, most
+    /// common comm)
+    pileup: Option<(u16, String, String)>,
+    /// Set when there are more zombies than ZOMBIE_THRESHOLD: (number of zombies, pid and comm of
+    /// the parent of most of them)
+    reaper: Option<(u16, u32, String)>,
 }
 
-/// Walk /proc and call the closure for each task, eg /proc/X/task/Y. Skips invalid files instead of
+/// Walk /proc and call the closure for each task, eg /proc/X/task/Y with (X, Y). Skips invalid files instead of
 /// panicking, as tasks are created/deleted all the time and scanning them in /proc is inherently
 /// racy. XXX: this would work better as an Iterator, but i don't know how to do that
 fn map_tasks<F>(p: &mut PathBuf, mut doit: F)
 where
-    F: FnMut(Pid),
+    F: FnMut(Pid, Pid),
 {
-    /* XXX: find if io_uring is worth using here */
     /* XXX: same, but with inotify watches */
     p.clear();
     p.push("/proc");
@@ -193,6 +1065,10 @@ where
             _ => continue,
         }
 
+        let pid = match process.file_name().to_str().map(|p| p.parse::<u32>()) {
+            Some(Ok(p)) => Pid(p),
+            _ => continue,
+        };
         p.push(process.file_name());
         p.push("task");
 
@@ -214,7 +1090,7 @@ where
                     _ => continue,
                 };
 
-                doit(taskid);
+                doit(pid, taskid);
             }
             break;
         }
@@ -225,40 +1101,141 @@ where
 }
 
 impl<'a> TaskStats<'a> {
-    pub fn set_max_tasks(&mut self, tasks: u16) {
-        self.maxtasks = tasks;
+    pub fn set_sort_key(&mut self, k: TaskSortKey) {
+        self.sort_key = k;
+    }
+
+    /// Show this many tasks whatever the height of the terminal, as with --tasks; alert lines
+    /// are added above them
+    pub fn set_max_tasks(&mut self, n: u16) {
+        self.maxtasks = n.saturating_add(self.alerts());
+    }
+
+    /// The GPU memory used by each pid, from HwmonStats; call it before update()
+    pub fn set_gpu_memory(&mut self, m: &FnvHashMap<u32, u64>) {
+        self.gpu_memory.clone_from(m);
+    }
+
+    /// The mountpoints shown by FilesystemStats, unescaped; call it before update()
+    pub fn set_mountpoints<'b>(&mut self, m: impl Iterator<Item = &'b str>) {
+        self.mountpoints.clear();
+        self.mountpoints.extend(m.map(String::from));
+    }
+
+    pub fn toggle_kernel_threads(&mut self) {
+        self.show_kthreads = !self.show_kthreads;
+    }
+
+    /// Read stat files one by one, as without the io-uring feature
+    #[cfg(feature = "io-uring")]
+    pub fn disable_io_uring(&mut self) {
+        self.ring = None;
+    }
+
+    /// Number of tasks in some states, as of the last update
+    pub fn state_counts(&self) -> TaskStateCounts {
+        self.states
+    }
+
+    /// The CPU the busiest shown task last ran on, if it uses at least HOT_TASK_MIN_CPU and the
+    /// lastcpu field is shown
+    pub fn hot_cpu(&self) -> Option<u16> {
+        self.hot_cpu
+    }
+
+    /// How many tasks are piling up in D state, and their most common command and wait channel
+    /// ("" if unknown), if above the threshold
+    pub fn pileup(&self) -> Option<(u16, &str, &str)> {
+        self.pileup
+            .as_ref()
+            .map(|(n, comm, wchan)| (*n, comm.as_str(), wchan.as_str()))
+    }
+
+    pub fn filter(&self) -> &str {
+        &self.filter
+    }
+
+    pub fn set_filter(&mut self, filter: &str) {
+        self.filter.clear();
+        self.filter.push_str(filter);
+    }
+
+    /// Does the comm or command line of a task match the filter? Like for update_commands(), the
+    /// command line is only read once, and again when comm changes.
+    fn task_matches(
+        root: &ProcRoot,
+        filter: &str,
+        buf: &mut String,
+        buf2: &mut String,
+        taskid: Pid,
+        ent: &mut TaskEntry,
+    ) -> bool {
+        if filter_matches(filter, &ent.comm) {
+            return true;
+        }
+
+        let cached = matches!(&ent.command, Some((_, c)) if *c == ent.comm);
+        if !cached {
+            buf2.clear();
+            write!(buf2, "/proc/{}/task/{}/cmdline", taskid.0, taskid.0).unwrap();
+            match root.read_to_string(&buf2, buf) {
+                /* Not read from the remote machine yet, hidden until it is */
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return false,
+                Err(_) => buf.clear(),
+                Ok(_) => (),
+            }
+            ent.command = Some((buf.clone(), ent.comm.clone()));
+        }
+
+        buf.clear();
+        buf.push_str(&ent.command.as_ref().unwrap().0);
+        /* Match against space separated arguments, replacing a NUL byte by a space keeps the
+         * string valid UTF-8 */
+        unsafe {
+            for b in buf.as_mut_vec().iter_mut() {
+                if *b == b'\0' {
+                    *b = b' ';
+                }
+            }
+        }
+        filter_matches(filter, buf)
     }
 
     // XXX: this would be much simpler as a method that mutates self, but the borrow checker won't
     // let us do that since we already take a &TaskEntry argument
-    /// Format a task's line to out String
+    /// Format a task's line to out String. If wrap is set and the command line is too long, it
+    /// continues on a second line; returns true if it did.
     fn format_task(
         settings: &Settings,
-        buf: &mut String,
         buf2: &mut String,
-        buf3: &mut String,
         out: &mut String,
         taskid: Pid,
-        cpupc: CPUPercentage,
+        extras: TaskExtras,
         ent: &TaskEntry,
-    ) {
-        /* XXX: find better way to do this */
-        buf2.clear();
-        write!(buf2, "/proc/{}/task/{}/cmdline", taskid.0, taskid.0).unwrap();
-        let cmdline = match read_to_string(&buf2, buf) {
-            Ok(_) => buf,
-            _ => "",
+        wrap: bool,
+    ) -> bool {
+        let TaskExtras {
+            cpupc,
+            age,
+            threads,
+            gpu,
+            depth,
+            user,
+        } = extras;
+        let (cmdline, comm) = match &ent.command {
+            Some((cmdline, comm)) => (cmdline.as_str(), comm.as_str()),
+            None => ("", ""),
         };
 
-        buf2.clear();
-        write!(buf2, "/proc/{}/task/{}/comm", taskid.0, taskid.0).unwrap();
-        let comm = match read_to_string(&buf2, buf3) {
-            Ok(_) => buf3.strip_suffix('\n').unwrap(),
-            _ => "",
+        /* With --show-unit, the unit stands for the whole command line. The unit of a container
+         * is an unreadable id, show which container before the command line instead. */
+        let (cmdline, comm) = match (settings.show_unit, &ent.unit, &ent.container) {
+            (true, Some(Some(u)), None) => (u.as_str(), u.as_str()),
+            _ => (cmdline, comm),
         };
 
         /* Format the cmdline: skip path of argv[0], split args by spaces */
-        let max_length = (settings.maxcols.get() - settings.colwidth.get() - 8).into();
+        let max_length = command_width(settings);
         let mut cmdline = cmdline.split('\0');
         let progname = cmdline.next().unwrap_or("");
         let progname = match progname.rsplit_once('/') {
@@ -266,14 +1243,92 @@ impl<'a> TaskStats<'a> {
             _ => progname,
         };
 
-        buf2.clear();
-        for arg in cmdline {
-            if buf2.len() >= max_length {
-                break;
+        /* Room left for args on the first line, after the prefixes and arg0. With --task-tree,
+         * two spaces per level. */
+        let depth = depth.min(max_length / 3);
+        let mut cmd_w = max_length.saturating_sub(2 * depth);
+        /* Where the task writes, eg. "→ /home " */
+        let arrow = match settings.smart.get() {
+            true => "→",
+            false => "->",
+        };
+        if let Some(m) = &ent.writes_to.0 {
+            cmd_w = cmd_w.saturating_sub(str_width(arrow) + str_width(m) + 2);
+        }
+        /* Before the command line, unless in its own column */
+        let gpu_prefix = match shown_fields(settings).any(|f| *f == TaskField::Gpu) {
+            true => None,
+            false => gpu,
+        };
+        if gpu_prefix.is_some() {
+            cmd_w = cmd_w.saturating_sub(11);
+        }
+        if let Some(Some(t)) = &ent.tag {
+            cmd_w = cmd_w.saturating_sub(str_width(t) + 3);
+        }
+        if let Some(c) = &ent.container {
+            cmd_w = cmd_w.saturating_sub(str_width(c) + 1);
+        }
+        /* "ppid 1234 " */
+        let zombie = settings.show_zombies && ent.state.0 == 'Z';
+        if zombie {
+            cmd_w = cmd_w.saturating_sub(ent.ppid.checked_ilog10().unwrap_or(0) as usize + 7);
+        }
+        /* What a task in D state waits on, eg. " [rq_qos_wait]". D state is rare, don't bother
+         * reusing a buffer. */
+        let mut wchan = String::new();
+        if ent.state.0 == 'D' {
+            buf2.clear();
+            write!(buf2, "/proc/{}/task/{}/wchan", taskid.0, taskid.0).unwrap();
+            if settings
+                .proc_root
+                .read_to_string(&buf2, &mut wchan)
+                .is_err()
+                || wchan.trim() == "0"
+            {
+                wchan.clear();
             }
-
-            /* Some half-assed shell-like escaping, should cover most cases, doesn't need to be
-             * perfect since it will be truncated anyway */
+        }
+        let wchan: String = wchan.trim().chars().take(max_length / 3).collect();
+        if !wchan.is_empty() {
+            cmd_w = cmd_w.saturating_sub(wchan.chars().count() + 3);
+        }
+        /* " stuck 2m30s" */
+        let stuck = match ent.state.0 == 'D' && ent.dstuck >= DSTUCK_REFRESHES {
+            true => format!(
+                " stuck {}",
+                HumanDuration(Duration::from_millis(ent.dstuck as u64 * settings.refresh))
+            ),
+            false => String::new(),
+        };
+        cmd_w = cmd_w.saturating_sub(stuck.len());
+        /* How likely the OOM killer is to pick this task; unreadable for kernel threads */
+        let oom = match shown_fields(settings).any(|f| *f == TaskField::Oom) {
+            true => {
+                buf2.clear();
+                write!(buf2, "/proc/{}/oom_score", taskid.0).unwrap();
+                let mut score = String::new();
+                match settings.proc_root.read_to_string(&buf2, &mut score) {
+                    Ok(_) => score.trim().parse::<u16>().ok(),
+                    Err(_) => None,
+                }
+            }
+            false => None,
+        };
+        let room = CommandLine(comm, progname, "").args_room(cmd_w);
+        let limit = if wrap { room + max_length } else { max_length };
+
+        /* When wrapping, end the first line after the last whole arg that fits, so quoted args
+         * aren't split */
+        let mut cut = 0;
+        buf2.clear();
+        for arg in cmdline {
+            if str_width(buf2) >= limit {
+                break;
+            }
+
+            /* Some half-assed shell-like escaping, should cover most cases, doesn't need to be
+             * perfect since it will be truncated anyway */
             match arg.contains(' ') {
                 false => write!(buf2, "{} ", arg).unwrap(),
                 true => match arg.contains('\'') {
@@ -282,195 +1337,628 @@ impl<'a> TaskStats<'a> {
                     true => write!(buf2, "'{}' ", arg.replace('\\', "\\'")).unwrap(),
                 },
             }
+            /* The trailing space can be truncated */
+            if str_width(buf2) <= room + 1 {
+                cut = buf2.len();
+            }
         }
 
-        let newline = MaybeSmart(Newline(), settings);
-        let w = settings.colwidth.get().into();
+        let wrapped = wrap && str_width(buf2.trim_end()) > room;
+        if !wrapped {
+            cut = buf2.len();
+        } else if cut == 0 {
+            /* Not even the first arg fits, split it */
+            cut = cells_prefix(buf2, room).len();
+        }
+        let (args, continuation) = buf2.split_at(cut);
+        /* Where the command line column starts */
+        let mut indent = 0;
+        let mut before_cmd = true;
 
-        write!(
-            out,
-            "{:>w$} {:1} {:>4} {:<max_length$}{}",
-            taskid.0,
-            MaybeSmart(ent.state, settings),
-            MaybeSmart(
-                Threshold {
-                    val: cpupc,
-                    med: CPUPercentage(40),
-                    high: CPUPercentage(60),
-                    crit: CPUPercentage(80),
+        for (i, field) in shown_fields(settings).enumerate() {
+            if i > 0 {
+                out.push(' ');
+            }
+
+            let w = field.width(settings);
+            if *field == TaskField::Cmd {
+                before_cmd = false;
+            } else if before_cmd {
+                indent += w + 1;
+            }
+            match field {
+                TaskField::Pid => write!(
+                    out,
+                    "{:>w$}",
+                    MaybeSmart(
+                        TaskPid {
+                            pid: taskid,
+                            fresh: ent.fresh,
+                            uid: ent.uid
+                        },
+                        settings
+                    )
+                )
+                .unwrap(),
+                TaskField::User => write!(out, "{:<w$.w$}", user).unwrap(),
+                TaskField::Tty => write!(out, "{:<w$}", ent.tty).unwrap(),
+                TaskField::State => {
+                    match !stuck.is_empty() && settings.smart.get() {
+                        /* Bold red, apart from the magenta of tasks only briefly in D state */
+                        true => write!(out, "\x1B[1;31m{}\x1B[0m", ent.state.0),
+                        false => write!(out, "{}", MaybeSmart(ent.state, settings)),
+                    }
+                    .unwrap();
+                    write!(out, "{:1$}", MaybeSmart(ent.policy, settings), w - 1).unwrap()
+                }
+                TaskField::Cpu if ent.cpu_unknown => write!(out, "{:>w$}", "?").unwrap(),
+                TaskField::Cpu => write!(
+                    out,
+                    "{:>w$}",
+                    MaybeSmart(
+                        Threshold {
+                            val: cpupc,
+                            med: CPUPercentage(settings.task_cpu_thresholds.0),
+                            high: CPUPercentage(settings.task_cpu_thresholds.1),
+                            crit: CPUPercentage(settings.task_cpu_thresholds.2),
+                        },
+                        settings
+                    ),
+                )
+                .unwrap(),
+                TaskField::Throttle => match ent.throttle {
+                    Some(pc) => write!(
+                        out,
+                        "{:>w$}",
+                        MaybeSmart(
+                            Threshold {
+                                val: pc,
+                                med: CPUPercentage(5),
+                                high: CPUPercentage(20),
+                                crit: CPUPercentage(50),
+                            },
+                            settings
+                        ),
+                    )
+                    .unwrap(),
+                    /* No CPU limit, or not on cgroup v2 */
+                    None => write!(out, "{:w$}", "").unwrap(),
                 },
-                settings
-            ),
-            MaybeSmart(CommandLine(comm, progname, buf2), settings),
-            newline
-        )
-        .unwrap();
-    }
+                TaskField::LastCpu => write!(out, "{:>w$}", ent.last_cpu).unwrap(),
+                TaskField::Wait => match ent.wait {
+                    Some(wait) => write!(
+                        out,
+                        "{:>w$}",
+                        MaybeSmart(
+                            Threshold {
+                                val: wait,
+                                med: CPUPercentage(10),
+                                high: CPUPercentage(25),
+                                crit: CPUPercentage(50),
+                            },
+                            settings
+                        ),
+                    )
+                    .unwrap(),
+                    None => write!(out, "{:w$}", "").unwrap(),
+                },
+                TaskField::Rss => write!(out, "{:>w$}", Bytes(ent.rss)).unwrap(),
+                /* Blank without any GPU memory */
+                TaskField::Gpu => match gpu {
+                    Some(b) => write!(out, "{:>w$}", Bytes(b)).unwrap(),
+                    None => write!(out, "{:w$}", "").unwrap(),
+                },
+                TaskField::Age => write!(out, "{:w$}", HumanDuration(age)).unwrap(),
+                /* Quiet for single-threaded programs */
+                TaskField::Threads => match threads {
+                    0 | 1 => write!(out, "{:w$}", "").unwrap(),
+                    n => write!(out, "{:>w$}", n).unwrap(),
+                },
+                TaskField::Oom => match oom {
+                    Some(score) => write!(
+                        out,
+                        "{:>w$}",
+                        MaybeSmart(
+                            Threshold {
+                                val: score,
+                                med: 400,
+                                high: 600,
+                                crit: 800,
+                            },
+                            settings
+                        ),
+                    )
+                    .unwrap(),
+                    None => write!(out, "{:>w$}", ".").unwrap(),
+                },
+                TaskField::Read => {
+                    let rate = ent.io_rates.map(|r| r.0).unwrap_or_default();
+                    write!(out, "{:>w$}", rate).unwrap()
+                }
+                TaskField::Write => {
+                    let rate = ent.io_rates.map(|r| r.1).unwrap_or_default();
+                    write!(out, "{:>w$}", rate).unwrap()
+                }
+                /* Until there are two samples */
+                TaskField::Csw => match ent.csw_rate {
+                    Some(r) => write!(out, "{:>w$}", r).unwrap(),
+                    None => write!(out, "{:>w$}", ".").unwrap(),
+                },
+                TaskField::Cmd => {
+                    write!(out, "{:1$}", "", 2 * depth).unwrap();
+                    if let Some(Some(t)) = &ent.tag {
+                        write!(out, "[{}] ", t).unwrap();
+                    }
+                    if let Some(c) = &ent.container {
+                        write!(out, "{} ", MaybeSmart(Container(c), settings)).unwrap();
+                    }
+                    if zombie {
+                        write!(out, "ppid {} ", ent.ppid).unwrap();
+                    }
+                    if let Some(m) = &ent.writes_to.0 {
+                        write!(out, "{} {} ", arrow, m).unwrap();
+                    }
+                    if let Some(b) = gpu_prefix {
+                        /* "GPU  512.0M " */
+                        write!(out, "GPU {:6.1} ", Bytes(b)).unwrap();
+                    }
 
-    fn open_task_stat(t: Pid, buf: &mut String) -> Option<FileDescriptor> {
-        buf.clear();
-        write!(buf, "/proc/{}/task/{}/stat\x00", t.0, t.0).unwrap();
-        let cstr = std::ffi::CStr::from_bytes_with_nul(buf.as_bytes()).unwrap();
-        let fd = unsafe { libc::open(cstr.as_ptr(), libc::O_RDONLY) };
-        if fd == -1 {
-            unsafe {
-                if *libc::__errno_location() == libc::ENOENT {
-                    // Task is done, this is fine
-                    return None;
+                    write!(
+                        out,
+                        "{:<cmd_w$}",
+                        MaybeSmart(CommandLine(comm, progname, args), settings)
+                    )
+                    .unwrap();
+                    if !wchan.is_empty() {
+                        write!(out, " [{}]", wchan).unwrap();
+                    }
+                    out.push_str(&stuck);
                 }
             }
-            dbg!(cstr);
-            libc_panic("open()");
         }
-        Some(FileDescriptor(fd))
-    }
-}
 
-impl<'a> StatBlock<'a> for TaskStats<'a> {
-    fn new(s: &'a Settings) -> Self {
-        let mut ts = TaskStats {
-            settings: s,
-            user_hz: unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u16,
-            buf: String::new(),
-            buf2: String::new(),
-            buf3: String::new(),
-            bufp: Default::default(),
-            bufstat: [0; 512],
-            tasks: FnvHashMap::default(),
-            sorted: BinaryHeap::new(),
-            relevant: Default::default(),
-            maxtasks: 10,
-            uptime: 0,
-            since_uptime: Instant::now(),
-            max_fds: unsafe {
-                let mut n = std::mem::MaybeUninit::<libc::rlimit>::uninit();
-                libc::getrlimit(libc::RLIMIT_NOFILE, n.as_mut_ptr());
-                let n = n.assume_init();
-                n.rlim_cur.saturating_sub(10)
-            },
-        };
-        ts.update();
-        ts
-    }
+        write!(out, "{}", MaybeSmart(Newline(), settings)).unwrap();
 
-    fn update(&mut self) {
-        /* Measure and store jiffies of each task in self.tasks */
-        for t in self.tasks.values_mut() {
-            t.stale = Stale(true);
+        if wrapped {
+            let trailing = (settings.maxcols.get() as usize).saturating_sub(indent + max_length);
+            let (pre, post) = match settings.smart.get() {
+                false => ("", ""),
+                true => ("\x1B[2m", "\x1B[0m"),
+            };
+            write!(
+                out,
+                "{:indent$}{}{:max_length$}{}{:trailing$}{}",
+                "",
+                pre,
+                Cells(continuation),
+                post,
+                "",
+                MaybeSmart(Newline(), settings)
+            )
+            .unwrap();
         }
+        wrapped
+    }
 
-        self.since_uptime = Instant::now();
-        /* /proc/uptime is never exposed to user data */
-        unsafe { read_to_string_unchecked("/proc/uptime", &mut self.buf) }.unwrap();
-        self.uptime = (self
-            .buf
-            .split_ascii_whitespace()
-            .next()
-            .unwrap()
-            .parse::<f32>()
-            .unwrap()
-            * 100.0) as u64
-            * self.user_hz as u64
-            / 100;
-
-        map_tasks(&mut self.bufp, |taskid| {
-            let uptime = self.uptime
-                + self.since_uptime.elapsed().as_millis() as u64 * self.user_hz as u64 / 1000;
-
-            let mut ent = match self.tasks.get_mut(&taskid) {
-                Some(e) => e,
-                _ => {
-                    let z = TaskEntry {
-                        filedes: if self.tasks.len() < self.max_fds as usize {
-                            Self::open_task_stat(taskid, &mut self.buf)
-                        } else {
-                            None
+    /// Format a group of tasks as one line: the pid of its busiest member, and sums of the others
+    fn format_group(
+        settings: &Settings,
+        out: &mut String,
+        leader: Pid,
+        comm: &str,
+        g: &TaskGroup,
+        cpupc: CPUPercentage,
+    ) {
+        for (i, field) in shown_fields(settings).enumerate() {
+            if i > 0 {
+                out.push(' ');
+            }
+
+            let w = field.width(settings);
+            match field {
+                TaskField::Pid => write!(out, "{:>w$}", leader.0).unwrap(),
+                /* Members can belong to different users */
+                TaskField::User => write!(out, "{:w$}", "").unwrap(),
+                /* Members can have different scheduling policies */
+                TaskField::State => {
+                    write!(out, "{}{:2$}", MaybeSmart(g.state, settings), "", w - 1).unwrap()
+                }
+                TaskField::Cpu => write!(
+                    out,
+                    "{:>w$}",
+                    MaybeSmart(
+                        Threshold {
+                            val: cpupc,
+                            med: CPUPercentage(settings.task_cpu_thresholds.0),
+                            high: CPUPercentage(settings.task_cpu_thresholds.1),
+                            crit: CPUPercentage(settings.task_cpu_thresholds.2),
                         },
-                        jiffies: (Jiffies(0, 0), Jiffies(0, 0)),
-                        state: TaskState('?'),
-                        stale: Stale(false),
+                        settings
+                    ),
+                )
+                .unwrap(),
+                /* Members can run on different CPUs, or terminals */
+                TaskField::LastCpu | TaskField::Tty | TaskField::Wait => {
+                    write!(out, "{:w$}", "").unwrap()
+                }
+                TaskField::Rss => write!(out, "{:>w$}", Bytes(g.rss)).unwrap(),
+                TaskField::Gpu => match g.gpu {
+                    0 => write!(out, "{:w$}", "").unwrap(),
+                    b => write!(out, "{:>w$}", Bytes(b)).unwrap(),
+                },
+                /* Members can be of different ages */
+                TaskField::Age | TaskField::Oom => write!(out, "{:w$}", "").unwrap(),
+                /* Threads of all members */
+                TaskField::Threads => match g.tasks {
+                    0 | 1 => write!(out, "{:w$}", "").unwrap(),
+                    n => write!(out, "{:>w$}", n).unwrap(),
+                },
+                /* Only read for shown tasks */
+                TaskField::Read | TaskField::Write | TaskField::Csw | TaskField::Throttle => {
+                    write!(out, "{:w$}", "").unwrap()
+                }
+                TaskField::Cmd => {
+                    /* "firefox ×23" */
+                    let n = match g.processes {
+                        0 | 1 => g.tasks,
+                        p => p,
                     };
-                    self.tasks.insert(taskid, z);
-                    self.tasks.get_mut(&taskid).unwrap()
+                    let count = n.checked_ilog10().unwrap_or(0) as usize + 3;
+                    let len = w.saturating_sub(count).min(str_width(comm));
+                    let pad = w.saturating_sub(len + count);
+                    match settings.smart.get() {
+                        false => write!(out, "{:len$} ×{}{:pad$}", Cells(comm), n, "").unwrap(),
+                        true => write!(out, "\x1B[1m{:len$}\x1B[0m ×{}{:pad$}", Cells(comm), n, "")
+                            .unwrap(),
+                    }
                 }
-            };
+            }
+        }
 
-            let stat;
-            let must_close = ent.filedes.is_none();
-            if must_close {
-                ent.filedes = Self::open_task_stat(taskid, &mut self.buf);
-                if ent.filedes.is_none() {
-                    return;
-                }
+        write!(out, "{}", MaybeSmart(Newline(), settings)).unwrap();
+    }
+
+    /// Format a --watch-pid task that no longer exists: "1234 gone"
+    fn format_gone(settings: &Settings, out: &mut String, pid: Pid) {
+        for (i, field) in shown_fields(settings).enumerate() {
+            if i > 0 {
+                out.push(' ');
             }
-            unsafe {
-                let ret = libc::read(
-                    ent.filedes.as_ref().unwrap().0,
-                    self.bufstat.as_mut_slice().as_mut_ptr() as *mut libc::c_void,
-                    511, // Leave 1 byte for the final \0
-                );
-                if ret == -1 {
-                    // XXX: -EIO with "No such process" sometimes happens, how to handle?
-                    return;
-                }
 
-                // The stat file contains only numbers, except for the process name (truncated to 16
-                // chars) which is inbetween parentheses. Skip over the process name to avoid
-                // checking for valid utf-8.
-                let mut i = ret as usize - 96; // XXX: 48 fields of at least 2 chars after comm
-                while self.bufstat[i] != b')' {
-                    i -= 1;
-                }
-                stat = std::str::from_utf8_unchecked(&self.bufstat[(i + 1)..]);
+            let w = field.width(settings);
+            match field {
+                TaskField::Pid => write!(out, "{:>w$}", pid.0).unwrap(),
+                TaskField::Cmd => write!(out, "{:<w$}", "gone").unwrap(),
+                _ => write!(out, "{:w$}", "").unwrap(),
             }
-            if must_close {
-                ent.filedes = None;
-            } else {
-                unsafe {
-                    if libc::lseek(ent.filedes.as_ref().unwrap().0, 0, libc::SEEK_SET) != 0 {
-                        libc_panic("lseek()");
-                    }
+        }
+
+        write!(out, "{}", MaybeSmart(Newline(), settings)).unwrap();
+    }
+
+    /// Detect many tasks piling up in D state, most likely waiting on the same resource
+    fn update_pileup(&mut self) {
+        let root = &self.settings.proc_root;
+        let count = |s| self.tasks.values().filter(|t| t.state.0 == s).count() as u16;
+        let states = TaskStateCounts {
+            running: count('R'),
+            dstate: count('D'),
+            zombie: count('Z'),
+            total: self.tasks.len() as u32,
+            fresh: self.tasks.values().filter(|t| t.fresh).count() as u32,
+            spawned: self.spawned,
+        };
+        self.states = states;
+
+        self.dstate.0 = self.dstate.1;
+        self.dstate.1 = states.dstate;
+
+        if !pileup_raised(self.dstate, self.settings.dstate_threshold) {
+            self.pileup = None;
+            return;
+        }
+
+        /* Find the most common comm and wait channel among stuck tasks */
+        let mut comms = FnvHashMap::<String, u16>::default();
+        let mut wchans = FnvHashMap::<String, u16>::default();
+        for (taskid, _) in self.tasks.iter().filter(|(_, t)| t.state.0 == 'D') {
+            for (file, counts) in [("comm", &mut comms), ("wchan", &mut wchans)] {
+                self.buf2.clear();
+                write!(self.buf2, "/proc/{}/task/{}/{}", taskid.0, taskid.0, file).unwrap();
+                if root.read_to_string(&self.buf2, &mut self.buf).is_err() {
+                    continue;
+                }
+                /* A wchan of 0 means running, or hidden by kptr_restrict */
+                match self.buf.trim_end_matches('\n') {
+                    "" | "0" => (),
+                    v => count_name(counts, v),
                 }
             }
+        }
+        self.pileup = Some((
+            self.dstate.1,
+            most_common(comms).unwrap_or_default(),
+            most_common(wchans).unwrap_or_default(),
+        ));
+    }
 
-            /* See https://www.kernel.org/doc/html/latest/filesystems/proc.html table 1-4 */
-            /* And proc(5) */
-            let mut stat = stat.split_ascii_whitespace();
-            let state = TaskState(stat.next().unwrap().chars().next().unwrap());
-            let used_jiffies = stat.nth(10).unwrap().parse::<u64>().unwrap()
-                + stat.next().unwrap().parse::<u64>().unwrap();
+    /// With too many zombies, find the parent of most of them
+    fn update_reaper(&mut self) {
+        let root = &self.settings.proc_root;
+        let zombies = self.states.zombie;
+        if zombies <= ZOMBIE_THRESHOLD {
+            self.reaper = None;
+            return;
+        }
 
-            if ent.stale == Stale(false) {
-                // This task was just created, fetch its start_time
-                ent.jiffies.1 .1 = stat.nth(6).unwrap().parse::<u64>().unwrap();
+        let mut parents = FnvHashMap::<u32, u16>::default();
+        for t in self.tasks.values().filter(|t| t.state.0 == 'Z') {
+            *parents.entry(t.ppid).or_default() += 1;
+        }
+        let ppid = match most_common(parents) {
+            Some(ppid) => ppid,
+            None => {
+                self.reaper = None;
+                return;
             }
+        };
 
-            ent.jiffies.0 = ent.jiffies.1;
-            ent.jiffies.1 = Jiffies(used_jiffies, uptime);
-            ent.state = state;
-            ent.stale = Stale(false);
-        });
-        self.tasks.retain(|_, t| t.stale == Stale(false));
+        self.buf2.clear();
+        write!(self.buf2, "/proc/{}/comm", ppid).unwrap();
+        let comm = match root.read_to_string(&self.buf2, &mut self.buf) {
+            Ok(_) => self.buf.trim_end_matches('\n'),
+            Err(_) => "?",
+        };
+        self.reaper = Some((zombies, ppid, String::from(comm)));
+    }
+
+    /// With --hide-self, sum the CPU usage of all threads of hitome
+    fn update_self_cpu(&mut self) {
+        if !self.settings.hide_self {
+            return;
+        }
+        let pc = self
+            .tasks
+            .values()
+            .filter(|t| t.process == self.self_pid && t.jiffies.0 .1 < t.jiffies.1 .1)
+            .map(|t| t.cpu_percentage().0)
+            .fold(0u16, |a, b| a.saturating_add(b));
+        self.self_cpu = Some(CPUPercentage(pc));
+    }
+
+    /// Lines printed above the task list heading, each one takes the place of a task
+    fn alerts(&self) -> u16 {
+        self.pileup.is_some() as u16 + self.reaper.is_some() as u16
+    }
+
+    /// Sort and format the most relevant tasks, without reading their stats again
+    pub fn select_tasks(&mut self) {
+        self.sort_tasks();
+        /* Newly shown tasks have no command line yet */
+        self.update_commands();
+        self.format_tasks();
+    }
 
-        /* Sort tasks by state/cpu% */
+    /// Pick the most relevant tasks, in order
+    fn sort_tasks(&mut self) {
+        /* Sort tasks by state/key */
         self.sorted.clear();
-        for (pid, task) in self.tasks.iter() {
+        self.groups.clear();
+        let by = self.sort_key;
+        let root = &self.settings.proc_root;
+        for (pid, task) in self.tasks.iter_mut() {
             if task.jiffies.0 .1 >= task.jiffies.1 .1 {
                 continue;
             }
+            if task.kthread && !self.show_kthreads {
+                continue;
+            }
+            if self.settings.hide_self && task.process == self.self_pid {
+                continue;
+            }
+            /* Zombies don't run, but may still look busy in the refresh they died */
+            if task.state.0 == 'Z' {
+                continue;
+            }
+            if !self.filter.is_empty()
+                && !Self::task_matches(
+                    root,
+                    &self.filter,
+                    &mut self.buf,
+                    &mut self.buf2,
+                    *pid,
+                    task,
+                )
+            {
+                continue;
+            }
+            let key = match by {
+                TaskSortKey::State | TaskSortKey::Cpu => task.cpu_percentage().0 as u64,
+                TaskSortKey::Memory => task.rss,
+            };
+            if !self.settings.group_tasks {
+                self.sorted.push((
+                    TaskSort::new(by, task.state, task.dstuck, key, task.jiffies.1 .0),
+                    *pid,
+                ));
+                continue;
+            }
+
+            let g = match self.groups.get_mut(&task.comm) {
+                Some(g) => g,
+                None => {
+                    self.groups.insert(
+                        task.comm.clone(),
+                        TaskGroup {
+                            leader: (*pid, key),
+                            tasks: 0,
+                            processes: 0,
+                            state: task.state,
+                            dstuck: 0,
+                            cpu: CPUPercentage(0),
+                            jiffies: 0,
+                            rss: 0,
+                            gpu: 0,
+                        },
+                    );
+                    self.groups.get_mut(&task.comm).unwrap()
+                }
+            };
+            if key > g.leader.1 {
+                g.leader = (*pid, key);
+            }
+            if task.state.rank() > g.state.rank() {
+                g.state = task.state;
+            }
+            g.dstuck = g.dstuck.max(task.dstuck);
+            g.tasks += 1;
+            g.cpu.0 = g.cpu.0.saturating_add(task.cpu_percentage().0);
+            g.jiffies += task.jiffies.1 .0;
+            /* Threads share the memory of their process */
+            if task.leader {
+                g.processes += 1;
+                g.rss += task.rss;
+                g.gpu += self.gpu_memory.get(&pid.0).unwrap_or(&0);
+            }
+        }
+
+        for g in self.groups.values() {
+            let key = match by {
+                TaskSortKey::State | TaskSortKey::Cpu => g.cpu.0 as u64,
+                TaskSortKey::Memory => g.rss,
+            };
             self.sorted.push((
-                TaskSort(
-                    task.state,
-                    CPUPercentage(
-                        (100 * (task.jiffies.1 .0 - task.jiffies.0 .0)
-                            / (task.jiffies.1 .1 - task.jiffies.0 .1))
-                            as u8,
-                    ),
-                ),
-                *pid,
+                TaskSort::new(by, g.state, g.dstuck, key, g.jiffies),
+                g.leader.0,
             ));
         }
 
+        /* Each alert line takes the place of a task */
+        let maxtasks = self.maxtasks.saturating_sub(self.alerts()) as usize;
+
+        /* Watched tasks come first, whatever their state and filters */
+        self.shown.clear();
+        self.gone.clear();
+        for pid in self.settings.watch_pids.iter().map(|p| Pid(*p)) {
+            let task = match self.tasks.get(&pid) {
+                Some(t) => t,
+                None => {
+                    self.gone.push(pid);
+                    continue;
+                }
+            };
+            if task.jiffies.0 .1 >= task.jiffies.1 .1 {
+                /* Just appeared, its CPU usage is not known yet */
+                continue;
+            }
+            /* Show the group it belongs to instead, if any */
+            let id = match self.groups.get(&task.comm) {
+                Some(g) if self.settings.group_tasks => g.leader.0,
+                _ => pid,
+            };
+            if !self.shown.contains(&id) {
+                self.shown.push(id);
+            }
+        }
+        self.gone.truncate(maxtasks);
+        self.shown.truncate(maxtasks - self.gone.len());
+
+        /* Zombies come last, but their rows are set aside before picking the busiest tasks */
+        self.zombies.clear();
+        if self.settings.show_zombies {
+            let room = maxtasks - self.gone.len() - self.shown.len();
+            self.zombies.extend(
+                self.tasks
+                    .iter()
+                    .filter(|(pid, t)| {
+                        t.state.0 == 'Z'
+                            && t.jiffies.0 .1 < t.jiffies.1 .1
+                            && !self.shown.contains(pid)
+                    })
+                    .map(|(pid, _)| *pid)
+                    .take(room),
+            );
+            self.zombies.sort();
+        }
+
+        while self.gone.len() + self.shown.len() + self.zombies.len() < maxtasks {
+            let (tasksort, taskid) = match self.sorted.pop() {
+                Some(x) => x,
+                _ => break,
+            };
+            if tasksort.3 .0 == 'S' && tasksort.1 == 0 {
+                /* Ran out of interesting tasks */
+                break;
+            }
+            if !self.shown.contains(&taskid) {
+                self.shown.push(taskid);
+            }
+        }
+        self.arrange_tree(maxtasks - self.gone.len() - self.zombies.len());
+        self.shown.append(&mut self.zombies);
+    }
+
+    /// With --task-tree, add the parents shared by several shown tasks (eg. make and its
+    /// compilers) while there is room, then order shown depth first, children under their parent
+    fn arrange_tree(&mut self, room: usize) {
+        self.depth.clear();
+        if !self.settings.task_tree || self.settings.group_tasks {
+            return;
+        }
+
+        let parent = |tasks: &FnvHashMap<Pid, TaskEntry>, pid: &Pid| tasks.get(pid).map(|t| t.ppid);
+        let mut i = 0;
+        while i < self.shown.len() && self.shown.len() < room {
+            /* init and kthreadd are everyone's parents, no need to say so */
+            let ppid = match parent(&self.tasks, &self.shown[i]) {
+                Some(p) if p > 2 && self.tasks.contains_key(&Pid(p)) => Pid(p),
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            let siblings = self
+                .shown
+                .iter()
+                .filter(|s| parent(&self.tasks, s) == Some(ppid.0))
+                .count();
+            if siblings >= 2 && !self.shown.contains(&ppid) {
+                /* Right before its busiest child */
+                self.shown.insert(i, ppid);
+            }
+            i += 1;
+        }
+
+        let parents: Vec<Option<Pid>> = self
+            .shown
+            .iter()
+            .map(|s| {
+                parent(&self.tasks, s)
+                    .map(Pid)
+                    .filter(|p| p != s && self.shown.contains(p))
+            })
+            .collect();
+        /* Roots, then children, keeping the order of sort_tasks() among siblings */
+        let mut stack: Vec<(usize, u8)> = (0..self.shown.len())
+            .rev()
+            .filter(|i| parents[*i].is_none())
+            .map(|i| (i, 0))
+            .collect();
+        let mut order = Vec::with_capacity(self.shown.len());
+        while let Some((i, d)) = stack.pop() {
+            order.push(self.shown[i]);
+            self.depth.push(d);
+            for c in (0..self.shown.len()).rev() {
+                if parents[c] == Some(self.shown[i]) {
+                    stack.push((c, d.saturating_add(1)));
+                }
+            }
+        }
+        self.shown = order;
+    }
+
+    /// Format the tasks chosen by sort_tasks()
+    fn format_tasks(&mut self) {
         for s in self.relevant.iter_mut() {
             s.clear();
         }
@@ -483,54 +1971,1882 @@ impl<'a> StatBlock<'a> for TaskStats<'a> {
             }
         }
 
-        /* Format the most important tasks */
-        for i in 0..(self.maxtasks as usize) {
-            let (tasksort, taskid) = match self.sorted.pop() {
-                Some(x) => x,
-                _ => break,
-            };
-            if tasksort.0 .0 == 'S' && tasksort.1 .0 == 0 {
-                /* Ran out of interesting tasks */
+        /* sort_tasks() left room for them */
+        for (i, pid) in self.gone.iter().enumerate() {
+            Self::format_gone(self.settings, &mut self.relevant[i], *pid);
+        }
+        let first = self.gone.len();
+
+        /* Format the most important tasks, each wrapped one takes a row from the next ones. With
+         * --tasks, that many are shown whether they wrap or not, main() left room for both rows. */
+        let fixed = self.settings.tasks.is_some();
+        let mut rows_left = self.maxtasks.saturating_sub(self.alerts() + first as u16);
+        let mut n = 0;
+        self.wrapped = 0;
+        for (i, taskid) in self.shown.iter().enumerate() {
+            let i = first + i;
+            if rows_left == 0 {
                 break;
             }
-            let ent = self.tasks.get(&taskid).unwrap();
-            Self::format_task(
+            let ent = self.tasks.get(taskid).unwrap();
+            let zombie = self.settings.show_zombies && ent.state.0 == 'Z';
+            if let Some(g) = self
+                .groups
+                .get(&ent.comm)
+                .filter(|g| g.tasks > 1 && !zombie)
+            {
+                Self::format_group(
+                    self.settings,
+                    &mut self.relevant[i],
+                    *taskid,
+                    &ent.comm,
+                    g,
+                    g.cpu.shown(self.settings, self.cpus),
+                );
+                rows_left -= 1;
+                n += 1;
+                continue;
+            }
+            let user = ent.uid.and_then(|u| self.users.get(&u));
+            let extras = TaskExtras {
+                cpupc: ent.cpu_percentage().shown(self.settings, self.cpus),
+                age: Duration::from_secs(
+                    self.uptime.saturating_sub(ent.start_time) / self.user_hz as u64,
+                ),
+                threads: self.threads.get(&ent.process).copied().unwrap_or(1),
+                gpu: self.gpu_memory.get(&taskid.0).copied(),
+                depth: self.depth.get(i - first).map_or(0, |d| *d as usize),
+                user: user.map_or("", |u| u.as_str()),
+            };
+            let wrapped = Self::format_task(
                 self.settings,
-                &mut self.buf,
                 &mut self.buf2,
-                &mut self.buf3,
                 &mut self.relevant[i],
-                taskid,
-                tasksort.1,
+                *taskid,
+                extras,
                 ent,
+                self.settings.task_wrap && (rows_left >= 2 || fixed),
             );
+            self.wrapped += wrapped as u16;
+            rows_left -= 1 + (wrapped && !fixed) as u16;
+            n += 1;
         }
+        self.shown.truncate(n);
+
+        /* Let the CPU block mark where the busiest task runs */
+        self.hot_cpu = match shown_fields(self.settings).any(|f| *f == TaskField::LastCpu) {
+            true => self
+                .shown
+                .iter()
+                .map(|p| self.tasks.get(p).unwrap())
+                .filter(|t| t.state.0 != 'Z')
+                .max_by_key(|t| t.cpu_percentage())
+                .filter(|t| t.cpu_percentage().0 >= HOT_TASK_MIN_CPU)
+                .map(|t| t.last_cpu),
+            false => None,
+        };
     }
 
-    fn columns(&self) -> u16 {
-        self.settings.maxcols.get()
+    /// The value of an environment variable of a task, from the first bytes of its environ
+    /// (environments can be large), truncated to max_len characters. None if it is not set, or
+    /// permission is denied, which is likely. An error if it wasn't read from the remote machine
+    /// yet.
+    fn environ_var(
+        root: &ProcRoot,
+        bufenv: &mut [u8],
+        path: &str,
+        var: &str,
+        max_len: usize,
+    ) -> std::io::Result<Option<String>> {
+        /* Short reads can happen, read until full or EOF */
+        let mut n = 0;
+        if !root.is_local() {
+            let mut env = String::new();
+            match root.read_to_string(path, &mut env) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Err(e),
+                Err(_) => (),
+                Ok(_) => {
+                    n = env.len().min(bufenv.len());
+                    bufenv[..n].copy_from_slice(&env.as_bytes()[..n]);
+                }
+            }
+        } else if let Ok(mut f) = std::fs::File::open(path) {
+            while n < bufenv.len() {
+                match f.read(&mut bufenv[n..]) {
+                    Ok(0) | Err(_) => break,
+                    Ok(r) => n += r,
+                }
+            }
+        }
+
+        /* When the buffer is full, the last variable was cut off: don't show half of its value */
+        let end = match n == bufenv.len() {
+            true => bufenv.iter().rposition(|b| *b == 0).map_or(0, |i| i + 1),
+            false => n,
+        };
+
+        Ok(bufenv[..end]
+            .split(|b| *b == 0)
+            .find_map(|kv| {
+                kv.strip_prefix(var.as_bytes())
+                    .and_then(|v| v.strip_prefix(b"="))
+            })
+            .map(|v| String::from_utf8_lossy(v).chars().take(max_len).collect()))
     }
 
-    fn rows(&self) -> u16 {
-        1 + self.maxtasks
+    /// For shown tasks, look for the --task-tag variable in their environment. If permission is
+    /// denied, don't try again.
+    fn update_tags(&mut self) {
+        let var = match &self.settings.task_tag {
+            Some(v) => v,
+            None => return,
+        };
+
+        for taskid in self.shown.iter() {
+            let ent = self.tasks.get_mut(taskid).unwrap();
+            if ent.tag.is_some() {
+                continue;
+            }
+
+            self.buf2.clear();
+            write!(self.buf2, "/proc/{}/task/{}/environ", taskid.0, taskid.0).unwrap();
+            let root = &self.settings.proc_root;
+            if let Ok(tag) =
+                Self::environ_var(root, &mut self.bufenv[..], &self.buf2, var, TAG_MAX_LEN)
+            {
+                ent.tag = Some(tag);
+            }
+        }
     }
-}
 
-impl<'a> fmt::Display for TaskStats<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{} {:1} {:4} {:<}{}",
-            MaybeSmart(Heading("PID"), self.settings),
-            MaybeSmart(Heading("S"), self.settings),
-            MaybeSmart(Heading("CPU%"), self.settings),
-            MaybeSmart(Heading("COMMAND"), self.settings),
-            MaybeSmart(Newline(), self.settings)
-        )?;
+    /// For shown tasks, read their command line the first time, or again if their comm changed.
+    /// XXX: misses tasks rewriting their own command line without exec(), eg. with
+    /// setproctitle()
+    fn update_commands(&mut self) {
+        if !self.settings.task_fields.contains(&TaskField::Cmd) {
+            return;
+        }
 
-        for s in self.relevant.iter() {
-            f.write_str(s)?;
+        for taskid in self.shown.iter() {
+            let ent = self.tasks.get_mut(taskid).unwrap();
+            self.buf2.clear();
+            write!(self.buf2, "/proc/{}/task/{}/comm", taskid.0, taskid.0).unwrap();
+            let root = &self.settings.proc_root;
+            if root.read_to_string(&self.buf2, &mut self.buf3).is_err() {
+                /* Probably gone, keep what was read before */
+                continue;
+            }
+            let comm = self.buf3.trim_end_matches('\n');
+            if ent.command.as_ref().is_some_and(|(_, c)| c == comm) {
+                continue;
+            }
+
+            self.buf2.clear();
+            write!(self.buf2, "/proc/{}/task/{}/cmdline", taskid.0, taskid.0).unwrap();
+            match root.read_to_string(&self.buf2, &mut self.buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Err(_) => self.buf.clear(),
+                Ok(_) => (),
+            }
+            ent.command = Some((self.buf.clone(), String::from(comm)));
         }
-        Ok(())
+    }
+
+    /// For shown tasks, find out which cgroup they belong to
+    fn update_units(&mut self) {
+        if !self.settings.show_unit {
+            return;
+        }
+
+        for taskid in self.shown.iter() {
+            let ent = self.tasks.get_mut(taskid).unwrap();
+            if ent.unit.is_some() {
+                continue;
+            }
+
+            self.buf2.clear();
+            write!(self.buf2, "/proc/{}/task/{}/cgroup", taskid.0, taskid.0).unwrap();
+            let root = &self.settings.proc_root;
+            ent.unit = Some(match root.read_to_string(&self.buf2, &mut self.buf) {
+                Ok(_) => cgroup_leaf(&self.buf),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Err(_) => None,
+            });
+
+            /* Kubernetes sets HOSTNAME to the name of the pod, docker to the short id by default */
+            let id = match ent.unit.as_ref().unwrap().as_deref().and_then(container_id) {
+                Some(id) => id,
+                None => continue,
+            };
+            self.buf2.clear();
+            write!(self.buf2, "/proc/{}/task/{}/environ", taskid.0, taskid.0).unwrap();
+            let hostname = match Self::environ_var(
+                root,
+                &mut self.bufenv[..],
+                &self.buf2,
+                "HOSTNAME",
+                CONTAINER_MAX_LEN,
+            ) {
+                Ok(h) => h,
+                /* Both are read again next time */
+                Err(_) => {
+                    ent.unit = None;
+                    continue;
+                }
+            };
+            ent.container = Some(
+                hostname
+                    .filter(|h| !h.is_empty())
+                    .unwrap_or_else(|| String::from(id)),
+            );
+        }
+    }
+
+    /// For shown tasks, find out who they belong to
+    fn update_users(&mut self) {
+        /* Also needed to colour PIDs */
+        let colours = self.settings.task_colours && self.settings.smart.get();
+        if !(colours || self.settings.task_fields.contains(&TaskField::User)) {
+            return;
+        }
+
+        let root = &self.settings.proc_root;
+        for taskid in self.shown.iter() {
+            let ent = self.tasks.get_mut(taskid).unwrap();
+            if !root.is_local() {
+                /* The effective uid, as owns /proc/pid */
+                self.buf2.clear();
+                write!(self.buf2, "/proc/{}/task/{}/status", taskid.0, taskid.0).unwrap();
+                ent.uid = match root.read_to_string(&self.buf2, &mut self.buf) {
+                    Ok(_) => self
+                        .buf
+                        .lines()
+                        .find_map(|l| l.strip_prefix("Uid:"))
+                        .and_then(|v| v.split_ascii_whitespace().nth(1)?.parse().ok()),
+                    Err(_) => None,
+                };
+            } else {
+                let mut st = std::mem::MaybeUninit::<libc::stat>::uninit();
+                let ret = match &ent.filedes {
+                    Some(fd) => unsafe { libc::fstat(fd.0, st.as_mut_ptr()) },
+                    None => {
+                        self.buf2.clear();
+                        write!(self.buf2, "/proc/{}/task/{}\x00", taskid.0, taskid.0).unwrap();
+                        let path =
+                            std::ffi::CStr::from_bytes_with_nul(self.buf2.as_bytes()).unwrap();
+                        unsafe { libc::stat(path.as_ptr(), st.as_mut_ptr()) }
+                    }
+                };
+                ent.uid = match ret {
+                    0 => Some(unsafe { st.assume_init() }.st_uid),
+                    _ => None,
+                };
+            }
+
+            let uid = match ent.uid {
+                Some(uid) if !self.users.contains_key(&uid) => uid,
+                _ => continue,
+            };
+            let name = match root.is_local() {
+                true => user_name(uid),
+                false => match root.read_to_string("/etc/passwd", &mut self.buf) {
+                    Ok(_) => passwd_name(&self.buf, uid),
+                    /* Looked up again next time */
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                    Err(_) => None,
+                },
+            };
+            self.users
+                .insert(uid, name.unwrap_or_else(|| uid.to_string()));
+        }
+    }
+
+    /// For shown tasks, measure how much they read from and wrote to block devices since the last
+    /// refresh
+    fn update_io(&mut self) {
+        let root = &self.settings.proc_root;
+        for taskid in self.shown.iter() {
+            let ent = self.tasks.get_mut(taskid).unwrap();
+
+            self.buf2.clear();
+            write!(self.buf2, "/proc/{}/task/{}/io", taskid.0, taskid.0).unwrap();
+            /* /proc/pid/io only contains numeric fields */
+            let field = |buf: &str, name| {
+                buf.lines()
+                    .find_map(|l| l.strip_prefix(name))
+                    .and_then(|v| v.parse::<u64>().ok())
+            };
+            let io = match unsafe { root.read_to_string_unchecked(&self.buf2, &mut self.buf) } {
+                Ok(_) => field(&self.buf, "read_bytes: ").zip(field(&self.buf, "write_bytes: ")),
+                _ => None,
+            };
+            let io = match io {
+                Some((r, w)) => (r, w, self.now),
+                _ => {
+                    /* Permission denied, most likely */
+                    ent.io = None;
+                    ent.io_rates = None;
+                    continue;
+                }
+            };
+            ent.io_rates = ent.io.replace(io).map(|(r, w, t)| {
+                let elapsed = io.2 - t;
+                (
+                    Rate::new(r, io.0, elapsed).unwrap_or_default(),
+                    Rate::new(w, io.1, elapsed).unwrap_or_default(),
+                )
+            });
+        }
+    }
+
+    /// For shown tasks, count context switches per second
+    fn update_csw(&mut self) {
+        let root = &self.settings.proc_root;
+        if !shown_fields(self.settings).any(|f| *f == TaskField::Csw) {
+            return;
+        }
+
+        for taskid in self.shown.iter() {
+            let ent = self.tasks.get_mut(taskid).unwrap();
+
+            self.buf2.clear();
+            write!(self.buf2, "/proc/{}/task/{}/status", taskid.0, taskid.0).unwrap();
+            let field = |buf: &str, name| {
+                buf.lines()
+                    .find_map(|l| l.strip_prefix(name))
+                    .and_then(|v| v.trim().parse::<u64>().ok())
+            };
+            /* Contains the name of the task, which can be anything */
+            let csw = match root.read_to_string(&self.buf2, &mut self.buf) {
+                Ok(_) => field(&self.buf, "voluntary_ctxt_switches:")
+                    .zip(field(&self.buf, "nonvoluntary_ctxt_switches:")),
+                _ => None,
+            };
+            let csw = match csw {
+                Some((v, n)) => (v + n, self.now),
+                _ => {
+                    ent.csw = None;
+                    ent.csw_rate = None;
+                    continue;
+                }
+            };
+            ent.csw_rate = ent
+                .csw
+                .replace(csw)
+                .and_then(|(prev, t)| Rate::new(prev, csw.0, csw.1 - t));
+        }
+    }
+
+    /// For shown tasks in a cgroup with a CPU limit, measure how long it was throttled
+    fn update_throttling(&mut self) {
+        let root = &self.settings.proc_root;
+        if !shown_fields(self.settings).any(|f| *f == TaskField::Throttle) {
+            return;
+        }
+
+        for taskid in self.shown.iter() {
+            let ent = self.tasks.get_mut(taskid).unwrap();
+            if ent.cgroup.is_none() {
+                self.buf2.clear();
+                write!(self.buf2, "/proc/{}/task/{}/cgroup", taskid.0, taskid.0).unwrap();
+                ent.cgroup = Some(match root.read_to_string(&self.buf2, &mut self.buf) {
+                    Ok(_) => cgroup_v2_path(&self.buf),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                    Err(_) => None,
+                });
+            }
+            let path = match &ent.cgroup {
+                Some(Some(p)) => p,
+                _ => continue,
+            };
+
+            self.buf2.clear();
+            write!(self.buf2, "/sys/fs/cgroup{}/cpu.stat", path).unwrap();
+            let field = |buf: &str, name| {
+                buf.lines()
+                    .find_map(|l| l.strip_prefix(name))
+                    .and_then(|v| v.parse::<u64>().ok())
+            };
+            /* nr_periods only counts up with a limit in cpu.max */
+            let usec = match root.read_to_string(&self.buf2, &mut self.buf) {
+                Ok(_) => field(&self.buf, "nr_periods ")
+                    .filter(|n| *n > 0)
+                    .and(field(&self.buf, "throttled_usec ")),
+                Err(_) => None,
+            };
+            let usec = match usec {
+                Some(u) => (u, self.now),
+                None => {
+                    ent.throttled = None;
+                    ent.throttle = None;
+                    continue;
+                }
+            };
+            ent.throttle = ent.throttled.replace(usec).and_then(|(prev, t)| {
+                let elapsed = (usec.1 - t).as_micros();
+                let delta = usec.0.checked_sub(prev)? as u128;
+                (elapsed > 0).then(|| CPUPercentage((100 * delta / elapsed).min(999) as u16))
+            });
+        }
+    }
+
+    /// For shown tasks writing a lot, find which filesystem they are most likely writing to. This
+    /// is only a guess based on the files they have opened.
+    fn update_write_targets(&mut self) {
+        /* XXX: fd symlinks aren't read from a remote machine */
+        if !self.settings.proc_root.is_local() {
+            return;
+        }
+
+        for taskid in self.shown.iter() {
+            let ent = self.tasks.get_mut(taskid).unwrap();
+            let rate = match ent.io_rates {
+                Some((_, Rate(w))) => w.0,
+                None => 0,
+            };
+
+            if ent.writes_to.1 > 0 {
+                ent.writes_to.1 -= 1;
+                continue;
+            }
+            if rate < WRITE_TARGET_MIN_RATE {
+                ent.writes_to.0 = None;
+                continue;
+            }
+            ent.writes_to.1 = WRITE_TARGET_TTL;
+
+            self.buf2.clear();
+            write!(self.buf2, "/proc/{}/task/{}/fd", taskid.0, taskid.0).unwrap();
+            let fds = match std::fs::read_dir(&self.buf2) {
+                Ok(fds) => fds,
+                _ => continue,
+            };
+            let targets = fds
+                .take(WRITE_TARGET_MAX_FDS)
+                .filter_map(|fd| fd.and_then(|fd| std::fs::read_link(fd.path())).ok());
+            ent.writes_to.0 = write_target(targets, &self.mountpoints).map(String::from);
+        }
+    }
+
+    /// For shown tasks, measure how long they waited to run since the last refresh
+    fn update_run_delays(&mut self) {
+        let root = &self.settings.proc_root;
+        if !self.settings.task_fields.contains(&TaskField::Wait) {
+            return;
+        }
+
+        for taskid in self.shown.iter() {
+            let ent = self.tasks.get_mut(taskid).unwrap();
+
+            self.buf2.clear();
+            write!(self.buf2, "/proc/{}/task/{}/schedstat", taskid.0, taskid.0).unwrap();
+            /* /proc/pid/schedstat only contains numeric fields: runtime, run delay, timeslices */
+            let delay = match unsafe { root.read_to_string_unchecked(&self.buf2, &mut self.buf) } {
+                Ok(_) => self
+                    .buf
+                    .split_ascii_whitespace()
+                    .nth(1)
+                    .and_then(|v| v.parse::<u64>().ok()),
+                _ => None,
+            };
+            let delay = match delay {
+                Some(d) => (d, self.now),
+                _ => {
+                    ent.run_delay = None;
+                    ent.wait = None;
+                    continue;
+                }
+            };
+            ent.wait = ent.run_delay.replace(delay).and_then(|(d, t)| {
+                match (delay.1 - t).as_nanos() as u64 {
+                    0 => None,
+                    ns => Some(CPUPercentage(
+                        (100 * delay.0.saturating_sub(d) / ns).min(100) as u16,
+                    )),
+                }
+            });
+        }
+    }
+
+    fn open_task_stat(t: Pid, buf: &mut String) -> Option<FileDescriptor> {
+        buf.clear();
+        write!(buf, "/proc/{}/task/{}/stat\x00", t.0, t.0).unwrap();
+        let cstr = std::ffi::CStr::from_bytes_with_nul(buf.as_bytes()).unwrap();
+        let fd = unsafe { libc::open(cstr.as_ptr(), libc::O_RDONLY) };
+        if fd == -1 {
+            unsafe {
+                if *libc::__errno_location() == libc::ENOENT {
+                    // Task is done, this is fine
+                    return None;
+                }
+            }
+            dbg!(cstr);
+            libc_panic("open()");
+        }
+        Some(FileDescriptor(fd))
+    }
+
+    /// The entry of a task, created if it's new, and whether its stat file must be closed after
+    /// reading it (past max_fds, counting the fds open so far in fds). None if the task exited
+    /// before it could be opened.
+    fn stat_entry<'t>(
+        tasks: &'t mut FnvHashMap<Pid, TaskEntry>,
+        max_fds: u64,
+        fds: &mut u64,
+        buf: &mut String,
+        taskid: Pid,
+    ) -> Option<(&'t mut TaskEntry, bool)> {
+        let ent = tasks.entry(taskid).or_insert_with(|| TaskEntry::new(None));
+
+        let mut must_close = false;
+        if ent.filedes.is_none() {
+            ent.filedes = Self::open_task_stat(taskid, buf);
+            ent.filedes.as_ref()?;
+            must_close = *fds >= max_fds;
+            if !must_close {
+                *fds += 1;
+            }
+        }
+        Some((ent, must_close))
+    }
+
+    /// Past max_fds, running tasks have to open their stat file again at every refresh. Make room
+    /// for them by closing the stat files of tasks that have been idle for a while; they get
+    /// opened at the next refresh.
+    fn balance_fds(&mut self) {
+        let open = self.tasks.values().filter(|t| t.filedes.is_some()).count() as u64;
+        let waiting = self
+            .tasks
+            .values()
+            .filter(|t| t.filedes.is_none() && t.idle == 0)
+            .count() as u64;
+        let mut evict = (open + waiting).saturating_sub(self.max_fds).min(waiting);
+        for t in self.tasks.values_mut() {
+            if evict == 0 {
+                break;
+            }
+            if t.filedes.is_some() && t.idle >= FD_IDLE_REFRESHES {
+                t.filedes = None;
+                evict -= 1;
+            }
+        }
+    }
+
+    /// Read a whole stat file into buf, and rewind it for the next refresh. Returns the length
+    /// read, or None if the task exited or the file is too long.
+    fn read_stat(ent: &mut TaskEntry, buf: &mut Vec<u8>, must_close: bool) -> Option<usize> {
+        unsafe {
+            /* Read until EOF, growing the buffer if needed: a short read is not the end of
+             * the file */
+            let fd = ent.filedes.as_ref().unwrap().0;
+            let mut len = 0;
+            loop {
+                if len == buf.len() {
+                    if len >= STAT_MAX_LEN {
+                        /* Reopened next time, instead of reading from where this stopped */
+                        ent.filedes = None;
+                        return None;
+                    }
+                    buf.resize(len * 2, 0);
+                }
+                let ret = libc::read(
+                    fd,
+                    buf[len..].as_mut_ptr() as *mut libc::c_void,
+                    buf.len() - len,
+                );
+                match ret {
+                    /* The task exited since it was opened (ESRCH, or EIO with "No such
+                     * process"): same as ENOENT when opening */
+                    -1 => {
+                        ent.filedes = None;
+                        ent.stale = Stale(true);
+                        return None;
+                    }
+                    0 => break,
+                    n => len += n as usize,
+                }
+            }
+
+            /* Rewind before parsing, so a malformed line isn't followed by empty reads */
+            if must_close || libc::lseek(fd, 0, libc::SEEK_SET) != 0 {
+                /* Reopened next time if lseek() failed */
+                ent.filedes = None;
+            }
+            Some(len)
+        }
+    }
+
+    /// Update a task from the contents of its stat file
+    fn apply_stat(
+        root: &ProcRoot,
+        ent: &mut TaskEntry,
+        pid: Pid,
+        taskid: Pid,
+        data: &[u8],
+        uptime: u64,
+        pagesize: u64,
+    ) {
+        let stat;
+        unsafe {
+            // The stat file contains only numbers, except for the process name (truncated to 16
+            // chars) which is inbetween parentheses. Skip over the process name to avoid checking
+            // for valid utf-8. The name itself can contain parentheses, but none of the fields
+            // after it can: look for the last one.
+            let i = match data.iter().rposition(|b| *b == b')') {
+                Some(i) => i,
+                None => return Self::apply_status(root, ent, pid, taskid, uptime),
+            };
+            stat = std::str::from_utf8_unchecked(&data[(i + 1)..]);
+
+            /* Look before the last ')', not past the end of this read */
+            let start = match data[..i].iter().position(|b| *b == b'(') {
+                Some(s) => s + 1,
+                None => return Self::apply_status(root, ent, pid, taskid, uptime),
+            };
+            /* Usually unchanged, don't convert it again */
+            if ent.comm.as_bytes() != &data[start..i] {
+                ent.comm.clear();
+                ent.comm.push_str(&String::from_utf8_lossy(&data[start..i]));
+            }
+        }
+
+        let StatLine {
+            state,
+            ppid,
+            tty,
+            flags,
+            used_jiffies,
+            start_time,
+            rss,
+            processor,
+            policy,
+        } = match StatLine::parse(stat) {
+            Some(l) => l,
+            None => return Self::apply_status(root, ent, pid, taskid, uptime),
+        };
+
+        if ent.stale == Stale(true) && ent.start_time != start_time {
+            /* The pid was reused since the last refresh, don't compare the new task with the old
+             * one, but keep the comm that was just read */
+            let comm = std::mem::take(&mut ent.comm);
+            *ent = TaskEntry::new(ent.filedes.take());
+            ent.comm = comm;
+        }
+        ent.fresh = ent.stale == Stale(false);
+        if ent.fresh {
+            // This task was just created, use its start_time
+            ent.jiffies.1 .1 = start_time;
+        }
+        ent.start_time = start_time;
+
+        ent.idle = match used_jiffies > ent.jiffies.1 .0 {
+            true => 0,
+            false => ent.idle.saturating_add(1),
+        };
+        ent.jiffies.0 = ent.jiffies.1;
+        ent.jiffies.1 = Jiffies(used_jiffies, uptime);
+        ent.rss = rss * pagesize;
+        ent.dstuck = match state.0 {
+            'D' => ent.dstuck.saturating_add(1),
+            _ => 0,
+        };
+        ent.state = state;
+        ent.kthread = flags & PF_KTHREAD != 0;
+        ent.ppid = ppid;
+        ent.tty = Tty(tty);
+        ent.last_cpu = processor;
+        ent.policy = SchedPolicy(policy);
+        ent.leader = pid == taskid;
+        ent.process = pid;
+        ent.cpu_unknown = false;
+        ent.stale = Stale(false);
+    }
+
+    /// When the stat file of a task cannot be parsed, make do with its state, parent and RSS from
+    /// /proc/pid/status. Its CPU usage is unknown until stat can be parsed again; the task is
+    /// dropped if status cannot be read either.
+    fn apply_status(root: &ProcRoot, ent: &mut TaskEntry, pid: Pid, taskid: Pid, uptime: u64) {
+        /* Should be rare, don't bother reusing buffers */
+        let mut status = String::new();
+        if root
+            .read_to_string(
+                format!("/proc/{}/task/{}/status", pid.0, taskid.0),
+                &mut status,
+            )
+            .is_err()
+        {
+            return;
+        }
+        let field = |name| {
+            status
+                .lines()
+                .find_map(|l| l.strip_prefix(name))
+                .map(str::trim)
+        };
+        let state = match field("State:").and_then(|v| v.chars().next()) {
+            Some(c) => TaskState(c),
+            None => return,
+        };
+        let ppid = match field("PPid:").and_then(|v| v.parse().ok()) {
+            Some(p) => p,
+            None => return,
+        };
+        /* Kernel threads have no memory map, and none of the Vm fields */
+        let rss =
+            field("VmRSS:").and_then(|v| v.trim_end_matches(" kB").trim().parse::<u64>().ok());
+
+        ent.fresh = ent.stale == Stale(false);
+        /* Without start_time, a reused pid cannot be told apart */
+        ent.idle = ent.idle.saturating_add(1);
+        ent.jiffies.0 = ent.jiffies.1;
+        ent.jiffies.1 .1 = uptime;
+        ent.rss = rss.unwrap_or(0) * 1024;
+        ent.dstuck = match state.0 {
+            'D' => ent.dstuck.saturating_add(1),
+            _ => 0,
+        };
+        ent.state = state;
+        ent.kthread = rss.is_none() && field("VmSize:").is_none();
+        ent.ppid = ppid;
+        if let Some(name) = field("Name:") {
+            ent.comm.clear();
+            ent.comm.push_str(name);
+        }
+        ent.leader = pid == taskid;
+        ent.process = pid;
+        ent.cpu_unknown = true;
+        ent.stale = Stale(false);
+    }
+
+    /// Read the stat file of each task, found by walking /proc or from the proc connector
+    fn read_stats(&mut self) {
+        /* With the proc connector, /proc is only walked again if some events were lost */
+        let mut walk = true;
+        self.spawned = None;
+        if let Some(c) = self.connector.as_mut() {
+            let (live, mut spawned) = (&mut self.live, 0);
+            walk = !c.drain(|e| match e {
+                TaskEvent::Fork(tid, tgid) => {
+                    live.insert(Pid(tid), Pid(tgid));
+                    spawned += 1;
+                }
+                TaskEvent::Exit(tid) => {
+                    live.remove(&Pid(tid));
+                }
+            }) || live.is_empty();
+            if !walk {
+                self.spawned = Some(spawned);
+            }
+        }
+
+        let mut fds = self.tasks.values().filter(|t| t.filedes.is_some()).count() as u64;
+        let mut visit = |pid, taskid| {
+            let n = self.threads.entry(pid).or_insert(0);
+            *n = n.saturating_add(1);
+
+            let (ent, must_close) = match Self::stat_entry(
+                &mut self.tasks,
+                self.max_fds,
+                &mut fds,
+                &mut self.buf,
+                taskid,
+            ) {
+                Some(e) => e,
+                None => return,
+            };
+
+            #[cfg(feature = "io-uring")]
+            if self.ring.is_some() {
+                /* Read later, all at once */
+                self.batch.push((pid, taskid, must_close));
+                return;
+            }
+
+            let uptime =
+                self.uptime + self.now.elapsed().as_millis() as u64 * self.user_hz as u64 / 1000;
+            if let Some(len) = Self::read_stat(ent, &mut self.bufstat, must_close) {
+                Self::apply_stat(
+                    &self.settings.proc_root,
+                    ent,
+                    pid,
+                    taskid,
+                    &self.bufstat[..len],
+                    uptime,
+                    self.pagesize,
+                );
+            }
+        };
+        if walk {
+            let (connected, live) = (self.connector.is_some(), &mut self.live);
+            live.clear();
+            map_tasks(&mut self.bufp, |pid, taskid| {
+                if connected {
+                    live.insert(taskid, pid);
+                }
+                visit(pid, taskid);
+            });
+        } else {
+            for (taskid, pid) in self.live.iter() {
+                visit(*pid, *taskid);
+            }
+        }
+        #[cfg(feature = "io-uring")]
+        self.update_batch();
+    }
+
+    /// Same as read_stats(), from the stat files of all tasks at once, as injected in ProcRoot
+    fn read_injected_stats(&mut self) {
+        let settings = self.settings;
+        let root = &settings.proc_root;
+        for path in root.matches("/proc/[0-9]*/task/[0-9]*/stat") {
+            let mut ids = path.split('/').filter_map(|p| p.parse::<u32>().ok());
+            let (pid, taskid) = match (ids.next(), ids.next()) {
+                (Some(pid), Some(taskid)) => (Pid(pid), Pid(taskid)),
+                _ => continue,
+            };
+            if root.read_to_string(&path, &mut self.buf).is_err() {
+                continue;
+            }
+            let n = self.threads.entry(pid).or_insert(0);
+            *n = n.saturating_add(1);
+            let ent = self
+                .tasks
+                .entry(taskid)
+                .or_insert_with(|| TaskEntry::new(None));
+            /* Read at about the same time, no need to extrapolate uptime */
+            let data = self.buf.as_bytes();
+            Self::apply_stat(root, ent, pid, taskid, data, self.uptime, self.pagesize);
+        }
+    }
+
+    /// Read the stat files of the tasks found by update(), a ring at a time. Only the submission
+    /// changes: a read that would not have returned the whole file in one go is done again the
+    /// usual way.
+    #[cfg(feature = "io-uring")]
+    fn update_batch(&mut self) {
+        let mut ring = match self.ring.take() {
+            Some(r) => r,
+            None => return,
+        };
+        let mut failed = false;
+
+        for chunk in self.batch.chunks(uring::ENTRIES) {
+            self.batchfds.clear();
+            self.batchfds.extend(
+                chunk
+                    .iter()
+                    .map(|(_, t, _)| self.tasks[t].filedes.as_ref().unwrap().0),
+            );
+            let res = match failed {
+                false => ring.read_all(&self.batchfds, &mut self.bufring, STAT_SLOT_LEN),
+                true => None,
+            };
+            failed = res.is_none();
+
+            let uptime =
+                self.uptime + self.now.elapsed().as_millis() as u64 * self.user_hz as u64 / 1000;
+            for (i, &(pid, taskid, must_close)) in chunk.iter().enumerate() {
+                let ent = self.tasks.get_mut(&taskid).unwrap();
+                let slot = &self.bufring[i * STAT_SLOT_LEN..(i + 1) * STAT_SLOT_LEN];
+                let data = match res.map(|r| r[i]) {
+                    Some(n) if n < 0 => {
+                        /* Same as a failed read() */
+                        ent.filedes = None;
+                        ent.stale = Stale(true);
+                        continue;
+                    }
+                    /* A stat file is a single line */
+                    Some(n)
+                        if (n as usize) < STAT_SLOT_LEN && slot[..n as usize].ends_with(b"\n") =>
+                    {
+                        if must_close {
+                            ent.filedes = None;
+                        }
+                        &slot[..n as usize]
+                    }
+                    /* The reads were at offset 0, the file position was left at the start */
+                    _ => match Self::read_stat(ent, &mut self.bufstat, must_close) {
+                        Some(len) => &self.bufstat[..len],
+                        None => continue,
+                    },
+                };
+                let root = &self.settings.proc_root;
+                Self::apply_stat(root, ent, pid, taskid, data, uptime, self.pagesize);
+            }
+        }
+
+        if !failed {
+            self.ring = Some(ring);
+        }
+    }
+}
+
+impl<'a> StatBlock<'a> for TaskStats<'a> {
+    fn new(s: &'a Settings) -> Self {
+        let machine = s.proc_root.machine();
+        let local = s.proc_root.is_local();
+        let mut ts = TaskStats {
+            settings: s,
+            user_hz: machine.user_hz,
+            pagesize: machine.pagesize,
+            cpus: machine.cpus,
+            buf: String::new(),
+            buf2: String::new(),
+            buf3: String::new(),
+            bufp: Default::default(),
+            bufstat: vec![0; 512],
+            #[cfg(feature = "io-uring")]
+            ring: match local {
+                true => uring::Ring::new(),
+                false => None,
+            },
+            #[cfg(feature = "io-uring")]
+            batch: Vec::new(),
+            #[cfg(feature = "io-uring")]
+            batchfds: Vec::with_capacity(uring::ENTRIES),
+            #[cfg(feature = "io-uring")]
+            bufring: vec![0; uring::ENTRIES * STAT_SLOT_LEN],
+            tasks: FnvHashMap::default(),
+            /* Only hears about the tasks of this machine */
+            connector: match local {
+                true => ProcConnector::new(),
+                false => None,
+            },
+            live: FnvHashMap::default(),
+            spawned: None,
+            self_pid: Pid(std::process::id()),
+            self_cpu: None,
+            threads: FnvHashMap::default(),
+            sorted: BinaryHeap::new(),
+            shown: Vec::new(),
+            gone: Vec::new(),
+            zombies: Vec::new(),
+            depth: Vec::new(),
+            relevant: Default::default(),
+            wrapped: 0,
+            bufenv: Box::new([0; 8192]),
+            gpu_memory: FnvHashMap::default(),
+            users: FnvHashMap::default(),
+            groups: FnvHashMap::default(),
+            maxtasks: 10,
+            uptime: 0,
+            now: Instant::now(),
+            max_fds: unsafe {
+                let mut n = std::mem::MaybeUninit::<libc::rlimit>::uninit();
+                libc::getrlimit(libc::RLIMIT_NOFILE, n.as_mut_ptr());
+                let n = n.assume_init();
+                n.rlim_cur.saturating_sub(10)
+            },
+            sort_key: s.sort_tasks,
+            show_kthreads: !s.hide_kthreads,
+            filter: s.task_filter.clone().unwrap_or_default(),
+            mountpoints: Vec::new(),
+            states: Default::default(),
+            hot_cpu: None,
+            dstate: (0, 0),
+            pileup: None,
+            reaper: None,
+        };
+        ts.set_sort_key(s.sort_tasks);
+        ts.update(&FrameClock::now());
+        ts
+    }
+
+    fn update(&mut self, clock: &FrameClock) {
+        /* Measure and store jiffies of each task in self.tasks */
+        for t in self.tasks.values_mut() {
+            t.stale = Stale(true);
+        }
+
+        self.now = clock.instant();
+        self.uptime = uptime_jiffies(clock.boottime(), self.user_hz);
+
+        self.threads.clear();
+        #[cfg(feature = "io-uring")]
+        self.batch.clear();
+        match self.settings.proc_root.is_local() {
+            true => self.read_stats(),
+            false => {
+                self.read_injected_stats();
+                /* The sysctl of a remote machine only arrives with the next refresh */
+                self.set_sort_key(self.sort_key);
+            }
+        }
+        self.tasks.retain(|_, t| t.stale == Stale(false));
+        /* Also forget tasks that exited without an event, eg. threads replaced by an execve() */
+        let tasks = &self.tasks;
+        self.live.retain(|taskid, _| tasks.contains_key(taskid));
+        self.balance_fds();
+
+        self.update_pileup();
+        self.update_reaper();
+        self.update_self_cpu();
+        self.sort_tasks();
+        self.update_users();
+        self.update_tags();
+        self.update_units();
+        self.update_commands();
+        self.update_io();
+        self.update_csw();
+        self.update_throttling();
+        self.update_write_targets();
+        self.update_run_delays();
+        self.format_tasks();
+    }
+
+    fn columns(&self) -> u16 {
+        if self.settings.task_fields.contains(&TaskField::Cmd) {
+            self.settings.maxcols.get()
+        } else {
+            let (w, n) = shown_fields(self.settings)
+                .fold((0, 0), |(w, n), f| (w + f.width(self.settings), n + 1));
+            (w + n).saturating_sub(1) as u16
+        }
+    }
+
+    fn rows(&self) -> u16 {
+        1 + self.alerts() + self.gone.len() as u16 + self.shown.len() as u16 + self.wrapped
+    }
+
+    fn min_rows(&self) -> u16 {
+        1 + MIN_TASKS
+    }
+
+    /// As many as there are tasks, the task list takes whatever room is left
+    fn preferred_rows(&self) -> u16 {
+        u16::MAX
+    }
+
+    fn fit(&mut self, rows: u16) {
+        /* The alert lines take the place of tasks */
+        self.maxtasks = rows.max(self.min_rows()) - 1;
+    }
+}
+
+impl<'a> fmt::Display for TaskStats<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((n, comm, wchan)) = &self.pileup {
+            let len =
+                (self.columns() as usize).saturating_sub(self.settings.colwidth.get() as usize + 1);
+            write!(
+                f,
+                "{} {:<len$.len$}{}",
+                MaybeSmart(Heading::new("D-STATE"), self.settings),
+                MaybeSmart(PileUp(*n, comm, wchan), self.settings),
+                MaybeSmart(Newline(), self.settings)
+            )?;
+        }
+        if let Some((n, ppid, comm)) = &self.reaper {
+            let len =
+                (self.columns() as usize).saturating_sub(self.settings.colwidth.get() as usize + 1);
+            write!(
+                f,
+                "{} {:<len$.len$}{}",
+                MaybeSmart(Heading::new("ZOMBIES"), self.settings),
+                MaybeSmart(Reaper(*n, *ppid, comm), self.settings),
+                MaybeSmart(Newline(), self.settings)
+            )?;
+        }
+
+        for (i, field) in shown_fields(self.settings).enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+
+            let w = field.width(self.settings);
+            let selfpc = self.self_cpu.map(|pc| pc.shown(self.settings, self.cpus));
+            let counts = Counts(self.states, self.cpus, selfpc);
+            match field {
+                /* "COMMAND          TASKS 1423  R 5  D 2", if it fits */
+                TaskField::Cmd if w > counts.width() + 8 => {
+                    let w = w - counts.width() - 1;
+                    write!(
+                        f,
+                        "{:<w$} {}",
+                        MaybeSmart(field.heading(), self.settings),
+                        MaybeSmart(counts, self.settings)
+                    )?
+                }
+                TaskField::User | TaskField::Tty | TaskField::State | TaskField::Cmd => {
+                    write!(f, "{:<w$}", MaybeSmart(field.heading(), self.settings))?
+                }
+                _ => write!(f, "{:w$}", MaybeSmart(field.heading(), self.settings))?,
+            }
+        }
+        write!(f, "{}", MaybeSmart(Newline(), self.settings))?;
+
+        for s in self.relevant.iter() {
+            f.write_str(s)?;
+        }
+        Ok(())
+    }
+}
+
+/// A one line summary of task states, meant to fill spare columns next to another block. Shows the
+/// counts of the last TaskStats update, as given by set_states().
+pub struct TaskStateSummary<'a> {
+    settings: &'a Settings,
+    states: TaskStateCounts,
+}
+
+impl<'a> TaskStateSummary<'a> {
+    /// Show these counts, from TaskStats::state_counts()
+    pub fn set_states(&mut self, states: TaskStateCounts) {
+        self.states = states;
+    }
+
+    fn cells(&self) -> [(char, u16); 3] {
+        [
+            ('R', self.states.running),
+            ('D', self.states.dstate),
+            ('Z', self.states.zombie),
+        ]
+    }
+}
+
+impl<'a> StatBlock<'a> for TaskStateSummary<'a> {
+    fn new(s: &'a Settings) -> Self {
+        TaskStateSummary {
+            settings: s,
+            states: Default::default(),
+        }
+    }
+
+    fn update(&mut self, _: &FrameClock) {}
+
+    fn columns(&self) -> u16 {
+        /* "R:3 D:1 Z:0" */
+        let w = self
+            .cells()
+            .iter()
+            .map(|(_, n)| 3 + n.checked_ilog10().unwrap_or(0) as u16)
+            .sum::<u16>()
+            + 2;
+        w.max(self.settings.colwidth.get())
+    }
+
+    fn rows(&self) -> u16 {
+        3
+    }
+
+    fn optional(&self) -> bool {
+        true
+    }
+}
+
+impl<'a> fmt::Display for TaskStateSummary<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let newline = MaybeSmart(Newline(), self.settings);
+        let c = self.columns() as usize;
+        write!(
+            f,
+            "{:<c$}{}",
+            MaybeSmart(Heading::new("TASKS"), self.settings),
+            newline
+        )?;
+
+        let mut w = 0;
+        for (i, (s, n)) in self.cells().into_iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            /* Only highlight states that aren't empty */
+            match n {
+                0 => write!(f, "{}:{}", s, n)?,
+                _ => write!(f, "{}:{}", MaybeSmart(TaskState(s), self.settings), n)?,
+            }
+            w += (i > 0) as usize + 3 + n.checked_ilog10().unwrap_or(0) as usize;
+        }
+        write!(f, "{:p$}{}{}", "", newline, newline, p = c - w)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A pid above pid_max, so falling back to /proc/pid/status finds nothing
+    const NO_PID: Pid = Pid(999_999_999);
+
+    /// A stat line as printed by the kernel: R state, ppid 1, tty 34816, 250+50 jiffies used,
+    /// started at 5000, 300 pages of RSS, last ran on CPU 3, 7 jiffies of block I/O delays
+    fn stat_line(comm: &str) -> String {
+        format!(
+            "1234 ({}) R 1 1234 1234 34816 1234 4194560 100 0 0 0 250 50 0 0 20 0 1 0 5000 \
+             10000000 300 18446744073709551615 1 1 0 0 0 0 0 0 0 0 0 0 17 3 0 0 7 0 0 0 0 0 0 \
+             0 0 0\n",
+            comm
+        )
+    }
+
+    fn apply(data: &[u8]) -> TaskEntry {
+        let mut ent = TaskEntry::new(None);
+        TaskStats::apply_stat(
+            &ProcRoot::default(),
+            &mut ent,
+            NO_PID,
+            NO_PID,
+            data,
+            6000,
+            4096,
+        );
+        ent
+    }
+
+    #[test]
+    fn uptime_long_running() {
+        /* Over 115 days, where an f32 can't tell apart consecutive centiseconds */
+        let up = Duration::new(10_000_000, 420_000_000);
+        let next = Duration::new(10_000_000, 430_000_000);
+        assert_eq!(uptime_jiffies(up, 100), 1_000_000_042);
+        assert_eq!(uptime_jiffies(up, 250), 2_500_000_105);
+        assert_eq!(uptime_jiffies(up, 1000), 10_000_000_420);
+        assert_eq!(uptime_jiffies(next, 100), 1_000_000_043);
+        /* Rounded down, like /proc/uptime */
+        assert_eq!(
+            uptime_jiffies(next - Duration::from_nanos(1), 100),
+            1_000_000_042
+        );
+        assert_eq!(uptime_jiffies(Duration::ZERO, 100), 0);
+
+        /* One jiffy of CPU in one jiffy of wall time is 100%, not 0% or 200% */
+        let mut ent = TaskEntry::new(None);
+        ent.jiffies = (
+            Jiffies(7, uptime_jiffies(up, 100)),
+            Jiffies(8, uptime_jiffies(next, 100)),
+        );
+        assert_eq!(ent.cpu_percentage().0, 100);
+    }
+
+    #[test]
+    fn stat_plain() {
+        let ent = apply(stat_line("bash").as_bytes());
+        assert_eq!(ent.comm, "bash");
+        assert!(ent.state == TaskState('R'));
+        assert_eq!(ent.ppid, 1);
+        assert_eq!(ent.tty.0, 34816);
+        assert_eq!((ent.jiffies.1 .0, ent.jiffies.1 .1), (300, 6000));
+        assert_eq!(ent.start_time, 5000);
+        assert_eq!(ent.rss, 300 * 4096);
+        assert_eq!(ent.last_cpu, 3);
+        assert!(!ent.kthread);
+        assert!(!ent.cpu_unknown);
+    }
+
+    #[test]
+    fn stat_hostile_comm() {
+        for comm in [
+            "(sd-pam)",
+            "foo) R 1 2",
+            "",
+            ")",
+            "(",
+            ") (",
+            "a b c",
+            "))))",
+        ] {
+            let ent = apply(stat_line(comm).as_bytes());
+            assert_eq!(ent.comm, comm);
+            assert!(ent.state == TaskState('R'), "{:?}", comm);
+            assert_eq!(ent.ppid, 1, "{:?}", comm);
+            assert_eq!(ent.jiffies.1 .0, 300, "{:?}", comm);
+        }
+
+        /* Not UTF-8 */
+        let mut data = stat_line("x").into_bytes();
+        data[6] = 0xFF;
+        let ent = apply(&data);
+        assert_eq!(ent.comm, "\u{FFFD}");
+        assert_eq!(ent.ppid, 1);
+    }
+
+    #[test]
+    fn stat_unparseable() {
+        for data in [
+            &b""[..],
+            b"1234 (bash",
+            b"1234 bash) R",
+            b"1234 (bash) R 1 1234",
+            b"1234 (foo) R 1 2) R x 1234 1234 34816",
+        ] {
+            /* Status can't be read either: left alone */
+            let ent = apply(data);
+            assert!(ent.state == TaskState('?'));
+            assert_eq!(ent.ppid, 0);
+        }
+    }
+
+    /// stat_line("x") of a task started at start, having used jiffies
+    fn stat_started(start: u64, jiffies: u64) -> String {
+        stat_line("x")
+            .replace(" 250 50 ", &format!(" {} 0 ", jiffies))
+            .replace(" 5000 ", &format!(" {} ", start))
+    }
+
+    #[test]
+    fn filter_reads_cmdline_once() {
+        let me = Pid(std::process::id());
+        let (mut buf, mut buf2) = (String::new(), String::new());
+        let mut matches = |filter, ent: &mut TaskEntry| {
+            TaskStats::task_matches(&ProcRoot::default(), filter, &mut buf, &mut buf2, me, ent)
+        };
+        let mut ent = TaskEntry::new(None);
+        ent.comm = String::from("x");
+
+        /* comm matches, no need for the command line */
+        assert!(matches("x", &mut ent));
+        assert!(ent.command.is_none());
+
+        /* The test binary lives under target/.../deps/ */
+        assert!(matches("deps/", &mut ent));
+        assert_eq!(ent.command.as_ref().unwrap().1, "x");
+
+        /* Then it is not read again while comm stays the same */
+        ent.command = Some((String::from("cached\0arg"), String::from("x")));
+        assert!(matches("cached arg", &mut ent));
+        assert!(!matches("deps/", &mut ent));
+
+        /* After an exec() */
+        ent.comm = String::from("y");
+        assert!(!matches("cached", &mut ent));
+        assert!(matches("deps/", &mut ent));
+    }
+
+    #[test]
+    fn pid_reuse() {
+        /* update() marks all known tasks stale before reading them again */
+        let refresh = |ent: &mut TaskEntry, data: String, uptime| {
+            ent.stale = Stale(true);
+            TaskStats::apply_stat(
+                &ProcRoot::default(),
+                ent,
+                NO_PID,
+                NO_PID,
+                data.as_bytes(),
+                uptime,
+                4096,
+            );
+        };
+        let mut ent = TaskEntry::new(None);
+        TaskStats::apply_stat(
+            &ProcRoot::default(),
+            &mut ent,
+            NO_PID,
+            NO_PID,
+            stat_started(5000, 300).as_bytes(),
+            6000,
+            4096,
+        );
+        assert!(ent.fresh);
+        refresh(&mut ent, stat_started(5000, 400), 6100);
+        assert!(!ent.fresh);
+        assert_eq!(ent.cpu_percentage().0, 100);
+
+        /* Another task got the pid: compared with its own start, not the old task's jiffies */
+        ent.comm = String::from("old");
+        refresh(&mut ent, stat_started(6150, 25), 6200);
+        assert!(ent.fresh);
+        assert_eq!(ent.start_time, 6150);
+        assert_eq!(ent.cpu_percentage().0, 50);
+        assert_eq!(ent.comm, "x");
+
+        refresh(&mut ent, stat_started(6150, 75), 6300);
+        assert!(!ent.fresh);
+        assert_eq!(ent.cpu_percentage().0, 50);
+    }
+
+    #[test]
+    fn stat_falls_back_to_status() {
+        let s = Settings::default();
+        let me = Pid(std::process::id());
+        for data in [
+            &b"1234 (bash) R 1 1234"[..],
+            b"truncated",
+            b"1234 (x) ? ? ? ?",
+        ] {
+            let mut ent = TaskEntry::new(None);
+            TaskStats::apply_stat(&ProcRoot::default(), &mut ent, me, me, data, 6000, 4096);
+            assert!(ent.cpu_unknown);
+            assert_eq!(ent.ppid, std::os::unix::process::parent_id());
+            assert!(ent.state != TaskState('?'));
+            assert!(ent.rss > 0 && !ent.kthread);
+            assert!(!ent.comm.is_empty());
+            assert!(ent.leader);
+            assert_eq!(ent.jiffies.1 .1, 6000);
+
+            let (mut buf2, mut out) = (String::new(), String::new());
+            TaskStats::format_task(
+                &s,
+                &mut buf2,
+                &mut out,
+                me,
+                TaskExtras {
+                    cpupc: CPUPercentage(0),
+                    age: Duration::ZERO,
+                    threads: 1,
+                    gpu: None,
+                    depth: 0,
+                    user: "root",
+                },
+                &ent,
+                false,
+            );
+            /* PID, USER, TTY, STATE, then CPU */
+            assert_eq!(out.split_ascii_whitespace().nth(4), Some("?"), "{:?}", out);
+
+            /* Known again once stat parses */
+            TaskStats::apply_stat(
+                &ProcRoot::default(),
+                &mut ent,
+                me,
+                me,
+                stat_line("x").as_bytes(),
+                6100,
+                4096,
+            );
+            assert!(!ent.cpu_unknown);
+            assert_eq!(ent.ppid, 1);
+        }
+    }
+
+    #[test]
+    fn pileup_hysteresis() {
+        let counts = [0, 9, 3, 9, 9, 9, 8, 9];
+        let raised: Vec<bool> = counts
+            .windows(2)
+            .map(|w| pileup_raised((w[0], w[1]), 8))
+            .collect();
+        assert_eq!(raised, [false, false, false, true, true, false, false]);
+    }
+
+    #[test]
+    fn pileup_most_common() {
+        let mut counts = FnvHashMap::default();
+        assert_eq!(most_common(counts.clone()), None);
+
+        for name in [
+            "kworker/u16:2",
+            "postgres",
+            "rsync",
+            "postgres",
+            "rsync",
+            "dd",
+        ] {
+            count_name(&mut counts, name);
+        }
+        /* Two each: the first by name wins */
+        assert_eq!(most_common(counts.clone()).as_deref(), Some("postgres"));
+        count_name(&mut counts, "rsync");
+        assert_eq!(most_common(counts).as_deref(), Some("rsync"));
+    }
+
+    /// A zombie of the given parent, which used a lot of CPU just before exiting
+    fn zombie(ppid: u32) -> TaskEntry {
+        let mut ent = TaskEntry::new(None);
+        ent.state = TaskState('Z');
+        ent.ppid = ppid;
+        ent.jiffies = (Jiffies(0, 100), Jiffies(100, 200));
+        ent
+    }
+
+    #[test]
+    fn reaper() {
+        let s = Settings::default();
+        let mut ts = TaskStats::new(&s);
+        ts.tasks.clear();
+        let no_such_pid = u32::MAX - 1;
+        for (i, ppid) in (0..150).map(|i| (i, if i % 3 == 0 { 1 } else { no_such_pid })) {
+            ts.tasks.insert(Pid(u32::MAX - 10 - i), zombie(ppid));
+        }
+
+        let mut states = TaskStateCounts {
+            zombie: ZOMBIE_THRESHOLD,
+            ..Default::default()
+        };
+        ts.states = states;
+        ts.update_reaper();
+        assert!(ts.reaper.is_none());
+
+        states.zombie = 150;
+        ts.states = states;
+        ts.update_reaper();
+        let (n, ppid, comm) = ts.reaper.clone().unwrap();
+        assert_eq!((n, ppid, comm.as_str()), (150, no_such_pid, "?"));
+        assert_eq!(ts.alerts(), 1);
+        assert_eq!(
+            format!("{:40}", MaybeSmart(Reaper(n, 1234, "supervisord"), &s)),
+            "150 zombies, parent: supervisord (pid 1234)"[..40]
+        );
+
+        /* Gone by the time they are looked at */
+        ts.tasks.clear();
+        ts.update_reaper();
+        assert!(ts.reaper.is_none());
+    }
+
+    /// The tasks shown with a running task and a zombie that used more CPU before exiting
+    fn shown_with_zombie(s: &Settings) -> Vec<u32> {
+        let mut ts = TaskStats::new(s);
+        ts.tasks.clear();
+        let mut ent = zombie(1);
+        ent.state = TaskState('R');
+        ent.jiffies.1 .0 = 50;
+        ts.tasks.insert(Pid(u32::MAX - 1), ent);
+        ts.tasks.insert(Pid(u32::MAX - 2), zombie(1));
+        ts.sort_tasks();
+        ts.shown.iter().map(|pid| pid.0).collect()
+    }
+
+    #[test]
+    fn stuck_in_d_state() {
+        let s = Settings::default();
+        s.smart.set(true);
+        let mut ent = TaskEntry::new(None);
+        ent.state = TaskState('D');
+        let extras = TaskExtras {
+            cpupc: CPUPercentage(0),
+            age: Duration::ZERO,
+            threads: 1,
+            gpu: None,
+            depth: 0,
+            user: "root",
+        };
+        let mut out = String::new();
+        for (dstuck, want) in [
+            (4, format!("{}D", s.theme.get().crit())),
+            (5, "\x1B[1;31mD".into()),
+        ] {
+            ent.dstuck = dstuck;
+            out.clear();
+            TaskStats::format_task(
+                &s,
+                &mut String::new(),
+                &mut out,
+                NO_PID,
+                extras,
+                &ent,
+                false,
+            );
+            assert!(out.contains(&want), "{:?}", out);
+            assert!(!out.contains("\x1B[5m"), "{:?}", out);
+            assert_eq!(out.contains(" stuck 10s"), dstuck == 5, "{:?}", out);
+        }
+    }
+
+    #[test]
+    fn zombies_not_sorted() {
+        assert_eq!(shown_with_zombie(&Settings::default()), [u32::MAX - 1]);
+        /* With --show-zombies, after the others */
+        let s = Settings {
+            show_zombies: true,
+            ..Default::default()
+        };
+        assert_eq!(shown_with_zombie(&s), [u32::MAX - 1, u32::MAX - 2]);
+    }
+
+    #[test]
+    fn pileup_message() {
+        let s = Settings::default();
+        assert_eq!(
+            format!("{:50}", MaybeSmart(PileUp(12, "postgres", ""), &s)),
+            "12 tasks in uninterruptible sleep, mostly postgres"
+        );
+        assert_eq!(
+            format!(
+                "{:70}",
+                MaybeSmart(PileUp(12, "postgres", "io_schedule"), &s)
+            ),
+            format!(
+                "{:70}",
+                "12 tasks in uninterruptible sleep, mostly postgres [io_schedule]"
+            )
+        );
+        assert_eq!(
+            format!(
+                "{:8}",
+                MaybeSmart(PileUp(12, "postgres", "io_schedule"), &s)
+            ),
+            "12 tasks"
+        );
+    }
+
+    /// Push tasks (state, stuck, key, jiffies, pid) in many orders, check they always pop as want
+    fn assert_pops(by: TaskSortKey, tasks: &[(char, u16, u64, u64, u32)], want: &[u32]) {
+        let mut order: Vec<usize> = (0..tasks.len()).collect();
+        let mut x = 0x9E37_79B9u32;
+        for _ in 0..50 {
+            for i in (1..order.len()).rev() {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                order.swap(i, x as usize % (i + 1));
+            }
+            let mut heap = BinaryHeap::new();
+            for &i in &order {
+                let (state, stuck, key, jiffies, pid) = tasks[i];
+                heap.push((
+                    TaskSort::new(by, TaskState(state), stuck, key, jiffies),
+                    Pid(pid),
+                ));
+            }
+            let popped: Vec<u32> = std::iter::from_fn(|| heap.pop())
+                .map(|(_, p)| p.0)
+                .collect();
+            assert_eq!(popped, want);
+        }
+    }
+
+    /* (state, stuck, key, jiffies, pid) */
+    const SORT_TASKS: [(char, u16, u64, u64, u32); 9] = [
+        ('S', 0, 1, 500, 40),
+        ('R', 0, 1, 500, 12),
+        ('S', 0, 1, 900, 7),
+        ('S', 0, 1, 500, 300),
+        ('D', 2, 0, 10, 5),
+        ('D', 2, 0, 10, 6),
+        ('D', 0, 0, 90, 8),
+        ('S', 0, 2, 0, 1),
+        ('I', 0, 1, 500, 41),
+    ];
+
+    #[test]
+    fn heap_ties_stable() {
+        assert_pops(
+            TaskSortKey::State,
+            &SORT_TASKS,
+            &[6, 5, 8, 1, 7, 300, 41, 40, 12],
+        );
+    }
+
+    #[test]
+    fn sort_key_ignores_state() {
+        /* Tasks in D state only come first when sorting by state */
+        for by in [TaskSortKey::Cpu, TaskSortKey::Memory] {
+            assert_pops(by, &SORT_TASKS, &[1, 7, 300, 41, 40, 12, 8, 6, 5]);
+        }
+    }
+
+    #[test]
+    fn stat_appended_fields() {
+        /* Fields added by future kernels, and a line longer than the initial buffer */
+        let mut line = stat_line("bash");
+        line.pop();
+        for i in 0..200 {
+            line.push_str(&format!(" {}", i * 1000));
+        }
+        line.push('\n');
+        assert!(line.len() > 512);
+        let ent = apply(line.as_bytes());
+        assert!(ent.state == TaskState('R'));
+        assert_eq!((ent.ppid, ent.tty.0, ent.start_time), (1, 34816, 5000));
+        assert_eq!(
+            (ent.jiffies.1 .0, ent.rss, ent.last_cpu),
+            (300, 300 * 4096, 3)
+        );
+    }
+
+    #[test]
+    fn stat_truncated() {
+        /* Cut anywhere: either all the fields are right, or the line is rejected */
+        let line = stat_line("a b) c");
+        for len in 0..line.len() {
+            let ent = apply(&line.as_bytes()[..len]);
+            if ent.state == TaskState('?') {
+                assert_eq!(ent.ppid, 0, "{}", len);
+                continue;
+            }
+            assert_eq!(
+                (ent.ppid, ent.tty.0, ent.start_time, ent.jiffies.1 .0),
+                (1, 34816, 5000, 300),
+                "{}",
+                len
+            );
+            assert_eq!((ent.rss, ent.last_cpu), (300 * 4096, 3), "{}", len);
+        }
+    }
+
+    /// A task entry reading from a file with the given contents
+    fn stat_file(name: &str, contents: &[u8]) -> TaskEntry {
+        let path =
+            std::env::temp_dir().join(format!("hitome-stat-{}-{}", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        let path = std::ffi::CString::new(path.into_os_string().into_string().unwrap()).unwrap();
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDONLY) };
+        assert!(fd >= 0);
+        unsafe { libc::unlink(path.as_ptr()) };
+        TaskEntry::new(Some(FileDescriptor(fd)))
+    }
+
+    #[test]
+    fn read_stat_grows() {
+        let line = stat_line("bash");
+        let mut ent = stat_file("grows", line.as_bytes());
+        let mut buf = vec![0; 16];
+        for _ in 0..2 {
+            /* Rewound after each read */
+            let len = TaskStats::read_stat(&mut ent, &mut buf, false).unwrap();
+            assert_eq!(&buf[..len], line.as_bytes());
+            assert!(ent.filedes.is_some());
+        }
+
+        let len = TaskStats::read_stat(&mut ent, &mut buf, true).unwrap();
+        assert_eq!(len, line.len());
+        assert!(ent.filedes.is_none());
+    }
+
+    #[test]
+    fn read_stat_oversized() {
+        let mut ent = stat_file("oversized", &vec![b'1'; STAT_MAX_LEN + 1]);
+        let mut buf = vec![0; 512];
+        assert_eq!(TaskStats::read_stat(&mut ent, &mut buf, false), None);
+        assert!(ent.filedes.is_none());
+        assert!(ent.stale == Stale(false));
+        assert!(buf.len() <= STAT_MAX_LEN);
+
+        let mut ent = stat_file("exact", &vec![b'1'; STAT_MAX_LEN - 1]);
+        assert_eq!(
+            TaskStats::read_stat(&mut ent, &mut buf, false),
+            Some(STAT_MAX_LEN - 1)
+        );
+    }
+
+    /// Look for var in an environ file with the given contents, through a small buffer
+    fn environ(name: &str, contents: &[u8], var: &str, buflen: usize) -> Option<String> {
+        let path =
+            std::env::temp_dir().join(format!("hitome-environ-{}-{}", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        let mut buf = vec![0; buflen];
+        let root = ProcRoot::default();
+        let v = TaskStats::environ_var(&root, &mut buf, path.to_str().unwrap(), var, TAG_MAX_LEN);
+        std::fs::remove_file(&path).unwrap();
+        v.unwrap()
+    }
+
+    #[test]
+    fn environ_lookup() {
+        let env = b"PATH=/usr/bin\0SERVICE=x\0SERVICE_NAME=billing\0EMPTY=\0";
+        assert_eq!(
+            environ("found", env, "SERVICE_NAME", 64).as_deref(),
+            Some("billing")
+        );
+        /* Not a prefix match */
+        assert_eq!(environ("prefix", env, "SERVICE", 64).as_deref(), Some("x"));
+        assert_eq!(environ("suffix", env, "NAME", 64), None);
+        assert_eq!(environ("missing", env, "HOME", 64), None);
+        assert_eq!(environ("empty", env, "EMPTY", 64).as_deref(), Some(""));
+        assert_eq!(
+            TaskStats::environ_var(
+                &ProcRoot::default(),
+                &mut [0; 64],
+                "/nonexistent/environ",
+                "PATH",
+                TAG_MAX_LEN
+            )
+            .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn environ_truncated() {
+        let env = "SERVICE_NAME=réservations-eu-west\0".as_bytes();
+        assert_eq!(
+            environ("long", env, "SERVICE_NAME", 64).as_deref(),
+            Some("réservations")
+        );
+    }
+
+    #[test]
+    fn passwd_lookup() {
+        let passwd = "root:x:0:0:root:/root:/bin/sh\n\
+                      # comment\n\
+                      nobody:x:65534:65534::/:/sbin/nologin\n\
+                      alice:x:1000:1000:Alice:/home/alice:/bin/bash\n";
+        assert_eq!(passwd_name(passwd, 0).as_deref(), Some("root"));
+        assert_eq!(passwd_name(passwd, 1000).as_deref(), Some("alice"));
+        /* Not the gid */
+        assert_eq!(passwd_name("wheel:x:1:10::/:/bin/sh", 10), None);
+        assert_eq!(passwd_name(passwd, 42), None);
+    }
+
+    #[test]
+    fn environ_capped() {
+        let env = b"PATH=/usr/bin\0SERVICE_NAME=billing\0LATE=1\0";
+        /* Past the end of the buffer */
+        assert_eq!(environ("past", env, "LATE", 36), None);
+        /* Cut off by the end of the buffer, don't show "bil" */
+        assert_eq!(environ("cut", env, "SERVICE_NAME", 30), None);
+        assert_eq!(
+            environ("fits", env, "SERVICE_NAME", 35).as_deref(),
+            Some("billing")
+        );
+        assert_eq!(
+            environ("exact", env, "LATE", env.len()).as_deref(),
+            Some("1")
+        );
+    }
+
+    #[test]
+    fn tags_cached() {
+        let s = Settings {
+            task_tag: Some(String::from("SERVICE_NAME")),
+            ..Default::default()
+        };
+        let mut ts = TaskStats::new(&s);
+        /* No such task: nothing to show, and not looked for again */
+        let pid = Pid(u32::MAX - 1);
+        ts.tasks.insert(pid, TaskEntry::new(None));
+        ts.shown = vec![pid];
+        ts.update_tags();
+        assert_eq!(ts.tasks[&pid].tag, Some(None));
+
+        ts.tasks.get_mut(&pid).unwrap().tag = Some(Some(String::from("billing")));
+        ts.update_tags();
+        assert_eq!(ts.tasks[&pid].tag, Some(Some(String::from("billing"))));
+
+        /* A reused pid looks again */
+        let ent = ts.tasks.get_mut(&pid).unwrap();
+        ent.stale = Stale(true);
+        ent.start_time = 1;
+        TaskStats::apply_stat(
+            &ProcRoot::default(),
+            ent,
+            NO_PID,
+            NO_PID,
+            stat_line("x").as_bytes(),
+            6000,
+            4096,
+        );
+        assert_eq!(ts.tasks[&pid].tag, None);
+    }
+
+    #[test]
+    fn write_targets() {
+        let dir = std::env::temp_dir().join(format!("hitome-write-target-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let files: Vec<_> = ["a", "b", "sub/c"].iter().map(|f| dir.join(f)).collect();
+        for f in files.iter() {
+            std::fs::write(f, "").unwrap();
+        }
+        let mountpoints = [String::from("/"), dir.to_string_lossy().into_owned()];
+        let other = PathBuf::from("/proc/self/exe");
+        let dev = PathBuf::from("/dev/null");
+
+        /* Devices don't count, the longest mountpoint wins */
+        let opened = [&files[0], &files[1], &other, &dev, &dev, &dev];
+        let t = write_target(opened.into_iter().cloned(), &mountpoints);
+        assert_eq!(t, Some(mountpoints[1].as_str()));
+        /* Half isn't most */
+        let opened = [&files[0], &files[2], &other, &other];
+        assert_eq!(
+            write_target(opened.into_iter().cloned(), &mountpoints),
+            None
+        );
+        let opened = [&dev, &files[2]];
+        let t = write_target(opened.into_iter().cloned(), &mountpoints[..1]);
+        assert_eq!(t, Some("/"));
+        assert_eq!(write_target([dev].into_iter(), &mountpoints), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
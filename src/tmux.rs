@@ -0,0 +1,245 @@
+/* Copyright 2022 Romain "Artefact2" Dal Maso <romain.dalmaso@artefact2.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *	   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/* A one line summary for tmux's status-right, eg. set -g status-right '#(hitome --tmux)'. tmux runs
+ * it again every status-interval, so it must be quick. */
+
+use crate::blockdev::BlockDeviceStats;
+use crate::common::*;
+use crate::cpu::{CpuStats, Glyphs, Load};
+use crate::hwmon::{Celsius, HwmonStats};
+use crate::mem::MemoryStats;
+use crate::network::NetworkStats;
+use crate::pressure::PressureStats;
+use std::fmt;
+use std::time::Duration;
+
+/// Time between the two samples rates are computed from
+const INTERVAL: Duration = Duration::from_millis(500);
+
+/// Longest line printed; CPUs get whatever the rest leaves
+const BUDGET: usize = 60;
+
+/// Everything shown in the status line
+pub struct StatusLine<'a> {
+    settings: &'a Settings,
+    cpus: Vec<Load>,
+    available: Bytes,
+    /// The worst avg10 value
+    pressure: Option<Threshold<Percentage>>,
+    rx: Bytes,
+    tx: Bytes,
+    read: Bytes,
+    written: Bytes,
+    temperature: Option<Threshold<Celsius>>,
+}
+
+impl<'a> StatusLine<'a> {
+    /// Take two samples, INTERVAL apart
+    pub fn sample(s: &'a Settings) -> StatusLine<'a> {
+        let mut cpu = CpuStats::new(s);
+        let mut mem = MemoryStats::new(s);
+        let mut psi = PressureStats::new(s);
+        let mut net = NetworkStats::new(s);
+        let mut bdev = BlockDeviceStats::new(s);
+        let mut hwmon = HwmonStats::new(s);
+
+        std::thread::sleep(INTERVAL);
+        let clock = FrameClock::now();
+        cpu.update(&clock);
+        mem.update(&clock);
+        psi.update(&clock);
+        net.update(&clock);
+        bdev.update(&clock);
+        hwmon.update(&clock);
+
+        let (rx, tx) = net.rates();
+        let (read, written) = bdev.throughput();
+        StatusLine {
+            settings: s,
+            cpus: cpu.loads(),
+            available: mem.available(),
+            pressure: psi.worst(),
+            rx,
+            tx,
+            read,
+            written,
+            temperature: hwmon.hottest(),
+        }
+    }
+
+    /// Everything but the CPUs
+    fn rest(&self) -> String {
+        let s = self.settings;
+        let mut out = format!("mem {}", short(self.available));
+        if let Some(p) = self.pressure {
+            out.push_str(&format!(" psi {:1.0}", MaybeSmart(p, s)));
+        }
+        out.push_str(&format!(" net {}/{}", short(self.rx), short(self.tx)));
+        out.push_str(&format!(" io {}/{}", short(self.read), short(self.written)));
+        if let Some(t) = self.temperature {
+            out.push_str(&format!(" {:1.0}", MaybeSmart(t, s)));
+        }
+        out
+    }
+}
+
+/// Bytes in at most 5 characters, eg. "12.3M" or "123M". Bytes has no unit past T, clamp so a
+/// bogus counter can't push the rest of the line off the status bar.
+fn short(b: Bytes) -> String {
+    let b = Bytes(b.0.min(9999 << 40));
+    String::from(format!("{:5.1}", b).trim_start())
+}
+
+/// Length of a line as shown by tmux, without the #[...] markup
+fn visible_len(line: &str) -> usize {
+    let mut len = 0;
+    let mut rest = line;
+    while let Some(i) = rest.find("#[") {
+        len += rest[..i].chars().count();
+        rest = &rest[(i + rest[i..].find(']').unwrap() + 1)..];
+    }
+    len + rest.chars().count()
+}
+
+/// At most BUDGET characters, eg. "cpu XOo. mem 3.1G psi 4% net 1.2M/34K io ./5.1M 54C". With
+/// more CPUs than fit, neighbours share a glyph.
+impl<'a> fmt::Display for StatusLine<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rest = self.rest();
+        let room = BUDGET.saturating_sub(visible_len(&rest) + "cpu  ".len());
+        if !self.cpus.is_empty() && room > 0 {
+            let glyphs = MaybeSmart(Glyphs(&self.cpus, room), self.settings);
+            write!(f, "cpu {} ", glyphs)?;
+        }
+        write!(f, "{}", rest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tmux() -> Settings {
+        let s = Settings {
+            theme: std::cell::Cell::new(Theme::Tmux),
+            ..Default::default()
+        };
+        s.smart.set(true);
+        s
+    }
+
+    fn line(s: &Settings, cpus: usize, val: u64, pct: f32) -> StatusLine<'_> {
+        let load = Load {
+            busy: pct / 100.0,
+            idle: 1.0 - pct / 100.0,
+        };
+        StatusLine {
+            settings: s,
+            cpus: vec![load; cpus],
+            available: Bytes(val),
+            pressure: Some(Threshold {
+                val: Percentage(pct),
+                med: Percentage(1.0),
+                high: Percentage(5.0),
+                crit: Percentage(10.0),
+            }),
+            rx: Bytes(val),
+            tx: Bytes(val),
+            read: Bytes(val),
+            written: Bytes(val),
+            temperature: Some(crate::hwmon::temperature(Celsius(pct), None)),
+        }
+    }
+
+    #[test]
+    fn length_budget() {
+        let s = tmux();
+        for cpus in [1, 8, 256] {
+            for val in [0, 1, 999, 1023, 1024, 99_999_999, 1 << 40, u64::MAX] {
+                for pct in [0.0, 9.5, 99.5, 100.0, 150.0] {
+                    let l = format!("{}", line(&s, cpus, val, pct));
+                    let len = visible_len(&l);
+                    assert!(len <= 60, "{:?} is {} characters", l, len);
+                    assert!(l.starts_with("cpu "), "{:?}", l);
+                    assert!(!l.contains('\n') && !l.contains('\x1b'), "{:?}", l);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn short_clamped() {
+        assert_eq!(short(Bytes(0)), ".");
+        assert_eq!(short(Bytes(12345)), "12.1K");
+        assert_eq!(short(Bytes(123 << 20)), "123M");
+        assert_eq!(short(Bytes(9999 << 40)), "9999T");
+        assert_eq!(short(Bytes(u64::MAX)), "9999T");
+    }
+
+    #[test]
+    fn missing_values() {
+        let s = tmux();
+        let mut l = line(&s, 0, 0, 0.0);
+        l.pressure = None;
+        l.temperature = None;
+        let v = format!("{}", l);
+        assert!(v.starts_with("mem "), "{:?}", v);
+        assert!(!v.contains("psi") && !v.ends_with('C'), "{:?}", v);
+    }
+
+    #[test]
+    fn glyphs() {
+        let s = tmux();
+        let l = format!("{}", line(&s, 4, 0, 0.0));
+        assert!(l.starts_with("cpu      mem "), "{:?}", l);
+        /* Few CPUs get one glyph each, many share what the budget leaves */
+        let l = format!("{}", line(&s, 8, 1 << 30, 30.0));
+        assert!(l.starts_with("cpu OOOOOOOO mem 1.0G "), "{:?}", l);
+        let l = format!("{}", line(&s, 256, 0, 0.0));
+        assert_eq!(visible_len(&l), 60, "{:?}", l);
+    }
+
+    #[test]
+    fn colours() {
+        let s = tmux();
+        let l = format!("{}", line(&s, 1, 0, 0.0));
+        assert_eq!(l.matches("#[").count(), 0, "{:?}", l);
+
+        /* Thresholds are the blocks' own: pressure is red past 5%, temperature yellow past 50C */
+        let l = format!("{}", line(&s, 1, 0, 7.0));
+        assert!(l.contains(" psi #[fg=red,bold]7%#[default] "), "{:?}", l);
+        assert!(l.ends_with(" 7C"), "{:?}", l);
+        let l = format!("{}", line(&s, 1, 0, 55.0));
+        assert!(l.ends_with(" #[fg=yellow,bold]55C#[default]"), "{:?}", l);
+        assert!(
+            l.contains(" psi #[fg=magenta,bold]55%#[default] "),
+            "{:?}",
+            l
+        );
+        /* A CPU with no idle time is at its worst */
+        let l = format!("{}", line(&s, 1, 0, 100.0));
+        assert!(
+            l.starts_with("cpu #[fg=magenta,bold]X#[default] "),
+            "{:?}",
+            l
+        );
+
+        /* Without colours, no markup either */
+        s.smart.set(false);
+        let l = format!("{}", line(&s, 1, 0, 100.0));
+        assert_eq!(l.matches("#[").count(), 0, "{:?}", l);
+    }
+}
@@ -0,0 +1,273 @@
+/* Copyright 2022 Romain "Artefact2" Dal Maso <romain.dalmaso@artefact2.com>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *	   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/* A minimal io_uring, only for reading many small files at once. See include/uapi/linux/io_uring.h
+ * and io_uring_setup(2). XXX: liburing does all this, but pulling a C library for a dozen
+ * syscalls is not worth it */
+
+use crate::common::FileDescriptor;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// How many reads are submitted at once, the callers split bigger batches
+pub const ENTRIES: usize = 256;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+/// IORING_OP_READ and this feature were both added in Linux 5.6
+const IORING_FEAT_RW_CUR_POS: u32 = 1 << 3;
+const IORING_OP_READ: u8 = 22;
+const IORING_ENTER_GETEVENTS: libc::c_uint = 1;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+/// struct io_uring_sqe, with only the fields a read needs
+#[repr(C)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    pad: [u64; 3],
+}
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A mmap()ed region, unmapped on drop
+struct Mapping(*mut u8, usize);
+
+impl Mapping {
+    fn new(fd: libc::c_int, len: usize, offset: libc::off_t) -> Option<Mapping> {
+        let p = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if p == libc::MAP_FAILED {
+            return None;
+        }
+        Some(Mapping(p as *mut u8, len))
+    }
+
+    /// Safety: off must come from the kernel (struct io_uring_params)
+    unsafe fn at<T>(&self, off: u32) -> *mut T {
+        self.0.add(off as usize) as *mut T
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.0 as *mut libc::c_void, self.1);
+        }
+    }
+}
+
+pub struct Ring {
+    /* The mappings are unmapped before the ring is closed */
+    sq: Mapping,
+    cq: Mapping,
+    sqes: Mapping,
+    params: Params,
+    fd: FileDescriptor,
+    /// Result of each read of the last batch, in submission order
+    res: Vec<i32>,
+}
+
+impl Ring {
+    /// None if the kernel is too old, or io_uring is disabled (kernel.io_uring_disabled, seccomp
+    /// filters of containers, etc.)
+    pub fn new() -> Option<Ring> {
+        let mut params: Params = Default::default();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                ENTRIES as u32,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return None;
+        }
+        let fd = FileDescriptor(fd as libc::c_int);
+        if params.features & IORING_FEAT_RW_CUR_POS == 0 {
+            return None;
+        }
+
+        let sq = Mapping::new(
+            fd.0,
+            params.sq_off.array as usize + params.sq_entries as usize * 4,
+            IORING_OFF_SQ_RING,
+        )?;
+        let cq = Mapping::new(
+            fd.0,
+            params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>(),
+            IORING_OFF_CQ_RING,
+        )?;
+        let sqes = Mapping::new(
+            fd.0,
+            params.sq_entries as usize * std::mem::size_of::<Sqe>(),
+            IORING_OFF_SQES,
+        )?;
+
+        /* Submission queue entries are always used in order, the indirection array never
+         * changes */
+        unsafe {
+            let array = sq.at::<u32>(params.sq_off.array);
+            for i in 0..params.sq_entries {
+                *array.add(i as usize) = i;
+            }
+        }
+
+        Some(Ring {
+            sq,
+            cq,
+            sqes,
+            params,
+            fd,
+            res: Vec::with_capacity(ENTRIES),
+        })
+    }
+
+    /// Read the beginning of each file, like pread(fds[i], buf[i * slot..], slot, 0), for at most
+    /// ENTRIES files. Returns what each pread() would have returned, or -errno. None if the
+    /// submission itself failed: the ring should not be used again.
+    pub fn read_all(&mut self, fds: &[libc::c_int], buf: &mut [u8], slot: usize) -> Option<&[i32]> {
+        assert!(fds.len() <= self.params.sq_entries as usize);
+        assert!(buf.len() >= fds.len() * slot);
+
+        let n = fds.len() as u32;
+        unsafe {
+            let sq_tail = &*self.sq.at::<AtomicU32>(self.params.sq_off.tail);
+            let sq_mask = *self.sq.at::<u32>(self.params.sq_off.ring_mask);
+            let sqes = self.sqes.at::<Sqe>(0);
+            let tail = sq_tail.load(Ordering::Relaxed);
+            for (i, fd) in fds.iter().enumerate() {
+                *sqes.add((tail.wrapping_add(i as u32) & sq_mask) as usize) = Sqe {
+                    opcode: IORING_OP_READ,
+                    flags: 0,
+                    ioprio: 0,
+                    fd: *fd,
+                    off: 0,
+                    addr: buf[i * slot..].as_mut_ptr() as u64,
+                    len: slot as u32,
+                    rw_flags: 0,
+                    user_data: i as u64,
+                    pad: [0; 3],
+                };
+            }
+            sq_tail.store(tail.wrapping_add(n), Ordering::Release);
+        }
+
+        self.res.clear();
+        self.res.resize(fds.len(), 0);
+        let (mut submitted, mut completed) = (0, 0);
+        while completed < n {
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd.0,
+                    n - submitted,
+                    n - completed,
+                    IORING_ENTER_GETEVENTS,
+                    std::ptr::null::<libc::sigset_t>(),
+                    0,
+                )
+            };
+            if ret < 0 {
+                if unsafe { *libc::__errno_location() } == libc::EINTR {
+                    continue;
+                }
+                return None;
+            }
+            submitted += ret as u32;
+
+            /* Completions come in any order, user_data tells which read it was */
+            unsafe {
+                let cq_head = &*self.cq.at::<AtomicU32>(self.params.cq_off.head);
+                let cq_tail = &*self.cq.at::<AtomicU32>(self.params.cq_off.tail);
+                let cq_mask = *self.cq.at::<u32>(self.params.cq_off.ring_mask);
+                let cqes = self.cq.at::<Cqe>(self.params.cq_off.cqes);
+                let mut head = cq_head.load(Ordering::Relaxed);
+                let tail = cq_tail.load(Ordering::Acquire);
+                while head != tail {
+                    let cqe = &*cqes.add((head & cq_mask) as usize);
+                    self.res[cqe.user_data as usize] = cqe.res;
+                    head = head.wrapping_add(1);
+                    completed += 1;
+                }
+                cq_head.store(head, Ordering::Release);
+            }
+        }
+
+        Some(&self.res)
+    }
+}